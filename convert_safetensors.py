@@ -16,6 +16,11 @@ parser.add_argument(
     default="./converted.st",
     help="Path to output safetensors model",
 )
+parser.add_argument(
+    "--hash",
+    action="store_true",
+    help="Record per-tensor content hashes for integrity checks at load time (slow)",
+)
 args = parser.parse_args()
 
 
@@ -26,7 +31,16 @@ def rename_key(rename, name):
     return name
 
 
-def convert_file(pt_filename: str, sf_filename: str, rename={}, transpose_names=[]):
+# 64-bit FNV-1a of the raw tensor bytes, matching `Loader::hash_tensors` in `src/runtime/loader.rs`
+def fnv1a_64(data: bytes) -> str:
+    value = 0xCBF29CE484222325
+    for byte in data:
+        value ^= byte
+        value = (value * 0x100000001B3) & 0xFFFFFFFFFFFFFFFF
+    return f"{value:016x}"
+
+
+def convert_file(pt_filename: str, sf_filename: str, rename={}, transpose_names=[], hash_tensors=False):
     loaded: collections.OrderedDict = torch.load(pt_filename, map_location="cpu")
     if "state_dict" in loaded:
         loaded = loaded["state_dict"]
@@ -44,6 +58,8 @@ def convert_file(pt_filename: str, sf_filename: str, rename={}, transpose_names=
                     version = max(5.2, version)
         if "time_maa" in x:
             version = max(6, version)
+        if "att.x_r" in x:
+            version = max(7, version)
 
     print(f"Model detected: v{version:.1f}")
 
@@ -56,6 +72,7 @@ def convert_file(pt_filename: str, sf_filename: str, rename={}, transpose_names=
                     loaded[k].unsqueeze(1).repeat(1, n_emb // loaded[k].shape[0])
                 )
 
+    metadata = {"format": "pt"}
     with torch.no_grad():
         for k in kk:
             new_k = rename_key(rename, k).lower()
@@ -66,15 +83,18 @@ def convert_file(pt_filename: str, sf_filename: str, rename={}, transpose_names=
                     dims = len(v.shape)
                     v = v.transpose(dims - 2, dims - 1)
             print(f"{new_k}\t{v.shape}\t{v.dtype}")
+            data = v.numpy().tobytes()
+            if hash_tensors:
+                metadata[f"hash.{new_k}"] = fnv1a_64(data)
             loaded[new_k] = {
                 "dtype": str(v.dtype).split(".")[-1],
                 "shape": v.shape,
-                "data": v.numpy().tobytes(),
+                "data": data,
             }
 
     dirname = os.path.dirname(sf_filename)
     os.makedirs(dirname, exist_ok=True)
-    serialize_file(loaded, sf_filename, metadata={"format": "pt"})
+    serialize_file(loaded, sf_filename, metadata=metadata)
     # reloaded = load_file(sf_filename)
     # for k in loaded:
     #     pt_tensor = torch.Tensor(
@@ -92,5 +112,7 @@ if __name__ == "__main__":
     convert_file(args.input, args.output,
                  rename={"time_faaaa": "time_first", "time_maa": "time_mix",
                          "lora_A": "lora.0", "lora_B": "lora.1"},
-                 transpose_names=["time_mix_w1", "time_mix_w2", "time_decay_w1", "time_decay_w2", "time_state", "lora.0"])
+                 transpose_names=["time_mix_w1", "time_mix_w2", "time_decay_w1", "time_decay_w2", "time_state", "lora.0",
+                                  "att.w1", "att.w2", "att.a1", "att.a2", "att.v1", "att.v2", "att.g1", "att.g2"],
+                 hash_tensors=args.hash)
     print(f"Saved to {args.output}")
@@ -14,9 +14,9 @@ use half::f16;
 use itertools::Itertools;
 use memmap2::Mmap;
 use safetensors::SafeTensors;
-use serde::Deserialize;
 use web_rwkv::{
     context::{Context, ContextBuilder, InstanceExt},
+    interactive::{Command, Prompt},
     model::{
         loader::{Loader, Lora},
         v4, v5, v6, Build, BuildFuture, ContextAutoLimits, Model, ModelBuilder, ModelInfo,
@@ -172,17 +172,7 @@ fn load_prompt(path: Option<PathBuf>) -> Result<Prompt> {
             reader.read_to_string(&mut contents)?;
             Ok(serde_json::from_str(&contents)?)
         }
-        None => Ok(Prompt {
-            user: String::from("User"),
-            bot: String::from("Assistant"),
-            intro: String::new(),
-            text: vec![
-                [
-                    String::from("Hi!"),
-                    String::from("Hello! I'm your AI assistant. I'm here to help you with various tasks, such as answering questions, brainstorming ideas, drafting emails, writing code, providing advice, and much more.")
-                ]
-            ],
-        }),
+        None => Ok(Prompt::default()),
     }
 }
 
@@ -276,15 +266,14 @@ where
 {
     let user = &prompt.user;
     let bot = &prompt.bot;
-    let prompt = prompt.build();
 
     let mut tokens = vec![ModelInput {
-        tokens: tokenizer.encode(prompt.as_bytes())?,
+        tokens: tokenizer.encode(prompt.build().as_bytes())?,
         ..Default::default()
     }];
 
-    println!("\n\nInstructions:\n\n+: Alternative reply\n-: Exit chatting\n\n------------");
-    print!("{}", prompt);
+    println!("\n\nInstructions:\n\n{}\n\n------------", Command::HELP);
+    print!("{}", prompt.build());
     std::io::stdout().flush()?;
 
     // run initial prompt
@@ -299,39 +288,59 @@ where
     let mut backed = state.back().await;
     let mut last_user_text = String::from("Hi!");
     let mut last_tokens = vec![];
+    let mut saved = HashMap::new();
 
     loop {
         let mut model_text = String::new();
-        let mut user_text = String::new();
         let mut occurrences = HashMap::new();
 
         print!("{}: ", user);
         std::io::stdout().flush()?;
 
-        while user_text.is_empty() {
-            std::io::stdin().read_line(&mut user_text)?;
-            user_text = user_text.trim().into();
-        }
-
-        if &user_text == "-" {
-            break;
-        } else if &user_text == "+" {
-            state.load(&backed)?;
-            user_text.clone_from(&last_user_text);
-            tokens.clone_from(&last_tokens);
-        } else {
-            backed = state.back().await;
-            last_user_text.clone_from(&user_text);
-            last_tokens.clone_from(&tokens);
-        }
+        let mut line = String::new();
+        let command = loop {
+            line.clear();
+            std::io::stdin().read_line(&mut line)?;
+            if let Some(command) = Command::parse(&line) {
+                break command;
+            }
+        };
+
+        let user_text = match command {
+            Command::Exit => break,
+            Command::Retry => {
+                state.load(&backed)?;
+                tokens.clone_from(&last_tokens);
+                last_user_text.clone()
+            }
+            Command::Save(name) => {
+                saved.insert(name, (state.back().await, tokens.clone()));
+                continue;
+            }
+            Command::Load(name) => {
+                match saved.get(&name) {
+                    Some((backed, saved_tokens)) => {
+                        state.load(backed)?;
+                        tokens.clone_from(saved_tokens);
+                    }
+                    None => println!("there is no chat saved as {name}\n"),
+                }
+                continue;
+            }
+            Command::Message(text) => {
+                backed = state.back().await;
+                last_user_text.clone_from(&text);
+                last_tokens.clone_from(&tokens);
+                text
+            }
+        };
 
         print!("\n{}:", bot);
         std::io::stdout().flush()?;
 
-        let prompt = format!("{user}: {user_text}\n\n{bot}:");
         tokens[0]
             .tokens
-            .append(&mut tokenizer.encode(prompt.as_bytes())?);
+            .append(&mut tokenizer.encode(prompt.turn(&user_text).as_bytes())?);
 
         loop {
             let mut logits = loop {
@@ -365,7 +374,7 @@ where
                 let count = occurrences.get(&token).unwrap_or(&1);
                 occurrences.insert(token, *count);
 
-                if token == 0 || model_text.contains("\n\n") {
+                if token == 0 || Prompt::is_reply_end(&model_text) {
                     break;
                 }
             }
@@ -416,34 +425,6 @@ struct Cli {
     adapter: bool,
 }
 
-#[derive(Debug, Deserialize)]
-struct Prompt {
-    user: String,
-    bot: String,
-    intro: String,
-    text: Vec<[String; 2]>,
-}
-
-impl Prompt {
-    fn build(&self) -> String {
-        let user = self.user.trim();
-        let bot = self.bot.trim();
-        let intro = self.intro.trim();
-        let text = self
-            .text
-            .iter()
-            .map(|turn| {
-                let user_text = turn[0].trim();
-                let bot_text = turn[1].trim();
-                format!("{user}: {user_text}\n\n{bot}: {bot_text}\n\n")
-            })
-            .join("");
-        format!("{intro}\n\n{text}")
-            .replace("{user}", user)
-            .replace("{bot}", bot)
-    }
-}
-
 #[tokio::main]
 async fn main() {
     let cli = Cli::parse();
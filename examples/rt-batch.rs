@@ -31,7 +31,7 @@ use web_rwkv::{
         loader::{Loader, Lora},
         model::{Build, ContextAutoLimits, ModelBuilder, ModelInfo, ModelVersion, Quant},
         softmax::softmax,
-        v4, v5, v6, JobRuntime,
+        v4, v5, v6, v7, JobRuntime,
     },
     tokenizer::Tokenizer,
 };
@@ -207,6 +207,11 @@ async fn main() -> Result<()> {
             let builder = v6::ModelRuntime::<f16>::new(model, cli.batch);
             JobRuntime::new(builder).await
         }
+        ModelVersion::V7 => {
+            let model = Build::<v7::Model>::build(builder).await?;
+            let builder = v7::ModelRuntime::<f16>::new(model, cli.batch);
+            JobRuntime::new(builder).await
+        }
     };
 
     #[cfg(not(debug_assertions))]
@@ -8,24 +8,21 @@ use half::f16;
 use itertools::Itertools;
 use memmap2::Mmap;
 use safetensors::SafeTensors;
-use serde::{Deserialize, Serialize};
 use tokio::{
     fs::File,
     io::{AsyncReadExt, BufReader},
 };
 use web_rwkv::{
     context::{Context, ContextBuilder, InstanceExt},
+    interactive::{Chat, Command, Prompt},
     runtime::{
-        infer::{InferInput, InferInputBatch, InferOption},
         loader::{Loader, Lora},
         model::{
             Build, ContextAutoLimits, ModelBuilder, ModelInfo, ModelRuntime, ModelVersion, Quant,
             State,
         },
-        softmax::softmax_one,
-        v4, v5, v6, JobRuntime,
+        v4, v5, v6, v7, JobRuntime,
     },
-    tensor::{TensorCpu, TensorInit, TensorShape},
     tokenizer::Tokenizer,
 };
 
@@ -79,17 +76,7 @@ async fn load_prompt(path: Option<PathBuf>) -> Result<Prompt> {
             reader.read_to_string(&mut contents).await?;
             Ok(serde_json::from_str(&contents)?)
         }
-        None => Ok(Prompt {
-            user: String::from("User"),
-            bot: String::from("Assistant"),
-            intro: String::new(),
-            text: vec![
-                [
-                    String::from("Hi!"),
-                    String::from("Hello! I'm your AI assistant. I'm here to help you with various tasks, such as answering questions, brainstorming ideas, drafting emails, writing code, providing advice, and much more.")
-                ]
-            ],
-        }),
+        None => Ok(Prompt::default()),
     }
 }
 
@@ -134,34 +121,6 @@ struct Cli {
     sampler: Sampler,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Prompt {
-    user: String,
-    bot: String,
-    intro: String,
-    text: Vec<[String; 2]>,
-}
-
-impl Prompt {
-    fn build(&self) -> String {
-        let user = self.user.trim();
-        let bot = self.bot.trim();
-        let intro = self.intro.trim();
-        let text = self
-            .text
-            .iter()
-            .map(|turn| {
-                let user_text = turn[0].trim();
-                let bot_text = turn[1].trim();
-                format!("{user}: {user_text}\n\n{bot}: {bot_text}\n\n")
-            })
-            .join("");
-        format!("{intro}\n\n{text}")
-            .replace("{user}", user)
-            .replace("{bot}", bot)
-    }
-}
-
 #[derive(Debug, Clone, Args)]
 struct Sampler {
     #[arg(long, default_value_t = 0.5)]
@@ -258,7 +217,7 @@ async fn main() -> Result<()> {
         None => builder,
     };
 
-    let (runtime, state): (_, Box<dyn State>) = match info.version {
+    let (runtime, state): (_, Box<dyn State + Send + Sync>) = match info.version {
         ModelVersion::V4 => {
             let model = Build::<v4::Model>::build(builder).await?;
             let builder = v4::ModelRuntime::<f16>::new(model, 1);
@@ -277,110 +236,65 @@ async fn main() -> Result<()> {
             let state = builder.state();
             (JobRuntime::new(builder).await, Box::new(state))
         }
+        ModelVersion::V7 => {
+            let model = Build::<v7::Model>::build(builder).await?;
+            let builder = v7::ModelRuntime::<f16>::new(model, 1);
+            let state = builder.state();
+            (JobRuntime::new(builder).await, Box::new(state))
+        }
     };
 
     // run initial prompt
     let prompt = load_prompt(cli.prompt).await?;
-    let mut inference = InferInput::new(
-        vec![InferInputBatch {
-            tokens: tokenizer.encode(prompt.build().as_bytes())?,
-            option: InferOption::Last,
-        }],
+    let mut chat = Chat::new(
+        &context,
+        runtime,
+        state,
+        tokenizer,
+        prompt,
         cli.token_chunk_size,
-    );
-
-    println!("\n\nInstructions:\n\n+: Alternative reply\n-: Exit chatting\n\n------------");
-
-    loop {
-        let input = inference.clone();
-        let (input, output) = runtime.infer(input).await;
-        inference = input;
+    )?;
+    chat.start().await?;
 
-        if output[0].size() > 0 {
-            assert_eq!(inference.batches[0].tokens.len(), 0);
-            break;
-        }
-    }
-
-    print!("{}", prompt.build());
+    println!("\n\nInstructions:\n\n{}\n\n------------", Command::HELP);
+    print!("{}", chat.prompt().build());
     std::io::stdout().flush()?;
 
-    // read back initial state
-    let mut backed = state.back(0).await?;
-    let mut last_user_text = String::from("Hi!");
-    let mut last_tokens = vec![];
+    let sample = |probs: &[f32]| cli.sampler.sample(probs);
+    let print = |text: &str| {
+        print!("{text}");
+        let _ = std::io::stdout().flush();
+    };
 
     loop {
-        let mut model_text = String::new();
-        let mut user_text = String::new();
-
-        print!("{}: ", prompt.user);
+        print!("{}: ", chat.prompt().user);
         std::io::stdout().flush()?;
 
-        while user_text.is_empty() {
-            std::io::stdin().read_line(&mut user_text)?;
-            user_text = user_text.trim().into();
-        }
-
-        match user_text.as_str() {
-            "-" => break,
-            "+" => {
-                user_text.clone_from(&last_user_text);
-                inference.batches[0] = InferInputBatch {
-                    tokens: last_tokens.clone(),
-                    option: InferOption::Last,
-                };
-                state.load(backed.clone(), 0)?;
-            }
-            _ => {
-                last_user_text.clone_from(&user_text);
-                last_tokens.clone_from(&inference.batches[0].tokens);
-                backed = state.back(0).await?;
+        let mut line = String::new();
+        let command = loop {
+            line.clear();
+            std::io::stdin().read_line(&mut line)?;
+            if let Some(command) = Command::parse(&line) {
+                break command;
             }
-        }
-
-        print!("\n{}:", prompt.bot);
-        std::io::stdout().flush()?;
-
-        let prompt = format!("{}: {}\n\n{}:", prompt.user, user_text, prompt.bot);
-        inference.batches[0]
-            .tokens
-            .append(&mut tokenizer.encode(prompt.as_bytes())?);
-
-        loop {
-            let input = inference.clone();
-            let (input, output) = runtime.infer(input).await;
-            inference = input;
-
-            let output = output[0].0.clone();
-            let shape = output.shape();
-            if output.size() == 0 {
-                // we are not finishing reading the prompt
-                continue;
+        };
+
+        let bot = chat.prompt().bot.clone();
+        let result = match command {
+            Command::Exit => break,
+            Command::Retry => {
+                print!("\n{bot}:");
+                chat.retry(sample, print).await.map(|_| ())
             }
-
-            let output = output.to_vec();
-            assert_eq!(output.len(), info.num_vocab);
-
-            let output = TensorCpu::from_data(shape, output)?;
-            let output = softmax_one(&context, output).await?;
-
-            let token = cli.sampler.sample(&output);
-            let decoded = tokenizer.decode(&[token])?;
-            let word = String::from_utf8_lossy(&decoded);
-
-            model_text += &word;
-            print!("{}", word);
-            std::io::stdout().flush()?;
-
-            inference.batches[0] = InferInputBatch {
-                tokens: vec![token],
-                option: InferOption::Last,
-            };
-
-            if model_text.contains("\n\n") {
-                break;
+            Command::Save(name) => chat.save(name).await,
+            Command::Load(name) => chat.load(&name),
+            Command::Message(text) => {
+                print!("\n{bot}:");
+                chat.reply(&text, sample, print).await.map(|_| ())
             }
+        };
+        if let Err(err) = result {
+            println!("{err}\n");
         }
     }
 
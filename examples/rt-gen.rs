@@ -23,7 +23,7 @@ use web_rwkv::{
         loader::{Loader, Lora},
         model::{Build, ContextAutoLimits, ModelBuilder, ModelInfo, ModelVersion, Quant},
         softmax::softmax_one,
-        v4, v5, v6, JobRuntime,
+        v4, v5, v6, v7, JobRuntime,
     },
     tokenizer::Tokenizer,
 };
@@ -187,6 +187,11 @@ async fn main() -> Result<()> {
             let builder = v6::ModelRuntime::<f16>::new(model, 1);
             JobRuntime::new(builder).await
         }
+        ModelVersion::V7 => {
+            let model = Build::<v7::Model>::build(builder).await?;
+            let builder = v7::ModelRuntime::<f16>::new(model, 1);
+            JobRuntime::new(builder).await
+        }
     };
 
     // const PROMPT: &str = "User: Hi!\n\nAssistant: Hello! I'm your AI assistant. I'm here to help you with various tasks, such as answering questions, brainstorming ideas, drafting emails, writing code, providing advice, and much more.\n\nUser: Hi!\n\nAssistant:";
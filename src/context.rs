@@ -7,9 +7,9 @@ use web_rwkv_derive::{Deref, DerefMut};
 use wgpu::{
     util::{BufferInitDescriptor, DeviceExt},
     Adapter, BindGroupLayout, BindGroupLayoutDescriptor, BindGroupLayoutEntry, Buffer,
-    BufferDescriptor, BufferUsages, ComputePipeline, ComputePipelineDescriptor, Device,
-    DeviceDescriptor, Features, Instance, Limits, PipelineLayoutDescriptor, PowerPreference, Queue,
-    RequestAdapterOptions, ShaderModuleDescriptor,
+    BufferDescriptor, BufferUsages, CommandBuffer, ComputePipeline, ComputePipelineDescriptor,
+    Device, DeviceDescriptor, ErrorFilter, Features, Instance, Limits, PipelineLayoutDescriptor,
+    PowerPreference, Queue, RequestAdapterOptions, ShaderModuleDescriptor,
 };
 
 use crate::tensor::{
@@ -60,12 +60,31 @@ pub struct ContextInternal {
     shape_cache: ResourceCache<View, Buffer>,
     buffer_cache: ResourceCache<BufferKey, Buffer>,
 
+    max_dispatch: Option<usize>,
+    single_submission: bool,
+
     #[cfg(not(target_arch = "wasm32"))]
     event: flume::Sender<ContextEvent>,
 }
 
-#[derive(Debug, Clone, Deref, DerefMut)]
-pub struct Context(Arc<ContextInternal>);
+/// A handle to the GPU device. Clones share the device and caches, while buffers checked out
+/// through each handle are attributed to its [`MemoryKind`] in the [`MemoryReport`].
+#[derive(Debug, Clone)]
+pub struct Context(Arc<ContextInternal>, MemoryKind);
+
+impl std::ops::Deref for Context {
+    type Target = Arc<ContextInternal>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl std::ops::DerefMut for Context {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
 
 #[cfg(not(target_arch = "wasm32"))]
 impl Drop for Context {
@@ -81,6 +100,8 @@ pub struct ContextBuilder {
     pub adapter: Adapter,
     pub features: Features,
     pub limits: Limits,
+    pub max_dispatch: Option<usize>,
+    pub single_submission: bool,
 }
 
 #[wasm_bindgen]
@@ -92,6 +113,48 @@ pub enum CreateEnvironmentError {
     RequestDeviceFailed,
 }
 
+/// The subsystem a GPU buffer is allocated for. See [`Context::with_memory_kind`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MemoryKind {
+    /// Model weights.
+    Weights,
+    /// Recurrent states.
+    State,
+    /// Runtime buffers of jobs and everything else.
+    #[default]
+    Scratch,
+}
+
+/// Bytes of GPU buffers currently allocated by a [`Context`], grouped by subsystem.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryReport {
+    /// Storage buffers in use holding model weights.
+    pub weights: u64,
+    /// Storage buffers in use holding recurrent states.
+    pub state: u64,
+    /// Storage buffers in use for runtime scratch, e.g., activations of jobs.
+    pub scratch: u64,
+    /// Uniform buffers in use, holding tensor shapes and views.
+    pub uniform: u64,
+    /// Staging buffers in use for reading back to the CPU.
+    pub staging: u64,
+    /// Buffers no longer in use but kept in the caches for reuse.
+    pub idle: u64,
+}
+
+impl MemoryReport {
+    /// Total bytes of all buffers allocated.
+    pub fn total(&self) -> u64 {
+        self.weights + self.state + self.scratch + self.uniform + self.staging + self.idle
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum ContextError {
+    #[error("out of GPU memory")]
+    OutOfMemory,
+}
+
 impl<'a> ContextBuilder {
     pub fn new(adapter: Adapter) -> Self {
         let features = Features::empty();
@@ -101,6 +164,8 @@ impl<'a> ContextBuilder {
             adapter,
             features,
             limits: Default::default(),
+            max_dispatch: None,
+            single_submission: false,
         }
     }
 
@@ -109,6 +174,8 @@ impl<'a> ContextBuilder {
             adapter,
             features,
             limits,
+            max_dispatch,
+            single_submission,
         } = self;
 
         let (device, queue) = adapter
@@ -134,10 +201,12 @@ impl<'a> ContextBuilder {
             pipeline_cache: Default::default(),
             shape_cache: Default::default(),
             buffer_cache: ResourceCache::new(2),
+            max_dispatch,
+            single_submission,
             #[cfg(not(target_arch = "wasm32"))]
             event,
         });
-        let context = Context(context);
+        let context = Context(context, MemoryKind::default());
 
         // start a thread for reading back buffers
         #[cfg(not(target_arch = "wasm32"))]
@@ -182,6 +251,21 @@ impl<'a> ContextBuilder {
         f(&mut self.features);
         self
     }
+
+    /// Limit the number of dispatches recorded into one compute pass, and submit each pass on its own.
+    /// Use this on platforms (e.g., browsers, some drivers) whose GPU watchdog resets long-running submissions.
+    pub fn max_dispatch(mut self, value: usize) -> Self {
+        self.max_dispatch = Some(value.max(1));
+        self
+    }
+
+    /// Record all commands of a job into one compute pass and one command buffer, ignoring the separators
+    /// between layer chunks. This reduces submission overhead on backends where it is expensive (e.g., browsers).
+    /// Has no effect if [`max_dispatch`](Self::max_dispatch) is set.
+    pub fn single_submission(mut self, value: bool) -> Self {
+        self.single_submission = value;
+        self
+    }
 }
 
 /// A container of macro definitions in shader.
@@ -232,11 +316,75 @@ impl PartialEq for Context {
 struct BufferKey {
     size: usize,
     usage: BufferUsages,
+    kind: MemoryKind,
 }
 
 impl Eq for Context {}
 
+impl Context {
+    /// A handle to the same device whose buffers are attributed to `kind` in the [`MemoryReport`].
+    pub fn with_memory_kind(&self, kind: MemoryKind) -> Self {
+        Self(self.0.clone(), kind)
+    }
+
+    #[inline]
+    pub fn memory_kind(&self) -> MemoryKind {
+        self.1
+    }
+
+    pub(crate) fn checkout_buffer_init(&self, contents: &[u8], usage: BufferUsages) -> Arc<Buffer> {
+        let size = std::mem::size_of_val(contents);
+        let key = BufferKey {
+            size,
+            usage,
+            kind: self.1,
+        };
+        let desc = BufferInitDescriptor {
+            label: None,
+            contents,
+            usage,
+        };
+        self.buffer_cache.checkout(
+            key,
+            || self.device.create_buffer_init(&desc),
+            |buffer| self.queue.write_buffer(buffer, 0, contents),
+        )
+    }
+
+    pub(crate) fn checkout_buffer(&self, size: usize, usage: BufferUsages) -> Arc<Buffer> {
+        let key = BufferKey {
+            size,
+            usage,
+            kind: self.1,
+        };
+        let desc = BufferDescriptor {
+            label: None,
+            size: size as u64,
+            usage,
+            mapped_at_creation: false,
+        };
+        self.buffer_cache
+            .checkout(key, || self.device.create_buffer(&desc), |_| {})
+    }
+}
+
 impl ContextInternal {
+    /// Run `future`, catching GPU out-of-memory errors raised by it (e.g., when creating buffers)
+    /// as [`ContextError::OutOfMemory`] instead of the panic of wgpu's default error handler.
+    /// Callers can then retry with a smaller setup, e.g., fewer batches or a CPU embed.
+    ///
+    /// Error scopes belong to the whole device, so the context must be idle otherwise while
+    /// `future` runs: errors of other tasks or threads would be caught here, and `future` must not
+    /// yield to tasks using the same device. Keep the scope to the allocations themselves.
+    pub async fn catch_oom<T>(&self, future: impl Future<Output = T>) -> Result<T, ContextError> {
+        self.device.push_error_scope(ErrorFilter::OutOfMemory);
+        let output = future.await;
+        match self.device.pop_error_scope().await {
+            Some(_) => Err(ContextError::OutOfMemory),
+            None => Ok(output),
+        }
+    }
+
     pub fn checkout_pipeline(
         &self,
         name: impl AsRef<str>,
@@ -318,31 +466,51 @@ impl ContextInternal {
             .checkout(view, || self.device.create_buffer_init(&desc), |_| {})
     }
 
-    pub(crate) fn checkout_buffer_init(&self, contents: &[u8], usage: BufferUsages) -> Arc<Buffer> {
-        let size = std::mem::size_of_val(contents);
-        let key = BufferKey { size, usage };
-        let desc = BufferInitDescriptor {
-            label: None,
-            contents,
-            usage,
-        };
-        self.buffer_cache.checkout(
-            key,
-            || self.device.create_buffer_init(&desc),
-            |buffer| self.queue.write_buffer(buffer, 0, contents),
-        )
+    /// Report the sizes of GPU buffers currently allocated.
+    pub fn memory_report(&self) -> MemoryReport {
+        let mut report = MemoryReport::default();
+        self.shape_cache.for_each(|_, buffer, used| match used {
+            true => report.uniform += buffer.size(),
+            false => report.idle += buffer.size(),
+        });
+        self.buffer_cache.for_each(|key, buffer, used| {
+            let size = buffer.size();
+            match (used, key.kind) {
+                (false, _) => report.idle += size,
+                _ if key.usage.contains(BufferUsages::MAP_READ) => report.staging += size,
+                (true, MemoryKind::Weights) => report.weights += size,
+                (true, MemoryKind::State) => report.state += size,
+                (true, MemoryKind::Scratch) => report.scratch += size,
+            }
+        });
+        report
     }
 
-    pub(crate) fn checkout_buffer(&self, size: usize, usage: BufferUsages) -> Arc<Buffer> {
-        let key = BufferKey { size, usage };
-        let desc = BufferDescriptor {
-            label: None,
-            size: size as u64,
-            usage,
-            mapped_at_creation: false,
-        };
-        self.buffer_cache
-            .checkout(key, || self.device.create_buffer(&desc), |_| {})
+    /// Maximum number of dispatches in one compute pass, if passes are split.
+    #[inline]
+    pub fn max_dispatch(&self) -> Option<usize> {
+        self.max_dispatch
+    }
+
+    /// Whether separators between layer chunks are ignored when encoding.
+    #[inline]
+    pub fn single_submission(&self) -> bool {
+        self.single_submission && self.max_dispatch.is_none()
+    }
+
+    /// Submit command buffers to the queue.
+    /// If passes are split by [`ContextBuilder::max_dispatch`], each one is submitted separately.
+    pub fn submit(&self, commands: Vec<CommandBuffer>) {
+        match self.max_dispatch {
+            Some(_) => {
+                for command in commands {
+                    self.queue.submit(Some(command));
+                }
+            }
+            None => {
+                self.queue.submit(commands);
+            }
+        }
     }
 
     #[inline]
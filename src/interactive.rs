@@ -0,0 +1,332 @@
+//! Building blocks of interactive chat frontends, shared by the TUI examples and meant for
+//! GUI frontends alike: prompt templates, user commands, and (with the `runtime` feature)
+//! a [`Chat`] session that streams replies and saves and loads chat states.
+
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+/// A chat prompt: an intro followed by example turns between the user and the bot.
+/// `{user}` and `{bot}` in the text are replaced by their names.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Prompt {
+    pub user: String,
+    pub bot: String,
+    pub intro: String,
+    pub text: Vec<[String; 2]>,
+}
+
+impl Default for Prompt {
+    fn default() -> Self {
+        Self {
+            user: String::from("User"),
+            bot: String::from("Assistant"),
+            intro: String::new(),
+            text: vec![
+                [
+                    String::from("Hi!"),
+                    String::from("Hello! I'm your AI assistant. I'm here to help you with various tasks, such as answering questions, brainstorming ideas, drafting emails, writing code, providing advice, and much more.")
+                ]
+            ],
+        }
+    }
+}
+
+impl Prompt {
+    /// The full text of the prompt, to be read before the first turn.
+    pub fn build(&self) -> String {
+        let user = self.user.trim();
+        let bot = self.bot.trim();
+        let intro = self.intro.trim();
+        let text = self
+            .text
+            .iter()
+            .map(|turn| {
+                let user_text = turn[0].trim();
+                let bot_text = turn[1].trim();
+                format!("{user}: {user_text}\n\n{bot}: {bot_text}\n\n")
+            })
+            .join("");
+        format!("{intro}\n\n{text}")
+            .replace("{user}", user)
+            .replace("{bot}", bot)
+    }
+
+    /// The text of a user's turn, ending where the bot starts to reply.
+    pub fn turn(&self, user_text: &str) -> String {
+        let user = self.user.trim();
+        let bot = self.bot.trim();
+        format!("{user}: {user_text}\n\n{bot}:")
+    }
+
+    /// Check if a reply is complete, i.e., the bot has ended its turn.
+    pub fn is_reply_end(reply: &str) -> bool {
+        reply.contains("\n\n")
+    }
+}
+
+/// A line of user input in a chat.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Exit chatting.
+    Exit,
+    /// Discard the last reply and generate another one.
+    Retry,
+    /// Save the chat under a name.
+    Save(String),
+    /// Go back to a chat saved under a name.
+    Load(String),
+    /// Send a message to the bot.
+    Message(String),
+}
+
+impl Command {
+    /// Help text listing the commands, to be shown to the user.
+    pub const HELP: &'static str = "+: Alternative reply\n-: Exit chatting\n/save <name>: Save the chat\n/load <name>: Go back to a saved chat";
+
+    /// Parse a line of user input, returning `None` if it is blank.
+    pub fn parse(line: &str) -> Option<Self> {
+        let line = line.trim();
+        let command = match line {
+            "" => return None,
+            "-" => Self::Exit,
+            "+" => Self::Retry,
+            line => match line.split_once(char::is_whitespace) {
+                Some(("/save", name)) => Self::Save(name.trim().into()),
+                Some(("/load", name)) => Self::Load(name.trim().into()),
+                _ => Self::Message(line.into()),
+            },
+        };
+        Some(command)
+    }
+}
+
+#[cfg(feature = "runtime")]
+pub use chat::Chat;
+
+#[cfg(feature = "runtime")]
+mod chat {
+    use std::collections::HashMap;
+
+    use anyhow::Result;
+
+    use super::Prompt;
+    use crate::{
+        context::Context,
+        runtime::{
+            infer::{InferInput, InferInputBatch, InferOption, InferOutput},
+            model::State,
+            softmax::softmax_one,
+            JobRuntime,
+        },
+        tensor::TensorCpu,
+        tokenizer::Tokenizer,
+    };
+
+    /// The state of a chat at some point, along with the tokens not yet read into it.
+    #[derive(Debug, Clone)]
+    struct Snapshot {
+        backed: TensorCpu<f32>,
+        tokens: Vec<u16>,
+    }
+
+    /// A chat session with a bot, driving a runtime with one batch.
+    ///
+    /// Sampling is left to the caller, and generated text is streamed through a callback,
+    /// so that frontends only handle input and display.
+    pub struct Chat {
+        context: Context,
+        runtime: JobRuntime<InferInput, InferOutput>,
+        state: Box<dyn State + Send + Sync>,
+        tokenizer: Tokenizer,
+        prompt: Prompt,
+        input: InferInput,
+        /// The chat before the last user turn, and the text of that turn.
+        last: Option<(Snapshot, String)>,
+        saved: HashMap<String, Snapshot>,
+    }
+
+    impl Chat {
+        /// Create a chat over a `runtime` of one batch whose state is `state`.
+        /// Call [`Chat::start`] to read the prompt before replying.
+        pub fn new(
+            context: &Context,
+            runtime: JobRuntime<InferInput, InferOutput>,
+            state: Box<dyn State + Send + Sync>,
+            tokenizer: Tokenizer,
+            prompt: Prompt,
+            token_chunk_size: usize,
+        ) -> Result<Self> {
+            let batch = InferInputBatch {
+                tokens: tokenizer.encode(prompt.build().as_bytes())?,
+                option: InferOption::Last,
+            };
+            let input = InferInput::new(vec![batch], token_chunk_size);
+            Ok(Self {
+                context: context.clone(),
+                runtime,
+                state,
+                tokenizer,
+                prompt,
+                input,
+                last: None,
+                saved: HashMap::new(),
+            })
+        }
+
+        #[inline]
+        pub fn prompt(&self) -> &Prompt {
+            &self.prompt
+        }
+
+        /// Read the prompt into the state.
+        pub async fn start(&mut self) -> Result<()> {
+            loop {
+                let (input, output) = self.runtime.infer(self.input.clone()).await;
+                self.input = input;
+                if output[0].0.size() > 0 {
+                    break Ok(());
+                }
+            }
+        }
+
+        /// Reply to `user_text`. Tokens are drawn by `sample` from the probabilities of the next token,
+        /// and their text is passed to `on_text` as it is generated. Returns the whole reply.
+        pub async fn reply(
+            &mut self,
+            user_text: &str,
+            sample: impl FnMut(&[f32]) -> u16,
+            on_text: impl FnMut(&str),
+        ) -> Result<String> {
+            let snapshot = self.snapshot().await?;
+            self.last = Some((snapshot, user_text.into()));
+            self.generate(user_text, sample, on_text).await
+        }
+
+        /// Discard the last reply and generate another one to the same user text.
+        pub async fn retry(
+            &mut self,
+            sample: impl FnMut(&[f32]) -> u16,
+            on_text: impl FnMut(&str),
+        ) -> Result<String> {
+            let Some((snapshot, user_text)) = self.last.clone() else {
+                anyhow::bail!("there is no reply to retry");
+            };
+            self.restore(&snapshot)?;
+            self.generate(&user_text, sample, on_text).await
+        }
+
+        /// Save the chat under `name`, replacing any chat saved under it before.
+        pub async fn save(&mut self, name: impl Into<String>) -> Result<()> {
+            let snapshot = self.snapshot().await?;
+            self.saved.insert(name.into(), snapshot);
+            Ok(())
+        }
+
+        /// Go back to the chat saved under `name`.
+        pub fn load(&mut self, name: &str) -> Result<()> {
+            let Some(snapshot) = self.saved.get(name).cloned() else {
+                anyhow::bail!("there is no chat saved as {name}");
+            };
+            self.restore(&snapshot)?;
+            self.last = None;
+            Ok(())
+        }
+
+        async fn snapshot(&self) -> Result<Snapshot> {
+            let backed = self.state.back(0).await?;
+            let tokens = self.input.batches[0].tokens.clone();
+            Ok(Snapshot { backed, tokens })
+        }
+
+        fn restore(&mut self, snapshot: &Snapshot) -> Result<()> {
+            self.state.load(snapshot.backed.clone(), 0)?;
+            self.input.batches[0] = InferInputBatch {
+                tokens: snapshot.tokens.clone(),
+                option: InferOption::Last,
+            };
+            Ok(())
+        }
+
+        async fn generate(
+            &mut self,
+            user_text: &str,
+            mut sample: impl FnMut(&[f32]) -> u16,
+            mut on_text: impl FnMut(&str),
+        ) -> Result<String> {
+            let turn = self.prompt.turn(user_text);
+            self.input.batches[0]
+                .tokens
+                .append(&mut self.tokenizer.encode(turn.as_bytes())?);
+
+            let mut reply = String::new();
+            while !Prompt::is_reply_end(&reply) {
+                let (input, output) = self.runtime.infer(self.input.clone()).await;
+                self.input = input;
+
+                let output = output[0].0.clone();
+                if output.size() == 0 {
+                    // we are not finishing reading the prompt
+                    continue;
+                }
+                let probs = softmax_one(&self.context, output).await?;
+
+                let token = sample(&probs);
+                let decoded = self.tokenizer.decode(&[token])?;
+                let text = String::from_utf8_lossy(&decoded);
+                on_text(&text);
+                reply += &text;
+
+                self.input.batches[0] = InferInputBatch {
+                    tokens: vec![token],
+                    option: InferOption::Last,
+                };
+            }
+            Ok(reply)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Command, Prompt};
+
+    #[test]
+    fn test_command() {
+        assert_eq!(Command::parse("  "), None);
+        assert_eq!(Command::parse("-"), Some(Command::Exit));
+        assert_eq!(Command::parse(" + "), Some(Command::Retry));
+        assert_eq!(
+            Command::parse("/save  draft "),
+            Some(Command::Save("draft".into()))
+        );
+        assert_eq!(
+            Command::parse("/load draft"),
+            Some(Command::Load("draft".into()))
+        );
+        assert_eq!(
+            Command::parse("/saved draft"),
+            Some(Command::Message("/saved draft".into()))
+        );
+        assert_eq!(
+            Command::parse("Hi there"),
+            Some(Command::Message("Hi there".into()))
+        );
+    }
+
+    #[test]
+    fn test_prompt() {
+        let prompt = Prompt {
+            user: "Alice".into(),
+            bot: "Bob".into(),
+            intro: "{user} talks to {bot}.".into(),
+            text: vec![["Hi!".into(), "Hello.".into()]],
+        };
+        assert_eq!(
+            prompt.build(),
+            "Alice talks to Bob.\n\nAlice: Hi!\n\nBob: Hello.\n\n"
+        );
+        assert_eq!(prompt.turn("How are you?"), "Alice: How are you?\n\nBob:");
+        assert!(!Prompt::is_reply_end(" I'm fine.\n"));
+        assert!(Prompt::is_reply_end(" I'm fine.\n\n"));
+    }
+}
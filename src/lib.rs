@@ -11,7 +11,7 @@
 //! - Int8 and NF4 quantization.
 //! - Very fast.
 //! - LoRA merging at loading time.
-//! - Support RWKV V4, V5 and V6.
+//! - Support RWKV V4, V5, V6 and V7.
 //!
 //! ## Notes
 //!
@@ -35,6 +35,7 @@
 #![doc = document_features::document_features!()]
 
 pub mod context;
+pub mod interactive;
 #[cfg(feature = "vanilla")]
 pub mod model;
 pub mod num;
@@ -356,7 +356,7 @@ impl<R: Reader> Loader<R> {
             ops.push(op);
         }
 
-        context.queue.submit(context.encode(&TensorOp::List(ops)));
+        context.submit(context.encode(&TensorOp::List(ops)));
         Ok(tensor)
     }
 
@@ -393,7 +393,7 @@ impl<R: Reader> Loader<R> {
         let op = TensorOp::opposite_exp(&tensor)?;
         ops.push(op);
 
-        context.queue.submit(context.encode(&TensorOp::List(ops)));
+        context.submit(context.encode(&TensorOp::List(ops)));
         Ok(tensor)
     }
 
@@ -431,7 +431,7 @@ impl<R: Reader> Loader<R> {
         let op = TensorOp::stable_exp(&tensor)?;
         ops.push(op);
 
-        context.queue.submit(context.encode(&TensorOp::List(ops)));
+        context.submit(context.encode(&TensorOp::List(ops)));
         Ok(tensor)
     }
 
@@ -477,7 +477,7 @@ impl<R: Reader> Loader<R> {
             )?;
             ops.push(op);
 
-            context.queue.submit(context.encode(&TensorOp::List(ops)));
+            context.submit(context.encode(&TensorOp::List(ops)));
             tensor_f16
         };
         Ok(tensor)
@@ -510,7 +510,7 @@ impl<R: Reader> Loader<R> {
             ops.push(op);
         }
 
-        context.queue.submit(context.encode(&TensorOp::List(ops)));
+        context.submit(context.encode(&TensorOp::List(ops)));
         Ok(tensor)
     }
 
@@ -544,7 +544,7 @@ impl<R: Reader> Loader<R> {
             ops.push(op);
         }
 
-        context.queue.submit(context.encode(&TensorOp::List(ops)));
+        context.submit(context.encode(&TensorOp::List(ops)));
         Ok(tensor)
     }
 
@@ -577,7 +577,7 @@ impl<R: Reader> Loader<R> {
             ops.push(op);
         }
 
-        context.queue.submit(context.encode(&TensorOp::List(ops)));
+        context.submit(context.encode(&TensorOp::List(ops)));
         Ok(())
     }
 
@@ -615,7 +615,7 @@ impl<R: Reader> Loader<R> {
             ops.push(op);
         }
 
-        context.queue.submit(context.encode(&TensorOp::List(ops)));
+        context.submit(context.encode(&TensorOp::List(ops)));
         Ok(())
     }
 
@@ -639,7 +639,7 @@ impl<R: Reader> Loader<R> {
                 ops.push(op);
             }
 
-            context.queue.submit(context.encode(&TensorOp::List(ops)));
+            context.submit(context.encode(&TensorOp::List(ops)));
             Ok(tensor.back().await)
         }
     }
@@ -664,6 +664,10 @@ impl<R: Reader> Loader<R> {
 
     pub async fn load_matrix(&self, name: String, quant: Quant) -> Result<Matrix> {
         let context = &self.context;
+
+        // converting and quantizing a matrix takes a while on CPU, so yield before each one
+        tokio::task::yield_now().await;
+
         match quant {
             Quant::None => Ok(Matrix::Fp16(self.load_matrix_f16(name).await?)),
             Quant::Int8 => {
@@ -688,6 +692,10 @@ impl<R: Reader> Loader<R> {
         discount: f32,
     ) -> Result<Matrix> {
         let context = &self.context;
+
+        // converting and quantizing a matrix takes a while on CPU, so yield before each one
+        tokio::task::yield_now().await;
+
         match quant {
             Quant::None => Ok(Matrix::Fp16(
                 self.load_matrix_f16_discount(name, discount).await?,
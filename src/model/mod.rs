@@ -2,6 +2,7 @@ use std::{collections::HashMap, future::Future};
 
 use anyhow::Result;
 use half::f16;
+use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use wasm_bindgen::prelude::wasm_bindgen;
@@ -12,10 +13,10 @@ use self::{
     softmax::ModelSoftmax,
 };
 use crate::{
-    context::{Context, ContextBuilder},
+    context::{Context, ContextBuilder, MemoryKind},
     impl_deserialize_seed,
     num::Scalar,
-    tensor::TensorError,
+    tensor::{TensorCpu, TensorError, TensorShape},
 };
 
 pub mod loader;
@@ -148,6 +149,43 @@ pub trait BackedState: Serialize + for<'a> Deserialize<'a> {
     fn embed(&self, batch: usize, layer: usize) -> Vec<f32>;
 }
 
+pub trait ReferenceLayout: BackedState {
+    /// Reorder one batch of the state into the layout of the reference Python implementation,
+    /// i.e., a list of tensors ordered by layers and then by components within a layer, for comparing against it.
+    /// See [`serialize_reference_layout`] for writing the list into a file.
+    fn to_reference_layout(&self, batch: usize) -> Result<Vec<TensorCpu<f32>>, TensorError>;
+}
+
+/// Serialize a state in the reference layout (see [`ReferenceLayout::to_reference_layout`]) into safetensors,
+/// with tensors named by their indices in the list and shapes in row-major order as in PyTorch.
+pub fn serialize_reference_layout(
+    tensors: &[TensorCpu<f32>],
+) -> Result<Vec<u8>, safetensors::SafeTensorError> {
+    use safetensors::{tensor::TensorView, Dtype};
+
+    let names = (0..tensors.len())
+        .map(|index| index.to_string())
+        .collect_vec();
+    let views: Vec<_> = tensors
+        .iter()
+        .zip(names.iter())
+        .map(|(tensor, name)| {
+            let shape = tensor.shape();
+            let shape = [shape[3], shape[2], shape[1], shape[0]]
+                .into_iter()
+                .skip_while(|&dim| dim == 1)
+                .collect_vec();
+            let shape = match shape.is_empty() {
+                true => vec![1],
+                false => shape,
+            };
+            let data: &[u8] = bytemuck::cast_slice(&tensor.data()[..]);
+            TensorView::new(Dtype::F32, shape, data).map(|view| (name.as_str(), view))
+        })
+        .try_collect()?;
+    safetensors::serialize(views, &None)
+}
+
 pub trait ModelState {
     type BackedState: BackedState;
 
@@ -251,7 +289,7 @@ impl<R: Reader> ModelBuilder<R> {
 
         let info = Loader::info(&model)?;
         let loader = Loader {
-            context: context.clone(),
+            context: context.with_memory_kind(MemoryKind::Weights),
             model,
             lora,
         };
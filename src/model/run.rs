@@ -9,12 +9,10 @@ use super::{
 };
 use crate::{
     context::Context,
-    num::{CoHom, Float},
+    num::Float,
     tensor::{
-        kind::ReadWrite,
-        ops::TensorOp,
-        shape::{Shape, TensorDimension},
-        TensorCpu, TensorError, TensorGpu, TensorReshape, TensorStack,
+        kind::ReadWrite, ops::TensorOp, shape::Shape, TensorCpu, TensorError, TensorGpu,
+        TensorStack,
     },
 };
 
@@ -71,27 +69,9 @@ pub(crate) trait ModelRunInternal: ModelBase {
         embed: &TensorCpu<f16>,
         tokens: &[Vec<u16>],
     ) -> Result<TensorStack<F>, TensorError> {
-        let info = self.info();
-        let context = self.context();
-
         let input: Vec<_> = tokens
             .iter()
-            .map(|tokens| -> Result<_, TensorError> {
-                let stack = TensorCpu::stack(
-                    tokens
-                        .iter()
-                        .map(|&token| embed.slice(.., token as usize, .., ..))
-                        .try_collect()?,
-                )
-                .unwrap_or_else(|_| context.zeros([info.num_emb, 1, 0, 1]))
-                .map(|x| CoHom::co_hom(*x));
-                stack.reshape(
-                    TensorDimension::Full,
-                    TensorDimension::Auto,
-                    TensorDimension::Dimension(1),
-                    TensorDimension::Full,
-                )
-            })
+            .map(|tokens| -> Result<_, TensorError> { Ok(embed.gather(tokens)?.to_float()) })
             .try_collect()?;
         TensorStack::try_from(input)
     }
@@ -73,7 +73,7 @@ impl<M: ModelBase> ModelSoftmax for M {
         softmax.buffer.load(&input)?;
 
         let op = TensorOp::softmax(&softmax.buffer)?;
-        context.queue.submit(context.encode(&op));
+        context.submit(context.encode(&op));
 
         let output = softmax.buffer.back().await;
         Ok(redirect
@@ -21,7 +21,7 @@ use crate::{
         matrix::Matrix,
         ops::{Activation, TensorCommand, TensorOp},
         shape::Shape,
-        DeepClone, IntoPackedCursors, TensorCpu, TensorError, TensorGpu, TensorGpuView,
+        DeepClone, IntoPackedCursors, TensorCpu, TensorError, TensorGpu, TensorGpuView, TensorInit,
         TensorShape,
     },
 };
@@ -328,7 +328,7 @@ impl super::ModelState for ModelState {
             self.view(.., .., from_batch, ..)?,
             other.view(.., .., to_batch, ..)?,
         )?;
-        context.queue.submit(context.encode(&op));
+        context.submit(context.encode(&op));
         Ok(())
     }
 }
@@ -391,6 +391,29 @@ impl super::BackedState for BackedState {
     }
 }
 
+impl super::ReferenceLayout for BackedState {
+    /// The reference layout has 5 vectors per layer: `ffn_x`, `att_x`, `aa`, `bb` and `pp`.
+    fn to_reference_layout(&self, batch: usize) -> Result<Vec<TensorCpu<f32>>, TensorError> {
+        let [num_emb, num_row, num_batch, _] = *self.shape;
+        if batch >= num_batch {
+            return Err(TensorError::BatchOutOfRange {
+                batch,
+                max: num_batch,
+            });
+        }
+
+        let row = |index: usize| {
+            let start = (batch * num_row + index) * num_emb;
+            let end = start + num_emb;
+            TensorCpu::from_data([num_emb, 1, 1, 1], self.data[start..end].to_vec())
+        };
+        (0..num_row / 5)
+            .flat_map(|layer| [4, 0, 1, 2, 3].map(|offset| 5 * layer + offset))
+            .map(row)
+            .collect()
+    }
+}
+
 impl<F: Float> Model<F> {
     pub const LN_EPS: f32 = 1.0e-5;
     pub const GN_EPS: f32 = 64.0e-5;
@@ -497,6 +520,10 @@ impl<R: Reader, F: Float> BuildFuture<Model<F>> for ModelBuilder<R> {
             context.queue.submit(None);
             context.device.poll(wgpu::MaintainBase::Wait);
 
+            // give other tasks a chance to run, since single-threaded executors (e.g., on web)
+            // would otherwise be blocked until the whole model is loaded
+            tokio::task::yield_now().await;
+
             layers.push(Layer {
                 att_layer_norm,
                 ffn_layer_norm,
@@ -872,7 +899,7 @@ impl<F: Float + Hom<f16>> ModelRunInternal for Model<F> {
             ]);
         }
 
-        context.queue.submit(context.encode(&TensorOp::List(ops)));
+        context.submit(context.encode(&TensorOp::List(ops)));
         Ok((header.head_o.clone(), redirect))
     }
 }
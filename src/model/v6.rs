@@ -21,7 +21,7 @@ use crate::{
         matrix::Matrix,
         ops::{Activation, TensorCommand, TensorOp},
         shape::{Shape, TensorDimension},
-        DeepClone, IntoPackedCursors, TensorCpu, TensorError, TensorGpu, TensorGpuView,
+        DeepClone, IntoPackedCursors, TensorCpu, TensorError, TensorGpu, TensorGpuView, TensorInit,
         TensorReshape, TensorShape,
     },
 };
@@ -428,7 +428,7 @@ impl super::ModelState for ModelState {
                 state.view(.., .., from_batch, ..)?,
                 other.view(.., .., to_batch, ..)?,
             )?;
-            context.queue.submit(context.encode(&op));
+            context.submit(context.encode(&op));
         }
         Ok(())
     }
@@ -497,6 +497,53 @@ impl super::BackedState for BackedState {
     }
 }
 
+impl super::ReferenceLayout for BackedState {
+    /// The reference layout has 3 tensors per layer: `att_x` of shape `(C)`,
+    /// `att_kv` of shape `(H, S, S)` indexed by head, key and value, and `ffn_x` of shape `(C)`.
+    fn to_reference_layout(&self, batch: usize) -> Result<Vec<TensorCpu<f32>>, TensorError> {
+        if batch >= self.num_batch {
+            return Err(TensorError::BatchOutOfRange {
+                batch,
+                max: self.num_batch,
+            });
+        }
+
+        let head_size = self.head_size;
+        let mut tensors = Vec::with_capacity(3 * super::BackedState::num_layer(self));
+        for (shape, data) in self.data.iter() {
+            let num_emb = shape[0];
+            let num_head = num_emb / head_size;
+            for offset in 0..self.chunk_size {
+                let base = (batch * self.chunk_size + offset) * (head_size + 2);
+                let row = |index: usize| {
+                    let start = (base + index) * num_emb;
+                    &data[start..start + num_emb]
+                };
+
+                let kv = (0..num_head)
+                    .flat_map(|head| {
+                        (0..head_size).flat_map(move |key| {
+                            let start = head * head_size;
+                            row(key + 1)[start..start + head_size].to_vec()
+                        })
+                    })
+                    .collect_vec();
+
+                tensors.push(TensorCpu::from_data([num_emb, 1, 1, 1], row(0).to_vec())?);
+                tensors.push(TensorCpu::from_data(
+                    [head_size, head_size, num_head, 1],
+                    kv,
+                )?);
+                tensors.push(TensorCpu::from_data(
+                    [num_emb, 1, 1, 1],
+                    row(head_size + 1).to_vec(),
+                )?);
+            }
+        }
+        Ok(tensors)
+    }
+}
+
 impl<F: Float> Model<F> {
     pub const LN_EPS: f32 = 1.0e-5;
     pub const GN_EPS: f32 = 64.0e-5;
@@ -644,6 +691,10 @@ impl<R: Reader, F: Float> BuildFuture<Model<F>> for ModelBuilder<R> {
             context.queue.submit(None);
             context.device.poll(wgpu::MaintainBase::Wait);
 
+            // give other tasks a chance to run, since single-threaded executors (e.g., on web)
+            // would otherwise be blocked until the whole model is loaded
+            tokio::task::yield_now().await;
+
             layers.push(Layer {
                 att_layer_norm,
                 ffn_layer_norm,
@@ -1166,7 +1217,7 @@ impl<F: Float> ModelRunInternal for Model<F> {
             ]);
         }
 
-        context.queue.submit(context.encode(&TensorOp::List(ops)));
+        context.submit(context.encode(&TensorOp::List(ops)));
         Ok((header.head_o.clone(), redirect))
     }
 }
@@ -0,0 +1,116 @@
+use std::collections::BTreeMap;
+
+use crate::tensor::TensorCpu;
+
+/// Token history of a session along with backed states at some positions,
+/// so that an edit at an earlier position (e.g., of a previous chat message) only replays from the nearest snapshot.
+#[derive(Debug, Default, Clone)]
+pub struct History {
+    tokens: Vec<u16>,
+    /// Backed states after the first `n` tokens, keyed by `n`.
+    snapshots: BTreeMap<usize, TensorCpu<f32>>,
+}
+
+/// Where to resume a session from after [`History::rebind`].
+#[derive(Debug, Clone)]
+pub struct Resume {
+    /// State to load before replaying, or `None` to start from the initial state.
+    pub state: Option<TensorCpu<f32>>,
+    /// Tokens to infer on top of `state`.
+    pub tokens: Vec<u16>,
+}
+
+impl History {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// All tokens of the session.
+    #[inline]
+    pub fn tokens(&self) -> &[u16] {
+        &self.tokens
+    }
+
+    /// Positions that have snapshots.
+    pub fn positions(&self) -> impl Iterator<Item = usize> + '_ {
+        self.snapshots.keys().copied()
+    }
+
+    /// Append tokens that have been inferred.
+    pub fn push(&mut self, tokens: &[u16]) {
+        self.tokens.extend_from_slice(tokens);
+    }
+
+    /// Record the backed state after all current tokens.
+    pub fn snapshot(&mut self, state: TensorCpu<f32>) {
+        self.snapshots.insert(self.tokens.len(), state);
+    }
+
+    /// Replace the history with `tokens`, e.g., after an earlier message is edited,
+    /// and find where to resume: the nearest snapshot within the prefix shared with the old history.
+    /// At least one token is always replayed so that the output for the last token is computed.
+    ///
+    /// Snapshots beyond the shared prefix are dropped. After replaying, the caller should
+    /// [`snapshot`](Self::snapshot) again if needed.
+    pub fn rebind(&mut self, tokens: &[u16]) -> Resume {
+        let prefix = self
+            .tokens
+            .iter()
+            .zip(tokens)
+            .take_while(|(x, y)| x == y)
+            .count();
+        self.snapshots.retain(|&position, _| position <= prefix);
+        self.tokens = tokens.to_vec();
+
+        let end = prefix.min(tokens.len().saturating_sub(1));
+        match self.snapshots.range(..=end).next_back() {
+            Some((&position, state)) => Resume {
+                state: Some(state.clone()),
+                tokens: tokens[position..].to_vec(),
+            },
+            None => Resume {
+                state: None,
+                tokens: tokens.to_vec(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::History;
+    use crate::tensor::{TensorCpu, TensorInit};
+
+    #[test]
+    fn test_rebind() -> Result<()> {
+        let state = |x: f32| TensorCpu::from_data([1, 1, 1, 1], vec![x]);
+
+        let mut history = History::new();
+        history.push(&[1, 2, 3]);
+        history.snapshot(state(3.0)?);
+        history.push(&[4, 5, 6]);
+        history.snapshot(state(6.0)?);
+
+        // edit at position 4: resume from the snapshot at 3
+        let resume = history.rebind(&[1, 2, 3, 4, 9, 9]);
+        assert_eq!(resume.state.map(|x| x.data()[0]), Some(3.0));
+        assert_eq!(resume.tokens, vec![4, 9, 9]);
+        assert_eq!(history.positions().collect::<Vec<_>>(), vec![3]);
+
+        // nothing changed up to a snapshot: still replay the last token
+        let resume = history.rebind(&[1, 2, 3]);
+        assert!(resume.state.is_none());
+        assert_eq!(resume.tokens, vec![1, 2, 3]);
+
+        // edit before any snapshot
+        history.snapshot(state(3.0)?);
+        let resume = history.rebind(&[7, 8]);
+        assert!(resume.state.is_none());
+        assert_eq!(resume.tokens, vec![7, 8]);
+        assert_eq!(history.positions().count(), 0);
+
+        Ok(())
+    }
+}
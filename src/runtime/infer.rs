@@ -1,12 +1,70 @@
+use std::sync::{Arc, Mutex};
+
 use itertools::Itertools;
+use thiserror::Error;
 use web_rwkv_derive::{Deref, DerefMut};
 
-use super::{JobInfo, JobInput};
-use crate::tensor::TensorCpu;
+use super::{JobInfo, JobInput, RuntimeError};
+use crate::tensor::{Cursor, TensorCpu, TensorShape};
 
 pub const MIN_TOKEN_CHUNK_SIZE: usize = 32;
+pub const MAX_TOKEN_CHUNK_SIZE: usize = Cursor::MAX_TOKEN + 1;
 pub const NUM_LAYER_CHUNK: usize = 4;
 
+/// Violations of the invariants of a chunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum InferError {
+    #[error("chunk has {0} batches vs. {1} of the input")]
+    NumBatch(usize, usize),
+    #[error("chunk of {tokens} tokens exceeds the chunk size {limit}")]
+    Oversized { tokens: usize, limit: usize },
+    #[error("chunk of {0} tokens is not a multiple of {MIN_TOKEN_CHUNK_SIZE}")]
+    Unaligned(usize),
+    #[error("batch {batch} reads {len} tokens but only has {remain} left")]
+    Overread {
+        batch: usize,
+        len: usize,
+        remain: usize,
+    },
+    #[error("chunk is empty while tokens remain")]
+    Stalled,
+    #[error("batch {batch} has {len} tokens vs. {expected} of the job")]
+    Mismatch {
+        batch: usize,
+        len: usize,
+        expected: usize,
+    },
+}
+
+/// Check the invariants of a chunk `info` taken from batches with `remains` tokens left each,
+/// under a chunk size of `limit`.
+fn check_chunk(remains: &[usize], info: &InferInfo, limit: usize) -> Result<(), InferError> {
+    if info.num_batch() != remains.len() {
+        return Err(InferError::NumBatch(info.num_batch(), remains.len()));
+    }
+    for (batch, (info, &remain)) in info.iter().zip(remains).enumerate() {
+        if info.len > remain {
+            return Err(InferError::Overread {
+                batch,
+                len: info.len,
+                remain,
+            });
+        }
+    }
+
+    let tokens = info.num_token();
+    if tokens > limit {
+        return Err(InferError::Oversized { tokens, limit });
+    }
+    if tokens > MIN_TOKEN_CHUNK_SIZE && tokens % MIN_TOKEN_CHUNK_SIZE != 0 {
+        return Err(InferError::Unaligned(tokens));
+    }
+    if tokens == 0 && remains.iter().any(|&remain| remain > 0) {
+        return Err(InferError::Stalled);
+    }
+    Ok(())
+}
+
 #[derive(Debug, Clone, Deref, DerefMut, PartialEq, Eq)]
 pub struct InferInfo(pub Vec<InferInfoBatch>);
 
@@ -70,6 +128,25 @@ impl InferInfo {
     }
 }
 
+impl InferRedirect {
+    /// Check that the chunk matches the layout of the job this redirect is built for.
+    pub fn check(&self, chunk: &InferChunk) -> Result<(), InferError> {
+        if chunk.num_batch() != self.inputs.len() {
+            return Err(InferError::NumBatch(chunk.num_batch(), self.inputs.len()));
+        }
+        for (batch, (chunk, &(start, end))) in chunk.iter().zip(&self.inputs).enumerate() {
+            if chunk.len() != end - start {
+                return Err(InferError::Mismatch {
+                    batch,
+                    len: chunk.len(),
+                    expected: end - start,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
 impl JobInfo for InferInfo {
     #[inline]
     fn check(&self, info: &Self) -> bool {
@@ -116,6 +193,25 @@ impl InferChunk {
     pub fn num_batch(&self) -> usize {
         self.0.len()
     }
+
+    /// Check that the chunk fits in a job of `limit` tokens and can be addressed by cursors.
+    pub fn check_overflow(&self, limit: usize) -> Result<(), RuntimeError> {
+        if self.num_batch() > Cursor::MAX_BATCH + 1 {
+            return Err(RuntimeError::BatchOverflow {
+                num_batch: self.num_batch(),
+                limit: Cursor::MAX_BATCH + 1,
+            });
+        }
+        let limit = limit.min(Cursor::MAX_LEN);
+        match self.iter().position(|batch| batch.len() > limit) {
+            Some(batch) => Err(RuntimeError::ChunkOverflow {
+                batch,
+                tokens: self[batch].len(),
+                limit,
+            }),
+            None => Ok(()),
+        }
+    }
 }
 
 #[derive(Debug, Default, Clone, Deref, DerefMut)]
@@ -134,16 +230,32 @@ pub struct InferInputBatch {
 pub struct InferInput {
     pub batches: Vec<InferInputBatch>,
     token_chunk_size: usize,
+    prefill_chunk_size: usize,
 }
 
 impl InferInput {
     pub fn new(batches: Vec<InferInputBatch>, token_chunk_size: usize) -> Self {
+        Self::new_with_prefill(batches, token_chunk_size, token_chunk_size)
+    }
+
+    /// Create an input with a separate (usually larger) chunk size used while any batch is still prefilling,
+    /// i.e., has more than one token left to read. Once all batches are decoding, `token_chunk_size` is used.
+    pub fn new_with_prefill(
+        batches: Vec<InferInputBatch>,
+        token_chunk_size: usize,
+        prefill_chunk_size: usize,
+    ) -> Self {
+        // tokens in a chunk are addressed by the cursors, which limits the size of a chunk
         let token_chunk_size = token_chunk_size
-            .max(MIN_TOKEN_CHUNK_SIZE)
+            .clamp(MIN_TOKEN_CHUNK_SIZE, MAX_TOKEN_CHUNK_SIZE)
+            .next_multiple_of(MIN_TOKEN_CHUNK_SIZE);
+        let prefill_chunk_size = prefill_chunk_size
+            .clamp(MIN_TOKEN_CHUNK_SIZE, MAX_TOKEN_CHUNK_SIZE)
             .next_multiple_of(MIN_TOKEN_CHUNK_SIZE);
         Self {
             batches,
             token_chunk_size,
+            prefill_chunk_size,
         }
     }
 
@@ -157,10 +269,28 @@ impl InferInput {
         self.token_chunk_size
     }
 
+    #[inline]
+    pub fn prefill_chunk_size(&self) -> usize {
+        self.prefill_chunk_size
+    }
+
     #[inline]
     pub fn num_token(&self) -> usize {
         self.batches.iter().map(|batch| batch.tokens.len()).sum()
     }
+
+    /// Check the invariants of a chunk `info` to be taken from the input at the current step:
+    /// it doesn't read beyond any batch, fits in the chunk size, is aligned to [`MIN_TOKEN_CHUNK_SIZE`]
+    /// if larger than that, and is not empty while there are tokens left.
+    pub fn check(&self, info: &InferInfo) -> Result<(), InferError> {
+        let remains = self
+            .batches
+            .iter()
+            .map(|batch| batch.tokens.len())
+            .collect_vec();
+        let limit = self.token_chunk_size.max(self.prefill_chunk_size);
+        check_chunk(&remains, info, limit)
+    }
 }
 
 impl JobInput for InferInput {
@@ -200,9 +330,11 @@ impl IntoIterator for &InferInput {
             .map(|batch| (BatchState::Read(batch.tokens.len()), batch.option))
             .collect();
         let token_chunk_size = self.token_chunk_size;
+        let prefill_chunk_size = self.prefill_chunk_size;
         Self::IntoIter {
             batches,
             token_chunk_size,
+            prefill_chunk_size,
         }
     }
 }
@@ -211,6 +343,7 @@ impl IntoIterator for &InferInput {
 pub struct InferIter {
     batches: Vec<(BatchState, InferOption)>,
     token_chunk_size: usize,
+    prefill_chunk_size: usize,
 }
 
 impl Iterator for InferIter {
@@ -226,9 +359,24 @@ impl Iterator for InferIter {
             })
             .collect_vec();
 
+        let prefill = self
+            .batches
+            .iter()
+            .any(|(x, _)| matches!(x, BatchState::Read(x) if *x > 1));
+        let token_chunk_size = match prefill {
+            true => self.prefill_chunk_size,
+            false => self.token_chunk_size,
+        };
+
+        // a batch can't read more tokens in one chunk than its cursor can hold; the rest are left for later chunks
+        let mut quotas = remains
+            .iter()
+            .map(|&x| x.min(Cursor::MAX_LEN))
+            .collect_vec();
+
         let num_batch = remains.len();
-        let num_token: usize = remains.iter().sum();
-        let num_token = num_token.min(self.token_chunk_size);
+        let num_token: usize = quotas.iter().sum();
+        let num_token = num_token.min(token_chunk_size);
         let mut num_token = match num_token > MIN_TOKEN_CHUNK_SIZE {
             true => num_token - num_token % MIN_TOKEN_CHUNK_SIZE,
             false => num_token,
@@ -236,9 +384,11 @@ impl Iterator for InferIter {
 
         let mut info = vec![InferInfoBatch::default(); num_batch];
         while num_token > 0 {
-            let mid = *remains.iter().filter(|&&x| x > 0).min().unwrap_or(&0);
-            for (info, batch) in info.iter_mut().zip_eq(remains.iter_mut()) {
-                if *batch == 0 {
+            let mid = *quotas.iter().filter(|&&x| x > 0).min().unwrap_or(&0);
+            for (info, quota, batch) in
+                itertools::multizip((info.iter_mut(), quotas.iter_mut(), remains.iter_mut()))
+            {
+                if *quota == 0 {
                     continue;
                 }
 
@@ -246,6 +396,7 @@ impl Iterator for InferIter {
                 num_token -= mid;
 
                 info.len += mid;
+                *quota -= mid;
                 *batch -= mid;
             }
         }
@@ -266,24 +417,111 @@ impl Iterator for InferIter {
             };
         }
 
-        Some(InferInfo(info))
+        let info = InferInfo(info);
+        debug_assert_eq!(
+            check_chunk(
+                &itertools::zip_eq(info.iter(), &remains)
+                    .map(|(info, remain)| info.len + remain)
+                    .collect_vec(),
+                &info,
+                token_chunk_size
+            ),
+            Ok(())
+        );
+        Some(info)
     }
 }
 
+/// Output of a batch, which is the logits of shape `[V, T]`.
+/// With sparse output enabled, it is instead the top `K` logits of shape `[K, T]`, in descending order.
 #[derive(Debug, Clone, Deref, DerefMut)]
 pub struct InferOutputBatch(pub TensorCpu<f32>);
 
-#[derive(Debug, Clone, Deref, DerefMut)]
-pub struct InferOutput(pub Vec<InferOutputBatch>);
+/// Outputs of all batches. With sparse output enabled, the second field holds the token indices
+/// of the top `K` logits of each batch, of shape `[K, T]`; otherwise it is empty.
+#[derive(Debug, Clone)]
+pub struct InferOutput(pub Vec<InferOutputBatch>, pub Vec<TensorCpu<u32>>);
+
+impl std::ops::Deref for InferOutput {
+    type Target = Vec<InferOutputBatch>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl std::ops::DerefMut for InferOutput {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+/// Usage counters of one batch slot of a runtime.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct InferSlotStats {
+    /// Number of input tokens processed.
+    pub tokens_in: usize,
+    /// Number of output rows read back.
+    pub tokens_out: usize,
+    /// Time when the slot last received input, if ever.
+    pub last_use: Option<instant::Instant>,
+}
+
+/// Handle to the per-slot usage counters of a runtime, e.g., for deciding which slot to evict.
+/// The handle is cheap to clone and stays valid after the runtime is moved into a [`JobRuntime`](super::JobRuntime).
+#[derive(Debug, Default, Clone)]
+pub struct InferStats(Arc<Mutex<Vec<InferSlotStats>>>);
+
+impl InferStats {
+    pub fn new(num_batch: usize) -> Self {
+        Self(Arc::new(Mutex::new(vec![Default::default(); num_batch])))
+    }
+
+    /// A snapshot of the counters of all slots.
+    pub fn get(&self) -> Vec<InferSlotStats> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// Clear the counters of a slot, e.g., when a new session is assigned to it.
+    pub fn reset(&self, batch: usize) {
+        if let Some(stats) = self.0.lock().unwrap().get_mut(batch) {
+            *stats = Default::default();
+        }
+    }
+
+    pub(crate) fn record_input(&self, chunk: &InferChunk) {
+        let now = instant::Instant::now();
+        let mut stats = self.0.lock().unwrap();
+        for (stats, tokens) in stats.iter_mut().zip(chunk.iter()) {
+            if !tokens.is_empty() {
+                stats.tokens_in += tokens.len();
+                stats.last_use = Some(now);
+            }
+        }
+    }
+
+    pub(crate) fn record_output(&self, output: &InferOutput) {
+        let mut stats = self.0.lock().unwrap();
+        for (stats, output) in stats.iter_mut().zip(output.iter()) {
+            stats.tokens_out += output.shape()[1];
+        }
+    }
+}
 
 #[cfg(test)]
 mod tests {
     use anyhow::Result;
 
-    use super::{InferInfo, InferInput, InferOption};
-    use crate::runtime::{
-        infer::{InferInfoBatch, InferInputBatch},
-        JobInput,
+    use super::{
+        InferChunk, InferChunkBatch, InferError, InferInfo, InferInput, InferOption, InferStats,
+        MIN_TOKEN_CHUNK_SIZE,
+    };
+    use crate::{
+        runtime::{
+            infer::{InferInfoBatch, InferInputBatch},
+            JobInput, RuntimeError,
+        },
+        tensor::Cursor,
     };
 
     impl From<(usize, Option<InferOption>)> for InferInfoBatch {
@@ -304,6 +542,7 @@ mod tests {
             .map(|(tokens, option)| InferInputBatch { tokens, option })
             .to_vec(),
             token_chunk_size: 128,
+            prefill_chunk_size: 128,
         };
         let mut iter = run.iter();
 
@@ -388,6 +627,7 @@ mod tests {
             .map(|(tokens, option)| InferInputBatch { tokens, option })
             .to_vec(),
             token_chunk_size: 128,
+            prefill_chunk_size: 128,
         };
 
         run.step();
@@ -416,6 +656,7 @@ mod tests {
             .map(|(tokens, option)| InferInputBatch { tokens, option })
             .to_vec(),
             token_chunk_size: 128,
+            prefill_chunk_size: 128,
         };
         assert_eq!(
             run.iter().next(),
@@ -434,6 +675,148 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_prefill_chunk() -> Result<()> {
+        let mut run = InferInput::new_with_prefill(
+            [
+                (vec![0; 300], InferOption::Last),
+                (vec![1; 1], InferOption::Last),
+            ]
+            .map(|(tokens, option)| InferInputBatch { tokens, option })
+            .to_vec(),
+            32,
+            256,
+        );
+
+        // prefilling uses the larger chunk
+        assert_eq!(run.iter().next().map(|info| info.num_token()), Some(256));
+        run.step();
+        assert_eq!(run.iter().next().map(|info| info.num_token()), Some(32));
+
+        // decoding falls back to the normal chunk
+        let run = InferInput::new_with_prefill(
+            (0..40)
+                .map(|_| InferInputBatch {
+                    tokens: vec![0],
+                    option: InferOption::Last,
+                })
+                .collect(),
+            32,
+            256,
+        );
+        assert_eq!(run.iter().next().map(|info| info.num_token()), Some(32));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_chunk() -> Result<()> {
+        fastrand::seed(42);
+
+        for _ in 0..200 {
+            let num_batch = fastrand::usize(1..=16);
+            let batches = (0..num_batch)
+                .map(|_| InferInputBatch {
+                    tokens: vec![0; fastrand::usize(0..=300)],
+                    option: match fastrand::bool() {
+                        true => InferOption::Last,
+                        false => InferOption::Full,
+                    },
+                })
+                .collect();
+            let mut run = InferInput::new_with_prefill(
+                batches,
+                fastrand::usize(1..=256),
+                fastrand::usize(1..=1024),
+            );
+
+            while run.num_token() > 0 {
+                let info = run.iter().next().expect("iter should not end");
+                run.check(&info)?;
+                let chunk = run.chunk();
+                info.redirect().check(&chunk)?;
+                run.step();
+            }
+        }
+
+        let run = InferInput::new(
+            [
+                (vec![0; 100], InferOption::Last),
+                (vec![0; 10], InferOption::Last),
+            ]
+            .map(|(tokens, option)| InferInputBatch { tokens, option })
+            .to_vec(),
+            64,
+        );
+        let info = |lens: &[usize]| InferInfo(lens.iter().map(|&len| (len, None).into()).collect());
+        assert_eq!(run.check(&info(&[40, 10])), Err(InferError::Unaligned(50)));
+        assert_eq!(
+            run.check(&info(&[54, 11])),
+            Err(InferError::Overread {
+                batch: 1,
+                len: 11,
+                remain: 10
+            })
+        );
+        assert_eq!(
+            run.check(&info(&[100, 10])),
+            Err(InferError::Oversized {
+                tokens: 110,
+                limit: 64
+            })
+        );
+        assert_eq!(run.check(&info(&[0, 0])), Err(InferError::Stalled));
+        assert_eq!(run.check(&info(&[MIN_TOKEN_CHUNK_SIZE, 0])), Ok(()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_chunk_overflow() -> Result<()> {
+        let mut run = InferInput::new_with_prefill(
+            [
+                (vec![0; 600], InferOption::Last),
+                (vec![1; 40], InferOption::Full),
+            ]
+            .map(|(tokens, option)| InferInputBatch { tokens, option })
+            .to_vec(),
+            32,
+            1024,
+        );
+
+        // a long batch is split across chunks instead of overflowing its cursor
+        while run.num_token() > 0 {
+            let info = run.iter().next().expect("iter should not end");
+            assert!(info.iter().all(|batch| batch.len <= Cursor::MAX_LEN));
+            run.check(&info)?;
+            run.chunk().check_overflow(info.num_token())?;
+            run.step();
+        }
+
+        let chunk = InferChunk(vec![
+            InferChunkBatch(vec![0; 10]),
+            InferChunkBatch(vec![0; 300]),
+        ]);
+        assert_eq!(
+            chunk.check_overflow(512),
+            Err(RuntimeError::ChunkOverflow {
+                batch: 1,
+                tokens: 300,
+                limit: Cursor::MAX_LEN
+            })
+        );
+        assert_eq!(
+            chunk.check_overflow(64),
+            Err(RuntimeError::ChunkOverflow {
+                batch: 1,
+                tokens: 300,
+                limit: 64
+            })
+        );
+
+        Ok(())
+    }
+
     #[test]
     fn test_redirect() -> Result<()> {
         let run = InferInput {
@@ -446,6 +829,7 @@ mod tests {
             .map(|(tokens, option)| InferInputBatch { tokens, option })
             .to_vec(),
             token_chunk_size: 128,
+            prefill_chunk_size: 128,
         };
         let redirect = run.iter().next().unwrap().redirect();
 
@@ -467,6 +851,7 @@ mod tests {
             .map(|(tokens, option)| InferInputBatch { tokens, option })
             .to_vec(),
             token_chunk_size: 32,
+            prefill_chunk_size: 32,
         };
         let redirect = run.iter().next().unwrap().redirect();
 
@@ -500,4 +885,26 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_stats() {
+        let stats = InferStats::new(3);
+        let chunk = InferChunk(vec![
+            InferChunkBatch(vec![1, 2, 3]),
+            InferChunkBatch(vec![]),
+            InferChunkBatch(vec![4]),
+        ]);
+        stats.record_input(&chunk);
+        stats.record_input(&chunk);
+
+        let slots = stats.get();
+        assert_eq!(slots[0].tokens_in, 6);
+        assert!(slots[0].last_use.is_some());
+        assert_eq!(slots[1].tokens_in, 0);
+        assert!(slots[1].last_use.is_none());
+        assert_eq!(slots[2].tokens_in, 2);
+
+        stats.reset(0);
+        assert_eq!(stats.get()[0].tokens_in, 0);
+    }
 }
@@ -1,10 +1,11 @@
-use std::{borrow::Cow, future::Future};
+use std::{borrow::Cow, collections::HashMap, future::Future};
 
 use anyhow::Result;
 use half::f16;
 use itertools::Itertools;
 use regex::Regex;
 use safetensors::{Dtype, SafeTensorError, SafeTensors};
+use thiserror::Error;
 use web_rwkv_derive::{Deref, DerefMut};
 
 use super::model::{ModelError, ModelInfo, ModelVersion, Quant};
@@ -56,6 +57,295 @@ impl ReaderSend for SafeTensors<'_> {
     }
 }
 
+/// A [`Reader`] that renames the tensors of another reader by regex rules, so that checkpoints exported
+/// with slightly different tensor names (e.g., with a `rwkv.` prefix) can be loaded without conversion.
+///
+/// A rule is a regex and its replacement, which may refer to capture groups (e.g., `$1`).
+/// Rules are applied to every name in order, each replacing the first match.
+#[derive(Debug, Clone)]
+pub struct RemapReader<R> {
+    reader: R,
+    /// Pairs of renamed and original names.
+    names: Vec<(String, String)>,
+    map: HashMap<String, usize>,
+}
+
+impl<R: Reader> RemapReader<R> {
+    pub fn new(reader: R, rules: &[(&str, &str)]) -> Result<Self> {
+        let rules: Vec<_> = rules
+            .iter()
+            .map(|&(pattern, replace)| Regex::new(pattern).map(|regex| (regex, replace)))
+            .try_collect()?;
+        let names = reader
+            .names()
+            .into_iter()
+            .map(|name| {
+                let renamed = rules
+                    .iter()
+                    .fold(name.to_string(), |name, (regex, replace)| {
+                        regex.replace(&name, *replace).into_owned()
+                    });
+                (renamed, name.to_string())
+            })
+            .collect_vec();
+
+        let mut map = HashMap::with_capacity(names.len());
+        for (index, (renamed, original)) in names.iter().enumerate() {
+            if map.insert(renamed.clone(), index).is_some() {
+                anyhow::bail!("tensor {original} is renamed to an existing name {renamed}");
+            }
+        }
+
+        Ok(Self { reader, names, map })
+    }
+
+    /// The original name of a renamed tensor; unknown names are passed through.
+    fn original<'a>(&'a self, name: &'a str) -> &'a str {
+        match self.map.get(name) {
+            Some(&index) => &self.names[index].1,
+            None => name,
+        }
+    }
+}
+
+impl<R: ReaderSend + Sync> ReaderSend for RemapReader<R> {
+    #[inline]
+    fn names(&self) -> Vec<&str> {
+        self.names.iter().map(|(name, _)| name.as_str()).collect()
+    }
+
+    #[inline]
+    fn contains(&self, name: &str) -> bool {
+        self.map.contains_key(name)
+    }
+
+    #[inline]
+    fn shape(&self, name: &str) -> Result<Vec<usize>, SafeTensorError> {
+        ReaderSend::shape(&self.reader, self.original(name))
+    }
+
+    #[inline]
+    async fn tensor(&self, name: &str) -> Result<ReaderTensor, SafeTensorError> {
+        ReaderSend::tensor(&self.reader, self.original(name)).await
+    }
+}
+
+/// A [`Reader`] that merges checkpoints of the same architecture into their weighted average
+/// ("model souping"), one tensor at a time as the model is loaded.
+///
+/// By default every tensor is merged with the same weights. A schedule rule is a regex over tensor names
+/// and the weights of tensors it matches (e.g., `(r"^blocks\.(1[2-9]|2\d)\.", &[0.0, 1.0])` to take later layers
+/// from the second checkpoint only); the first matching rule applies. Only float tensors are merged;
+/// others are taken from the first checkpoint.
+#[derive(Debug, Clone)]
+pub struct MergeReader<R> {
+    readers: Vec<R>,
+    weights: Vec<f32>,
+    schedule: Vec<(Regex, Vec<f32>)>,
+}
+
+impl<R: Reader> MergeReader<R> {
+    pub fn new(readers: Vec<R>, weights: &[f32]) -> Result<Self> {
+        let Some(first) = readers.first() else {
+            anyhow::bail!("no checkpoint to merge");
+        };
+        if weights.len() != readers.len() {
+            anyhow::bail!(
+                "expect {} merge weights, got {}",
+                readers.len(),
+                weights.len()
+            );
+        }
+        for name in first.names() {
+            let shape = first.shape(name)?;
+            for reader in readers.iter().skip(1) {
+                if !reader.contains(name) || reader.shape(name)? != shape {
+                    anyhow::bail!("tensor {name} mismatches between merged checkpoints");
+                }
+            }
+        }
+        Ok(Self {
+            readers,
+            weights: weights.to_vec(),
+            schedule: vec![],
+        })
+    }
+
+    /// Merge tensors whose names match `pattern` with `weights` instead.
+    pub fn schedule(mut self, pattern: &str, weights: &[f32]) -> Result<Self> {
+        if weights.len() != self.readers.len() {
+            anyhow::bail!(
+                "expect {} merge weights, got {}",
+                self.readers.len(),
+                weights.len()
+            );
+        }
+        self.schedule.push((Regex::new(pattern)?, weights.to_vec()));
+        Ok(self)
+    }
+
+    fn weights(&self, name: &str) -> &[f32] {
+        self.schedule
+            .iter()
+            .find(|(regex, _)| regex.is_match(name))
+            .map(|(_, weights)| weights.as_slice())
+            .unwrap_or(&self.weights)
+    }
+}
+
+impl<R: ReaderSend + Sync> ReaderSend for MergeReader<R> {
+    #[inline]
+    fn names(&self) -> Vec<&str> {
+        ReaderSend::names(&self.readers[0])
+    }
+
+    #[inline]
+    fn contains(&self, name: &str) -> bool {
+        ReaderSend::contains(&self.readers[0], name)
+    }
+
+    #[inline]
+    fn shape(&self, name: &str) -> Result<Vec<usize>, SafeTensorError> {
+        ReaderSend::shape(&self.readers[0], name)
+    }
+
+    async fn tensor(&self, name: &str) -> Result<ReaderTensor, SafeTensorError> {
+        let mut tensors = Vec::with_capacity(self.readers.len());
+        for (reader, &weight) in self.readers.iter().zip_eq(self.weights(name)) {
+            tensors.push((ReaderSend::tensor(reader, name).await?, weight));
+        }
+        weighted_sum(tensors)
+    }
+}
+
+/// A [`Reader`] that applies a delta checkpoint (fine-tuned minus base weights) on top of a base checkpoint.
+///
+/// Each tensor present in the delta is read as `base + strength * delta`; other tensors are read from the base.
+/// Like a LoRA, but the delta is full-rank, which suits distributing small fine-tune patches of a few tensors.
+#[derive(Debug, Clone)]
+pub struct DeltaReader<R> {
+    base: R,
+    delta: R,
+    strength: f32,
+}
+
+impl<R: Reader> DeltaReader<R> {
+    pub fn new(base: R, delta: R, strength: f32) -> Result<Self> {
+        for name in delta.names() {
+            if !base.contains(name) || base.shape(name)? != delta.shape(name)? {
+                anyhow::bail!("delta tensor {name} mismatches the base checkpoint");
+            }
+        }
+        Ok(Self {
+            base,
+            delta,
+            strength,
+        })
+    }
+}
+
+impl<R: ReaderSend + Sync> ReaderSend for DeltaReader<R> {
+    #[inline]
+    fn names(&self) -> Vec<&str> {
+        ReaderSend::names(&self.base)
+    }
+
+    #[inline]
+    fn contains(&self, name: &str) -> bool {
+        ReaderSend::contains(&self.base, name)
+    }
+
+    #[inline]
+    fn shape(&self, name: &str) -> Result<Vec<usize>, SafeTensorError> {
+        ReaderSend::shape(&self.base, name)
+    }
+
+    async fn tensor(&self, name: &str) -> Result<ReaderTensor, SafeTensorError> {
+        let base = ReaderSend::tensor(&self.base, name).await?;
+        match ReaderSend::contains(&self.delta, name) {
+            true => {
+                let delta = ReaderSend::tensor(&self.delta, name).await?;
+                weighted_sum(vec![(base, 1.0), (delta, self.strength)])
+            }
+            false => Ok(base),
+        }
+    }
+}
+
+/// Sum float tensors of the same type and shape with weights. Non-float tensors are returned as the first one.
+fn weighted_sum<'a>(
+    mut tensors: Vec<(ReaderTensor<'a>, f32)>,
+) -> Result<ReaderTensor<'a>, SafeTensorError> {
+    let ((dt, shape, _), _) = &tensors[0];
+    let (dt, shape) = (*dt, shape.clone());
+    if !matches!(dt, Dtype::F16 | Dtype::F32) {
+        return Ok(tensors.swap_remove(0).0);
+    }
+
+    let mut sum = vec![0.0f32; shape.iter().product()];
+    for ((x_dt, x_shape, data), weight) in tensors {
+        if x_dt != dt || x_shape != shape {
+            return Err(SafeTensorError::InvalidTensorView(
+                x_dt,
+                x_shape,
+                data.len(),
+            ));
+        }
+        match dt {
+            Dtype::F16 => {
+                let data: Vec<f16> = bytemuck::pod_collect_to_vec(&data);
+                itertools::zip_eq(&mut sum, data).for_each(|(y, x)| *y += weight * x.to_f32());
+            }
+            _ => {
+                let data: Vec<f32> = bytemuck::pod_collect_to_vec(&data);
+                itertools::zip_eq(&mut sum, data).for_each(|(y, x)| *y += weight * x);
+            }
+        }
+    }
+
+    let data = match dt {
+        Dtype::F16 => {
+            let sum = sum.into_iter().map(f16::from_f32).collect_vec();
+            bytemuck::cast_slice(&sum).to_vec()
+        }
+        _ => bytemuck::cast_slice(&sum).to_vec(),
+    };
+    Ok((dt, shape, Cow::Owned(data)))
+}
+
+/// Read the `__metadata__` entries of a safetensors file; empty if there are none.
+pub fn read_metadata(data: &[u8]) -> HashMap<String, String> {
+    SafeTensors::read_metadata(data)
+        .ok()
+        .and_then(|(_, metadata)| metadata.metadata().clone())
+        .unwrap_or_default()
+}
+
+/// Read the rescale layer interval from the `rescale_layer` entry of a safetensors file's metadata, if present.
+pub fn read_rescale_layer(data: &[u8]) -> Option<usize> {
+    parse_rescale_layer(&read_metadata(data))
+}
+
+/// Parse the rescale layer interval from the `rescale_layer` entry of metadata read by [`read_metadata`].
+pub fn parse_rescale_layer(metadata: &HashMap<String, String>) -> Option<usize> {
+    metadata.get("rescale_layer")?.parse().ok()
+}
+
+/// Read per-layer residual scales from the `layer_scale` entry of a safetensors file's metadata, if present.
+pub fn read_layer_scale(data: &[u8]) -> Option<Vec<f32>> {
+    parse_layer_scale(&read_metadata(data))
+}
+
+/// Parse per-layer residual scales from the `layer_scale` entry of metadata read by [`read_metadata`].
+/// The entry is a comma-separated list of factors, one for each layer.
+pub fn parse_layer_scale(metadata: &HashMap<String, String>) -> Option<Vec<f32>> {
+    metadata
+        .get("layer_scale")?
+        .split(',')
+        .map(|x| x.trim().parse().ok())
+        .collect()
+}
+
 pub trait TensorFromReader<T: Scalar> {
     /// Create a tensor from safetensors reader.
     fn from_reader(reader: ReaderTensor) -> Result<TensorCpu<T>, TensorError>;
@@ -78,6 +368,54 @@ impl<T: Scalar> TensorFromReader<T> for TensorCpu<T> {
     }
 }
 
+/// Tensors of a checkpoint that do not match what the model expects, reported all at once.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("missing tensors: {missing:?}; unexpected tensors: {unexpected:?}")]
+pub struct TensorMismatchError {
+    pub missing: Vec<String>,
+    pub unexpected: Vec<String>,
+}
+
+/// Tensors whose contents do not match the hashes recorded in the checkpoint.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("tensors failed the integrity check: {0:?}")]
+pub struct IntegrityError(pub Vec<String>);
+
+/// Prefix of the metadata keys holding per-tensor content hashes. See [`Loader::hash_tensors`].
+///
+/// The hash of a tensor is the 64-bit FNV-1a of its raw bytes as stored in the file, written as
+/// 16 lowercase hex digits under the key `hash.<name>`. `convert_safetensors.py --hash` emits them.
+pub const TENSOR_HASH_PREFIX: &str = "hash.";
+
+/// Read per-tensor content hashes from a safetensors file's metadata; empty if there are none.
+pub fn read_tensor_hashes(data: &[u8]) -> HashMap<String, u64> {
+    parse_tensor_hashes(&read_metadata(data))
+}
+
+/// Parse per-tensor content hashes from metadata read by [`read_metadata`]; empty if there are none.
+pub fn parse_tensor_hashes(metadata: &HashMap<String, String>) -> HashMap<String, u64> {
+    metadata
+        .iter()
+        .filter_map(|(key, value)| {
+            let name = key.strip_prefix(TENSOR_HASH_PREFIX)?;
+            let hash = u64::from_str_radix(value, 16).ok()?;
+            Some((name.to_string(), hash))
+        })
+        .collect()
+}
+
+/// Upload a tensor from the reader to GPU directly from its bytes, without a CPU-side copy.
+fn upload<T: Scalar>(
+    context: &Context,
+    (dt, shape, data): ReaderTensor,
+) -> Result<TensorGpu<T, ReadWrite>, TensorError> {
+    if T::DATA_TYPE != dt {
+        return Err(TensorError::Type);
+    }
+    let shape = Shape::from_slice_rev(&shape)?;
+    TensorGpu::from_bytes(context, shape, &data)
+}
+
 /// A LoRA that adds to the model when loading.
 #[derive(Clone)]
 pub struct Lora<R> {
@@ -174,6 +512,53 @@ struct LoraMatrix {
     alpha: f32,
 }
 
+/// 64-bit FNV-1a hasher, whose output is stable across processes, versions and platforms.
+pub(crate) struct Fnv64(pub(crate) u64);
+
+impl Fnv64 {
+    const OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x100000001b3;
+
+    pub(crate) fn new() -> Self {
+        Self(Self::OFFSET_BASIS)
+    }
+
+    pub(crate) fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= byte as u64;
+            self.0 = self.0.wrapping_mul(Self::PRIME);
+        }
+    }
+
+    pub(crate) fn write_usize(&mut self, value: usize) {
+        self.write(&(value as u64).to_le_bytes());
+    }
+}
+
+/// Fixed code of each data type hashed into fingerprints, so that they do not depend on
+/// how [`Dtype`] is printed or laid out.
+fn dtype_code(dt: Dtype) -> Option<u8> {
+    let code = match dt {
+        Dtype::BOOL => 0,
+        Dtype::U8 => 1,
+        Dtype::I8 => 2,
+        Dtype::F8_E5M2 => 3,
+        Dtype::F8_E4M3 => 4,
+        Dtype::I16 => 5,
+        Dtype::U16 => 6,
+        Dtype::F16 => 7,
+        Dtype::BF16 => 8,
+        Dtype::I32 => 9,
+        Dtype::U32 => 10,
+        Dtype::F32 => 11,
+        Dtype::F64 => 12,
+        Dtype::I64 => 13,
+        Dtype::U64 => 14,
+        _ => return None,
+    };
+    Some(code)
+}
+
 #[derive(Clone)]
 pub struct Loader<R> {
     pub context: Context,
@@ -197,7 +582,6 @@ impl<R: Reader> Loader<R> {
 
         let embed = model.shape("emb.weight")?;
         let ffn = model.shape("blocks.0.ffn.key.weight")?;
-        let time_first = model.shape("blocks.0.att.time_first")?;
 
         let v5 = [
             "blocks.0.att.gate.weight",
@@ -222,18 +606,49 @@ impl<R: Reader> Loader<R> {
         ]
         .into_iter()
         .all(|name| model.contains(name));
+        let v7 = [
+            "blocks.0.att.x_r",
+            "blocks.0.att.x_w",
+            "blocks.0.att.x_k",
+            "blocks.0.att.x_v",
+            "blocks.0.att.x_a",
+            "blocks.0.att.x_g",
+            "blocks.0.att.w0",
+            "blocks.0.att.w1",
+            "blocks.0.att.w2",
+            "blocks.0.att.a0",
+            "blocks.0.att.a1",
+            "blocks.0.att.a2",
+            "blocks.0.att.g1",
+            "blocks.0.att.g2",
+            "blocks.0.att.k_k",
+            "blocks.0.att.k_a",
+            "blocks.0.att.r_k",
+            "blocks.0.ffn.x_k",
+        ]
+        .into_iter()
+        .all(|name| model.contains(name));
 
-        let version = match (v5, v6) {
-            (false, false) => ModelVersion::V4,
-            (true, false) => ModelVersion::V5,
-            (true, true) => ModelVersion::V6,
+        let version = match (v5, v6, v7) {
+            (false, false, false) => ModelVersion::V4,
+            (true, false, false) => ModelVersion::V5,
+            (true, true, false) => ModelVersion::V6,
+            (false, false, true) => ModelVersion::V7,
             _ => return Err(ModelError::InvalidVersion.into()),
         };
 
         let num_emb = embed[1];
         let num_hidden = ffn[0];
         let num_vocab = embed[0];
-        let num_head = time_first[0];
+        // V5 and V6 store `time_first` as `[H, S]`; flattened ones fall back to the stock head size
+        let num_head = match version {
+            ModelVersion::V4 => model.shape("blocks.0.att.time_first")?[0],
+            ModelVersion::V7 => model.shape("blocks.0.att.r_k")?[0],
+            _ => match model.shape("blocks.0.att.time_first")?.as_slice() {
+                &[num_head, head_size, ..] if head_size > 1 => num_head,
+                _ => num_emb / ModelInfo::HEAD_SIZE,
+            },
+        };
 
         let time_mix_adapter_size = model
             .shape("blocks.0.att.time_mix_w1")
@@ -256,6 +671,225 @@ impl<R: Reader> Loader<R> {
         })
     }
 
+    /// Names of all tensors read when building a model of the given info.
+    pub fn tensor_names(info: &ModelInfo) -> Vec<String> {
+        let mut names = [
+            "emb.weight",
+            "blocks.0.ln0.weight",
+            "blocks.0.ln0.bias",
+            "ln_out.weight",
+            "ln_out.bias",
+            "head.weight",
+        ]
+        .map(String::from)
+        .to_vec();
+
+        let att = match info.version {
+            ModelVersion::V4 => vec![
+                "time_decay",
+                "time_first",
+                "time_mix_k",
+                "time_mix_v",
+                "time_mix_r",
+                "key.weight",
+                "value.weight",
+                "receptance.weight",
+                "output.weight",
+            ],
+            ModelVersion::V5 => vec![
+                "time_decay",
+                "time_first",
+                "time_mix_k",
+                "time_mix_v",
+                "time_mix_r",
+                "time_mix_g",
+                "key.weight",
+                "value.weight",
+                "receptance.weight",
+                "gate.weight",
+                "output.weight",
+                "ln_x.weight",
+                "ln_x.bias",
+            ],
+            ModelVersion::V6 => vec![
+                "time_decay",
+                "time_decay_w1",
+                "time_decay_w2",
+                "time_first",
+                "time_mix_x",
+                "time_mix_w",
+                "time_mix_k",
+                "time_mix_v",
+                "time_mix_r",
+                "time_mix_g",
+                "time_mix_w1",
+                "time_mix_w2",
+                "key.weight",
+                "value.weight",
+                "receptance.weight",
+                "gate.weight",
+                "output.weight",
+                "ln_x.weight",
+                "ln_x.bias",
+            ],
+            ModelVersion::V7 => vec![
+                "x_r",
+                "x_w",
+                "x_k",
+                "x_v",
+                "x_a",
+                "x_g",
+                "w0",
+                "w1",
+                "w2",
+                "a0",
+                "a1",
+                "a2",
+                "g1",
+                "g2",
+                "k_k",
+                "k_a",
+                "r_k",
+                "key.weight",
+                "value.weight",
+                "receptance.weight",
+                "output.weight",
+                "ln_x.weight",
+                "ln_x.bias",
+            ],
+        };
+        // the value residual of v7 mixes values of later layers with that of the first
+        let residual = match info.version {
+            ModelVersion::V7 => vec!["v0", "v1", "v2"],
+            _ => vec![],
+        };
+        let norm = ["ln1.weight", "ln1.bias", "ln2.weight", "ln2.bias"];
+        let ffn = match info.version {
+            ModelVersion::V7 => vec!["x_k", "key.weight", "value.weight"],
+            _ => vec![
+                "time_mix_k",
+                "time_mix_r",
+                "key.weight",
+                "value.weight",
+                "receptance.weight",
+            ],
+        };
+
+        for layer in 0..info.num_layer {
+            let block = format!("blocks.{layer}");
+            names.extend(norm.iter().map(|x| format!("{block}.{x}")));
+            names.extend(att.iter().map(|x| format!("{block}.att.{x}")));
+            if layer > 0 {
+                names.extend(residual.iter().map(|x| format!("{block}.att.{x}")));
+            }
+            names.extend(ffn.iter().map(|x| format!("{block}.ffn.{x}")));
+        }
+        names
+    }
+
+    /// Check that the model contains all tensors needed and nothing else,
+    /// reporting all mismatches at once.
+    /// State tensors (`time_state`) and the head bias (`head.bias`) are allowed to be present.
+    /// The head (`head.weight`) is not expected if it is tied to the embedding.
+    pub fn check(model: &R, info: &ModelInfo, tied_head: bool) -> Result<(), TensorMismatchError> {
+        let expected = Self::tensor_names(info)
+            .into_iter()
+            .filter(|name| !tied_head || name != "head.weight")
+            .collect_vec();
+        let missing = expected
+            .iter()
+            .filter(|name| !model.contains(name))
+            .cloned()
+            .collect_vec();
+        let unexpected = model
+            .names()
+            .into_iter()
+            .filter(|name| !name.ends_with(".att.time_state") && *name != "head.bias")
+            .filter(|name| !expected.iter().any(|x| x == name))
+            .map(String::from)
+            .sorted()
+            .collect_vec();
+
+        match missing.is_empty() && unexpected.is_empty() {
+            true => Ok(()),
+            false => Err(TensorMismatchError {
+                missing,
+                unexpected,
+            }),
+        }
+    }
+
+    /// Compute a fingerprint of the model over the names, shapes and data types of all its tensors.
+    /// If `sample` is set, the first and last bytes of each tensor's content are also hashed,
+    /// which distinguishes fine-tunes sharing the same architecture.
+    ///
+    /// The fingerprint is stable across runs and platforms, so it can be stored alongside
+    /// backed states or caches to check that they are applied to the model they came from.
+    /// It needs the tensors themselves, hence it lives here rather than on [`ModelInfo`], whose
+    /// [`ModelInfo::fingerprint`] only covers the architecture.
+    pub async fn fingerprint(model: &R, sample: bool) -> Result<u64> {
+        const SAMPLE_SIZE: usize = 64;
+
+        let mut hasher = Fnv64::new();
+        for name in model.names().into_iter().sorted_unstable() {
+            let (dt, shape, data) = model.tensor(name).await?;
+            let Some(code) = dtype_code(dt) else {
+                anyhow::bail!("tensor {name} has an unsupported data type {dt:?}");
+            };
+            hasher.write(name.as_bytes());
+            hasher.write(&[code]);
+            hasher.write_usize(shape.len());
+            shape.into_iter().for_each(|dim| hasher.write_usize(dim));
+            if sample {
+                let head = data.len().min(SAMPLE_SIZE);
+                let tail = data.len().saturating_sub(SAMPLE_SIZE).max(head);
+                hasher.write(&data[..head]);
+                hasher.write(&data[tail..]);
+            }
+        }
+        Ok(hasher.0)
+    }
+
+    /// Hash the contents of every tensor, returning metadata entries to be written into
+    /// the checkpoint at convert time (e.g., as `data_info` of [`safetensors::serialize`]).
+    pub async fn hash_tensors(model: &R) -> Result<HashMap<String, String>> {
+        let mut hashes = HashMap::new();
+        for name in model.names() {
+            let (_, _, data) = model.tensor(name).await?;
+            let mut hasher = Fnv64::new();
+            hasher.write(&data);
+            let key = format!("{TENSOR_HASH_PREFIX}{name}");
+            hashes.insert(key, format!("{:016x}", hasher.0));
+        }
+        Ok(hashes)
+    }
+
+    /// Verify tensor contents against hashes read by [`read_tensor_hashes`], reporting all
+    /// corrupted or missing tensors at once. Tensors without a recorded hash are not checked,
+    /// but having no hashes at all fails with [`ModelError::MissingHashes`].
+    pub async fn verify(model: &R, hashes: &HashMap<String, u64>) -> Result<()> {
+        if hashes.is_empty() {
+            return Err(ModelError::MissingHashes.into());
+        }
+
+        let mut corrupted = vec![];
+        for (name, &hash) in hashes.iter().sorted_unstable_by_key(|(name, _)| *name) {
+            let Ok((_, _, data)) = model.tensor(name).await else {
+                corrupted.push(name.clone());
+                continue;
+            };
+            let mut hasher = Fnv64::new();
+            hasher.write(&data);
+            if hasher.0 != hash {
+                corrupted.push(name.clone());
+            }
+        }
+        match corrupted.is_empty() {
+            true => Ok(()),
+            false => Err(IntegrityError(corrupted).into()),
+        }
+    }
+
     /// Load all lora and blend factors about the vector with a given name.
     /// In each LoRA, only the last matched pattern is loaded.
     async fn lora_vectors(&self, name: impl AsRef<str>) -> Result<Vec<LoraVector>> {
@@ -355,7 +989,7 @@ impl<R: Reader> Loader<R> {
             ops.push(op);
         }
 
-        context.queue.submit(context.encode(&TensorOp::List(ops)));
+        context.submit(context.encode(&TensorOp::List(ops)));
         Ok(tensor)
     }
 
@@ -392,7 +1026,7 @@ impl<R: Reader> Loader<R> {
         let op = TensorOp::opposite_exp(&tensor)?;
         ops.push(op);
 
-        context.queue.submit(context.encode(&TensorOp::List(ops)));
+        context.submit(context.encode(&TensorOp::List(ops)));
         Ok(tensor)
     }
 
@@ -430,7 +1064,7 @@ impl<R: Reader> Loader<R> {
         let op = TensorOp::stable_exp(&tensor)?;
         ops.push(op);
 
-        context.queue.submit(context.encode(&TensorOp::List(ops)));
+        context.submit(context.encode(&TensorOp::List(ops)));
         Ok(tensor)
     }
 
@@ -443,9 +1077,7 @@ impl<R: Reader> Loader<R> {
         let lora = self.lora_vectors(name.as_ref()).await?;
         let tensor = self.model.tensor(name.as_ref()).await?;
         let tensor = if lora.is_empty() {
-            TensorCpu::from_reader(tensor)?
-                .reshape(Auto, Dimension(1), Dimension(1), Dimension(1))?
-                .transfer_into(context)
+            upload(context, tensor)?.reshape(Auto, Dimension(1), Dimension(1), Dimension(1))?
         } else {
             let tensor_f32: TensorGpu<f32, _> = TensorCpu::<f16>::from_reader(tensor)?
                 .map(|x| x.to_f32())
@@ -476,7 +1108,7 @@ impl<R: Reader> Loader<R> {
             )?;
             ops.push(op);
 
-            context.queue.submit(context.encode(&TensorOp::List(ops)));
+            context.submit(context.encode(&TensorOp::List(ops)));
             tensor_f16
         };
         Ok(tensor)
@@ -488,7 +1120,7 @@ impl<R: Reader> Loader<R> {
     ) -> Result<TensorGpu<f16, ReadWrite>> {
         let context = &self.context;
         let tensor = self.model.tensor(name.as_ref()).await?;
-        let tensor: TensorGpu<_, _> = TensorCpu::from_reader(tensor)?.transfer_into(context);
+        let tensor: TensorGpu<f16, _> = upload(context, tensor)?;
 
         let mut ops = vec![];
         for lora in self.lora_matrices(name.as_ref()).await? {
@@ -509,7 +1141,7 @@ impl<R: Reader> Loader<R> {
             ops.push(op);
         }
 
-        context.queue.submit(context.encode(&TensorOp::List(ops)));
+        context.submit(context.encode(&TensorOp::List(ops)));
         Ok(tensor)
     }
 
@@ -543,7 +1175,7 @@ impl<R: Reader> Loader<R> {
             ops.push(op);
         }
 
-        context.queue.submit(context.encode(&TensorOp::List(ops)));
+        context.submit(context.encode(&TensorOp::List(ops)));
         Ok(tensor)
     }
 
@@ -553,9 +1185,11 @@ impl<R: Reader> Loader<R> {
         name: impl AsRef<str>,
     ) -> Result<()> {
         let context = &self.context;
-        let tensor = self.model.tensor(name.as_ref()).await?;
-        let tensor = TensorCpu::from_reader(tensor)?;
-        matrix.load(&tensor)?;
+        let (dt, _, tensor) = self.model.tensor(name.as_ref()).await?;
+        if dt != f16::DATA_TYPE {
+            return Err(TensorError::Type.into());
+        }
+        matrix.load_bytes(&tensor)?;
 
         let mut ops = vec![];
         for lora in self.lora_matrices(name.as_ref()).await? {
@@ -576,7 +1210,7 @@ impl<R: Reader> Loader<R> {
             ops.push(op);
         }
 
-        context.queue.submit(context.encode(&TensorOp::List(ops)));
+        context.submit(context.encode(&TensorOp::List(ops)));
         Ok(())
     }
 
@@ -614,7 +1248,7 @@ impl<R: Reader> Loader<R> {
             ops.push(op);
         }
 
-        context.queue.submit(context.encode(&TensorOp::List(ops)));
+        context.submit(context.encode(&TensorOp::List(ops)));
         Ok(())
     }
 
@@ -637,7 +1271,7 @@ impl<R: Reader> Loader<R> {
                 let op = TensorOp::blend(&factor, &lora.tensor, &tensor)?;
                 ops.push(op);
             }
-            context.queue.submit(context.encode(&TensorOp::List(ops)));
+            context.submit(context.encode(&TensorOp::List(ops)));
             Ok(tensor.back().await)
         }
     }
@@ -662,6 +1296,10 @@ impl<R: Reader> Loader<R> {
 
     pub async fn load_matrix(&self, name: String, quant: Quant) -> Result<Matrix> {
         let context = &self.context;
+
+        // converting and quantizing a matrix takes a while on CPU, so yield before each one
+        tokio::task::yield_now().await;
+
         match quant {
             Quant::None => Ok(Matrix::Fp16(self.load_matrix_f16(name).await?)),
             Quant::Int8 => {
@@ -686,6 +1324,10 @@ impl<R: Reader> Loader<R> {
         discount: f32,
     ) -> Result<Matrix> {
         let context = &self.context;
+
+        // converting and quantizing a matrix takes a while on CPU, so yield before each one
+        tokio::task::yield_now().await;
+
         match quant {
             Quant::None => Ok(Matrix::Fp16(
                 self.load_matrix_f16_discount(name, discount).await?,
@@ -707,3 +1349,269 @@ impl<R: Reader> Loader<R> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use anyhow::Result;
+    use half::f16;
+    use itertools::Itertools;
+    use safetensors::{tensor::TensorView, Dtype, SafeTensors};
+
+    use super::{
+        parse_layer_scale, parse_rescale_layer, read_layer_scale, read_metadata,
+        read_rescale_layer, read_tensor_hashes, DeltaReader, IntegrityError, Loader, MergeReader,
+        Reader, RemapReader,
+    };
+    use crate::runtime::model::{ModelError, ModelInfo, ModelVersion};
+
+    #[test]
+    fn test_remap_reader() -> Result<()> {
+        let data = [0u8; 16];
+        let tensors = [
+            (
+                "rwkv.emb.weight",
+                TensorView::new(Dtype::F16, vec![2, 4], &data)?,
+            ),
+            (
+                "rwkv.head.weight",
+                TensorView::new(Dtype::F16, vec![4, 2], &data)?,
+            ),
+        ];
+        let buffer = safetensors::serialize(tensors, &None)?;
+        let model = SafeTensors::deserialize(&buffer)?;
+
+        let reader = RemapReader::new(model, &[(r"^rwkv\.", ""), (r"^head\.", "head_out.")])?;
+        assert!(reader.contains("emb.weight"));
+        assert!(reader.contains("head_out.weight"));
+        assert!(!reader.contains("rwkv.emb.weight"));
+        assert_eq!(reader.shape("head_out.weight")?, vec![4, 2]);
+
+        let (dt, shape, _) = pollster::block_on(reader.tensor("emb.weight"))?;
+        assert_eq!(dt, Dtype::F16);
+        assert_eq!(shape, vec![2, 4]);
+
+        let model = SafeTensors::deserialize(&buffer)?;
+        assert!(RemapReader::new(model, &[(r"^rwkv\.(emb|head)", "x")]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_reader() -> Result<()> {
+        let a = [1.0f32, 2.0, 3.0, 4.0].map(f16::from_f32);
+        let b = [3.0f32, 4.0, 5.0, 6.0].map(f16::from_f32);
+        let serialize = |data: &[f16]| -> Result<Vec<u8>> {
+            let data: &[u8] = bytemuck::cast_slice(data);
+            let tensors = [
+                ("blocks.0.w", TensorView::new(Dtype::F16, vec![2, 2], data)?),
+                ("blocks.1.w", TensorView::new(Dtype::F16, vec![2, 2], data)?),
+            ];
+            Ok(safetensors::serialize(tensors, &None)?)
+        };
+        let (a, b) = (serialize(&a)?, serialize(&b)?);
+        let models = vec![SafeTensors::deserialize(&a)?, SafeTensors::deserialize(&b)?];
+
+        let reader =
+            MergeReader::new(models, &[0.5, 0.5])?.schedule(r"^blocks\.1\.", &[0.0, 1.0])?;
+        let read = |name| -> Result<Vec<f32>> {
+            let (dt, shape, data) = pollster::block_on(reader.tensor(name))?;
+            assert_eq!(dt, Dtype::F16);
+            assert_eq!(shape, vec![2, 2]);
+            let data: Vec<f16> = bytemuck::pod_collect_to_vec(&data);
+            Ok(data.into_iter().map(f16::to_f32).collect())
+        };
+        assert_eq!(read("blocks.0.w")?, vec![2.0, 3.0, 4.0, 5.0]);
+        assert_eq!(read("blocks.1.w")?, vec![3.0, 4.0, 5.0, 6.0]);
+
+        let models = vec![SafeTensors::deserialize(&a)?];
+        assert!(MergeReader::new(models, &[0.5, 0.5]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_delta_reader() -> Result<()> {
+        let base = [1.0f32, 2.0, 3.0, 4.0].map(f16::from_f32);
+        let delta = [2.0f32, 2.0, -2.0, 0.0].map(f16::from_f32);
+        let base = safetensors::serialize(
+            [
+                (
+                    "a",
+                    TensorView::new(Dtype::F16, vec![4], bytemuck::cast_slice(&base))?,
+                ),
+                (
+                    "b",
+                    TensorView::new(Dtype::F16, vec![4], bytemuck::cast_slice(&base))?,
+                ),
+            ],
+            &None,
+        )?;
+        let delta = safetensors::serialize(
+            [(
+                "a",
+                TensorView::new(Dtype::F16, vec![4], bytemuck::cast_slice(&delta))?,
+            )],
+            &None,
+        )?;
+
+        let reader = DeltaReader::new(
+            SafeTensors::deserialize(&base)?,
+            SafeTensors::deserialize(&delta)?,
+            0.5,
+        )?;
+        let read = |name| -> Result<Vec<f32>> {
+            let (_, _, data) = pollster::block_on(reader.tensor(name))?;
+            let data: Vec<f16> = bytemuck::pod_collect_to_vec(&data);
+            Ok(data.into_iter().map(f16::to_f32).collect())
+        };
+        assert_eq!(read("a")?, vec![2.0, 3.0, 2.0, 4.0]);
+        assert_eq!(read("b")?, vec![1.0, 2.0, 3.0, 4.0]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rescale_layer() -> Result<()> {
+        let data = [0u8; 8];
+        let tensors = [("a", TensorView::new(Dtype::F16, vec![4], &data)?)];
+        let metadata = [("rescale_layer".to_string(), "4".to_string())];
+        let buffer = safetensors::serialize(tensors.clone(), &Some(metadata.into()))?;
+        assert_eq!(read_rescale_layer(&buffer), Some(4));
+        assert_eq!(parse_rescale_layer(&read_metadata(&buffer)), Some(4));
+
+        let buffer = safetensors::serialize(tensors, &None)?;
+        assert!(read_metadata(&buffer).is_empty());
+        assert_eq!(read_rescale_layer(&buffer), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_layer_scale() -> Result<()> {
+        let data = [0u8; 8];
+        let tensors = [("a", TensorView::new(Dtype::F16, vec![4], &data)?)];
+        let metadata = [("layer_scale".to_string(), "1, 0.5,0.25".to_string())];
+        let buffer = safetensors::serialize(tensors.clone(), &Some(metadata.into()))?;
+        assert_eq!(read_layer_scale(&buffer), Some(vec![1.0, 0.5, 0.25]));
+        assert_eq!(
+            parse_layer_scale(&read_metadata(&buffer)),
+            Some(vec![1.0, 0.5, 0.25])
+        );
+
+        let metadata = [("layer_scale".to_string(), "1,half".to_string())];
+        let buffer = safetensors::serialize(tensors, &Some(metadata.into()))?;
+        assert_eq!(read_layer_scale(&buffer), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tied_head() -> Result<()> {
+        let info = ModelInfo {
+            version: ModelVersion::V4,
+            num_layer: 2,
+            num_emb: 4,
+            num_hidden: 8,
+            num_vocab: 8,
+            num_head: 1,
+            time_mix_adapter_size: 0,
+            time_decay_adapter_size: 0,
+        };
+        let data = [0u8; 2];
+        let names = Loader::<SafeTensors>::tensor_names(&info)
+            .into_iter()
+            .filter(|name| name != "head.weight")
+            .collect_vec();
+        let tensors: Vec<_> = names
+            .iter()
+            .map(|name| Ok((name, TensorView::new(Dtype::F16, vec![1], &data)?)))
+            .collect::<Result<_>>()?;
+        let buffer = safetensors::serialize(tensors, &None)?;
+        let model = SafeTensors::deserialize(&buffer)?;
+
+        // a missing head is reported unless it is tied to the embedding
+        let err = Loader::check(&model, &info, false).unwrap_err();
+        assert_eq!(err.missing, vec!["head.weight".to_string()]);
+        Loader::check(&model, &info, true)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tensor_hashes() -> Result<()> {
+        let data = [1u8, 2, 3, 4, 5, 6, 7, 8];
+        let tensors = [
+            ("a", TensorView::new(Dtype::F16, vec![4], &data)?),
+            ("b", TensorView::new(Dtype::F16, vec![2, 2], &data)?),
+        ];
+        let buffer = safetensors::serialize(tensors, &None)?;
+        let model = SafeTensors::deserialize(&buffer)?;
+        let hashes = pollster::block_on(Loader::hash_tensors(&model))?;
+        assert_eq!(hashes.len(), 2);
+        assert_eq!(hashes["hash.a"], "7eb5108b368a78ed");
+
+        let err = pollster::block_on(Loader::verify(&model, &HashMap::new())).unwrap_err();
+        assert_eq!(err.downcast::<ModelError>()?, ModelError::MissingHashes);
+
+        let corrupted = [1u8, 2, 3, 4, 5, 6, 7, 0];
+        let tensors = [
+            ("a", TensorView::new(Dtype::F16, vec![4], &data)?),
+            ("b", TensorView::new(Dtype::F16, vec![2, 2], &corrupted)?),
+        ];
+        let buffer = safetensors::serialize(tensors, &Some(hashes))?;
+        let hashes = read_tensor_hashes(&buffer);
+        assert_eq!(hashes.len(), 2);
+
+        let model = SafeTensors::deserialize(&buffer)?;
+        let err = pollster::block_on(Loader::verify(&model, &hashes)).unwrap_err();
+        let err = err.downcast::<IntegrityError>()?;
+        assert_eq!(err.0, vec!["b".to_string()]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fingerprint() -> Result<()> {
+        let data = [1u8, 2, 3, 4, 5, 6, 7, 8];
+        let tensors = [
+            ("a", TensorView::new(Dtype::F16, vec![4], &data)?),
+            ("b", TensorView::new(Dtype::F16, vec![2, 2], &data)?),
+        ];
+        let buffer = safetensors::serialize(tensors, &None)?;
+        let model = SafeTensors::deserialize(&buffer)?;
+        let fingerprint = pollster::block_on(Loader::fingerprint(&model, false))?;
+        // pinned, since fingerprints are stored alongside states and caches
+        assert_eq!(fingerprint, 0x355ce195372ace7d);
+
+        let other = [8u8, 7, 6, 5, 4, 3, 2, 1];
+        let tensors = [
+            ("a", TensorView::new(Dtype::F16, vec![4], &data)?),
+            ("b", TensorView::new(Dtype::F16, vec![2, 2], &other)?),
+        ];
+        let buffer = safetensors::serialize(tensors, &None)?;
+        let other = SafeTensors::deserialize(&buffer)?;
+        assert_eq!(
+            pollster::block_on(Loader::fingerprint(&other, false))?,
+            fingerprint
+        );
+        assert_ne!(
+            pollster::block_on(Loader::fingerprint(&other, true))?,
+            pollster::block_on(Loader::fingerprint(&model, true))?
+        );
+
+        let tensors = [
+            ("a", TensorView::new(Dtype::U16, vec![4], &data)?),
+            ("b", TensorView::new(Dtype::F16, vec![2, 2], &data)?),
+        ];
+        let buffer = safetensors::serialize(tensors, &None)?;
+        let other = SafeTensors::deserialize(&buffer)?;
+        assert_ne!(
+            pollster::block_on(Loader::fingerprint(&other, false))?,
+            fingerprint
+        );
+
+        Ok(())
+    }
+}
@@ -1,17 +1,55 @@
-use std::future::Future;
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+};
 
 use anyhow::Result;
+use thiserror::Error;
 
+pub mod history;
 pub mod infer;
 pub mod loader;
 pub mod model;
+pub mod retrieval;
 pub mod softmax;
+pub mod store;
 pub mod v4;
 pub mod v5;
 pub mod v6;
+pub mod v7;
 
 // const MAX_QUEUE_SIZE: usize = 2;
 
+/// Number of [`JobEvent`]s buffered for each subscriber before the oldest ones are dropped.
+const EVENT_CAPACITY: usize = 256;
+
+// models, states and runtimes are meant to be shared between threads on native platforms
+#[cfg(not(target_arch = "wasm32"))]
+const _: () = {
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[allow(dead_code)]
+    fn assert_all<F: crate::num::Float>() {
+        assert_send_sync::<crate::context::Context>();
+        assert_send_sync::<v4::Model>();
+        assert_send_sync::<v4::State>();
+        assert_send_sync::<v4::ModelRuntime<F>>();
+        assert_send_sync::<v5::Model>();
+        assert_send_sync::<v5::State>();
+        assert_send_sync::<v5::ModelRuntime<F>>();
+        assert_send_sync::<v6::Model>();
+        assert_send_sync::<v6::State>();
+        assert_send_sync::<v6::ModelRuntime<F>>();
+        assert_send_sync::<v7::Model>();
+        assert_send_sync::<v7::State>();
+        assert_send_sync::<v7::ModelRuntime<F>>();
+        assert_send_sync::<JobRuntime<infer::InferInput, infer::InferOutput>>();
+    }
+};
+
 pub trait JobInfo: Send + Clone + 'static {
     /// Check if the info are compatible.
     fn check(&self, info: &Self) -> bool;
@@ -41,6 +79,7 @@ pub trait JobBuilder<J: Job>: Send + Clone + 'static {
 
 #[derive(Debug)]
 struct Submission<I, O> {
+    id: u64,
     input: I,
     sender: tokio::sync::oneshot::Sender<(I, O)>,
 }
@@ -55,8 +94,64 @@ pub trait JobInput: Send + 'static {
     fn chunk(&self) -> Self::Chunk;
 }
 
+/// Handle to a worker that builds, submits and reads back [`Job`]s.
+///
+/// The handle is cheap to clone and can be shared across threads and tasks. Submissions from all
+/// handles go through one channel and are executed one at a time, in the order they arrive.
+/// Note that [`State`](model::State) is shared between the runtime and the application; loading or
+/// reading a batch of the state while a job on that batch is in flight gives unspecified results.
 #[derive(Debug, Clone)]
-pub struct JobRuntime<I, O>(tokio::sync::mpsc::Sender<Submission<I, O>>);
+pub struct JobRuntime<I, O> {
+    sender: tokio::sync::mpsc::Sender<Submission<I, O>>,
+    suspect: Arc<AtomicBool>,
+    events: tokio::sync::broadcast::Sender<JobEvent>,
+    counter: Arc<AtomicU64>,
+}
+
+/// What happens to submissions in a [`JobRuntime`], see [`JobRuntime::subscribe`].
+/// Each submission (i.e., one call of [`JobRuntime::infer`]) carries an ID from [`JobRuntime::next_id`],
+/// or the one passed to [`JobRuntime::infer_with_id`].
+/// Submissions whose input has nothing left to infer are dropped without any event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobEvent {
+    /// The submission is received by the worker.
+    Received(u64),
+    /// Jobs built ahead of time were evicted from the pipeline, since the input of the submission
+    /// did not match what they were built for.
+    Evicted(u64),
+    /// The job of the submission is ready and starts loading the input.
+    Started(u64),
+    /// The job of the submission is loaded and submitted to the GPU.
+    Submitted(u64),
+    /// The output of the submission is read back and sent to the caller.
+    Completed(u64),
+    /// The output of the submission is read back, but the caller is no longer waiting for it.
+    Cancelled(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum JobError {
+    #[error("job did not complete in time")]
+    Timeout,
+    #[error("job runtime has stopped")]
+    Stopped,
+    #[error("input has nothing left to infer")]
+    Empty,
+    #[error("job returned a wrong answer")]
+    Unhealthy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum RuntimeError {
+    #[error("batch {batch} has {tokens} tokens in a chunk, exceeding the limit {limit}")]
+    ChunkOverflow {
+        batch: usize,
+        tokens: usize,
+        limit: usize,
+    },
+    #[error("{num_batch} batches exceed the limit {limit}")]
+    BatchOverflow { num_batch: usize, limit: usize },
+}
 
 #[allow(clippy::type_complexity)]
 impl<I, O, T, F> JobRuntime<I, O>
@@ -72,19 +167,26 @@ where
         J: Job<Info = T, Input = I::Chunk, Output = O>,
     {
         let (sender, receiver) = tokio::sync::mpsc::channel(1);
-        let handle = tokio::spawn(Self::run(builder, receiver));
+        let (events, _) = tokio::sync::broadcast::channel(EVENT_CAPACITY);
+        let handle = tokio::spawn(Self::run(builder, receiver, events.clone()));
         tokio::spawn(async move {
             match handle.await {
                 Ok(_) => {}
                 Err(err) => log::error!("{}", err),
             }
         });
-        Self(sender)
+        Self {
+            sender,
+            suspect: Default::default(),
+            events,
+            counter: Default::default(),
+        }
     }
 
     async fn run<J>(
         builder: impl JobBuilder<J, Info = T>,
         mut receiver: tokio::sync::mpsc::Receiver<Submission<I, O>>,
+        events: tokio::sync::broadcast::Sender<JobEvent>,
     ) -> Result<()>
     where
         J: Job<Info = T, Input = I::Chunk, Output = O>,
@@ -93,24 +195,32 @@ where
         let mut iter: Option<F> = None;
         let mut predict: usize = 0;
 
-        while let Some(Submission { input, sender }) = receiver.recv().await {
+        while let Some(Submission { id, input, sender }) = receiver.recv().await {
             let Some(info) = (&input).into_iter().next() else {
                 continue;
             };
+            let _ = events.send(JobEvent::Received(id));
 
             let chunk = input.chunk();
 
-            let mut job = loop {
+            let job = loop {
                 let mut candidates = vec![];
                 let mut remain = vec![];
+                let mut evicted = false;
                 for (key, handle) in queue.drain(..) {
                     match (candidates.is_empty(), info.check(&key)) {
-                        (true, false) => handle.abort(),
+                        (true, false) => {
+                            handle.abort();
+                            evicted = true;
+                        }
                         (false, false) => remain.push((key, handle)),
                         (_, true) => candidates.push(handle),
                     }
                 }
                 queue = remain;
+                if evicted {
+                    let _ = events.send(JobEvent::Evicted(id));
+                }
 
                 predict = match predict {
                     2 => 1,
@@ -152,24 +262,35 @@ where
                     queue.append(&mut remain);
                     break job??;
                 }
-            }
-            .load(&chunk)?;
+            };
+            let _ = events.send(JobEvent::Started(id));
+            let mut job = job.load(&chunk)?;
+
+            // loading gathers embeddings on CPU; let other tasks run before submitting
+            tokio::task::yield_now().await;
 
             async fn back<J: Job, I: JobInput>(
                 job: J,
                 mut input: I,
                 sender: tokio::sync::oneshot::Sender<(I, J::Output)>,
+                events: tokio::sync::broadcast::Sender<JobEvent>,
+                id: u64,
             ) -> Result<()> {
                 let output = job.back().await?;
                 input.step();
-                let _ = sender.send((input, output));
+                let event = match sender.send((input, output)) {
+                    Ok(_) => JobEvent::Completed(id),
+                    Err(_) => JobEvent::Cancelled(id),
+                };
+                let _ = events.send(event);
                 Ok(())
             }
 
             #[cfg(feature = "trace")]
             let _span = tracing::trace_span!("submit").entered();
             job.submit();
-            tokio::spawn(back(job, input, sender));
+            let _ = events.send(JobEvent::Submitted(id));
+            tokio::spawn(back(job, input, sender, events.clone(), id));
         }
         Ok(())
     }
@@ -177,9 +298,274 @@ where
     /// Perform (partial) inference and return the remaining input and (perhaps partial) output.
     /// The amount of input processed during one call is bound by the input chunk size.
     pub async fn infer(&self, input: I) -> (I, O) {
+        self.infer_with_id(self.next_id(), input).await
+    }
+
+    /// Like [`JobRuntime::infer`], but tag the [`JobEvent`]s of this submission with `id`,
+    /// e.g., one from [`JobRuntime::next_id`] to match the events against this call.
+    pub async fn infer_with_id(&self, id: u64, input: I) -> (I, O) {
         let (sender, receiver) = tokio::sync::oneshot::channel();
-        let submission = Submission { input, sender };
-        let _ = self.0.send(submission).await;
+        let submission = Submission { id, input, sender };
+        let _ = self.sender.send(submission).await;
         receiver.await.expect("receive infer output error")
     }
+
+    /// Like [`JobRuntime::infer`], but fail with [`JobError::Timeout`] if the output doesn't arrive within `timeout`,
+    /// instead of waiting forever when the device hangs. A timeout marks the runtime as [suspect](JobRuntime::is_suspect).
+    ///
+    /// On failure, the input is handed back as it was submitted. Note that a job that timed out is not cancelled:
+    /// it keeps running on the device, and its output is discarded when it completes.
+    /// An input with nothing left to infer fails with [`JobError::Empty`] without being submitted.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn infer_timeout(
+        &self,
+        input: I,
+        timeout: std::time::Duration,
+    ) -> Result<(I, O), (I, JobError)>
+    where
+        I: Clone,
+    {
+        if (&input).into_iter().next().is_none() {
+            return Err((input, JobError::Empty));
+        }
+
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        let id = self.next_id();
+        let backup = input.clone();
+        let submission = Submission { id, input, sender };
+        let job = async {
+            if let Err(err) = self.sender.send(submission).await {
+                return Err((err.0.input, JobError::Stopped));
+            }
+            receiver
+                .await
+                .map_err(|_| (backup.clone(), JobError::Stopped))
+        };
+        match tokio::time::timeout(timeout, job).await {
+            Ok(output) => output,
+            Err(_) => {
+                self.suspect.store(true, Ordering::Release);
+                Err((backup, JobError::Timeout))
+            }
+        }
+    }
+
+    /// Run a known-answer `probe` through the runtime and check its output with `verify`,
+    /// e.g., for a load balancer to detect a wedged device and recreate the context.
+    /// Fails with [`JobError::Timeout`] if the answer doesn't arrive within `timeout`, marking the runtime as
+    /// [suspect](JobRuntime::is_suspect) as [`JobRuntime::infer_timeout`] does, or with [`JobError::Unhealthy`]
+    /// if `verify` rejects it.
+    ///
+    /// The probe is inferred like any other input, so it updates the state of the batches it has tokens in.
+    /// Keep a batch slot for it and reset that slot before each check, so that the answer stays the same
+    /// as the one recorded on a healthy device.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn health_check(
+        &self,
+        probe: I,
+        timeout: std::time::Duration,
+        verify: impl FnOnce(&O) -> bool,
+    ) -> Result<(), JobError>
+    where
+        I: Clone,
+    {
+        let (_, output) = self
+            .infer_timeout(probe, timeout)
+            .await
+            .map_err(|(_, err)| err)?;
+        match verify(&output) {
+            true => Ok(()),
+            false => Err(JobError::Unhealthy),
+        }
+    }
+
+    /// Allocate a submission ID, unique among all handles of this runtime.
+    pub fn next_id(&self) -> u64 {
+        self.counter.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Subscribe to [`JobEvent`]s of all later submissions, e.g., for monitoring.
+    /// Events are dropped if nobody subscribes, and the oldest ones are skipped if a subscriber lags behind.
+    pub fn subscribe(&self) -> tokio::sync::broadcast::Receiver<JobEvent> {
+        self.events.subscribe()
+    }
+
+    /// Whether a job has timed out on this runtime. If so, the device may have hung,
+    /// and later jobs may never complete; consider recreating the context.
+    pub fn is_suspect(&self) -> bool {
+        self.suspect.load(Ordering::Acquire)
+    }
+
+    /// Clear the [suspect](JobRuntime::is_suspect) mark, e.g., after a later job has completed in time,
+    /// showing that the device has recovered.
+    pub fn clear_suspect(&self) {
+        self.suspect.store(false, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use anyhow::Result;
+
+    use super::{Job, JobBuilder, JobError, JobEvent, JobInfo, JobInput, JobRuntime};
+
+    #[derive(Debug, Clone)]
+    struct Info;
+
+    impl JobInfo for Info {
+        fn check(&self, _info: &Self) -> bool {
+            true
+        }
+    }
+
+    /// Number of steps left.
+    #[derive(Debug, Clone)]
+    struct Input(usize);
+
+    impl JobInput for Input {
+        type Chunk = ();
+
+        fn step(&mut self) {
+            self.0 -= 1;
+        }
+
+        fn chunk(&self) -> Self::Chunk {}
+    }
+
+    impl IntoIterator for &Input {
+        type Item = Info;
+        type IntoIter = std::iter::RepeatN<Info>;
+
+        fn into_iter(self) -> Self::IntoIter {
+            std::iter::repeat_n(Info, self.0)
+        }
+    }
+
+    struct Step;
+
+    impl Job for Step {
+        type Info = Info;
+        type Input = ();
+        type Output = ();
+
+        fn load(self, _input: &Self::Input) -> Result<Self> {
+            Ok(self)
+        }
+
+        fn submit(&mut self) {}
+
+        async fn back(self) -> Result<Self::Output> {
+            Ok(())
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    struct Builder;
+
+    impl JobBuilder<Step> for Builder {
+        type Info = Info;
+
+        fn build(&self, _info: Self::Info) -> Result<Step> {
+            Ok(Step)
+        }
+    }
+
+    /// A job that never completes, as if the device hung.
+    struct Stall;
+
+    impl Job for Stall {
+        type Info = Info;
+        type Input = ();
+        type Output = ();
+
+        fn load(self, _input: &Self::Input) -> Result<Self> {
+            Ok(self)
+        }
+
+        fn submit(&mut self) {}
+
+        async fn back(self) -> Result<Self::Output> {
+            std::future::pending().await
+        }
+    }
+
+    impl JobBuilder<Stall> for Builder {
+        type Info = Info;
+
+        fn build(&self, _info: Self::Info) -> Result<Stall> {
+            Ok(Stall)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_job_timeout() -> Result<()> {
+        let runtime: JobRuntime<Input, ()> = JobRuntime::new::<Stall>(Builder).await;
+        assert!(!runtime.is_suspect());
+
+        let output = runtime
+            .infer_timeout(Input(2), Duration::from_millis(100))
+            .await;
+        assert!(matches!(output, Err((Input(2), JobError::Timeout))));
+        assert!(runtime.is_suspect());
+
+        let output = runtime
+            .health_check(Input(1), Duration::from_millis(100), |_| true)
+            .await;
+        assert_eq!(output, Err(JobError::Timeout));
+
+        runtime.clear_suspect();
+        assert!(!runtime.is_suspect());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_job_events() -> Result<()> {
+        let runtime = JobRuntime::new::<Step>(Builder).await;
+        let mut events = runtime.subscribe();
+        let timeout = Duration::from_secs(5);
+
+        // an input with nothing to infer is handed back without events
+        let output = runtime.infer_timeout(Input(0), timeout).await;
+        assert!(matches!(output, Err((Input(0), JobError::Empty))));
+
+        let id = runtime.next_id();
+        let (input, _) = runtime.infer_with_id(id, Input(2)).await;
+        assert_eq!(input.0, 1);
+        for event in [
+            JobEvent::Received(id),
+            JobEvent::Started(id),
+            JobEvent::Submitted(id),
+            JobEvent::Completed(id),
+        ] {
+            assert_eq!(tokio::time::timeout(timeout, events.recv()).await??, event);
+        }
+
+        // ids of later submissions are distinct
+        assert_ne!(runtime.next_id(), id);
+
+        // a submission whose caller stops waiting is cancelled instead of completed
+        let id = runtime.next_id();
+        let mut call = Box::pin(runtime.infer_with_id(id, Input(1)));
+        assert!(futures::poll!(&mut call).is_pending());
+        drop(call);
+        for event in [
+            JobEvent::Received(id),
+            JobEvent::Started(id),
+            JobEvent::Submitted(id),
+            JobEvent::Cancelled(id),
+        ] {
+            assert_eq!(tokio::time::timeout(timeout, events.recv()).await??, event);
+        }
+
+        // health checks verify the answer of the probe
+        let output = runtime.health_check(Input(1), timeout, |_| true).await;
+        assert_eq!(output, Ok(()));
+        let output = runtime.health_check(Input(1), timeout, |_| false).await;
+        assert_eq!(output, Err(JobError::Unhealthy));
+
+        Ok(())
+    }
 }
@@ -1,18 +1,22 @@
-use std::{any::Any, collections::HashMap, future::Future};
+use std::{any::Any, collections::HashMap, future::Future, sync::Arc};
 
 use anyhow::Result;
 use futures::future::BoxFuture;
 use half::f16;
+use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use wasm_bindgen::prelude::wasm_bindgen;
 
-use super::loader::{Lora, Reader};
+use super::loader::{Fnv64, Lora, Reader};
 use crate::{
     context::{Context, ContextBuilder},
     impl_deserialize_seed,
     num::Scalar,
-    tensor::{kind::ReadWrite, TensorCpu, TensorError, TensorGpu, TensorGpuView},
+    tensor::{
+        kind::ReadWrite, ops::TensorCommand, shape::Shape, TensorCpu, TensorError, TensorGpu,
+        TensorGpuView, TensorInit, TensorShape,
+    },
 };
 
 #[wasm_bindgen]
@@ -21,6 +25,7 @@ pub enum ModelVersion {
     V4,
     V5,
     V6,
+    V7,
 }
 
 #[wasm_bindgen]
@@ -28,6 +33,22 @@ pub enum ModelVersion {
 pub enum ModelError {
     #[error("invalid model version")]
     InvalidVersion,
+    #[error("real vocab size exceeds the size of the head")]
+    InvalidVocab,
+    #[error("invalid quantization preset")]
+    InvalidQuant,
+    #[error("invalid head size")]
+    InvalidHeadSize,
+    #[error("states of different versions or head sizes cannot be projected")]
+    IncompatibleState,
+    #[error("projection strategy does not fit the target size")]
+    InvalidProjection,
+    #[error("tensor not found or cannot be patched")]
+    InvalidTensor,
+    #[error("no tensor hashes to verify against")]
+    MissingHashes,
+    #[error("number of layer scales does not match the number of layers")]
+    InvalidLayerScale,
 }
 
 #[wasm_bindgen]
@@ -46,6 +67,47 @@ pub struct ModelInfo {
 impl ModelInfo {
     pub const BUFFER_SIZE: usize = 256 << 20;
     pub const STORAGE_BUFFER_BINDING_SIZE: usize = 128 << 20;
+    /// Stock head size of V5 and above, assumed if the checkpoint doesn't tell.
+    pub const HEAD_SIZE: usize = 64;
+
+    /// Override the number of heads by the size of each head, which must be a multiple of 4
+    /// (at most 1024) that divides the embedding size.
+    pub fn set_head_size(&mut self, head_size: usize) -> Result<(), ModelError> {
+        match head_size {
+            x if (4..=1024).contains(&x) && x % 4 == 0 && self.num_emb % x == 0 => {
+                self.num_head = self.num_emb / head_size;
+                Ok(())
+            }
+            _ => Err(ModelError::InvalidHeadSize),
+        }
+    }
+
+    /// Fingerprint of the model architecture, i.e., the version and sizes in this info.
+    /// Backed states only depend on these, so the fingerprint can be stored with them to check
+    /// that they are applied to a compatible model. To tell apart checkpoints of the same
+    /// architecture, use [`Loader::fingerprint`](super::loader::Loader::fingerprint).
+    pub fn fingerprint(&self) -> u64 {
+        let version: u8 = match self.version {
+            ModelVersion::V4 => 4,
+            ModelVersion::V5 => 5,
+            ModelVersion::V6 => 6,
+            ModelVersion::V7 => 7,
+        };
+        let mut hasher = Fnv64::new();
+        hasher.write(&[version]);
+        [
+            self.num_layer,
+            self.num_emb,
+            self.num_hidden,
+            self.num_vocab,
+            self.num_head,
+            self.time_mix_adapter_size,
+            self.time_decay_adapter_size,
+        ]
+        .into_iter()
+        .for_each(|x| hasher.write_usize(x));
+        hasher.0
+    }
 }
 
 impl_deserialize_seed!(ModelInfo);
@@ -63,6 +125,93 @@ impl ModelInfo {
     }
 }
 
+/// Layer-normalize each row of a `[C, V]` matrix on CPU.
+pub(crate) fn layer_norm_rows(
+    x: &TensorCpu<f16>,
+    w: &TensorCpu<f16>,
+    b: &TensorCpu<f16>,
+    eps: f32,
+) -> TensorCpu<f32> {
+    let num_emb = x.shape()[0];
+    let data = x
+        .chunks(num_emb)
+        .flat_map(|row| {
+            let row = row.iter().map(|x| x.to_f32()).collect::<Vec<_>>();
+            let mean = row.iter().sum::<f32>() / num_emb as f32;
+            let var = row.iter().map(|x| (x - mean) * (x - mean)).sum::<f32>() / num_emb as f32;
+            let scale = (var + eps).sqrt().recip();
+            itertools::multizip((row, w.iter(), b.iter()))
+                .map(|(x, w, b)| (x - mean) * scale * w.to_f32() + b.to_f32())
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    TensorCpu::from_data(x.shape(), data).expect("this never happens")
+}
+
+/// Resolve the factor the residual is scaled by after each layer.
+/// Explicit `layer_scale` takes precedence; otherwise the residual is halved every `rescale` layers.
+pub(crate) fn resolve_layer_scale(
+    num_layer: usize,
+    rescale: usize,
+    layer_scale: Option<Vec<f32>>,
+) -> Result<Vec<f32>, ModelError> {
+    match layer_scale {
+        Some(scale) if scale.len() != num_layer => Err(ModelError::InvalidLayerScale),
+        Some(scale) => Ok(scale),
+        None => Ok((0..num_layer)
+            .map(|layer| match rescale {
+                0 => 1.0,
+                rescale if (layer + 1) % rescale == 0 => 0.5,
+                _ => 1.0,
+            })
+            .collect()),
+    }
+}
+
+/// The discount applied to the output weights of each layer, i.e., the product of the scales of all layers before it.
+pub(crate) fn layer_discounts(layer_scale: &[f32]) -> Vec<f32> {
+    layer_scale
+        .iter()
+        .scan(1.0, |acc, scale| {
+            let discount = *acc;
+            *acc *= scale;
+            Some(discount)
+        })
+        .collect()
+}
+
+/// Read back multiple batches of a state kept as per-layer tensors of shape `[C, S, B]`.
+/// All layers of all batches are copied into one staging tensor, so it takes one submission and one readback.
+pub(crate) async fn back_batches(
+    context: &Context,
+    data: &[TensorGpu<f32, ReadWrite>],
+    batches: Vec<usize>,
+) -> Result<Vec<TensorCpu<f32>>, TensorError> {
+    let num_layer = data.len();
+    let Some(shape) = data.first().map(|data| data.shape()) else {
+        return Err(TensorError::Empty);
+    };
+    if batches.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let tensor: TensorGpu<f32, ReadWrite> =
+        context.tensor_init([shape[0], shape[1], batches.len() * num_layer, 1]);
+    let mut encoder = context.device.create_command_encoder(&Default::default());
+    for (index, &batch) in batches.iter().enumerate() {
+        for (layer, data) in data.iter().enumerate() {
+            encoder.copy_tensor_batch(data, &tensor, batch, index * num_layer + layer)?;
+        }
+    }
+    context.queue.submit(Some(encoder.finish()));
+
+    let layers = tensor.back().await.split(2)?;
+    layers
+        .chunks(num_layer)
+        .map(|layers| TensorCpu::stack(layers.to_vec()))
+        .collect()
+}
+
 pub trait AsAny {
     fn as_any(&self) -> &dyn Any;
 }
@@ -80,14 +229,222 @@ pub trait State {
     fn load(&self, tensor: TensorCpu<f32>, batch: usize) -> Result<(), TensorError>;
     /// Read back a batch of the state from GPU to CPU.
     fn back(&self, batch: usize) -> BoxFuture<Result<TensorCpu<f32>, TensorError>>;
+    /// Read back multiple batches of the state from GPU to CPU.
+    /// By default the batches are read back one by one; the built-in states override this to
+    /// read back all of them with one submission and one readback.
+    fn back_many(&self, batches: &[usize]) -> BoxFuture<Result<Vec<TensorCpu<f32>>, TensorError>> {
+        let futures = batches.iter().map(|&batch| self.back(batch)).collect_vec();
+        Box::pin(futures::future::try_join_all(futures))
+    }
     /// Write into the state from a GPU tensor.
     fn write(&self, tensor: TensorGpu<f32, ReadWrite>, batch: usize) -> Result<(), TensorError>;
     /// Read the state out into a GPU tensor.
     fn read(&self, batch: usize) -> Result<TensorGpu<f32, ReadWrite>, TensorError>;
     /// Get an embed vector from a backed state.
     fn embed(&self, layer: usize, backed: TensorCpu<f32>) -> Result<TensorCpu<f32>, TensorError>;
+    /// Extract the WKV states of all heads of a layer from a backed state (V5 and above).
+    /// Returns a tensor of shape `[S, S, H, 1]`, where `S` is the head size and `H` the number of heads;
+    /// the head state keeps the packed layout's order.
+    fn head_state(
+        &self,
+        _backed: &TensorCpu<f32>,
+        _layer: usize,
+    ) -> Result<TensorCpu<f32>, TensorError> {
+        Err(TensorError::HeadState)
+    }
+    /// Replace the WKV states of all heads of a layer in a backed state (V5 and above).
+    /// The heads must be of shape `[S, S, H, 1]`, as returned by [`State::head_state`].
+    fn set_head_state(
+        &self,
+        _backed: TensorCpu<f32>,
+        _layer: usize,
+        _heads: &TensorCpu<f32>,
+    ) -> Result<TensorCpu<f32>, TensorError> {
+        Err(TensorError::HeadState)
+    }
+    /// Multiply the WKV state of one head of a layer in a backed state by `factor` (V5 and above).
+    fn scale_head(
+        &self,
+        _backed: TensorCpu<f32>,
+        _layer: usize,
+        _head: usize,
+        _factor: f32,
+    ) -> Result<TensorCpu<f32>, TensorError> {
+        Err(TensorError::HeadState)
+    }
+    /// Zero the WKV state of one head of a layer in a backed state (V5 and above).
+    fn zero_head(
+        &self,
+        backed: TensorCpu<f32>,
+        layer: usize,
+        head: usize,
+    ) -> Result<TensorCpu<f32>, TensorError> {
+        self.scale_head(backed, layer, head, 0.0)
+    }
+    /// Reorder a backed state into the layout of the reference Python implementation,
+    /// i.e., a list of tensors ordered by layers and then by components within a layer, for comparing against it.
+    /// See [`serialize_reference_layout`](crate::model::serialize_reference_layout) for writing the list into a file.
+    fn to_reference_layout(
+        &self,
+        _backed: &TensorCpu<f32>,
+    ) -> Result<Vec<TensorCpu<f32>>, TensorError> {
+        Err(TensorError::ReferenceLayout)
+    }
 }
 
+/// How [`BackedState::project`] maps a backed state onto a model of another size.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum StateProjection {
+    /// Keep the leading layers and channels. The target must not be larger along any axis.
+    #[default]
+    Truncate,
+    /// Keep all layers and channels, and fill the new ones from the target's initial state.
+    /// The target must not be smaller along any axis.
+    Pad,
+    /// Linearly resample layers and channels (heads for WKV states).
+    Interpolate,
+}
+
+impl StateProjection {
+    /// Weighted sources of each of the `to` target indices. Targets without sources keep their initial values.
+    fn sources(self, from: usize, to: usize) -> Result<Vec<Vec<(usize, f32)>>, ModelError> {
+        match self {
+            StateProjection::Truncate if to > from => Err(ModelError::InvalidProjection),
+            StateProjection::Pad if to < from => Err(ModelError::InvalidProjection),
+            StateProjection::Truncate | StateProjection::Pad => Ok((0..to)
+                .map(|index| match index < from {
+                    true => vec![(index, 1.0)],
+                    false => vec![],
+                })
+                .collect()),
+            StateProjection::Interpolate if from == 0 => Ok(vec![vec![]; to]),
+            StateProjection::Interpolate => Ok((0..to)
+                .map(|index| {
+                    let x = match to {
+                        1 => 0.0,
+                        _ => (index * (from - 1)) as f32 / (to - 1) as f32,
+                    };
+                    let lo = x.floor() as usize;
+                    let hi = (lo + 1).min(from - 1);
+                    let t = x - lo as f32;
+                    match lo == hi {
+                        true => vec![(lo, 1.0)],
+                        false => vec![(lo, 1.0 - t), (hi, t)],
+                    }
+                })
+                .collect()),
+        }
+    }
+}
+
+/// Rows of each layer in a backed state, and the shape of the backed state.
+fn state_layout(info: &ModelInfo) -> (usize, Shape) {
+    match info.version {
+        ModelVersion::V4 => (5, Shape::new(info.num_emb, 5 * info.num_layer, 1, 1)),
+        _ => {
+            let head_size = info.num_emb / info.num_head;
+            let shape = Shape::new(info.num_emb, head_size + 2, info.num_layer, 1);
+            (head_size + 2, shape)
+        }
+    }
+}
+
+/// The initial backed state of a model, as given by [`State::init`].
+fn state_init(info: &ModelInfo) -> TensorCpu<f32> {
+    let (num_row, shape) = state_layout(info);
+    let data = (0..shape.len())
+        .map(|index| match info.version {
+            // the 4th row of each V4 layer holds the running maximum of the attention
+            ModelVersion::V4 if index / info.num_emb % num_row == 3 => f32::MIN,
+            _ => 0.0,
+        })
+        .collect::<Vec<_>>();
+    TensorCpu::from_data(shape, data).unwrap()
+}
+
+/// A backed state of one batch (see [`State::back`]), together with the info of the model it belongs to.
+#[derive(Debug, Clone)]
+pub struct BackedState {
+    pub info: ModelInfo,
+    pub data: TensorCpu<f32>,
+}
+
+impl BackedState {
+    /// Pair a backed state with the info of its model, checking that the shapes match.
+    pub fn new(info: ModelInfo, data: TensorCpu<f32>) -> Result<Self, TensorError> {
+        let (_, shape) = state_layout(&info);
+        data.check_shape(shape)?;
+        Ok(Self { info, data })
+    }
+
+    /// Map the state onto a model of the same version and head size, but with different numbers
+    /// of layers or embedding sizes. This is a best-effort tool for research on transferring states;
+    /// the result is not expected to be what the target model would produce.
+    ///
+    /// Whatever the source doesn't cover is filled from the target's initial state.
+    pub fn project(&self, to: &ModelInfo, strategy: StateProjection) -> Result<Self> {
+        let from = &self.info;
+        if from.version != to.version
+            || (from.version != ModelVersion::V4
+                && from.num_emb / from.num_head != to.num_emb / to.num_head)
+        {
+            return Err(ModelError::IncompatibleState.into());
+        }
+
+        let (num_row, from_shape) = state_layout(from);
+        self.data.check_shape(from_shape)?;
+
+        let layers = strategy.sources(from.num_layer, to.num_layer)?;
+        let channels = strategy.sources(from.num_emb, to.num_emb)?;
+        // WKV states are resampled by heads, keeping the channels within each head
+        let heads = match from.version {
+            ModelVersion::V4 => vec![],
+            _ => strategy.sources(from.num_head, to.num_head)?,
+        };
+        let head_size = to.num_emb / to.num_head;
+
+        let source = self.data.data();
+        let init = state_init(to);
+        let shape = init.shape();
+        let mut data = init.to_vec();
+        for (layer, layer_sources) in layers.iter().enumerate() {
+            for row in 0..num_row {
+                let wkv = from.version != ModelVersion::V4 && (1..num_row - 1).contains(&row);
+                for (channel, sources) in channels.iter().enumerate() {
+                    let channel_sources = match wkv {
+                        true => heads[channel / head_size]
+                            .iter()
+                            .map(|&(head, w)| (head * head_size + channel % head_size, w))
+                            .collect(),
+                        false => sources.clone(),
+                    };
+                    if layer_sources.is_empty() || channel_sources.is_empty() {
+                        continue;
+                    }
+
+                    let mut value = 0.0;
+                    for &(from_layer, w_layer) in layer_sources {
+                        for &(from_channel, w_channel) in &channel_sources {
+                            let index = (from_layer * num_row + row) * from.num_emb + from_channel;
+                            value += w_layer * w_channel * source[index];
+                        }
+                    }
+                    data[(layer * num_row + row) * to.num_emb + channel] = value;
+                }
+            }
+        }
+
+        let info = to.clone();
+        let data = TensorCpu::from_data(shape, data)?;
+        Ok(Self { info, data })
+    }
+}
+
+/// A runtime of some model version, holding its own state and intermediate buffers.
+///
+/// Weights are reference-counted GPU buffers shared by all clones of a model, so cloning one is cheap.
+/// Multiple runtimes (e.g., with different batch numbers or hooks) can thus be created over clones of
+/// the same model without duplicating weights, and driven from different threads independently.
 pub trait ModelRuntime {
     fn info(&self) -> ModelInfo;
     fn state(&self) -> impl State + AsAny + Send + Sync + 'static;
@@ -107,6 +464,78 @@ pub enum Quant {
     NF4,
 }
 
+impl std::str::FromStr for Quant {
+    type Err = ModelError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" | "fp16" => Ok(Self::None),
+            "int8" => Ok(Self::Int8),
+            "nf4" => Ok(Self::NF4),
+            _ => Err(ModelError::InvalidQuant),
+        }
+    }
+}
+
+/// Quantization of the layers from `start` up to (not including) `end`,
+/// or up to the last layer if `end` is `None`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct QuantRule {
+    pub quant: Quant,
+    pub start: usize,
+    #[serde(default)]
+    pub end: Option<usize>,
+}
+
+/// A list of [`QuantRule`]s, expanded into the per-layer quantization map for [`ModelBuilder::quant`].
+/// Later rules override earlier ones on overlapping layers.
+///
+/// The string form is a comma-separated list of `quant:start..end`, e.g., `"fp16:0..8,nf4:8.."`.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct QuantPreset(pub Vec<QuantRule>);
+
+impl QuantPreset {
+    /// Expand the rules into a per-layer map, checking that all ranges lie within the model's layers.
+    pub fn resolve(&self, info: &ModelInfo) -> Result<HashMap<usize, Quant>, ModelError> {
+        let mut map = HashMap::new();
+        for rule in &self.0 {
+            let end = rule.end.unwrap_or(info.num_layer);
+            if rule.start > end || end > info.num_layer {
+                return Err(ModelError::InvalidQuant);
+            }
+            map.extend((rule.start..end).map(|layer| (layer, rule.quant)));
+        }
+        Ok(map)
+    }
+}
+
+impl std::str::FromStr for QuantPreset {
+    type Err = ModelError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_rule = |rule: &str| -> Option<QuantRule> {
+            let (quant, range) = rule.trim().split_once(':')?;
+            let (start, end) = range.trim().split_once("..")?;
+            let quant = quant.trim().parse().ok()?;
+            let start = match start.trim() {
+                "" => 0,
+                start => start.parse().ok()?,
+            };
+            let end = match end.trim() {
+                "" => None,
+                end => Some(end.parse().ok()?),
+            };
+            Some(QuantRule { quant, start, end })
+        };
+        s.split(',')
+            .filter(|rule| !rule.trim().is_empty())
+            .map(|rule| parse_rule(rule).ok_or(ModelError::InvalidQuant))
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
 /// Device to put the model's embed tensor.
 #[wasm_bindgen]
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
@@ -116,6 +545,32 @@ pub enum EmbedDevice {
     Gpu,
 }
 
+/// Part of the model being loaded when the GPU ran out of memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OomStage {
+    /// The embedding and the head.
+    Embed,
+    /// The layer of the given index.
+    Layer(usize),
+}
+
+/// How to go on after running out of GPU memory while loading the model.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OomAction {
+    /// Fail with [`ContextError::OutOfMemory`](crate::context::ContextError::OutOfMemory).
+    #[default]
+    Fail,
+    /// Keep the embed tensor on the CPU only and retry. Applies to [`OomStage::Embed`].
+    EmbedCpu,
+    /// Retry the layer and load all following ones with the given quantization.
+    /// Applies to [`OomStage::Layer`].
+    Quant(Quant),
+}
+
+/// A user policy deciding what to do when the GPU runs out of memory while loading the model.
+/// See [`ModelBuilder::oom_policy`].
+pub type OomPolicy = Arc<dyn Fn(OomStage) -> OomAction + Send + Sync>;
+
 pub trait Build<T> {
     fn build(self) -> impl Future<Output = Result<T>>;
 }
@@ -126,6 +581,17 @@ pub struct ModelBuilder<R: Reader> {
     pub lora: Vec<Lora<R>>,
     pub quant: HashMap<usize, Quant>,
     pub embed_device: EmbedDevice,
+    pub rescale: Option<usize>,
+    pub layer_scale: Option<Vec<f32>>,
+    pub ln_eps: Option<f32>,
+    pub gn_eps: Option<f32>,
+    pub real_vocab: Option<usize>,
+    pub head_size: Option<usize>,
+    pub strict: bool,
+    pub tied_head: bool,
+    pub verify: bool,
+    pub metadata: HashMap<String, String>,
+    pub oom_policy: Option<OomPolicy>,
 }
 
 impl<R: Reader> ModelBuilder<R> {
@@ -136,6 +602,17 @@ impl<R: Reader> ModelBuilder<R> {
             lora: vec![],
             quant: Default::default(),
             embed_device: Default::default(),
+            rescale: None,
+            layer_scale: None,
+            ln_eps: None,
+            gn_eps: None,
+            real_vocab: None,
+            head_size: None,
+            strict: false,
+            tied_head: false,
+            verify: false,
+            metadata: Default::default(),
+            oom_policy: None,
         }
     }
 
@@ -153,6 +630,102 @@ impl<R: Reader> ModelBuilder<R> {
         self.lora.push(value);
         self
     }
+
+    /// Halve the residual every `value` layers, or never if `value` is 0.
+    /// Defaults to the `rescale_layer` entry of the checkpoint's [`metadata`](Self::metadata) if present,
+    /// or the version's `Model::RESCALE_LAYER` otherwise.
+    pub fn rescale(mut self, value: usize) -> Self {
+        self.rescale = Some(value);
+        self
+    }
+
+    /// Scale the residual by `value[layer]` after each layer instead of halving it periodically,
+    /// e.g., to keep the activations of an unusual fine-tune within FP16 range. There must be one
+    /// factor for each layer, or the build fails with [`ModelError::InvalidLayerScale`].
+    /// Weights are discounted accordingly, so outputs are unchanged up to precision.
+    /// If not set, this is read from the `layer_scale` entry of the [`metadata`](Self::metadata).
+    pub fn layer_scale(mut self, value: Vec<f32>) -> Self {
+        self.layer_scale = Some(value);
+        self
+    }
+
+    /// Epsilon of layer norms. Defaults to the version's `Model::LN_EPS`.
+    pub fn ln_eps(mut self, value: f32) -> Self {
+        self.ln_eps = Some(value);
+        self
+    }
+
+    /// Epsilon of group norms (V5 and above). Defaults to the version's `Model::GN_EPS`.
+    pub fn gn_eps(mut self, value: f32) -> Self {
+        self.gn_eps = Some(value);
+        self
+    }
+
+    /// Number of real tokens for checkpoints with padded heads (e.g., 65529 out of 65536).
+    /// [`ModelInfo::num_vocab`] reports this number, while outputs keep the head's length,
+    /// with logits of the padding rows set to `-inf`.
+    pub fn real_vocab(mut self, value: usize) -> Self {
+        self.real_vocab = Some(value);
+        self
+    }
+
+    /// Size of each head (V5 and above), for checkpoints whose head size cannot be detected
+    /// from the shapes of their tensors.
+    pub fn head_size(mut self, value: usize) -> Self {
+        self.head_size = Some(value);
+        self
+    }
+
+    /// Verify tensor contents against the hashes recorded in the checkpoint's [`metadata`](Self::metadata)
+    /// before loading, failing with an [`IntegrityError`](super::loader::IntegrityError) that lists
+    /// every corrupted tensor. Fails with [`ModelError::MissingHashes`] if the metadata holds no hashes,
+    /// e.g., when it is not set or the checkpoint was converted without them.
+    pub fn verify(mut self, value: bool) -> Self {
+        self.verify = value;
+        self
+    }
+
+    /// Metadata of the checkpoint, read from the safetensors file `data` with
+    /// [`read_metadata`](super::loader::read_metadata). Settings that are not set explicitly are
+    /// detected from it, e.g., the interval of [`rescale`](Self::rescale).
+    pub fn metadata(mut self, data: &[u8]) -> Self {
+        self.metadata = super::loader::read_metadata(data);
+        self
+    }
+
+    /// Check all tensors before loading, failing with a
+    /// [`TensorMismatchError`](super::loader::TensorMismatchError) that lists every missing and
+    /// unexpected tensor, instead of failing on the first missing one.
+    pub fn strict(mut self, value: bool) -> Self {
+        self.strict = value;
+        self
+    }
+
+    /// Use the embedding as the head, for checkpoints whose head is tied to the embedding and thus has
+    /// no `head.weight` of its own. With the embed on [GPU](EmbedDevice::Gpu), both share one buffer.
+    pub fn tied_head(mut self, value: bool) -> Self {
+        self.tied_head = value;
+        self
+    }
+
+    /// Consult `policy` when the GPU runs out of memory while loading, e.g., to spill the embed
+    /// to the CPU or to quantize the remaining layers, instead of failing with
+    /// [`ContextError::OutOfMemory`](crate::context::ContextError::OutOfMemory) right away.
+    /// An action that changes nothing fails the build.
+    ///
+    /// Only loading the model is covered. Allocations of states, runtimes and jobs later on are not;
+    /// wrap them in [`catch_oom`](crate::context::ContextInternal::catch_oom) to retry with, e.g.,
+    /// fewer batches or a smaller token chunk size.
+    ///
+    /// Out-of-memory errors are caught for the whole device, so nothing else may use the context
+    /// while the model builds, or its errors would be taken as the model's.
+    pub fn oom_policy(
+        mut self,
+        policy: impl Fn(OomStage) -> OomAction + Send + Sync + 'static,
+    ) -> Self {
+        self.oom_policy = Some(Arc::new(policy));
+        self
+    }
 }
 
 pub trait ContextAutoLimits {
@@ -172,3 +745,175 @@ impl ContextAutoLimits for ContextBuilder {
         self
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        layer_discounts, resolve_layer_scale, BackedState, ModelError, ModelInfo, ModelVersion,
+        Quant, QuantPreset, StateProjection,
+    };
+    use crate::tensor::{TensorCpu, TensorInit};
+
+    #[test]
+    fn test_quant_preset() -> Result<(), ModelError> {
+        let info = ModelInfo {
+            version: ModelVersion::V5,
+            num_layer: 24,
+            num_emb: 2048,
+            num_hidden: 7168,
+            num_vocab: 65536,
+            num_head: 32,
+            time_mix_adapter_size: 0,
+            time_decay_adapter_size: 0,
+        };
+
+        let preset: QuantPreset = "fp16:0..8, nf4:8..".parse()?;
+        let quant = preset.resolve(&info)?;
+        assert_eq!(quant.len(), 24);
+        assert_eq!(quant[&7], Quant::None);
+        assert_eq!(quant[&8], Quant::NF4);
+        assert_eq!(quant[&23], Quant::NF4);
+
+        // later rules override earlier ones
+        let preset: QuantPreset = "int8:..,nf4:20..22".parse()?;
+        let quant = preset.resolve(&info)?;
+        assert_eq!(quant[&19], Quant::Int8);
+        assert_eq!(quant[&21], Quant::NF4);
+        assert_eq!(quant[&22], Quant::Int8);
+
+        assert!("int4:0..8".parse::<QuantPreset>().is_err());
+        assert!("int8:8".parse::<QuantPreset>().is_err());
+        let preset: QuantPreset = "int8:0..32".parse()?;
+        assert_eq!(preset.resolve(&info), Err(ModelError::InvalidQuant));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_head_size() -> Result<(), ModelError> {
+        let mut info = ModelInfo {
+            version: ModelVersion::V5,
+            num_layer: 24,
+            num_emb: 2560,
+            num_hidden: 8960,
+            num_vocab: 65536,
+            num_head: 40,
+            time_mix_adapter_size: 0,
+            time_decay_adapter_size: 0,
+        };
+
+        info.set_head_size(80)?;
+        assert_eq!(info.num_head, 32);
+
+        for head_size in [0, 2, 30, 96, 2560] {
+            assert_eq!(
+                info.set_head_size(head_size),
+                Err(ModelError::InvalidHeadSize)
+            );
+        }
+        assert_eq!(info.num_head, 32);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_project_state() -> anyhow::Result<()> {
+        let info = |num_layer, num_emb, num_head| ModelInfo {
+            version: ModelVersion::V5,
+            num_layer,
+            num_emb,
+            num_hidden: num_emb * 4,
+            num_vocab: 256,
+            num_head,
+            time_mix_adapter_size: 0,
+            time_decay_adapter_size: 0,
+        };
+        // rows: att x, 4 rows of WKV state, ffn x
+        let index = |info: &ModelInfo, layer: usize, row: usize, channel: usize| {
+            (layer * 6 + row) * info.num_emb + channel
+        };
+
+        let from = info(2, 8, 2);
+        let data = (0..8 * 6 * 2).map(|x| x as f32 + 1.0).collect::<Vec<_>>();
+        let backed = BackedState::new(from.clone(), TensorCpu::from_data([8, 6, 2, 1], data)?)?;
+        let source = backed.data.to_vec();
+
+        // pad to 3 layers and 3 heads
+        let to = info(3, 12, 3);
+        let padded = backed.project(&to, StateProjection::Pad)?;
+        let output = padded.data.to_vec();
+        assert_eq!(padded.info, to);
+        assert_eq!(output[index(&to, 1, 2, 5)], source[index(&from, 1, 2, 5)]);
+        assert_eq!(output[index(&to, 1, 5, 9)], 0.0);
+        assert_eq!(output[index(&to, 2, 0, 0)], 0.0);
+
+        // truncating the padded state recovers the source
+        let output = padded.project(&from, StateProjection::Truncate)?;
+        assert_eq!(output.data.to_vec(), source);
+
+        // interpolate: the middle layer is the mean of the two, and the middle head of the WKV state
+        // is the mean of the two heads
+        let output = backed
+            .project(&to, StateProjection::Interpolate)?
+            .data
+            .to_vec();
+        let mean = |a: f32, b: f32| 0.5 * (a + b);
+        assert_eq!(
+            output[index(&to, 1, 0, 0)],
+            mean(source[index(&from, 0, 0, 0)], source[index(&from, 1, 0, 0)])
+        );
+        assert_eq!(
+            output[index(&to, 0, 3, 5)],
+            mean(source[index(&from, 0, 3, 1)], source[index(&from, 0, 3, 5)])
+        );
+
+        let error =
+            |result: anyhow::Result<BackedState>| result.unwrap_err().downcast::<ModelError>().ok();
+        assert_eq!(
+            error(backed.project(&to, StateProjection::Truncate)),
+            Some(ModelError::InvalidProjection)
+        );
+        assert_eq!(
+            error(backed.project(&info(2, 12, 2), StateProjection::Pad)),
+            Some(ModelError::IncompatibleState)
+        );
+        assert!(BackedState::new(to, backed.data.clone()).is_err());
+
+        // new layers of a V4 state start from its initial state
+        let info = |num_layer| ModelInfo {
+            version: ModelVersion::V4,
+            ..info(num_layer, 4, 1)
+        };
+        let backed = BackedState::new(info(1), TensorCpu::from_data([4, 5, 1, 1], vec![1.0; 20])?)?;
+        let output = backed
+            .project(&info(2), StateProjection::Pad)?
+            .data
+            .to_vec();
+        assert_eq!(&output[..20], &[1.0; 20]);
+        assert_eq!(&output[32..36], &[f32::MIN; 4]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_layer_scale() {
+        // the default schedule matches halving the residual every `rescale` layers
+        let scale = resolve_layer_scale(12, 6, None).unwrap();
+        let discounts = layer_discounts(&scale);
+        for (layer, discount) in discounts.into_iter().enumerate() {
+            assert_eq!(discount, 2.0_f32.powi(-((layer / 6) as i32)));
+        }
+        assert!(resolve_layer_scale(12, 0, None)
+            .unwrap()
+            .iter()
+            .all(|&x| x == 1.0));
+
+        let scale = resolve_layer_scale(4, 6, Some(vec![0.5, 1.0, 0.25, 1.0])).unwrap();
+        assert_eq!(layer_discounts(&scale), vec![1.0, 0.5, 0.5, 0.125]);
+
+        assert_eq!(
+            resolve_layer_scale(4, 6, Some(vec![0.5, 1.0, 0.25])),
+            Err(ModelError::InvalidLayerScale)
+        );
+    }
+}
@@ -0,0 +1,204 @@
+use half::f16;
+use itertools::Itertools;
+
+use crate::{
+    context::Context,
+    tensor::{
+        kind::ReadWrite,
+        ops::{Activation, TensorOp},
+        TensorCpu, TensorError, TensorGpu, TensorInit, TensorInto, TensorShape,
+    },
+};
+
+/// A set of key vectors (e.g., embeddings taken from backed states with [`State::embed`](super::model::State::embed),
+/// or pooled hidden outputs) to compare queries against by cosine similarity on GPU.
+///
+/// Keys are normalized and stored in half precision, so similarities are accurate to about `1e-3`.
+#[derive(Debug, Clone)]
+pub struct Retrieval {
+    context: Context,
+    keys: TensorGpu<f16, ReadWrite>,
+    num_emb: usize,
+    num_key: usize,
+}
+
+impl Retrieval {
+    /// Build the key set. All keys must have the same number of elements; their shapes are otherwise ignored.
+    pub fn new(context: &Context, keys: &[TensorCpu<f32>]) -> Result<Self, TensorError> {
+        let num_emb = keys.first().ok_or(TensorError::Empty)?.len();
+        let num_key = keys.len();
+
+        // the matmul kernel works on blocks of 4 elements and 4 rows; zero paddings do not change the dot products
+        let num_emb_padded = num_emb.next_multiple_of(4);
+        let num_key_padded = num_key.next_multiple_of(4);
+
+        let mut data = vec![f16::ZERO; num_emb_padded * num_key_padded];
+        for (key, row) in keys.iter().zip(data.chunks_mut(num_emb_padded)) {
+            if key.len() != num_emb {
+                return Err(TensorError::Size(key.len(), num_emb));
+            }
+            for (x, y) in normalize(key).into_iter().zip(row.iter_mut()) {
+                *y = f16::from_f32(x);
+            }
+        }
+        let keys: TensorCpu<f16> =
+            TensorInit::from_data([num_emb_padded, num_key_padded, 1, 1], data)?;
+        let keys = keys.transfer_into(context);
+
+        Ok(Self {
+            context: context.clone(),
+            keys,
+            num_emb,
+            num_key,
+        })
+    }
+
+    #[inline]
+    pub fn num_key(&self) -> usize {
+        self.num_key
+    }
+
+    /// Compute cosine similarities between each query and all keys.
+    /// Returns a tensor of shape `[K, Q]`, where `K` is the number of keys and `Q` the number of queries.
+    pub async fn similarity(
+        &self,
+        queries: &[TensorCpu<f32>],
+    ) -> Result<TensorCpu<f32>, TensorError> {
+        let context = &self.context;
+        let num_query = queries.len();
+        if num_query == 0 {
+            return Err(TensorError::Empty);
+        }
+
+        let num_emb_padded = self.keys.shape()[0];
+        let num_key_padded = self.keys.shape()[1];
+
+        let mut data = vec![0.0; num_emb_padded * num_query];
+        for (query, row) in queries.iter().zip(data.chunks_mut(num_emb_padded)) {
+            if query.len() != self.num_emb {
+                return Err(TensorError::Size(query.len(), self.num_emb));
+            }
+            row[..self.num_emb].copy_from_slice(&normalize(query));
+        }
+        let input: TensorGpu<f32, ReadWrite> =
+            context.tensor_from_data([num_emb_padded, num_query, 1, 1], data)?;
+        let output: TensorGpu<f32, ReadWrite> =
+            context.tensor_init([num_key_padded, num_query, 1, 1]);
+
+        let op = TensorOp::matmul_vec_fp16(
+            &self.keys,
+            input.view(.., .., .., ..)?,
+            output.view(.., .., .., ..)?,
+            Activation::None,
+        )?;
+        context.submit(context.encode(&op));
+
+        let output = output.back().await;
+        let data = output
+            .chunks(num_key_padded)
+            .flat_map(|scores| scores[..self.num_key].to_vec())
+            .collect_vec();
+        TensorInit::from_data([self.num_key, num_query, 1, 1], data)
+    }
+
+    /// Find the `top` most similar keys for each query, sorted by descending similarity.
+    pub async fn search(
+        &self,
+        queries: &[TensorCpu<f32>],
+        top: usize,
+    ) -> Result<Vec<Vec<(usize, f32)>>, TensorError> {
+        let similarity = self.similarity(queries).await?;
+        let result = similarity
+            .chunks(self.num_key)
+            .map(|scores| {
+                scores
+                    .iter()
+                    .copied()
+                    .enumerate()
+                    .sorted_by(|(_, x), (_, y)| y.total_cmp(x))
+                    .take(top)
+                    .collect()
+            })
+            .collect();
+        Ok(result)
+    }
+}
+
+fn normalize(x: &TensorCpu<f32>) -> Vec<f32> {
+    let norm = x
+        .iter()
+        .map(|x| x * x)
+        .sum::<f32>()
+        .sqrt()
+        .max(f32::EPSILON);
+    x.iter().map(|x| x / norm).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use itertools::Itertools;
+    use wgpu::{Instance, PowerPreference};
+
+    use super::Retrieval;
+    use crate::{
+        context::{Context, ContextBuilder, InstanceExt},
+        tensor::{TensorCpu, TensorInit, TensorShape},
+    };
+
+    async fn create_context() -> Result<Context> {
+        let instance = Instance::default();
+        let adapter = instance.adapter(PowerPreference::HighPerformance).await?;
+        let context = ContextBuilder::new(adapter).build().await?;
+        Ok(context)
+    }
+
+    #[test]
+    fn test_similarity() -> Result<()> {
+        let context = match pollster::block_on(create_context()) {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+        fastrand::seed(42);
+
+        const C: usize = 257;
+        const K: usize = 7;
+        const Q: usize = 3;
+
+        let vectors = |count: usize| {
+            (0..count)
+                .map(|_| {
+                    let data = (0..C).map(|_| fastrand::f32() - 0.5).collect_vec();
+                    TensorCpu::from_data([C, 1, 1, 1], data).unwrap()
+                })
+                .collect_vec()
+        };
+        let keys = vectors(K);
+        let mut queries = vectors(Q);
+        // a scaled copy of a key must be most similar to that key
+        queries[0] = keys[5].clone().map(|x| 3.0 * x);
+
+        let retrieval = Retrieval::new(&context, &keys)?;
+        let similarity = pollster::block_on(retrieval.similarity(&queries))?;
+        assert_eq!(similarity.shape(), [K, Q, 1, 1].into());
+
+        let cosine = |x: &TensorCpu<f32>, y: &TensorCpu<f32>| {
+            let dot: f32 = x.iter().zip_eq(y.iter()).map(|(x, y)| x * y).sum();
+            let norm = |x: &TensorCpu<f32>| x.iter().map(|x| x * x).sum::<f32>().sqrt();
+            dot / (norm(x) * norm(y))
+        };
+        for (q, query) in queries.iter().enumerate() {
+            for (k, key) in keys.iter().enumerate() {
+                let a = similarity[(k, q, 0, 0)];
+                let b = cosine(query, key);
+                assert!((a - b).abs() < 1.0e-2, "query {q}, key {k}: {a} vs. {b}");
+            }
+        }
+
+        let search = pollster::block_on(retrieval.search(&queries, 2))?;
+        assert_eq!(search[0][0].0, 5);
+        assert_eq!(search[0].len(), 2);
+
+        Ok(())
+    }
+}
@@ -14,7 +14,7 @@ pub async fn softmax_one<T: Float>(
 
     let tensor: TensorGpu<_, _> = input.transfer_into(context);
     let op = TensorOp::softmax(&tensor)?;
-    context.queue.submit(context.encode(&op));
+    context.submit(context.encode(&op));
 
     let output = tensor.back().await;
     Ok(output)
@@ -34,7 +34,7 @@ pub async fn softmax<T: Float>(
         }
         tensors.push(tensor);
     }
-    context.queue.submit(context.encode(&TensorOp::List(ops)));
+    context.submit(context.encode(&TensorOp::List(ops)));
 
     let mut output = Vec::with_capacity(tensors.len());
     for tensor in tensors.into_iter() {
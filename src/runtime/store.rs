@@ -0,0 +1,163 @@
+use std::{collections::HashMap, future::Future, sync::RwLock};
+
+use anyhow::Result;
+
+use crate::tensor::TensorCpu;
+
+/// Key-value storage of backed states (e.g., for a prefix cache), which may live in memory, on disk or remotely.
+/// Implement this to plug in other backends, such as a distributed cache.
+#[trait_variant::make(StateStoreSend: Send)]
+pub trait StateStore {
+    /// Get the state stored under `key`, if any.
+    fn get(&self, key: &str) -> impl Future<Output = Result<Option<TensorCpu<f32>>>>;
+    /// Store `state` under `key`, replacing any existing one.
+    fn put(&self, key: &str, state: TensorCpu<f32>) -> impl Future<Output = Result<()>>;
+    /// Remove the state stored under `key`, if any.
+    fn delete(&self, key: &str) -> impl Future<Output = Result<()>>;
+}
+
+/// A [`StateStore`] in host memory.
+#[derive(Debug, Default)]
+pub struct MemoryStore(RwLock<HashMap<String, TensorCpu<f32>>>);
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Default::default()
+    }
+}
+
+impl StateStoreSend for MemoryStore {
+    async fn get(&self, key: &str) -> Result<Option<TensorCpu<f32>>> {
+        Ok(self.0.read().unwrap().get(key).cloned())
+    }
+
+    async fn put(&self, key: &str, state: TensorCpu<f32>) -> Result<()> {
+        self.0.write().unwrap().insert(key.to_string(), state);
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.0.write().unwrap().remove(key);
+        Ok(())
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use file::FileStore;
+
+#[cfg(not(target_arch = "wasm32"))]
+mod file {
+    use std::path::PathBuf;
+
+    use anyhow::Result;
+    use safetensors::{tensor::TensorView, Dtype, SafeTensors};
+
+    use super::StateStoreSend;
+    use crate::{
+        runtime::loader::TensorFromReader,
+        tensor::{TensorCpu, TensorShape},
+    };
+
+    /// Name of the tensor in each stored file.
+    const STATE_NAME: &str = "state";
+
+    /// A [`StateStore`](super::StateStore) keeping each state as a safetensors file in a directory.
+    /// Keys are used as file names, so they must not contain path separators.
+    /// Files are read and written synchronously.
+    #[derive(Debug, Clone)]
+    pub struct FileStore {
+        dir: PathBuf,
+    }
+
+    impl FileStore {
+        pub fn new(dir: impl Into<PathBuf>) -> Result<Self> {
+            let dir = dir.into();
+            std::fs::create_dir_all(&dir)?;
+            Ok(Self { dir })
+        }
+
+        fn path(&self, key: &str) -> Result<PathBuf> {
+            if key.is_empty() || key.contains(['/', '\\']) || key == "." || key == ".." {
+                anyhow::bail!("invalid state key {key}");
+            }
+            Ok(self.dir.join(format!("{key}.st")))
+        }
+    }
+
+    impl StateStoreSend for FileStore {
+        async fn get(&self, key: &str) -> Result<Option<TensorCpu<f32>>> {
+            let path = self.path(key)?;
+            let data = match std::fs::read(path) {
+                Ok(data) => data,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                Err(err) => return Err(err.into()),
+            };
+
+            let model = SafeTensors::deserialize(&data)?;
+            let tensor = model.tensor(STATE_NAME)?;
+            let shape = tensor.shape().to_vec();
+            let tensor = (tensor.dtype(), shape, tensor.data().into());
+            Ok(Some(TensorCpu::from_reader(tensor)?))
+        }
+
+        async fn put(&self, key: &str, state: TensorCpu<f32>) -> Result<()> {
+            let path = self.path(key)?;
+            let shape = state.shape();
+            let shape = vec![shape[3], shape[2], shape[1], shape[0]];
+            let data: &[u8] = bytemuck::cast_slice(&state.data()[..]);
+            let tensors = [(STATE_NAME, TensorView::new(Dtype::F32, shape, data)?)];
+            let data = safetensors::serialize(tensors, &None)?;
+            std::fs::write(path, data)?;
+            Ok(())
+        }
+
+        async fn delete(&self, key: &str) -> Result<()> {
+            let path = self.path(key)?;
+            match std::fs::remove_file(path) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+                _ => Ok(()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::{MemoryStore, StateStore};
+    use crate::tensor::{TensorCpu, TensorInit, TensorShape};
+
+    async fn check(store: &impl StateStore) -> Result<()> {
+        let state: TensorCpu<f32> =
+            TensorCpu::from_data([4, 3, 2, 1], (0..24).map(|x| x as f32).collect::<Vec<_>>())?;
+
+        assert!(store.get("a").await?.is_none());
+        store.put("a", state.clone()).await?;
+        let loaded = store.get("a").await?.expect("state should be stored");
+        assert_eq!(loaded.shape(), state.shape());
+        assert_eq!(loaded.to_vec(), state.to_vec());
+
+        store.delete("a").await?;
+        assert!(store.get("a").await?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_memory_store() -> Result<()> {
+        pollster::block_on(check(&MemoryStore::new()))
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[test]
+    fn test_file_store() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("web-rwkv-store-{}", std::process::id()));
+        let store = super::FileStore::new(&dir)?;
+        pollster::block_on(check(&store))?;
+
+        let state = TensorCpu::from_data([1, 1, 1, 1], vec![0.0f32])?;
+        assert!(pollster::block_on(store.put("../a", state)).is_err());
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
@@ -9,28 +9,38 @@ use web_rwkv_derive::DeserializeSeed;
 use wgpu::CommandBuffer;
 
 use super::{
-    infer::{InferChunk, InferInfo, InferOutput, InferOutputBatch, InferRedirect},
-    loader::{Loader, Reader},
-    model::{AsAny, Build, EmbedDevice, ModelBuilder, ModelInfo, Quant, State as _},
+    infer::{InferChunk, InferInfo, InferOutput, InferOutputBatch, InferRedirect, InferStats},
+    loader::{parse_layer_scale, parse_rescale_layer, parse_tensor_hashes, Loader, Reader},
+    model::{
+        AsAny, Build, EmbedDevice, ModelBuilder, ModelError, ModelInfo, OomAction, OomStage, Quant,
+        State as _,
+    },
     Job, JobBuilder,
 };
 use crate::{
-    context::Context,
+    context::{Context, MemoryKind},
     num::Float,
     tensor::{
         kind::ReadWrite,
         matrix::Matrix,
         ops::{Activation, TensorCommand, TensorOp},
         shape::Shape,
-        DeepClone, IntoPackedCursors, TensorCpu, TensorError, TensorGpu, TensorGpuView, TensorInit,
-        TensorShape, TensorStack,
+        Cursor, DeepClone, IntoPackedCursors, TensorCpu, TensorError, TensorGpu, TensorGpuView,
+        TensorInit, TensorMask, TensorShape, TensorStack,
     },
 };
 
+/// Weights of a model. Cheap to clone; see [`ModelRuntime`](super::model::ModelRuntime) on sharing weights.
 #[derive(Debug, Clone, Serialize, DeserializeSeed)]
 pub struct Model {
     pub context: Context,
     pub info: ModelInfo,
+    /// Halve the residual every this many layers (0 for no rescaling).
+    pub rescale: usize,
+    /// Factor the residual is scaled by after each layer.
+    pub layer_scale: Vec<f32>,
+    /// Epsilon of layer norms.
+    pub ln_eps: f32,
     pub tensor: ModelTensor,
 }
 
@@ -39,6 +49,55 @@ impl Model {
 
     pub const LN_EPS: f32 = 1.0e-5;
     pub const GN_EPS: f32 = 64.0e-5;
+
+    /// Compute the layer-normed embedding matrix of shape `[C, V]`,
+    /// i.e., the vector each token feeds into the first layer.
+    pub async fn normed_embed(&self) -> TensorCpu<f32> {
+        let embed = &self.tensor.embed;
+        let w = embed.layer_norm.w.back().await;
+        let b = embed.layer_norm.b.back().await;
+        super::model::layer_norm_rows(&embed.w, &w, &b, self.ln_eps)
+    }
+
+    /// Overwrite a weight matrix in place with `matrix` given in the checkpoint's layout,
+    /// re-quantizing just that matrix if the layer is quantized. This allows testing weight edits
+    /// (e.g., ablations) without reloading the model. Supported are the projection matrices of layers
+    /// (e.g., `blocks.0.att.key.weight`) and `head.weight`.
+    ///
+    /// Weights are shared, so the change is seen by all clones of the model and their runtimes.
+    /// A head [tied](ModelBuilder::tied_head) to an embedding on GPU shares its buffer, so patching
+    /// `head.weight` then rewrites the GPU embedding too, but not the CPU copy of the embedding.
+    pub fn patch_matrix(&self, name: &str, matrix: &TensorCpu<f16>) -> Result<()> {
+        let (w, discount) = self.matrix(name).ok_or(ModelError::InvalidTensor)?;
+        if discount == 1.0 {
+            w.patch(matrix)?;
+        } else {
+            w.patch(&matrix.clone().map(|x| f16::from_f32(discount * x.to_f32())))?;
+        }
+        Ok(())
+    }
+
+    /// Find a patchable matrix by its name, along with the discount applied to it when loaded.
+    fn matrix(&self, name: &str) -> Option<(&Matrix, f32)> {
+        if name == "head.weight" {
+            return Some((&self.tensor.head.w, 1.0));
+        }
+
+        let (layer, name) = name.strip_prefix("blocks.")?.split_once('.')?;
+        let layer: usize = layer.parse().ok()?;
+        let tensor = self.tensor.layers.get(layer)?;
+        let discount = super::model::layer_discounts(&self.layer_scale)[layer];
+        match name {
+            "att.key.weight" => Some((&tensor.att.w_k, 1.0)),
+            "att.value.weight" => Some((&tensor.att.w_v, 1.0)),
+            "att.receptance.weight" => Some((&tensor.att.w_r, 1.0)),
+            "att.output.weight" => Some((&tensor.att.w_o, discount)),
+            "ffn.key.weight" => Some((&tensor.ffn.w_k, 1.0)),
+            "ffn.value.weight" => Some((&tensor.ffn.w_v, discount)),
+            "ffn.receptance.weight" => Some((&tensor.ffn.w_r, 1.0)),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, DeserializeSeed)]
@@ -98,6 +157,10 @@ pub struct Embed {
 pub struct Head {
     pub layer_norm: LayerNorm,
     pub w: Matrix,
+    /// Bias added to the logits, if the checkpoint has one.
+    pub b: Option<TensorGpu<f32, ReadWrite>>,
+    /// Mask selecting the real vocab rows, masking out the padding rows beyond it.
+    pub mask: Option<TensorMask>,
 }
 
 #[derive(Debug, Clone, Serialize, DeserializeSeed)]
@@ -173,13 +236,22 @@ impl super::model::State for State {
         Box::pin(self.back(batch))
     }
 
+    fn back_many(&self, batches: &[usize]) -> BoxFuture<Result<Vec<TensorCpu<f32>>, TensorError>> {
+        let batches = batches.to_vec();
+        Box::pin(super::model::back_batches(
+            &self.context,
+            std::slice::from_ref(&self.data),
+            batches,
+        ))
+    }
+
     fn write(&self, tensor: TensorGpu<f32, ReadWrite>, batch: usize) -> Result<(), TensorError> {
         tensor.check_shape([self.info.num_emb, self.info.num_layer * 5, 1, 1])?;
         let op = TensorOp::blit(
             tensor.view(.., .., .., ..)?,
             self.data.view(.., .., batch, ..)?,
         )?;
-        self.context.queue.submit(self.context.encode(&op));
+        self.context.submit(self.context.encode(&op));
         Ok(())
     }
 
@@ -190,13 +262,32 @@ impl super::model::State for State {
             self.data.view(.., .., batch, ..)?,
             tensor.view(.., .., .., ..)?,
         )?;
-        self.context.queue.submit(self.context.encode(&op));
+        self.context.submit(self.context.encode(&op));
         Ok(tensor)
     }
 
     fn embed(&self, layer: usize, backed: TensorCpu<f32>) -> Result<TensorCpu<f32>, TensorError> {
         backed.slice(.., layer, .., ..)
     }
+    /// The reference layout has 5 vectors per layer: `ffn_x`, `att_x`, `aa`, `bb` and `pp`.
+    fn to_reference_layout(
+        &self,
+        backed: &TensorCpu<f32>,
+    ) -> Result<Vec<TensorCpu<f32>>, TensorError> {
+        let info = &self.info;
+        let num_emb = info.num_emb;
+        backed.check_shape([num_emb, 5 * info.num_layer, 1, 1])?;
+
+        let row = |index: usize| {
+            let start = index * num_emb;
+            let data = backed.data()[start..start + num_emb].to_vec();
+            TensorCpu::from_data([num_emb, 1, 1, 1], data)
+        };
+        (0..info.num_layer)
+            .flat_map(|layer| [4, 0, 1, 2, 3].map(|offset| 5 * layer + offset))
+            .map(row)
+            .collect()
+    }
 }
 
 impl DeepClone for State {
@@ -270,16 +361,35 @@ impl<F: Float> Runtime<F> {
 pub struct Header<F: Float> {
     pub head_x: TensorGpu<F, ReadWrite>,
     pub head_o: TensorGpu<f32, ReadWrite>,
+    /// Half-precision copy of `head_o` to read back, if half output is enabled.
+    pub head_h: Option<TensorGpu<f16, ReadWrite>>,
+    /// Indices and logits of the top `K` tokens, if sparse output is enabled.
+    pub head_k: Option<(TensorGpu<u32, ReadWrite>, TensorGpu<f32, ReadWrite>)>,
 }
 
 impl<F: Float> Header<F> {
-    pub fn new(context: &Context, info: &ModelInfo, num_header: usize) -> Self {
+    /// Create header buffers. Here `num_vocab` is the size of the head, including padding rows.
+    pub fn new(
+        context: &Context,
+        info: &ModelInfo,
+        num_vocab: usize,
+        num_header: usize,
+        half_output: bool,
+        sparse_output: Option<usize>,
+    ) -> Self {
         let head_shape = Shape::new(info.num_emb, num_header, 1, 1);
-        let output_shape = Shape::new(info.num_vocab, num_header, 1, 1);
+        let output_shape = Shape::new(num_vocab, num_header, 1, 1);
+        let head_k = sparse_output.map(|k| {
+            let shape = Shape::new(k, num_header, 1, 1);
+            (context.tensor_init(shape), context.tensor_init(shape))
+        });
 
         Self {
             head_x: context.tensor_init(head_shape),
             head_o: context.tensor_init(output_shape),
+            head_h: (half_output && sparse_output.is_none())
+                .then(|| context.tensor_init(output_shape)),
+            head_k,
         }
     }
 }
@@ -325,6 +435,11 @@ pub struct InferJob {
     tokens: TensorGpu<u32, ReadWrite>,
     input: TensorGpu<f16, ReadWrite>,
     output: TensorGpu<f32, ReadWrite>,
+    output_half: Option<TensorGpu<f16, ReadWrite>>,
+    output_sparse: Option<(TensorGpu<u32, ReadWrite>, TensorGpu<f32, ReadWrite>)>,
+
+    stats: InferStats,
+    validate: bool,
 }
 
 impl Job for InferJob {
@@ -333,30 +448,23 @@ impl Job for InferJob {
     type Output = InferOutput;
 
     fn load(self, input: &Self::Input) -> Result<Self> {
+        input.check_overflow(self.cursors.shape()[0])?;
+        if self.validate {
+            self.redirect.check(input)?;
+        }
+        self.stats.record_input(input);
         if input.num_token() == 0 {
             return Ok(self);
         }
 
         let stack: Vec<TensorCpu<f16>> = input
             .iter()
-            .map(|chunk| {
-                let num_emb = self.embed.shape()[0];
-                let num_token = chunk.len();
-                let data = self.embed.data();
-                let data = chunk
-                    .iter()
-                    .map(|&token| {
-                        let start = num_emb * token as usize;
-                        let end = start + num_emb;
-                        data[start..end].to_vec()
-                    })
-                    .concat();
-                let data = data.into_iter().collect_vec();
-                let shape = Shape::new(num_emb, num_token, 1, 1);
-                TensorCpu::from_data(shape, data)
-            })
+            .map(|chunk| self.embed.gather(chunk))
             .try_collect()?;
         let stack = TensorStack::try_from(stack)?;
+        if self.validate {
+            Cursor::check(&stack.cursors)?;
+        }
 
         let cursors = stack.cursors.clone().into_cursors();
         let cursors = TensorCpu::from_data(self.cursors.shape(), cursors)?;
@@ -382,19 +490,26 @@ impl Job for InferJob {
 
     fn submit(&mut self) {
         let commands = std::mem::take(&mut self.commands);
-        self.output.context.queue.submit(commands);
+        self.output.context.submit(commands);
     }
 
     async fn back(self) -> Result<Self::Output> {
-        let output = self.output.back().await;
-        let batches: Vec<_> = self
-            .redirect
-            .outputs
-            .into_iter()
-            .map(|(start, end)| output.slice(.., start..end, .., ..))
-            .try_collect()?;
-        let batches = batches.into_iter().map(InferOutputBatch).collect();
-        Ok(InferOutput(batches))
+        let (output, indices) = match (self.output_sparse, self.output_half) {
+            (Some((indices, values)), _) => (values.back().await, Some(indices.back().await)),
+            (None, Some(output)) => (output.back().await.map(|x| x.to_f32()), None),
+            (None, None) => (self.output.back().await, None),
+        };
+        let mut batches = vec![];
+        let mut sparse = vec![];
+        for (start, end) in self.redirect.outputs {
+            batches.push(InferOutputBatch(output.slice(.., start..end, .., ..)?));
+            if let Some(indices) = &indices {
+                sparse.push(indices.slice(.., start..end, .., ..)?);
+            }
+        }
+        let output = InferOutput(batches, sparse);
+        self.stats.record_output(&output);
+        Ok(output)
     }
 }
 
@@ -413,6 +528,10 @@ pub struct ModelRuntime<F: Float> {
     model: Model,
     state: State,
     hooks: Arc<HookMap<F>>,
+    half_output: bool,
+    sparse_output: Option<usize>,
+    stats: InferStats,
+    validate: bool,
     phantom: PhantomData<F>,
 }
 
@@ -434,10 +553,13 @@ impl<F: Float> super::model::ModelRuntime for ModelRuntime<F> {
 }
 
 impl<F: Float> ModelRuntime<F> {
+    /// Create a runtime with its own state of `num_batch` batches.
+    /// Pass in a clone of the model to share weights with other runtimes.
     pub fn new(model: Model, num_batch: usize) -> Self {
         let context = model.context.clone();
         let info = model.info.clone();
         let state = {
+            let context = context.with_memory_kind(MemoryKind::State);
             let shape = Shape::new(info.num_emb, 5 * info.num_layer, num_batch, 1);
             let data = (0..info.num_layer * num_batch)
                 .map(|_| {
@@ -463,6 +585,10 @@ impl<F: Float> ModelRuntime<F> {
             model,
             state,
             hooks: Default::default(),
+            half_output: false,
+            sparse_output: None,
+            stats: InferStats::new(num_batch),
+            validate: false,
             phantom: PhantomData,
         }
     }
@@ -473,6 +599,33 @@ impl<F: Float> ModelRuntime<F> {
             ..Self::new(model, num_batch)
         }
     }
+
+    /// Read logits back in half precision, halving the transfer size of the output.
+    /// The output is converted back to `f32` on CPU.
+    pub fn half_output(mut self, value: bool) -> Self {
+        self.half_output = value;
+        self
+    }
+
+    /// Only read back the top `K` logits and their token indices of each output token,
+    /// which are selected on GPU. Overrides [`ModelRuntime::half_output`].
+    /// Building jobs fails if `K` is zero.
+    pub fn sparse_output(mut self, value: Option<usize>) -> Self {
+        self.sparse_output = value;
+        self
+    }
+
+    /// Handle to the usage counters of each batch slot of this runtime.
+    pub fn stats(&self) -> InferStats {
+        self.stats.clone()
+    }
+
+    /// Check the invariants of every chunk and its packed cursors when loading jobs,
+    /// failing the job instead of running the kernels on a malformed layout. Meant for debugging.
+    pub fn validate(mut self, value: bool) -> Self {
+        self.validate = value;
+        self
+    }
 }
 
 fn turbo(num_token: usize) -> bool {
@@ -506,7 +659,17 @@ impl<F: Float> JobBuilder<InferJob> for ModelRuntime<F> {
         let num_header = redirect.headers.len();
 
         let buffer = Runtime::<F>::new(context, info, num_token);
-        let header = Header::<F>::new(context, info, num_header);
+        if self.sparse_output == Some(0) {
+            return Err(TensorError::Empty.into());
+        }
+        let header = Header::<F>::new(
+            context,
+            info,
+            tensor.head.w.num_row(),
+            num_header,
+            self.half_output,
+            self.sparse_output,
+        );
         let frame = Frame {
             state: state.clone(),
             buffer: buffer.clone(),
@@ -529,6 +692,10 @@ impl<F: Float> JobBuilder<InferJob> for ModelRuntime<F> {
                 cursors: buffer.cursors,
                 input: buffer.input,
                 output: header.head_o,
+                output_half: header.head_h,
+                output_sparse: header.head_k,
+                stats: self.stats.clone(),
+                validate: self.validate,
             });
         }
 
@@ -579,7 +746,7 @@ impl<F: Float> JobBuilder<InferJob> for ModelRuntime<F> {
                     &tensor.embed.layer_norm.w,
                     &tensor.embed.layer_norm.b,
                     &buffer.input,
-                    Model::LN_EPS,
+                    model.ln_eps,
                 )?,
                 TensorOp::blit(
                     buffer.input.view(.., .., .., ..)?,
@@ -598,7 +765,7 @@ impl<F: Float> JobBuilder<InferJob> for ModelRuntime<F> {
             let frame = frame.clone();
             let layer = layer.clone();
 
-            let op = build_layer(hooks, frame, layer, index, num_token)?;
+            let op = build_layer(hooks, frame, layer, index, num_token, model)?;
             ops.push(op);
 
             if (index + 1) % (info.num_layer / super::infer::NUM_LAYER_CHUNK) == 0 {
@@ -614,7 +781,15 @@ impl<F: Float> JobBuilder<InferJob> for ModelRuntime<F> {
             let frame = frame.clone();
             let head = model.tensor.head.clone();
 
-            let op = build_header(hooks, frame, head, head_x, num_header, head_ops)?;
+            let op = build_header(
+                hooks,
+                frame,
+                head,
+                head_x,
+                num_header,
+                model.ln_eps,
+                head_ops,
+            )?;
             ops.push(op);
         }
 
@@ -633,6 +808,10 @@ impl<F: Float> JobBuilder<InferJob> for ModelRuntime<F> {
             cursors: buffer.cursors,
             input: buffer.input,
             output: header.head_o,
+            output_half: header.head_h,
+            output_sparse: header.head_k,
+            stats: self.stats.clone(),
+            validate: self.validate,
         })
     }
 }
@@ -644,6 +823,7 @@ fn build_layer<F: Float>(
     layer: Layer,
     index: usize,
     num_token: usize,
+    model: &Model,
 ) -> Result<TensorOp> {
     let hook_op = |hook: Hook| hook_op(&hooks, &hook, &frame);
     let Frame { state, buffer, .. } = &frame;
@@ -660,7 +840,7 @@ fn build_layer<F: Float>(
             &layer.att_layer_norm.w,
             &layer.att_layer_norm.b,
             &buffer.att_x,
-            Model::LN_EPS,
+            model.ln_eps,
         )?,
         hook_op(Hook::PostAttLayerNorm(index))?,
         hook_op(Hook::PreAttTokenShift(index))?,
@@ -754,7 +934,7 @@ fn build_layer<F: Float>(
             &layer.ffn_layer_norm.w,
             &layer.ffn_layer_norm.b,
             &buffer.ffn_x,
-            Model::LN_EPS,
+            model.ln_eps,
         )?,
         hook_op(Hook::PostFfnLayerNorm(index))?,
         hook_op(Hook::PreFfnTokenShift(index))?,
@@ -812,8 +992,9 @@ fn build_layer<F: Float>(
         hook_op(Hook::PostFfn(index))?,
     ]);
 
-    if (index + 1) % Model::RESCALE_LAYER == 0 {
-        ops.push(TensorOp::discount(&buffer.x, 0.5, 0.0)?);
+    match model.layer_scale.get(index) {
+        Some(&scale) if scale != 1.0 => ops.push(TensorOp::discount(&buffer.x, scale, 0.0)?),
+        _ => {}
     }
 
     Ok(TensorOp::List(ops))
@@ -825,6 +1006,7 @@ fn build_header<F: Float>(
     head: Head,
     head_x: TensorGpu<F, ReadWrite>,
     num_header: usize,
+    ln_eps: f32,
     mut ops: Vec<TensorOp>,
 ) -> Result<TensorOp> {
     let hook_op = |hook: Hook| hook_op(&hooks, &hook, &frame);
@@ -833,12 +1015,7 @@ fn build_header<F: Float>(
     if num_header > 0 {
         ops.append(&mut vec![
             hook_op(Hook::PreHead)?,
-            TensorOp::layer_norm(
-                &head.layer_norm.w,
-                &head.layer_norm.b,
-                &head_x,
-                Model::LN_EPS,
-            )?,
+            TensorOp::layer_norm(&head.layer_norm.w, &head.layer_norm.b, &head_x, ln_eps)?,
             hook_op(Hook::PostHeadLayerNorm)?,
             head.w.matmul_op(
                 head_x.view(.., .., .., ..)?,
@@ -846,141 +1023,278 @@ fn build_header<F: Float>(
                 Activation::None,
                 turbo(num_header),
             )?,
-            hook_op(Hook::PostHead)?,
         ]);
+        if let Some(b) = &head.b {
+            ops.push(TensorOp::add(
+                b.view(.., .., .., ..)?,
+                header.head_o.view(.., .., .., ..)?,
+            )?);
+        }
+        ops.push(hook_op(Hook::PostHead)?);
+        if let Some(mask) = &head.mask {
+            ops.push(TensorOp::masked_fill(
+                mask,
+                &header.head_o,
+                f32::NEG_INFINITY,
+            )?);
+        }
+        if let Some(head_h) = &header.head_h {
+            ops.push(TensorOp::blit(
+                header.head_o.view(.., .., .., ..)?,
+                head_h.view(.., .., .., ..)?,
+            )?);
+        }
+        if let Some((indices, values)) = &header.head_k {
+            ops.push(TensorOp::top_k(&header.head_o, indices, values)?);
+        }
     }
     Ok(TensorOp::List(ops))
 }
 
 impl<R: Reader> Build<Model> for ModelBuilder<R> {
+    /// Build the model. Running out of GPU memory while loading fails with
+    /// [`ContextError::OutOfMemory`](crate::context::ContextError::OutOfMemory),
+    /// unless the [`oom_policy`](ModelBuilder::oom_policy) finds a way out.
+    /// The context must not be used by anything else until the build finishes.
     async fn build(self) -> Result<Model> {
-        let ModelBuilder {
-            context,
-            model,
-            lora,
-            quant,
-            embed_device,
-        } = self;
-
-        let info = Loader::info(&model)?;
-        let loader = Loader {
-            context: context.clone(),
-            model,
-            lora,
-        };
-
-        let embed = Embed {
-            layer_norm: LayerNorm {
-                w: loader.load_vector_f16("blocks.0.ln0.weight").await?,
-                b: loader.load_vector_f16("blocks.0.ln0.bias").await?,
-            },
-            w: loader.load_embed().await?,
-            u: match embed_device {
-                EmbedDevice::Cpu => None,
-                EmbedDevice::Gpu => Some(loader.load_matrix_f16("emb.weight").await?),
-            },
-        };
-
-        let head = Head {
-            layer_norm: LayerNorm {
-                w: loader.load_vector_f16("ln_out.weight").await?,
-                b: loader.load_vector_f16("ln_out.bias").await?,
-            },
-            w: Matrix::Fp16(loader.load_matrix_f16("head.weight").await?),
-        };
-
-        context.queue.submit(None);
-        context.device.poll(wgpu::MaintainBase::Wait);
-
-        let load_matrix = |name: String, quant: Quant| loader.load_matrix(name, quant);
-        let load_matrix_discount = |name: String, quant: Quant, discount: f32| {
-            loader.load_matrix_discount(name, quant, discount)
-        };
+        build_model(self).await
+    }
+}
 
-        let mut layers = vec![];
-        for layer in 0..info.num_layer {
-            let quant = quant.get(&layer).copied().unwrap_or_default();
-            let discount = 2.0_f32.powi(-((layer / Model::RESCALE_LAYER) as i32));
-
-            let att_layer_norm = LayerNorm {
-                w: loader
-                    .load_vector_f16(format!("blocks.{layer}.ln1.weight"))
-                    .await?,
-                b: loader
-                    .load_vector_f16(format!("blocks.{layer}.ln1.bias"))
-                    .await?,
+async fn build_model<R: Reader>(builder: ModelBuilder<R>) -> Result<Model> {
+    let ModelBuilder {
+        context,
+        model,
+        lora,
+        quant,
+        embed_device,
+        rescale,
+        layer_scale,
+        ln_eps,
+        gn_eps: _,
+        real_vocab,
+        head_size: _,
+        strict,
+        tied_head,
+        verify,
+        metadata,
+        oom_policy,
+    } = builder;
+
+    let rescale = rescale
+        .or_else(|| parse_rescale_layer(&metadata))
+        .unwrap_or(Model::RESCALE_LAYER);
+    let layer_scale = layer_scale.or_else(|| parse_layer_scale(&metadata));
+    let ln_eps = ln_eps.unwrap_or(Model::LN_EPS);
+
+    let mut info = Loader::info(&model)?;
+    let layer_scale = super::model::resolve_layer_scale(info.num_layer, rescale, layer_scale)?;
+    let discounts = super::model::layer_discounts(&layer_scale);
+    if strict {
+        Loader::check(&model, &info, tied_head)?;
+    }
+    if verify {
+        Loader::verify(&model, &parse_tensor_hashes(&metadata)).await?;
+    }
+    let loader = Loader {
+        context: context.with_memory_kind(MemoryKind::Weights),
+        model,
+        lora,
+    };
+
+    // allocations are caught in small scopes, since other tasks may run at the yields in between
+    let mut embed_device = embed_device;
+    let (embed, head) = loop {
+        let load = async {
+            let embed = Embed {
+                layer_norm: LayerNorm {
+                    w: loader.load_vector_f16("blocks.0.ln0.weight").await?,
+                    b: loader.load_vector_f16("blocks.0.ln0.bias").await?,
+                },
+                w: loader.load_embed().await?,
+                u: match embed_device {
+                    EmbedDevice::Cpu => None,
+                    EmbedDevice::Gpu => Some(loader.load_matrix_f16("emb.weight").await?),
+                },
             };
 
-            let att = format!("blocks.{layer}.att");
-            let time_decay = loader
-                .load_vector_exp_f32(format!("{att}.time_decay"))
-                .await?;
-            let time_first = loader.load_vector_f32(format!("{att}.time_first")).await?;
-            let time_mix_k = loader.load_vector_f16(format!("{att}.time_mix_k")).await?;
-            let time_mix_v = loader.load_vector_f16(format!("{att}.time_mix_v")).await?;
-            let time_mix_r = loader.load_vector_f16(format!("{att}.time_mix_r")).await?;
-
-            let att = Att {
-                time_decay,
-                time_first,
-                time_mix_k,
-                time_mix_v,
-                time_mix_r,
-                w_k: load_matrix(format!("{att}.key.weight"), quant).await?,
-                w_v: load_matrix(format!("{att}.value.weight"), quant).await?,
-                w_r: load_matrix(format!("{att}.receptance.weight"), quant).await?,
-                w_o: load_matrix_discount(format!("{att}.output.weight"), quant, discount).await?,
+            // a head tied to the embedding shares its buffer, which has the same layout `[C, V]`
+            let head_w = match (tied_head, &embed.u) {
+                (false, _) => loader.load_matrix_f16("head.weight").await?,
+                (true, Some(u)) => u.clone(),
+                (true, None) => loader.load_matrix_f16("emb.weight").await?,
             };
-
-            let ffn_layer_norm = LayerNorm {
-                w: loader
-                    .load_vector_f16(format!("blocks.{layer}.ln2.weight"))
-                    .await?,
-                b: loader
-                    .load_vector_f16(format!("blocks.{layer}.ln2.bias"))
-                    .await?,
+            let head = Head {
+                layer_norm: LayerNorm {
+                    w: loader.load_vector_f16("ln_out.weight").await?,
+                    b: loader.load_vector_f16("ln_out.bias").await?,
+                },
+                w: Matrix::Fp16(head_w),
+                b: match loader.model.contains("head.bias") {
+                    true => Some(loader.load_vector_f32("head.bias").await?),
+                    false => None,
+                },
+                mask: None,
             };
 
-            let ffn = format!("blocks.{layer}.ffn");
-            let time_mix_k = loader.load_vector_f16(format!("{ffn}.time_mix_k")).await?;
-            let time_mix_r = loader.load_vector_f16(format!("{ffn}.time_mix_r")).await?;
-
-            let ffn = Ffn {
-                time_mix_k,
-                time_mix_r,
-                w_r: load_matrix(format!("{ffn}.receptance.weight"), quant).await?,
-                w_k: load_matrix(format!("{ffn}.key.weight"), quant).await?,
-                w_v: load_matrix_discount(format!("{ffn}.value.weight"), quant, discount).await?,
+            let head = match real_vocab {
+                Some(num_vocab) if num_vocab > head.w.num_row() => {
+                    return Err(ModelError::InvalidVocab.into());
+                }
+                Some(num_vocab) if num_vocab < head.w.num_row() => {
+                    let flags = (0..head.w.num_row())
+                        .map(|index| index < num_vocab)
+                        .collect_vec();
+                    let mask = TensorMask::new(&loader.context, [flags.len(), 1, 1, 1], &flags)?;
+                    info.num_vocab = num_vocab;
+                    Head {
+                        mask: Some(mask),
+                        ..head
+                    }
+                }
+                Some(num_vocab) => {
+                    info.num_vocab = num_vocab;
+                    head
+                }
+                None => head,
             };
 
-            context.queue.submit(None);
-            context.device.poll(wgpu::MaintainBase::Wait);
-
-            layers.push(Layer {
-                att_layer_norm,
-                ffn_layer_norm,
-                att,
-                ffn,
-            })
+            anyhow::Ok((embed, head))
+        };
+        let err = match context.catch_oom(load).await {
+            Ok(output) => break output?,
+            Err(err) => err,
+        };
+        match oom_policy
+            .as_ref()
+            .map_or(OomAction::Fail, |f| f(OomStage::Embed))
+        {
+            OomAction::EmbedCpu if embed_device == EmbedDevice::Gpu => {
+                embed_device = EmbedDevice::Cpu
+            }
+            _ => return Err(err.into()),
         }
+    };
+
+    context.queue.submit(None);
+    context.device.poll(wgpu::MaintainBase::Wait);
+
+    let load_matrix = |name: String, quant: Quant| loader.load_matrix(name, quant);
+    let load_matrix_discount = |name: String, quant: Quant, discount: f32| {
+        loader.load_matrix_discount(name, quant, discount)
+    };
+
+    let mut layers = vec![];
+    let mut fallback = None;
+    for (layer, discount) in (0..info.num_layer).zip(discounts) {
+        let mut quant = fallback.unwrap_or(quant.get(&layer).copied().unwrap_or_default());
+        let block = loop {
+            let load = async {
+                let att_layer_norm = LayerNorm {
+                    w: loader
+                        .load_vector_f16(format!("blocks.{layer}.ln1.weight"))
+                        .await?,
+                    b: loader
+                        .load_vector_f16(format!("blocks.{layer}.ln1.bias"))
+                        .await?,
+                };
+
+                let att = format!("blocks.{layer}.att");
+                let time_decay = loader
+                    .load_vector_exp_f32(format!("{att}.time_decay"))
+                    .await?;
+                let time_first = loader.load_vector_f32(format!("{att}.time_first")).await?;
+                let time_mix_k = loader.load_vector_f16(format!("{att}.time_mix_k")).await?;
+                let time_mix_v = loader.load_vector_f16(format!("{att}.time_mix_v")).await?;
+                let time_mix_r = loader.load_vector_f16(format!("{att}.time_mix_r")).await?;
+
+                let att = Att {
+                    time_decay,
+                    time_first,
+                    time_mix_k,
+                    time_mix_v,
+                    time_mix_r,
+                    w_k: load_matrix(format!("{att}.key.weight"), quant).await?,
+                    w_v: load_matrix(format!("{att}.value.weight"), quant).await?,
+                    w_r: load_matrix(format!("{att}.receptance.weight"), quant).await?,
+                    w_o: load_matrix_discount(format!("{att}.output.weight"), quant, discount)
+                        .await?,
+                };
+
+                let ffn_layer_norm = LayerNorm {
+                    w: loader
+                        .load_vector_f16(format!("blocks.{layer}.ln2.weight"))
+                        .await?,
+                    b: loader
+                        .load_vector_f16(format!("blocks.{layer}.ln2.bias"))
+                        .await?,
+                };
+
+                let ffn = format!("blocks.{layer}.ffn");
+                let time_mix_k = loader.load_vector_f16(format!("{ffn}.time_mix_k")).await?;
+                let time_mix_r = loader.load_vector_f16(format!("{ffn}.time_mix_r")).await?;
+
+                let ffn = Ffn {
+                    time_mix_k,
+                    time_mix_r,
+                    w_r: load_matrix(format!("{ffn}.receptance.weight"), quant).await?,
+                    w_k: load_matrix(format!("{ffn}.key.weight"), quant).await?,
+                    w_v: load_matrix_discount(format!("{ffn}.value.weight"), quant, discount)
+                        .await?,
+                };
+
+                anyhow::Ok(Layer {
+                    att_layer_norm,
+                    ffn_layer_norm,
+                    att,
+                    ffn,
+                })
+            };
+            let err = match context.catch_oom(load).await {
+                Ok(output) => break output?,
+                Err(err) => err,
+            };
+            match oom_policy
+                .as_ref()
+                .map_or(OomAction::Fail, |f| f(OomStage::Layer(layer)))
+            {
+                OomAction::Quant(value) if value != quant => {
+                    quant = value;
+                    fallback = Some(value);
+                }
+                _ => return Err(err.into()),
+            }
+        };
 
         context.queue.submit(None);
         context.device.poll(wgpu::MaintainBase::Wait);
 
-        let tensor = ModelTensor {
-            embed,
-            head,
-            layers,
-        };
-        let model = {
-            let context = context.clone();
-            let info = info.clone();
-            Model {
-                context,
-                info,
-                tensor,
-            }
-        };
-        Ok(model)
+        // give other tasks a chance to run, since single-threaded executors (e.g., on web)
+        // would otherwise be blocked until the whole model is loaded
+        tokio::task::yield_now().await;
+
+        layers.push(block);
     }
+
+    context.queue.submit(None);
+    context.device.poll(wgpu::MaintainBase::Wait);
+
+    let tensor = ModelTensor {
+        embed,
+        head,
+        layers,
+    };
+    let model = {
+        let context = context.clone();
+        let info = info.clone();
+        Model {
+            context,
+            info,
+            rescale,
+            layer_scale,
+            ln_eps,
+            tensor,
+        }
+    };
+    Ok(model)
 }
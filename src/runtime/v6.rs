@@ -9,28 +9,40 @@ use web_rwkv_derive::DeserializeSeed;
 use wgpu::CommandBuffer;
 
 use super::{
-    infer::{InferChunk, InferInfo, InferOutput, InferOutputBatch, InferRedirect},
-    loader::{Loader, Reader},
-    model::{AsAny, Build, EmbedDevice, ModelBuilder, ModelInfo, Quant, State as _},
+    infer::{InferChunk, InferInfo, InferOutput, InferOutputBatch, InferRedirect, InferStats},
+    loader::{parse_layer_scale, parse_rescale_layer, parse_tensor_hashes, Loader, Reader},
+    model::{
+        AsAny, Build, EmbedDevice, ModelBuilder, ModelError, ModelInfo, OomAction, OomStage, Quant,
+        State as _,
+    },
     Job, JobBuilder,
 };
 use crate::{
-    context::Context,
+    context::{Context, MemoryKind},
     num::Float,
     tensor::{
         kind::ReadWrite,
         matrix::Matrix,
         ops::{Activation, TensorCommand, TensorOp},
         shape::{Shape, TensorDimension},
-        DeepClone, IntoPackedCursors, TensorCpu, TensorError, TensorGpu, TensorGpuView, TensorInit,
-        TensorReshape, TensorShape, TensorStack,
+        Cursor, DeepClone, IntoPackedCursors, TensorCpu, TensorError, TensorGpu, TensorGpuView,
+        TensorInit, TensorMask, TensorReshape, TensorShape, TensorStack,
     },
 };
 
+/// Weights of a model. Cheap to clone; see [`ModelRuntime`](super::model::ModelRuntime) on sharing weights.
 #[derive(Debug, Clone, Serialize, DeserializeSeed)]
 pub struct Model {
     pub context: Context,
     pub info: ModelInfo,
+    /// Halve the residual every this many layers (0 for no rescaling).
+    pub rescale: usize,
+    /// Factor the residual is scaled by after each layer.
+    pub layer_scale: Vec<f32>,
+    /// Epsilon of layer norms.
+    pub ln_eps: f32,
+    /// Epsilon of group norms.
+    pub gn_eps: f32,
     pub tensor: ModelTensor,
 }
 
@@ -39,6 +51,56 @@ impl Model {
 
     pub const LN_EPS: f32 = 1.0e-5;
     pub const GN_EPS: f32 = 64.0e-5;
+
+    /// Compute the layer-normed embedding matrix of shape `[C, V]`,
+    /// i.e., the vector each token feeds into the first layer.
+    pub async fn normed_embed(&self) -> TensorCpu<f32> {
+        let embed = &self.tensor.embed;
+        let w = embed.layer_norm.w.back().await;
+        let b = embed.layer_norm.b.back().await;
+        super::model::layer_norm_rows(&embed.w, &w, &b, self.ln_eps)
+    }
+
+    /// Overwrite a weight matrix in place with `matrix` given in the checkpoint's layout,
+    /// re-quantizing just that matrix if the layer is quantized. This allows testing weight edits
+    /// (e.g., ablations) without reloading the model. Supported are the projection matrices of layers
+    /// (e.g., `blocks.0.att.key.weight`) and `head.weight`.
+    ///
+    /// Weights are shared, so the change is seen by all clones of the model and their runtimes.
+    /// A head [tied](ModelBuilder::tied_head) to an embedding on GPU shares its buffer, so patching
+    /// `head.weight` then rewrites the GPU embedding too, but not the CPU copy of the embedding.
+    pub fn patch_matrix(&self, name: &str, matrix: &TensorCpu<f16>) -> Result<()> {
+        let (w, discount) = self.matrix(name).ok_or(ModelError::InvalidTensor)?;
+        if discount == 1.0 {
+            w.patch(matrix)?;
+        } else {
+            w.patch(&matrix.clone().map(|x| f16::from_f32(discount * x.to_f32())))?;
+        }
+        Ok(())
+    }
+
+    /// Find a patchable matrix by its name, along with the discount applied to it when loaded.
+    fn matrix(&self, name: &str) -> Option<(&Matrix, f32)> {
+        if name == "head.weight" {
+            return Some((&self.tensor.head.w, 1.0));
+        }
+
+        let (layer, name) = name.strip_prefix("blocks.")?.split_once('.')?;
+        let layer: usize = layer.parse().ok()?;
+        let tensor = self.tensor.layers.get(layer)?;
+        let discount = super::model::layer_discounts(&self.layer_scale)[layer];
+        match name {
+            "att.key.weight" => Some((&tensor.att.w_k, 1.0)),
+            "att.value.weight" => Some((&tensor.att.w_v, 1.0)),
+            "att.receptance.weight" => Some((&tensor.att.w_r, 1.0)),
+            "att.gate.weight" => Some((&tensor.att.w_g, 1.0)),
+            "att.output.weight" => Some((&tensor.att.w_o, discount)),
+            "ffn.key.weight" => Some((&tensor.ffn.w_k, 1.0)),
+            "ffn.value.weight" => Some((&tensor.ffn.w_v, discount)),
+            "ffn.receptance.weight" => Some((&tensor.ffn.w_r, 1.0)),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, DeserializeSeed)]
@@ -105,6 +167,10 @@ pub struct Embed {
 pub struct Head {
     pub layer_norm: LayerNorm,
     pub w: Matrix,
+    /// Bias added to the logits, if the checkpoint has one.
+    pub b: Option<TensorGpu<f32, ReadWrite>>,
+    /// Mask selecting the real vocab rows, masking out the padding rows beyond it.
+    pub mask: Option<TensorMask>,
 }
 
 #[derive(Debug, Clone, Serialize, DeserializeSeed)]
@@ -133,6 +199,48 @@ impl State {
         }
         TensorCpu::stack(backed)
     }
+
+    /// Flat indices of a head's WKV state in a backed state, in the order of `[S, S]`.
+    fn head_indices(&self, layer: usize, head: usize) -> impl Iterator<Item = usize> {
+        let info = &self.info;
+        let num_emb = info.num_emb;
+        let head_size = info.num_emb / info.num_head;
+        let offset = layer * num_emb * (head_size + 2);
+        (0..head_size).flat_map(move |row| {
+            let start = offset + (row + 1) * num_emb + head * head_size;
+            start..start + head_size
+        })
+    }
+
+    fn check_backed(&self, backed: &TensorCpu<f32>, layer: usize) -> Result<(), TensorError> {
+        let info = &self.info;
+        let head_size = info.num_emb / info.num_head;
+        backed.check_shape([info.num_emb, head_size + 2, info.num_layer, 1])?;
+        match layer < info.num_layer {
+            true => Ok(()),
+            false => Err(TensorError::SliceOutOfRange {
+                dim: info.num_layer,
+                start: layer,
+                end: layer + 1,
+            }),
+        }
+    }
+
+    fn update(
+        &self,
+        backed: TensorCpu<f32>,
+        layer: usize,
+        values: impl IntoIterator<Item = (usize, f32)>,
+    ) -> Result<TensorCpu<f32>, TensorError> {
+        self.check_backed(&backed, layer)?;
+
+        let shape = backed.shape();
+        let mut data: Vec<_> = backed.into();
+        for (index, value) in values {
+            data[index] = value;
+        }
+        TensorCpu::from_data(shape, data)
+    }
 }
 
 impl AsAny for State {
@@ -180,6 +288,15 @@ impl super::model::State for State {
         Box::pin(self.back(batch))
     }
 
+    fn back_many(&self, batches: &[usize]) -> BoxFuture<Result<Vec<TensorCpu<f32>>, TensorError>> {
+        let batches = batches.to_vec();
+        Box::pin(super::model::back_batches(
+            &self.context,
+            &self.data,
+            batches,
+        ))
+    }
+
     fn write(&self, tensor: TensorGpu<f32, ReadWrite>, batch: usize) -> Result<(), TensorError> {
         let head_size = self.info.num_emb / self.info.num_head;
         tensor.check_shape([self.info.num_emb, head_size + 2, self.info.num_layer, 1])?;
@@ -192,7 +309,7 @@ impl super::model::State for State {
                 data.view(.., .., batch, ..)?,
             )?);
         }
-        context.queue.submit(context.encode(&TensorOp::List(ops)));
+        context.submit(context.encode(&TensorOp::List(ops)));
 
         Ok(())
     }
@@ -210,7 +327,7 @@ impl super::model::State for State {
                 tensor.view(.., .., layer, ..)?,
             )?);
         }
-        context.queue.submit(context.encode(&TensorOp::List(ops)));
+        context.submit(context.encode(&TensorOp::List(ops)));
 
         Ok(tensor)
     }
@@ -218,6 +335,82 @@ impl super::model::State for State {
     fn embed(&self, layer: usize, backed: TensorCpu<f32>) -> Result<TensorCpu<f32>, TensorError> {
         backed.slice(.., 0, layer, ..)
     }
+
+    fn head_state(
+        &self,
+        backed: &TensorCpu<f32>,
+        layer: usize,
+    ) -> Result<TensorCpu<f32>, TensorError> {
+        self.check_backed(backed, layer)?;
+
+        let info = &self.info;
+        let head_size = info.num_emb / info.num_head;
+        let data = (0..info.num_head)
+            .flat_map(|head| self.head_indices(layer, head))
+            .map(|index| backed.data()[index])
+            .collect_vec();
+        TensorCpu::from_data([head_size, head_size, info.num_head, 1], data)
+    }
+
+    fn set_head_state(
+        &self,
+        backed: TensorCpu<f32>,
+        layer: usize,
+        heads: &TensorCpu<f32>,
+    ) -> Result<TensorCpu<f32>, TensorError> {
+        let info = &self.info;
+        let head_size = info.num_emb / info.num_head;
+        heads.check_shape([head_size, head_size, info.num_head, 1])?;
+
+        let indices = (0..info.num_head).flat_map(|head| self.head_indices(layer, head));
+        self.update(backed, layer, indices.zip_eq(heads.iter().copied()))
+    }
+
+    fn scale_head(
+        &self,
+        backed: TensorCpu<f32>,
+        layer: usize,
+        head: usize,
+        factor: f32,
+    ) -> Result<TensorCpu<f32>, TensorError> {
+        self.check_backed(&backed, layer)?;
+        if head >= self.info.num_head {
+            return Err(TensorError::SliceOutOfRange {
+                dim: self.info.num_head,
+                start: head,
+                end: head + 1,
+            });
+        }
+        let values = self
+            .head_indices(layer, head)
+            .map(|index| (index, backed.data()[index] * factor))
+            .collect_vec();
+        self.update(backed, layer, values)
+    }
+    /// The reference layout has 3 tensors per layer: `att_x` of shape `(C)`,
+    /// `att_kv` of shape `(H, S, S)` indexed by head, key and value, and `ffn_x` of shape `(C)`.
+    fn to_reference_layout(
+        &self,
+        backed: &TensorCpu<f32>,
+    ) -> Result<Vec<TensorCpu<f32>>, TensorError> {
+        let info = &self.info;
+        let num_emb = info.num_emb;
+        let head_size = num_emb / info.num_head;
+        backed.check_shape([num_emb, head_size + 2, info.num_layer, 1])?;
+
+        let mut tensors = Vec::with_capacity(3 * info.num_layer);
+        for layer in 0..info.num_layer {
+            let row = |index: usize| {
+                let start = (layer * (head_size + 2) + index) * num_emb;
+                let data = backed.data()[start..start + num_emb].to_vec();
+                TensorCpu::from_data([num_emb, 1, 1, 1], data)
+            };
+            tensors.push(row(0)?);
+            tensors.push(self.head_state(backed, layer)?);
+            tensors.push(row(head_size + 1)?);
+        }
+        Ok(tensors)
+    }
 }
 
 impl DeepClone for State {
@@ -311,16 +504,35 @@ impl<F: Float> Runtime<F> {
 pub struct Header<F: Float> {
     pub head_x: TensorGpu<F, ReadWrite>,
     pub head_o: TensorGpu<f32, ReadWrite>,
+    /// Half-precision copy of `head_o` to read back, if half output is enabled.
+    pub head_h: Option<TensorGpu<f16, ReadWrite>>,
+    /// Indices and logits of the top `K` tokens, if sparse output is enabled.
+    pub head_k: Option<(TensorGpu<u32, ReadWrite>, TensorGpu<f32, ReadWrite>)>,
 }
 
 impl<F: Float> Header<F> {
-    pub fn new(context: &Context, info: &ModelInfo, num_header: usize) -> Self {
+    /// Create header buffers. Here `num_vocab` is the size of the head, including padding rows.
+    pub fn new(
+        context: &Context,
+        info: &ModelInfo,
+        num_vocab: usize,
+        num_header: usize,
+        half_output: bool,
+        sparse_output: Option<usize>,
+    ) -> Self {
         let head_shape = Shape::new(info.num_emb, num_header, 1, 1);
-        let output_shape = Shape::new(info.num_vocab, num_header, 1, 1);
+        let output_shape = Shape::new(num_vocab, num_header, 1, 1);
+        let head_k = sparse_output.map(|k| {
+            let shape = Shape::new(k, num_header, 1, 1);
+            (context.tensor_init(shape), context.tensor_init(shape))
+        });
 
         Self {
             head_x: context.tensor_init(head_shape),
             head_o: context.tensor_init(output_shape),
+            head_h: (half_output && sparse_output.is_none())
+                .then(|| context.tensor_init(output_shape)),
+            head_k,
         }
     }
 }
@@ -378,6 +590,11 @@ pub struct InferJob {
     tokens: TensorGpu<u32, ReadWrite>,
     input: TensorGpu<f16, ReadWrite>,
     output: TensorGpu<f32, ReadWrite>,
+    output_half: Option<TensorGpu<f16, ReadWrite>>,
+    output_sparse: Option<(TensorGpu<u32, ReadWrite>, TensorGpu<f32, ReadWrite>)>,
+
+    stats: InferStats,
+    validate: bool,
 }
 
 impl Job for InferJob {
@@ -386,30 +603,23 @@ impl Job for InferJob {
     type Output = InferOutput;
 
     fn load(self, input: &Self::Input) -> Result<Self> {
+        input.check_overflow(self.cursors.shape()[0])?;
+        if self.validate {
+            self.redirect.check(input)?;
+        }
+        self.stats.record_input(input);
         if input.num_token() == 0 {
             return Ok(self);
         }
 
         let stack: Vec<TensorCpu<f16>> = input
             .iter()
-            .map(|chunk| {
-                let num_emb = self.embed.shape()[0];
-                let num_token = chunk.len();
-                let data = self.embed.data();
-                let data = chunk
-                    .iter()
-                    .map(|&token| {
-                        let start = num_emb * token as usize;
-                        let end = start + num_emb;
-                        data[start..end].to_vec()
-                    })
-                    .concat();
-                let data = data.into_iter().collect_vec();
-                let shape = Shape::new(num_emb, num_token, 1, 1);
-                TensorCpu::from_data(shape, data)
-            })
+            .map(|chunk| self.embed.gather(chunk))
             .try_collect()?;
         let stack = TensorStack::try_from(stack)?;
+        if self.validate {
+            Cursor::check(&stack.cursors)?;
+        }
 
         let cursors = stack.cursors.clone().into_cursors();
         let cursors = TensorCpu::from_data(self.cursors.shape(), cursors)?;
@@ -435,19 +645,26 @@ impl Job for InferJob {
 
     fn submit(&mut self) {
         let commands = std::mem::take(&mut self.commands);
-        self.output.context.queue.submit(commands);
+        self.output.context.submit(commands);
     }
 
     async fn back(self) -> Result<Self::Output> {
-        let output = self.output.back().await;
-        let batches: Vec<_> = self
-            .redirect
-            .outputs
-            .into_iter()
-            .map(|(start, end)| output.slice(.., start..end, .., ..))
-            .try_collect()?;
-        let batches = batches.into_iter().map(InferOutputBatch).collect();
-        Ok(InferOutput(batches))
+        let (output, indices) = match (self.output_sparse, self.output_half) {
+            (Some((indices, values)), _) => (values.back().await, Some(indices.back().await)),
+            (None, Some(output)) => (output.back().await.map(|x| x.to_f32()), None),
+            (None, None) => (self.output.back().await, None),
+        };
+        let mut batches = vec![];
+        let mut sparse = vec![];
+        for (start, end) in self.redirect.outputs {
+            batches.push(InferOutputBatch(output.slice(.., start..end, .., ..)?));
+            if let Some(indices) = &indices {
+                sparse.push(indices.slice(.., start..end, .., ..)?);
+            }
+        }
+        let output = InferOutput(batches, sparse);
+        self.stats.record_output(&output);
+        Ok(output)
     }
 }
 
@@ -466,14 +683,21 @@ pub struct ModelRuntime<F: Float> {
     model: Model,
     state: State,
     hooks: Arc<HookMap<F>>,
+    half_output: bool,
+    sparse_output: Option<usize>,
+    stats: InferStats,
+    validate: bool,
     phantom: PhantomData<F>,
 }
 
 impl<F: Float> ModelRuntime<F> {
+    /// Create a runtime with its own state of `num_batch` batches.
+    /// Pass in a clone of the model to share weights with other runtimes.
     pub fn new(model: Model, num_batch: usize) -> Self {
         let context = model.context.clone();
         let info = model.info.clone();
         let state = {
+            let context = context.with_memory_kind(MemoryKind::State);
             let head_size = info.num_emb / info.num_head;
             let shape = Shape::new(info.num_emb, head_size + 2, num_batch, 1);
             let data = (0..info.num_layer).map(|_| context.zeros(shape)).collect();
@@ -487,6 +711,10 @@ impl<F: Float> ModelRuntime<F> {
             model,
             state,
             hooks: Default::default(),
+            half_output: false,
+            sparse_output: None,
+            stats: InferStats::new(num_batch),
+            validate: false,
             phantom: PhantomData,
         }
     }
@@ -497,6 +725,33 @@ impl<F: Float> ModelRuntime<F> {
             ..Self::new(model, num_batch)
         }
     }
+
+    /// Read logits back in half precision, halving the transfer size of the output.
+    /// The output is converted back to `f32` on CPU.
+    pub fn half_output(mut self, value: bool) -> Self {
+        self.half_output = value;
+        self
+    }
+
+    /// Only read back the top `K` logits and their token indices of each output token,
+    /// which are selected on GPU. Overrides [`ModelRuntime::half_output`].
+    /// Building jobs fails if `K` is zero.
+    pub fn sparse_output(mut self, value: Option<usize>) -> Self {
+        self.sparse_output = value;
+        self
+    }
+
+    /// Handle to the usage counters of each batch slot of this runtime.
+    pub fn stats(&self) -> InferStats {
+        self.stats.clone()
+    }
+
+    /// Check the invariants of every chunk and its packed cursors when loading jobs,
+    /// failing the job instead of running the kernels on a malformed layout. Meant for debugging.
+    pub fn validate(mut self, value: bool) -> Self {
+        self.validate = value;
+        self
+    }
 }
 
 impl<F: Float> super::model::ModelRuntime for ModelRuntime<F> {
@@ -548,7 +803,17 @@ impl<F: Float> JobBuilder<InferJob> for ModelRuntime<F> {
         let num_header = redirect.headers.len();
 
         let buffer = Runtime::<F>::new(context, info, num_token);
-        let header = Header::<F>::new(context, info, num_header);
+        if self.sparse_output == Some(0) {
+            return Err(TensorError::Empty.into());
+        }
+        let header = Header::<F>::new(
+            context,
+            info,
+            tensor.head.w.num_row(),
+            num_header,
+            self.half_output,
+            self.sparse_output,
+        );
         let frame = Frame {
             state: state.clone(),
             buffer: buffer.clone(),
@@ -571,6 +836,10 @@ impl<F: Float> JobBuilder<InferJob> for ModelRuntime<F> {
                 cursors: buffer.cursors,
                 input: buffer.input,
                 output: header.head_o,
+                output_half: header.head_h,
+                output_sparse: header.head_k,
+                stats: self.stats.clone(),
+                validate: self.validate,
             });
         }
 
@@ -621,7 +890,7 @@ impl<F: Float> JobBuilder<InferJob> for ModelRuntime<F> {
                     &tensor.embed.layer_norm.w,
                     &tensor.embed.layer_norm.b,
                     &buffer.input,
-                    Model::LN_EPS,
+                    model.ln_eps,
                 )?,
                 TensorOp::blit(
                     buffer.input.view(.., .., .., ..)?,
@@ -640,7 +909,7 @@ impl<F: Float> JobBuilder<InferJob> for ModelRuntime<F> {
             let frame = frame.clone();
             let layer = layer.clone();
 
-            let op = build_layer(hooks, frame, layer, index, num_token, head_size)?;
+            let op = build_layer(hooks, frame, layer, index, num_token, model, head_size)?;
             ops.push(op);
 
             if (index + 1) % (info.num_layer / super::infer::NUM_LAYER_CHUNK) == 0 {
@@ -656,7 +925,15 @@ impl<F: Float> JobBuilder<InferJob> for ModelRuntime<F> {
             let frame = frame.clone();
             let head = model.tensor.head.clone();
 
-            let op = build_header(hooks, frame, head, head_x, num_header, head_ops)?;
+            let op = build_header(
+                hooks,
+                frame,
+                head,
+                head_x,
+                num_header,
+                model.ln_eps,
+                head_ops,
+            )?;
             ops.push(op);
         }
 
@@ -675,6 +952,10 @@ impl<F: Float> JobBuilder<InferJob> for ModelRuntime<F> {
             cursors: buffer.cursors,
             input: buffer.input,
             output: header.head_o,
+            output_half: header.head_h,
+            output_sparse: header.head_k,
+            stats: self.stats.clone(),
+            validate: self.validate,
         })
     }
 }
@@ -686,6 +967,7 @@ fn build_layer<F: Float>(
     layer: Layer,
     index: usize,
     num_token: usize,
+    model: &Model,
     head_size: usize,
 ) -> Result<TensorOp> {
     let hook_op = |hook: Hook| hook_op(&hooks, &hook, &frame);
@@ -744,7 +1026,7 @@ fn build_layer<F: Float>(
             &layer.att_layer_norm.w,
             &layer.att_layer_norm.b,
             &buffer.att_x,
-            Model::LN_EPS,
+            model.ln_eps,
         )?,
         hook_op(Hook::PostAttLayerNorm(index))?,
         hook_op(Hook::PreAttTokenShift(index))?,
@@ -857,7 +1139,7 @@ fn build_layer<F: Float>(
             &layer.att.group_norm.w,
             &layer.att.group_norm.b,
             &aux_x,
-            Model::GN_EPS,
+            model.gn_eps,
         )?,
         TensorOp::blit(
             buffer.aux_x.view(.., .., .., ..)?,
@@ -892,7 +1174,7 @@ fn build_layer<F: Float>(
             &layer.ffn_layer_norm.w,
             &layer.ffn_layer_norm.b,
             &buffer.ffn_x,
-            Model::LN_EPS,
+            model.ln_eps,
         )?,
         hook_op(Hook::PostFfnLayerNorm(index))?,
         hook_op(Hook::PreFfnTokenShift(index))?,
@@ -950,8 +1232,9 @@ fn build_layer<F: Float>(
         hook_op(Hook::PostFfn(index))?,
     ]);
 
-    if (index + 1) % Model::RESCALE_LAYER == 0 {
-        ops.push(TensorOp::discount(&buffer.x, 0.5, 0.0)?);
+    match model.layer_scale.get(index) {
+        Some(&scale) if scale != 1.0 => ops.push(TensorOp::discount(&buffer.x, scale, 0.0)?),
+        _ => {}
     }
 
     Ok(TensorOp::List(ops))
@@ -963,6 +1246,7 @@ fn build_header<F: Float>(
     head: Head,
     head_x: TensorGpu<F, ReadWrite>,
     num_header: usize,
+    ln_eps: f32,
     mut ops: Vec<TensorOp>,
 ) -> Result<TensorOp> {
     let hook_op = |hook: Hook| hook_op(&hooks, &hook, &frame);
@@ -971,12 +1255,7 @@ fn build_header<F: Float>(
     if num_header > 0 {
         ops.append(&mut vec![
             hook_op(Hook::PreHead)?,
-            TensorOp::layer_norm(
-                &head.layer_norm.w,
-                &head.layer_norm.b,
-                &head_x,
-                Model::LN_EPS,
-            )?,
+            TensorOp::layer_norm(&head.layer_norm.w, &head.layer_norm.b, &head_x, ln_eps)?,
             hook_op(Hook::PostHeadLayerNorm)?,
             head.w.matmul_op(
                 head_x.view(.., .., .., ..)?,
@@ -984,208 +1263,350 @@ fn build_header<F: Float>(
                 Activation::None,
                 turbo(num_header),
             )?,
-            hook_op(Hook::PostHead)?,
         ]);
+        if let Some(b) = &head.b {
+            ops.push(TensorOp::add(
+                b.view(.., .., .., ..)?,
+                header.head_o.view(.., .., .., ..)?,
+            )?);
+        }
+        ops.push(hook_op(Hook::PostHead)?);
+        if let Some(mask) = &head.mask {
+            ops.push(TensorOp::masked_fill(
+                mask,
+                &header.head_o,
+                f32::NEG_INFINITY,
+            )?);
+        }
+        if let Some(head_h) = &header.head_h {
+            ops.push(TensorOp::blit(
+                header.head_o.view(.., .., .., ..)?,
+                head_h.view(.., .., .., ..)?,
+            )?);
+        }
+        if let Some((indices, values)) = &header.head_k {
+            ops.push(TensorOp::top_k(&header.head_o, indices, values)?);
+        }
     }
     Ok(TensorOp::List(ops))
 }
 
 impl<R: Reader> Build<Model> for ModelBuilder<R> {
+    /// Build the model. Running out of GPU memory while loading fails with
+    /// [`ContextError::OutOfMemory`](crate::context::ContextError::OutOfMemory),
+    /// unless the [`oom_policy`](ModelBuilder::oom_policy) finds a way out.
+    /// The context must not be used by anything else until the build finishes.
     async fn build(self) -> Result<Model> {
-        let ModelBuilder {
-            context,
-            model,
-            lora,
-            quant,
-            embed_device,
-        } = self;
-
-        let info = Loader::info(&model)?;
-        let loader = Loader {
-            context: context.clone(),
-            model,
-            lora,
-        };
-
-        let embed = Embed {
-            layer_norm: LayerNorm {
-                w: loader.load_vector_f16("blocks.0.ln0.weight").await?,
-                b: loader.load_vector_f16("blocks.0.ln0.bias").await?,
-            },
-            w: loader.load_embed().await?,
-            u: match embed_device {
-                EmbedDevice::Cpu => None,
-                EmbedDevice::Gpu => Some(loader.load_matrix_f16("emb.weight").await?),
-            },
-        };
-
-        let head = Head {
-            layer_norm: LayerNorm {
-                w: loader.load_vector_f16("ln_out.weight").await?,
-                b: loader.load_vector_f16("ln_out.bias").await?,
-            },
-            w: Matrix::Fp16(loader.load_matrix_f16("head.weight").await?),
-        };
-
-        context.queue.submit(None);
-        context.device.poll(wgpu::MaintainBase::Wait);
-
-        let load_matrix = |name: String, quant: Quant| loader.load_matrix(name, quant);
-        let load_matrix_discount = |name: String, quant: Quant, discount: f32| {
-            loader.load_matrix_discount(name, quant, discount)
-        };
+        build_model(self).await
+    }
+}
 
-        let mut layers = vec![];
-        for layer in 0..info.num_layer {
-            let quant = quant.get(&layer).copied().unwrap_or_default();
-            let discount = 2.0_f32.powi(-((layer / Model::RESCALE_LAYER) as i32));
-
-            let att_layer_norm = LayerNorm {
-                w: loader
-                    .load_vector_f16(format!("blocks.{layer}.ln1.weight"))
-                    .await?,
-                b: loader
-                    .load_vector_f16(format!("blocks.{layer}.ln1.bias"))
-                    .await?,
-            };
+async fn build_model<R: Reader>(builder: ModelBuilder<R>) -> Result<Model> {
+    let ModelBuilder {
+        context,
+        model,
+        lora,
+        quant,
+        embed_device,
+        rescale,
+        layer_scale,
+        ln_eps,
+        gn_eps,
+        real_vocab,
+        head_size,
+        strict,
+        tied_head,
+        verify,
+        metadata,
+        oom_policy,
+    } = builder;
+
+    let rescale = rescale
+        .or_else(|| parse_rescale_layer(&metadata))
+        .unwrap_or(Model::RESCALE_LAYER);
+    let layer_scale = layer_scale.or_else(|| parse_layer_scale(&metadata));
+    let ln_eps = ln_eps.unwrap_or(Model::LN_EPS);
+    let gn_eps = gn_eps.unwrap_or(Model::GN_EPS);
+
+    let mut info = Loader::info(&model)?;
+    let layer_scale = super::model::resolve_layer_scale(info.num_layer, rescale, layer_scale)?;
+    let discounts = super::model::layer_discounts(&layer_scale);
+    if let Some(head_size) = head_size {
+        info.set_head_size(head_size)?;
+    }
+    if strict {
+        Loader::check(&model, &info, tied_head)?;
+    }
+    if verify {
+        Loader::verify(&model, &parse_tensor_hashes(&metadata)).await?;
+    }
+    let loader = Loader {
+        context: context.with_memory_kind(MemoryKind::Weights),
+        model,
+        lora,
+    };
 
-            let att = format!("blocks.{layer}.att");
-            let time_decay = loader.load_vector_f16(format!("{att}.time_decay")).await?;
-            let time_first = loader.load_vector_f32(format!("{att}.time_first")).await?;
-            let time_mix_x = loader.load_vector_f16(format!("{att}.time_mix_x")).await?;
-            let time_mix = {
-                let time_mix: TensorGpu<_, _> = context.zeros([info.num_emb, 1, 5, 1]);
-                let time_mix_w = loader.load_vector_f16(format!("{att}.time_mix_w")).await?;
-                let time_mix_k = loader.load_vector_f16(format!("{att}.time_mix_k")).await?;
-                let time_mix_v = loader.load_vector_f16(format!("{att}.time_mix_v")).await?;
-                let time_mix_r = loader.load_vector_f16(format!("{att}.time_mix_r")).await?;
-                let time_mix_g = loader.load_vector_f16(format!("{att}.time_mix_g")).await?;
-
-                let ops = TensorOp::List(vec![
-                    TensorOp::blit(
-                        time_mix_w.view(.., .., .., ..)?,
-                        time_mix.view(.., .., 0, ..)?,
-                    )?,
-                    TensorOp::blit(
-                        time_mix_k.view(.., .., .., ..)?,
-                        time_mix.view(.., .., 1, ..)?,
-                    )?,
-                    TensorOp::blit(
-                        time_mix_v.view(.., .., .., ..)?,
-                        time_mix.view(.., .., 2, ..)?,
-                    )?,
-                    TensorOp::blit(
-                        time_mix_r.view(.., .., .., ..)?,
-                        time_mix.view(.., .., 3, ..)?,
-                    )?,
-                    TensorOp::blit(
-                        time_mix_g.view(.., .., .., ..)?,
-                        time_mix.view(.., .., 4, ..)?,
-                    )?,
-                ]);
-                context.queue.submit(context.encode(&ops));
-                time_mix
+    // allocations are caught in small scopes, since other tasks may run at the yields in between
+    let mut embed_device = embed_device;
+    let (embed, head) = loop {
+        let load = async {
+            let embed = Embed {
+                layer_norm: LayerNorm {
+                    w: loader.load_vector_f16("blocks.0.ln0.weight").await?,
+                    b: loader.load_vector_f16("blocks.0.ln0.bias").await?,
+                },
+                w: loader.load_embed().await?,
+                u: match embed_device {
+                    EmbedDevice::Cpu => None,
+                    EmbedDevice::Gpu => Some(loader.load_matrix_f16("emb.weight").await?),
+                },
             };
 
-            let time_decay_w1 = loader
-                .load_matrix_f16(format!("{att}.time_decay_w1"))
-                .await?;
-            let time_decay_w2 = loader
-                .load_matrix_f16(format!("{att}.time_decay_w2"))
-                .await?;
-
-            let time_mix_w1 = loader.load_matrix_f16(format!("{att}.time_mix_w1")).await?;
-            let time_mix_w2 = loader.load_matrix_f16(format!("{att}.time_mix_w2")).await?;
-
-            let group_norm = LayerNorm {
-                w: loader
-                    .load_vector_f16(format!("{att}.ln_x.weight"))
-                    .await?
-                    .reshape(
-                        TensorDimension::Auto,
-                        TensorDimension::Dimension(info.num_head),
-                        TensorDimension::Dimension(1),
-                        TensorDimension::Dimension(1),
-                    )?,
-                b: loader
-                    .load_vector_f16(format!("{att}.ln_x.bias"))
-                    .await?
-                    .reshape(
-                        TensorDimension::Auto,
-                        TensorDimension::Dimension(info.num_head),
-                        TensorDimension::Dimension(1),
-                        TensorDimension::Dimension(1),
-                    )?,
+            // a head tied to the embedding shares its buffer, which has the same layout `[C, V]`
+            let head_w = match (tied_head, &embed.u) {
+                (false, _) => loader.load_matrix_f16("head.weight").await?,
+                (true, Some(u)) => u.clone(),
+                (true, None) => loader.load_matrix_f16("emb.weight").await?,
             };
-
-            let att = Att {
-                time_decay,
-                time_first,
-                time_mix_x,
-                time_mix,
-                time_decay_w1: Matrix::Fp16(time_decay_w1),
-                time_decay_w2: Matrix::Fp16(time_decay_w2),
-                time_mix_w1: Matrix::Fp16(time_mix_w1),
-                time_mix_w2: Matrix::Fp16(time_mix_w2),
-                w_k: load_matrix(format!("{att}.key.weight"), quant).await?,
-                w_v: load_matrix(format!("{att}.value.weight"), quant).await?,
-                w_r: load_matrix(format!("{att}.receptance.weight"), quant).await?,
-                w_g: load_matrix(format!("{att}.gate.weight"), quant).await?,
-                w_o: load_matrix_discount(format!("{att}.output.weight"), quant, discount).await?,
-                group_norm,
+            let head = Head {
+                layer_norm: LayerNorm {
+                    w: loader.load_vector_f16("ln_out.weight").await?,
+                    b: loader.load_vector_f16("ln_out.bias").await?,
+                },
+                w: Matrix::Fp16(head_w),
+                b: match loader.model.contains("head.bias") {
+                    true => Some(loader.load_vector_f32("head.bias").await?),
+                    false => None,
+                },
+                mask: None,
             };
 
-            let ffn_layer_norm = LayerNorm {
-                w: loader
-                    .load_vector_f16(format!("blocks.{layer}.ln2.weight"))
-                    .await?,
-                b: loader
-                    .load_vector_f16(format!("blocks.{layer}.ln2.bias"))
-                    .await?,
+            let head = match real_vocab {
+                Some(num_vocab) if num_vocab > head.w.num_row() => {
+                    return Err(ModelError::InvalidVocab.into());
+                }
+                Some(num_vocab) if num_vocab < head.w.num_row() => {
+                    let flags = (0..head.w.num_row())
+                        .map(|index| index < num_vocab)
+                        .collect_vec();
+                    let mask = TensorMask::new(&loader.context, [flags.len(), 1, 1, 1], &flags)?;
+                    info.num_vocab = num_vocab;
+                    Head {
+                        mask: Some(mask),
+                        ..head
+                    }
+                }
+                Some(num_vocab) => {
+                    info.num_vocab = num_vocab;
+                    head
+                }
+                None => head,
             };
 
-            let ffn = format!("blocks.{layer}.ffn");
-            let time_mix_k = loader.load_vector_f16(format!("{ffn}.time_mix_k")).await?;
-            let time_mix_r = loader.load_vector_f16(format!("{ffn}.time_mix_r")).await?;
+            anyhow::Ok((embed, head))
+        };
+        let err = match context.catch_oom(load).await {
+            Ok(output) => break output?,
+            Err(err) => err,
+        };
+        match oom_policy
+            .as_ref()
+            .map_or(OomAction::Fail, |f| f(OomStage::Embed))
+        {
+            OomAction::EmbedCpu if embed_device == EmbedDevice::Gpu => {
+                embed_device = EmbedDevice::Cpu
+            }
+            _ => return Err(err.into()),
+        }
+    };
 
-            let ffn = Ffn {
-                time_mix_k,
-                time_mix_r,
-                w_r: load_matrix(format!("{ffn}.receptance.weight"), quant).await?,
-                w_k: load_matrix(format!("{ffn}.key.weight"), quant).await?,
-                w_v: load_matrix_discount(format!("{ffn}.value.weight"), quant, discount).await?,
-            };
+    context.queue.submit(None);
+    context.device.poll(wgpu::MaintainBase::Wait);
 
-            context.queue.submit(None);
-            context.device.poll(wgpu::MaintainBase::Wait);
+    let load_matrix = |name: String, quant: Quant| loader.load_matrix(name, quant);
+    let load_matrix_discount = |name: String, quant: Quant, discount: f32| {
+        loader.load_matrix_discount(name, quant, discount)
+    };
 
-            layers.push(Layer {
-                att_layer_norm,
-                ffn_layer_norm,
-                att,
-                ffn,
-            })
-        }
+    let mut layers = vec![];
+    let mut fallback = None;
+    for (layer, discount) in (0..info.num_layer).zip(discounts) {
+        let mut quant = fallback.unwrap_or(quant.get(&layer).copied().unwrap_or_default());
+        let block = loop {
+            let load = async {
+                let att_layer_norm = LayerNorm {
+                    w: loader
+                        .load_vector_f16(format!("blocks.{layer}.ln1.weight"))
+                        .await?,
+                    b: loader
+                        .load_vector_f16(format!("blocks.{layer}.ln1.bias"))
+                        .await?,
+                };
+
+                let att = format!("blocks.{layer}.att");
+                let time_decay = loader.load_vector_f16(format!("{att}.time_decay")).await?;
+                let time_first = loader.load_vector_f32(format!("{att}.time_first")).await?;
+                let time_mix_x = loader.load_vector_f16(format!("{att}.time_mix_x")).await?;
+                let time_mix = {
+                    let time_mix: TensorGpu<_, _> = context.zeros([info.num_emb, 1, 5, 1]);
+                    let time_mix_w = loader.load_vector_f16(format!("{att}.time_mix_w")).await?;
+                    let time_mix_k = loader.load_vector_f16(format!("{att}.time_mix_k")).await?;
+                    let time_mix_v = loader.load_vector_f16(format!("{att}.time_mix_v")).await?;
+                    let time_mix_r = loader.load_vector_f16(format!("{att}.time_mix_r")).await?;
+                    let time_mix_g = loader.load_vector_f16(format!("{att}.time_mix_g")).await?;
+
+                    let ops = TensorOp::List(vec![
+                        TensorOp::blit(
+                            time_mix_w.view(.., .., .., ..)?,
+                            time_mix.view(.., .., 0, ..)?,
+                        )?,
+                        TensorOp::blit(
+                            time_mix_k.view(.., .., .., ..)?,
+                            time_mix.view(.., .., 1, ..)?,
+                        )?,
+                        TensorOp::blit(
+                            time_mix_v.view(.., .., .., ..)?,
+                            time_mix.view(.., .., 2, ..)?,
+                        )?,
+                        TensorOp::blit(
+                            time_mix_r.view(.., .., .., ..)?,
+                            time_mix.view(.., .., 3, ..)?,
+                        )?,
+                        TensorOp::blit(
+                            time_mix_g.view(.., .., .., ..)?,
+                            time_mix.view(.., .., 4, ..)?,
+                        )?,
+                    ]);
+                    context.submit(context.encode(&ops));
+                    time_mix
+                };
+
+                let time_decay_w1 = loader
+                    .load_matrix_f16(format!("{att}.time_decay_w1"))
+                    .await?;
+                let time_decay_w2 = loader
+                    .load_matrix_f16(format!("{att}.time_decay_w2"))
+                    .await?;
+
+                let time_mix_w1 = loader.load_matrix_f16(format!("{att}.time_mix_w1")).await?;
+                let time_mix_w2 = loader.load_matrix_f16(format!("{att}.time_mix_w2")).await?;
+
+                let group_norm = LayerNorm {
+                    w: loader
+                        .load_vector_f16(format!("{att}.ln_x.weight"))
+                        .await?
+                        .reshape(
+                            TensorDimension::Auto,
+                            TensorDimension::Dimension(info.num_head),
+                            TensorDimension::Dimension(1),
+                            TensorDimension::Dimension(1),
+                        )?,
+                    b: loader
+                        .load_vector_f16(format!("{att}.ln_x.bias"))
+                        .await?
+                        .reshape(
+                            TensorDimension::Auto,
+                            TensorDimension::Dimension(info.num_head),
+                            TensorDimension::Dimension(1),
+                            TensorDimension::Dimension(1),
+                        )?,
+                };
+
+                let att = Att {
+                    time_decay,
+                    time_first,
+                    time_mix_x,
+                    time_mix,
+                    time_decay_w1: Matrix::Fp16(time_decay_w1),
+                    time_decay_w2: Matrix::Fp16(time_decay_w2),
+                    time_mix_w1: Matrix::Fp16(time_mix_w1),
+                    time_mix_w2: Matrix::Fp16(time_mix_w2),
+                    w_k: load_matrix(format!("{att}.key.weight"), quant).await?,
+                    w_v: load_matrix(format!("{att}.value.weight"), quant).await?,
+                    w_r: load_matrix(format!("{att}.receptance.weight"), quant).await?,
+                    w_g: load_matrix(format!("{att}.gate.weight"), quant).await?,
+                    w_o: load_matrix_discount(format!("{att}.output.weight"), quant, discount)
+                        .await?,
+                    group_norm,
+                };
+
+                let ffn_layer_norm = LayerNorm {
+                    w: loader
+                        .load_vector_f16(format!("blocks.{layer}.ln2.weight"))
+                        .await?,
+                    b: loader
+                        .load_vector_f16(format!("blocks.{layer}.ln2.bias"))
+                        .await?,
+                };
+
+                let ffn = format!("blocks.{layer}.ffn");
+                let time_mix_k = loader.load_vector_f16(format!("{ffn}.time_mix_k")).await?;
+                let time_mix_r = loader.load_vector_f16(format!("{ffn}.time_mix_r")).await?;
+
+                let ffn = Ffn {
+                    time_mix_k,
+                    time_mix_r,
+                    w_r: load_matrix(format!("{ffn}.receptance.weight"), quant).await?,
+                    w_k: load_matrix(format!("{ffn}.key.weight"), quant).await?,
+                    w_v: load_matrix_discount(format!("{ffn}.value.weight"), quant, discount)
+                        .await?,
+                };
+
+                anyhow::Ok(Layer {
+                    att_layer_norm,
+                    ffn_layer_norm,
+                    att,
+                    ffn,
+                })
+            };
+            let err = match context.catch_oom(load).await {
+                Ok(output) => break output?,
+                Err(err) => err,
+            };
+            match oom_policy
+                .as_ref()
+                .map_or(OomAction::Fail, |f| f(OomStage::Layer(layer)))
+            {
+                OomAction::Quant(value) if value != quant => {
+                    quant = value;
+                    fallback = Some(value);
+                }
+                _ => return Err(err.into()),
+            }
+        };
 
         context.queue.submit(None);
         context.device.poll(wgpu::MaintainBase::Wait);
 
-        let tensor = ModelTensor {
-            embed,
-            head,
-            layers,
-        };
-        let model = {
-            let context = context.clone();
-            let info = info.clone();
-            Model {
-                context,
-                info,
-                tensor,
-            }
-        };
-        Ok(model)
+        // give other tasks a chance to run, since single-threaded executors (e.g., on web)
+        // would otherwise be blocked until the whole model is loaded
+        tokio::task::yield_now().await;
+
+        layers.push(block);
     }
+
+    context.queue.submit(None);
+    context.device.poll(wgpu::MaintainBase::Wait);
+
+    let tensor = ModelTensor {
+        embed,
+        head,
+        layers,
+    };
+    let model = {
+        let context = context.clone();
+        let info = info.clone();
+        Model {
+            context,
+            info,
+            rescale,
+            layer_scale,
+            ln_eps,
+            gn_eps,
+            tensor,
+        }
+    };
+    Ok(model)
 }
 
 /// Read the pre-trained state from the file.
@@ -1226,7 +1647,101 @@ pub async fn read_state<R: Reader>(
             )?,
         ]);
     }
-    context.queue.submit(context.encode(&TensorOp::List(ops)));
+    context.submit(context.encode(&TensorOp::List(ops)));
 
     Ok(data.back().await)
 }
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use itertools::Itertools;
+    use wgpu::{Instance, PowerPreference};
+
+    use super::State;
+    use crate::{
+        context::{Context, ContextBuilder, InstanceExt},
+        runtime::model::{ModelInfo, ModelVersion, State as _},
+        tensor::{TensorCpu, TensorInit, TensorShape},
+    };
+
+    async fn create_context() -> Result<Context> {
+        let instance = Instance::default();
+        let adapter = instance.adapter(PowerPreference::HighPerformance).await?;
+        let context = ContextBuilder::new(adapter).build().await?;
+        Ok(context)
+    }
+
+    #[test]
+    fn test_head_state() -> Result<()> {
+        let context = match pollster::block_on(create_context()) {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        const C: usize = 8;
+        const H: usize = 2;
+        const S: usize = C / H;
+        const L: usize = 2;
+
+        let info = ModelInfo {
+            version: ModelVersion::V6,
+            num_layer: L,
+            num_emb: C,
+            num_hidden: C,
+            num_vocab: 1,
+            num_head: H,
+            time_mix_adapter_size: 0,
+            time_decay_adapter_size: 0,
+        };
+        let state = State {
+            context,
+            info,
+            data: vec![],
+        };
+
+        let shape = state.init().shape();
+        let backed = TensorCpu::from_data(shape, (0..shape.len()).map(|x| x as f32).collect_vec())?;
+
+        let heads = state.head_state(&backed, 1)?;
+        heads.check_shape([S, S, H, 1])?;
+        for (head, row, col) in itertools::iproduct!(0..H, 0..S, 0..S) {
+            assert_eq!(
+                heads[(col, row, head, 0)],
+                backed[(head * S + col, row + 1, 1, 0)]
+            );
+        }
+
+        let scaled = state.scale_head(backed.clone(), 1, 1, 2.0)?;
+        for (index, (&a, &b)) in itertools::zip_eq(scaled.iter(), backed.iter()).enumerate() {
+            let [col, row, layer] = [index % C, index / C % (S + 2), index / C / (S + 2)];
+            let inside = layer == 1 && col >= S && (1..=S).contains(&row);
+            assert_eq!(
+                a,
+                if inside { 2.0 * b } else { b },
+                "failed at index {index}"
+            );
+        }
+
+        let zeroed = state.zero_head(backed.clone(), 0, 0)?;
+        let heads = state.head_state(&zeroed, 0)?;
+        assert!(heads.iter().take(S * S).all(|&x| x == 0.0));
+        assert!(heads.iter().skip(S * S).all(|&x| x != 0.0));
+
+        let heads = state.head_state(&backed, 1)?;
+        let moved = state.set_head_state(backed.clone(), 0, &heads)?;
+        assert_eq!(
+            state.head_state(&moved, 0)?.to_vec(),
+            state.head_state(&backed, 1)?.to_vec()
+        );
+
+        // invalid layers, heads and shapes are errors rather than panics
+        assert!(state.scale_head(backed.clone(), L, 0, 2.0).is_err());
+        assert!(state.scale_head(backed.clone(), 0, H, 2.0).is_err());
+        let wrong = TensorCpu::from_data([C, S + 2, 1, 1], vec![0.0; C * (S + 2)])?;
+        assert!(state.scale_head(wrong.clone(), 0, 0, 2.0).is_err());
+        assert!(state.head_state(&wrong, 0).is_err());
+
+        Ok(())
+    }
+}
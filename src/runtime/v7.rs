@@ -0,0 +1,1923 @@
+use std::{collections::HashMap, marker::PhantomData, sync::Arc};
+
+use anyhow::Result;
+use futures::future::BoxFuture;
+use half::f16;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use web_rwkv_derive::DeserializeSeed;
+use wgpu::CommandBuffer;
+
+use super::{
+    infer::{InferChunk, InferInfo, InferOutput, InferOutputBatch, InferRedirect, InferStats},
+    loader::{parse_layer_scale, parse_rescale_layer, parse_tensor_hashes, Loader, Reader},
+    model::{
+        AsAny, Build, EmbedDevice, ModelBuilder, ModelError, ModelInfo, OomAction, OomStage, Quant,
+        State as _,
+    },
+    Job, JobBuilder,
+};
+use crate::{
+    context::{Context, MemoryKind},
+    impl_deserialize_seed,
+    num::Float,
+    tensor::{
+        kind::ReadWrite,
+        matrix::Matrix,
+        ops::{Activation, TensorCommand, TensorOp},
+        shape::{Shape, TensorDimension},
+        Cursor, DeepClone, IntoPackedCursors, TensorCpu, TensorError, TensorGpu, TensorGpuView,
+        TensorInit, TensorMask, TensorReshape, TensorShape, TensorStack,
+    },
+};
+
+/// Weights of a model. Cheap to clone; see [`ModelRuntime`](super::model::ModelRuntime) on sharing weights.
+#[derive(Debug, Clone, Serialize, DeserializeSeed)]
+pub struct Model {
+    pub context: Context,
+    pub info: ModelInfo,
+    /// Halve the residual every this many layers (0 for no rescaling).
+    pub rescale: usize,
+    /// Factor the residual is scaled by after each layer.
+    pub layer_scale: Vec<f32>,
+    /// Epsilon of layer norms.
+    pub ln_eps: f32,
+    /// Epsilon of group norms.
+    pub gn_eps: f32,
+    /// Ranks of the low-rank adapters of the time mix.
+    pub adapter: AdapterSize,
+    pub tensor: ModelTensor,
+}
+
+impl Model {
+    pub const RESCALE_LAYER: usize = 6;
+
+    pub const LN_EPS: f32 = 1.0e-5;
+    pub const GN_EPS: f32 = 64.0e-5;
+    /// Epsilon of the L2 normalization of the removal key.
+    pub const L2_EPS: f32 = 1.0e-12;
+
+    /// Compute the layer-normed embedding matrix of shape `[C, V]`,
+    /// i.e., the vector each token feeds into the first layer.
+    pub async fn normed_embed(&self) -> TensorCpu<f32> {
+        let embed = &self.tensor.embed;
+        let w = embed.layer_norm.w.back().await;
+        let b = embed.layer_norm.b.back().await;
+        super::model::layer_norm_rows(&embed.w, &w, &b, self.ln_eps)
+    }
+
+    /// Overwrite a weight matrix in place with `matrix` given in the checkpoint's layout,
+    /// re-quantizing just that matrix if the layer is quantized. This allows testing weight edits
+    /// (e.g., ablations) without reloading the model. Supported are the projection matrices of layers
+    /// (e.g., `blocks.0.att.key.weight`) and `head.weight`.
+    ///
+    /// Weights are shared, so the change is seen by all clones of the model and their runtimes.
+    /// A head [tied](ModelBuilder::tied_head) to an embedding on GPU shares its buffer, so patching
+    /// `head.weight` then rewrites the GPU embedding too, but not the CPU copy of the embedding.
+    pub fn patch_matrix(&self, name: &str, matrix: &TensorCpu<f16>) -> Result<()> {
+        let (w, discount) = self.matrix(name).ok_or(ModelError::InvalidTensor)?;
+        if discount == 1.0 {
+            w.patch(matrix)?;
+        } else {
+            w.patch(&matrix.clone().map(|x| f16::from_f32(discount * x.to_f32())))?;
+        }
+        Ok(())
+    }
+
+    /// Find a patchable matrix by its name, along with the discount applied to it when loaded.
+    fn matrix(&self, name: &str) -> Option<(&Matrix, f32)> {
+        if name == "head.weight" {
+            return Some((&self.tensor.head.w, 1.0));
+        }
+
+        let (layer, name) = name.strip_prefix("blocks.")?.split_once('.')?;
+        let layer: usize = layer.parse().ok()?;
+        let tensor = self.tensor.layers.get(layer)?;
+        let discount = super::model::layer_discounts(&self.layer_scale)[layer];
+        match name {
+            "att.key.weight" => Some((&tensor.att.w_k, 1.0)),
+            "att.value.weight" => Some((&tensor.att.w_v, 1.0)),
+            "att.receptance.weight" => Some((&tensor.att.w_r, 1.0)),
+            "att.output.weight" => Some((&tensor.att.w_o, discount)),
+            "ffn.key.weight" => Some((&tensor.ffn.w_k, 1.0)),
+            "ffn.value.weight" => Some((&tensor.ffn.w_v, discount)),
+            _ => None,
+        }
+    }
+}
+
+/// Ranks of the low-rank adapters of the time mix, which are read from the checkpoint.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct AdapterSize {
+    /// Rank of the time decay adapter (`w1`, `w2`).
+    pub w: usize,
+    /// Rank of the in-context learning rate adapter (`a1`, `a2`).
+    pub a: usize,
+    /// Rank of the value residual adapter (`v1`, `v2`).
+    pub v: usize,
+    /// Rank of the gate adapter (`g1`, `g2`).
+    pub g: usize,
+}
+
+impl_deserialize_seed!(AdapterSize);
+
+#[derive(Debug, Clone, Serialize, DeserializeSeed)]
+pub struct ModelTensor {
+    pub embed: Embed,
+    pub head: Head,
+    pub layers: Vec<Layer>,
+}
+
+#[derive(Debug, Clone, Serialize, DeserializeSeed)]
+pub struct LayerNorm {
+    pub w: TensorGpu<f16, ReadWrite>,
+    pub b: TensorGpu<f16, ReadWrite>,
+}
+
+#[derive(Debug, Clone, Serialize, DeserializeSeed)]
+pub struct Att {
+    pub x_r: TensorGpu<f16, ReadWrite>,
+    pub x_w: TensorGpu<f16, ReadWrite>,
+    pub x_k: TensorGpu<f16, ReadWrite>,
+    pub x_v: TensorGpu<f16, ReadWrite>,
+    pub x_a: TensorGpu<f16, ReadWrite>,
+    pub x_g: TensorGpu<f16, ReadWrite>,
+
+    /// Bias of the time decay.
+    pub w0: TensorGpu<f16, ReadWrite>,
+    pub w1: Matrix,
+    pub w2: Matrix,
+    /// Bias of the in-context learning rate.
+    pub a0: TensorGpu<f16, ReadWrite>,
+    pub a1: Matrix,
+    pub a2: Matrix,
+    /// Bias of the value residual gate. The value residual is absent in the first layer.
+    pub v0: Option<TensorGpu<f16, ReadWrite>>,
+    pub v1: Option<Matrix>,
+    pub v2: Option<Matrix>,
+    pub g1: Matrix,
+    pub g2: Matrix,
+
+    /// Scale of the key to remove from the state.
+    pub k_k: TensorGpu<f16, ReadWrite>,
+    /// Portion of the key replaced by the in-context learning rate.
+    pub k_a: TensorGpu<f16, ReadWrite>,
+    /// Weight of the bonus of the current token, `[S, H, 1]`.
+    pub r_k: TensorGpu<f16, ReadWrite>,
+
+    pub w_k: Matrix,
+    pub w_v: Matrix,
+    pub w_r: Matrix,
+    pub w_o: Matrix,
+
+    pub group_norm: LayerNorm,
+}
+
+#[derive(Debug, Clone, Serialize, DeserializeSeed)]
+pub struct Ffn {
+    pub x_k: TensorGpu<f16, ReadWrite>,
+
+    pub w_k: Matrix,
+    pub w_v: Matrix,
+}
+
+#[derive(Debug, Clone, Serialize, DeserializeSeed)]
+pub struct Layer {
+    pub att_layer_norm: LayerNorm,
+    pub ffn_layer_norm: LayerNorm,
+    pub att: Att,
+    pub ffn: Ffn,
+}
+
+#[derive(Debug, Clone, Serialize, DeserializeSeed)]
+pub struct Embed {
+    pub layer_norm: LayerNorm,
+    pub w: TensorCpu<f16>,
+    pub u: Option<TensorGpu<f16, ReadWrite>>,
+}
+
+#[derive(Debug, Clone, Serialize, DeserializeSeed)]
+pub struct Head {
+    pub layer_norm: LayerNorm,
+    pub w: Matrix,
+    /// Bias added to the logits, if the checkpoint has one.
+    pub b: Option<TensorGpu<f32, ReadWrite>>,
+    /// Mask selecting the real vocab rows, masking out the padding rows beyond it.
+    pub mask: Option<TensorMask>,
+}
+
+#[derive(Debug, Clone, Serialize, DeserializeSeed)]
+pub struct State {
+    pub context: Context,
+    pub info: ModelInfo,
+    pub data: Vec<TensorGpu<f32, ReadWrite>>,
+}
+
+impl State {
+    async fn back(&self, batch: usize) -> Result<TensorCpu<f32>, TensorError> {
+        let context = &self.context;
+        let mut tensors = Vec::with_capacity(self.info.num_layer);
+        let mut encoder = context.device.create_command_encoder(&Default::default());
+        for data in self.data.iter() {
+            let shape = data.shape();
+            let destination = context.tensor_init([shape[0], shape[1], 1, 1]);
+            encoder.copy_tensor_batch(data, &destination, batch, 0)?;
+            tensors.push(destination);
+        }
+        context.queue.submit(Some(encoder.finish()));
+
+        let mut backed = Vec::with_capacity(tensors.len());
+        for tensor in tensors.into_iter() {
+            backed.push(tensor.back().await);
+        }
+        TensorCpu::stack(backed)
+    }
+
+    /// Flat indices of a head's WKV state in a backed state, in the order of `[S, S]`.
+    fn head_indices(&self, layer: usize, head: usize) -> impl Iterator<Item = usize> {
+        let info = &self.info;
+        let num_emb = info.num_emb;
+        let head_size = info.num_emb / info.num_head;
+        let offset = layer * num_emb * (head_size + 2);
+        (0..head_size).flat_map(move |row| {
+            let start = offset + (row + 1) * num_emb + head * head_size;
+            start..start + head_size
+        })
+    }
+
+    fn check_backed(&self, backed: &TensorCpu<f32>, layer: usize) -> Result<(), TensorError> {
+        let info = &self.info;
+        let head_size = info.num_emb / info.num_head;
+        backed.check_shape([info.num_emb, head_size + 2, info.num_layer, 1])?;
+        match layer < info.num_layer {
+            true => Ok(()),
+            false => Err(TensorError::SliceOutOfRange {
+                dim: info.num_layer,
+                start: layer,
+                end: layer + 1,
+            }),
+        }
+    }
+
+    fn update(
+        &self,
+        backed: TensorCpu<f32>,
+        layer: usize,
+        values: impl IntoIterator<Item = (usize, f32)>,
+    ) -> Result<TensorCpu<f32>, TensorError> {
+        self.check_backed(&backed, layer)?;
+
+        let shape = backed.shape();
+        let mut data: Vec<_> = backed.into();
+        for (index, value) in values {
+            data[index] = value;
+        }
+        TensorCpu::from_data(shape, data)
+    }
+}
+
+impl AsAny for State {
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+}
+
+impl super::model::State for State {
+    #[inline]
+    fn num_batch(&self) -> usize {
+        self.data[0].shape()[2]
+    }
+
+    fn init(&self) -> TensorCpu<f32> {
+        let info = &self.info;
+        let head_size = info.num_emb / info.num_head;
+        let shape = Shape::new(info.num_emb, head_size + 2, info.num_layer, 1);
+        let data = vec![0.0; shape.len()];
+        TensorCpu::from_data(shape, data).unwrap()
+    }
+
+    fn att(&self, layer: usize) -> Result<TensorGpuView<f32>, TensorError> {
+        let head_size = self.info.num_emb / self.info.num_head;
+        let end = head_size + 1;
+        self.data[layer].view(.., 0..end, .., ..)
+    }
+
+    fn ffn(&self, layer: usize) -> Result<TensorGpuView<f32>, TensorError> {
+        let head_size = self.info.num_emb / self.info.num_head;
+        let start = head_size + 1;
+        self.data[layer].view(.., start, .., ..)
+    }
+
+    fn load(&self, tensor: TensorCpu<f32>, batch: usize) -> Result<(), TensorError> {
+        let head_size = self.info.num_emb / self.info.num_head;
+        tensor.check_shape([self.info.num_emb, head_size + 2, self.info.num_layer, 1])?;
+        for (data, source) in self.data.iter().zip(tensor.split(2)?.into_iter()) {
+            data.load_batch(&source, batch)?;
+        }
+        Ok(())
+    }
+
+    fn back(&self, batch: usize) -> BoxFuture<Result<TensorCpu<f32>, TensorError>> {
+        Box::pin(self.back(batch))
+    }
+
+    fn back_many(&self, batches: &[usize]) -> BoxFuture<Result<Vec<TensorCpu<f32>>, TensorError>> {
+        let batches = batches.to_vec();
+        Box::pin(super::model::back_batches(
+            &self.context,
+            &self.data,
+            batches,
+        ))
+    }
+
+    fn write(&self, tensor: TensorGpu<f32, ReadWrite>, batch: usize) -> Result<(), TensorError> {
+        let head_size = self.info.num_emb / self.info.num_head;
+        tensor.check_shape([self.info.num_emb, head_size + 2, self.info.num_layer, 1])?;
+
+        let context = &self.context;
+        let mut ops = Vec::with_capacity(self.data.len());
+        for (layer, data) in self.data.iter().enumerate() {
+            ops.push(TensorOp::blit(
+                tensor.view(.., .., layer, ..)?,
+                data.view(.., .., batch, ..)?,
+            )?);
+        }
+        context.submit(context.encode(&TensorOp::List(ops)));
+
+        Ok(())
+    }
+
+    fn read(&self, batch: usize) -> Result<TensorGpu<f32, ReadWrite>, TensorError> {
+        let context = &self.context;
+        let head_size = self.info.num_emb / self.info.num_head;
+        let shape = [self.info.num_emb, head_size + 2, self.info.num_layer, 1];
+        let tensor: TensorGpu<_, _> = context.tensor_init(shape);
+
+        let mut ops = Vec::with_capacity(self.data.len());
+        for (layer, data) in self.data.iter().enumerate() {
+            ops.push(TensorOp::blit(
+                data.view(.., .., batch, ..)?,
+                tensor.view(.., .., layer, ..)?,
+            )?);
+        }
+        context.submit(context.encode(&TensorOp::List(ops)));
+
+        Ok(tensor)
+    }
+
+    fn embed(&self, layer: usize, backed: TensorCpu<f32>) -> Result<TensorCpu<f32>, TensorError> {
+        backed.slice(.., 0, layer, ..)
+    }
+
+    fn head_state(
+        &self,
+        backed: &TensorCpu<f32>,
+        layer: usize,
+    ) -> Result<TensorCpu<f32>, TensorError> {
+        self.check_backed(backed, layer)?;
+
+        let info = &self.info;
+        let head_size = info.num_emb / info.num_head;
+        let data = (0..info.num_head)
+            .flat_map(|head| self.head_indices(layer, head))
+            .map(|index| backed.data()[index])
+            .collect_vec();
+        TensorCpu::from_data([head_size, head_size, info.num_head, 1], data)
+    }
+
+    fn set_head_state(
+        &self,
+        backed: TensorCpu<f32>,
+        layer: usize,
+        heads: &TensorCpu<f32>,
+    ) -> Result<TensorCpu<f32>, TensorError> {
+        let info = &self.info;
+        let head_size = info.num_emb / info.num_head;
+        heads.check_shape([head_size, head_size, info.num_head, 1])?;
+
+        let indices = (0..info.num_head).flat_map(|head| self.head_indices(layer, head));
+        self.update(backed, layer, indices.zip_eq(heads.iter().copied()))
+    }
+
+    fn scale_head(
+        &self,
+        backed: TensorCpu<f32>,
+        layer: usize,
+        head: usize,
+        factor: f32,
+    ) -> Result<TensorCpu<f32>, TensorError> {
+        self.check_backed(&backed, layer)?;
+        if head >= self.info.num_head {
+            return Err(TensorError::SliceOutOfRange {
+                dim: self.info.num_head,
+                start: head,
+                end: head + 1,
+            });
+        }
+        let values = self
+            .head_indices(layer, head)
+            .map(|index| (index, backed.data()[index] * factor))
+            .collect_vec();
+        self.update(backed, layer, values)
+    }
+    /// The reference layout has 3 tensors per layer: `att_x` of shape `(C)`,
+    /// `att_kv` of shape `(H, S, S)` indexed by head, value and key, and `ffn_x` of shape `(C)`.
+    fn to_reference_layout(
+        &self,
+        backed: &TensorCpu<f32>,
+    ) -> Result<Vec<TensorCpu<f32>>, TensorError> {
+        let info = &self.info;
+        let num_emb = info.num_emb;
+        let num_head = info.num_head;
+        let head_size = num_emb / num_head;
+        backed.check_shape([num_emb, head_size + 2, info.num_layer, 1])?;
+
+        let mut tensors = Vec::with_capacity(3 * info.num_layer);
+        for layer in 0..info.num_layer {
+            let row = |index: usize| {
+                let start = (layer * (head_size + 2) + index) * num_emb;
+                let data = backed.data()[start..start + num_emb].to_vec();
+                TensorCpu::from_data([num_emb, 1, 1, 1], data)
+            };
+
+            // the packed rows are indexed by key, while the reference is transposed
+            let heads = self.head_state(backed, layer)?;
+            let kv = itertools::iproduct!(0..num_head, 0..head_size, 0..head_size)
+                .map(|(head, value, key)| heads[(value, key, head, 0)])
+                .collect_vec();
+
+            tensors.push(row(0)?);
+            tensors.push(TensorCpu::from_data(heads.shape(), kv)?);
+            tensors.push(row(head_size + 1)?);
+        }
+        Ok(tensors)
+    }
+}
+
+impl DeepClone for State {
+    fn deep_clone(&self) -> Self {
+        let data = self.data.iter().map(|tensor| tensor.deep_clone()).collect();
+        Self {
+            data,
+            ..self.clone()
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Runtime<F: Float> {
+    pub cursors: TensorGpu<u32, ReadWrite>,
+    pub tokens: TensorGpu<u32, ReadWrite>,
+    pub input: TensorGpu<f16, ReadWrite>,
+
+    pub x: TensorGpu<F, ReadWrite>,
+    pub aux_x: TensorGpu<f32, ReadWrite>,
+
+    pub att_x: TensorGpu<F, ReadWrite>,
+    pub att_rx: TensorGpu<F, ReadWrite>,
+    pub att_wx: TensorGpu<F, ReadWrite>,
+    pub att_kx: TensorGpu<F, ReadWrite>,
+    pub att_vx: TensorGpu<F, ReadWrite>,
+    pub att_ax: TensorGpu<F, ReadWrite>,
+    pub att_gx: TensorGpu<F, ReadWrite>,
+
+    pub att_r: TensorGpu<f32, ReadWrite>,
+    /// Time decay before activation.
+    pub att_w: TensorGpu<f32, ReadWrite>,
+    pub att_k: TensorGpu<f32, ReadWrite>,
+    pub att_v: TensorGpu<f32, ReadWrite>,
+    /// In-context learning rate.
+    pub att_a: TensorGpu<f32, ReadWrite>,
+    /// Normalized key to remove from the state.
+    pub att_kk: TensorGpu<f32, ReadWrite>,
+    /// Value of the first layer, mixed into values of later layers.
+    pub att_v0: TensorGpu<f32, ReadWrite>,
+    /// Value residual gate.
+    pub att_vg: TensorGpu<f32, ReadWrite>,
+    pub att_g: TensorGpu<F, ReadWrite>,
+    pub att_o: TensorGpu<F, ReadWrite>,
+
+    /// Time decay adapter intermediate, `[D, T]`.
+    pub adapt_w: TensorGpu<F, ReadWrite>,
+    /// In-context learning rate adapter intermediate, `[D, T]`.
+    pub adapt_a: TensorGpu<F, ReadWrite>,
+    /// Value residual adapter intermediate, `[D, T]`.
+    pub adapt_v: TensorGpu<F, ReadWrite>,
+    /// Gate adapter intermediate, `[D, T]`.
+    pub adapt_g: TensorGpu<F, ReadWrite>,
+
+    pub ffn_x: TensorGpu<F, ReadWrite>,
+    pub ffn_kx: TensorGpu<F, ReadWrite>,
+    pub ffn_k: TensorGpu<F, ReadWrite>,
+    pub ffn_v: TensorGpu<F, ReadWrite>,
+}
+
+impl<F: Float> Runtime<F> {
+    pub fn new(
+        context: &Context,
+        info: &ModelInfo,
+        adapter: AdapterSize,
+        num_token: usize,
+    ) -> Self {
+        let shape = Shape::new(info.num_emb, num_token, 1, 1);
+        let cursors_shape = Shape::new(num_token, 1, 1, 1);
+        let tokens_shape = Shape::new(num_token, 1, 1, 1);
+        let hidden_shape = Shape::new(info.num_hidden, num_token, 1, 1);
+        let adapter_shape = |size: usize| Shape::new(size, num_token, 1, 1);
+
+        Self {
+            cursors: context.tensor_init(cursors_shape),
+            tokens: context.tensor_init(tokens_shape),
+            input: context.tensor_init(shape),
+            x: context.tensor_init(shape),
+            aux_x: context.tensor_init(shape),
+            att_x: context.tensor_init(shape),
+            att_rx: context.tensor_init(shape),
+            att_wx: context.tensor_init(shape),
+            att_kx: context.tensor_init(shape),
+            att_vx: context.tensor_init(shape),
+            att_ax: context.tensor_init(shape),
+            att_gx: context.tensor_init(shape),
+            att_r: context.tensor_init(shape),
+            att_w: context.tensor_init(shape),
+            att_k: context.tensor_init(shape),
+            att_v: context.tensor_init(shape),
+            att_a: context.tensor_init(shape),
+            att_kk: context.tensor_init(shape),
+            att_v0: context.tensor_init(shape),
+            att_vg: context.tensor_init(shape),
+            att_g: context.tensor_init(shape),
+            att_o: context.tensor_init(shape),
+            adapt_w: context.tensor_init(adapter_shape(adapter.w)),
+            adapt_a: context.tensor_init(adapter_shape(adapter.a)),
+            adapt_v: context.tensor_init(adapter_shape(adapter.v)),
+            adapt_g: context.tensor_init(adapter_shape(adapter.g)),
+            ffn_x: context.tensor_init(shape),
+            ffn_kx: context.tensor_init(shape),
+            ffn_k: context.tensor_init(hidden_shape),
+            ffn_v: context.tensor_init(shape),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Header<F: Float> {
+    pub head_x: TensorGpu<F, ReadWrite>,
+    pub head_o: TensorGpu<f32, ReadWrite>,
+    /// Half-precision copy of `head_o` to read back, if half output is enabled.
+    pub head_h: Option<TensorGpu<f16, ReadWrite>>,
+    /// Indices and logits of the top `K` tokens, if sparse output is enabled.
+    pub head_k: Option<(TensorGpu<u32, ReadWrite>, TensorGpu<f32, ReadWrite>)>,
+}
+
+impl<F: Float> Header<F> {
+    /// Create header buffers. Here `num_vocab` is the size of the head, including padding rows.
+    pub fn new(
+        context: &Context,
+        info: &ModelInfo,
+        num_vocab: usize,
+        num_header: usize,
+        half_output: bool,
+        sparse_output: Option<usize>,
+    ) -> Self {
+        let head_shape = Shape::new(info.num_emb, num_header, 1, 1);
+        let output_shape = Shape::new(num_vocab, num_header, 1, 1);
+        let head_k = sparse_output.map(|k| {
+            let shape = Shape::new(k, num_header, 1, 1);
+            (context.tensor_init(shape), context.tensor_init(shape))
+        });
+
+        Self {
+            head_x: context.tensor_init(head_shape),
+            head_o: context.tensor_init(output_shape),
+            head_h: (half_output && sparse_output.is_none())
+                .then(|| context.tensor_init(output_shape)),
+            head_k,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Hook {
+    PostEmbedLoaded,
+    PostEmbedLayerNorm,
+    PreAtt(usize),
+    PostAttLayerNorm(usize),
+    PreAttTokenShift(usize),
+    PostAttTokenShift(usize),
+    PreAttLinear(usize),
+    PostAttLinear(usize),
+    PreAttTimeDecayAdapt(usize),
+    PostAttTimeDecayAdapt(usize),
+    PostAttTimeDecayAdaptActivate(usize),
+    PreAttControl(usize),
+    PostAttControl(usize),
+    PreAttValueResidual(usize),
+    PostAttValueResidual(usize),
+    PreAttGateAdapt(usize),
+    PostAttGateAdapt(usize),
+    PreAttTimeMix(usize),
+    PostAttTimeMix(usize),
+    PreAttGate(usize),
+    PostAttGate(usize),
+    PreAttOut(usize),
+    PostAttOut(usize),
+    PostAtt(usize),
+    PreFfn(usize),
+    PostFfnLayerNorm(usize),
+    PreFfnTokenShift(usize),
+    PostFfnTokenShift(usize),
+    PreFfnLinear(usize),
+    PostFfnLinear(usize),
+    PostFfnActivate(usize),
+    PreFfnChannelMix(usize),
+    PostFfnChannelMix(usize),
+    PostFfn(usize),
+    PreHead,
+    PostHeadLayerNorm,
+    PostHead,
+}
+
+pub struct InferJob {
+    commands: Vec<CommandBuffer>,
+    redirect: InferRedirect,
+
+    embed_device: EmbedDevice,
+    embed: TensorCpu<f16>,
+
+    cursors: TensorGpu<u32, ReadWrite>,
+    tokens: TensorGpu<u32, ReadWrite>,
+    input: TensorGpu<f16, ReadWrite>,
+    output: TensorGpu<f32, ReadWrite>,
+    output_half: Option<TensorGpu<f16, ReadWrite>>,
+    output_sparse: Option<(TensorGpu<u32, ReadWrite>, TensorGpu<f32, ReadWrite>)>,
+
+    stats: InferStats,
+    validate: bool,
+}
+
+impl Job for InferJob {
+    type Info = InferInfo;
+    type Input = InferChunk;
+    type Output = InferOutput;
+
+    fn load(self, input: &Self::Input) -> Result<Self> {
+        input.check_overflow(self.cursors.shape()[0])?;
+        if self.validate {
+            self.redirect.check(input)?;
+        }
+        self.stats.record_input(input);
+        if input.num_token() == 0 {
+            return Ok(self);
+        }
+
+        let stack: Vec<TensorCpu<f16>> = input
+            .iter()
+            .map(|chunk| self.embed.gather(chunk))
+            .try_collect()?;
+        let stack = TensorStack::try_from(stack)?;
+        if self.validate {
+            Cursor::check(&stack.cursors)?;
+        }
+
+        let cursors = stack.cursors.clone().into_cursors();
+        let cursors = TensorCpu::from_data(self.cursors.shape(), cursors)?;
+        self.cursors.load(&cursors)?;
+
+        match self.embed_device {
+            EmbedDevice::Cpu => self.input.load(&stack.tensor)?,
+            EmbedDevice::Gpu => {
+                let tokens = input
+                    .iter()
+                    .map(|chunk| chunk.0.clone())
+                    .concat()
+                    .into_iter()
+                    .map(|token| token as u32)
+                    .collect_vec();
+                let tokens = TensorCpu::from_data(self.tokens.shape(), tokens)?;
+                self.tokens.load(&tokens)?;
+            }
+        }
+
+        Ok(self)
+    }
+
+    fn submit(&mut self) {
+        let commands = std::mem::take(&mut self.commands);
+        self.output.context.submit(commands);
+    }
+
+    async fn back(self) -> Result<Self::Output> {
+        let (output, indices) = match (self.output_sparse, self.output_half) {
+            (Some((indices, values)), _) => (values.back().await, Some(indices.back().await)),
+            (None, Some(output)) => (output.back().await.map(|x| x.to_f32()), None),
+            (None, None) => (self.output.back().await, None),
+        };
+        let mut batches = vec![];
+        let mut sparse = vec![];
+        for (start, end) in self.redirect.outputs {
+            batches.push(InferOutputBatch(output.slice(.., start..end, .., ..)?));
+            if let Some(indices) = &indices {
+                sparse.push(indices.slice(.., start..end, .., ..)?);
+            }
+        }
+        let output = InferOutput(batches, sparse);
+        self.stats.record_output(&output);
+        Ok(output)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Frame<F: Float> {
+    pub state: State,
+    pub buffer: Runtime<F>,
+    pub header: Header<F>,
+}
+
+pub type HookFn<F> = Box<dyn Fn(Frame<F>) -> Result<TensorOp, TensorError> + Send + Sync>;
+pub type HookMap<F> = HashMap<Hook, HookFn<F>>;
+
+#[derive(Clone)]
+pub struct ModelRuntime<F: Float> {
+    model: Model,
+    state: State,
+    hooks: Arc<HookMap<F>>,
+    half_output: bool,
+    sparse_output: Option<usize>,
+    stats: InferStats,
+    validate: bool,
+    phantom: PhantomData<F>,
+}
+
+impl<F: Float> ModelRuntime<F> {
+    /// Create a runtime with its own state of `num_batch` batches.
+    /// Pass in a clone of the model to share weights with other runtimes.
+    pub fn new(model: Model, num_batch: usize) -> Self {
+        let context = model.context.clone();
+        let info = model.info.clone();
+        let state = {
+            let context = context.with_memory_kind(MemoryKind::State);
+            let head_size = info.num_emb / info.num_head;
+            let shape = Shape::new(info.num_emb, head_size + 2, num_batch, 1);
+            let data = (0..info.num_layer).map(|_| context.zeros(shape)).collect();
+            State {
+                context,
+                info,
+                data,
+            }
+        };
+        Self {
+            model,
+            state,
+            hooks: Default::default(),
+            half_output: false,
+            sparse_output: None,
+            stats: InferStats::new(num_batch),
+            validate: false,
+            phantom: PhantomData,
+        }
+    }
+
+    pub fn new_with_hooks(model: Model, num_batch: usize, hooks: HookMap<F>) -> Self {
+        Self {
+            hooks: Arc::new(hooks),
+            ..Self::new(model, num_batch)
+        }
+    }
+
+    /// Read logits back in half precision, halving the transfer size of the output.
+    /// The output is converted back to `f32` on CPU.
+    pub fn half_output(mut self, value: bool) -> Self {
+        self.half_output = value;
+        self
+    }
+
+    /// Only read back the top `K` logits and their token indices of each output token,
+    /// which are selected on GPU. Overrides [`ModelRuntime::half_output`].
+    /// Building jobs fails if `K` is zero.
+    pub fn sparse_output(mut self, value: Option<usize>) -> Self {
+        self.sparse_output = value;
+        self
+    }
+
+    /// Handle to the usage counters of each batch slot of this runtime.
+    pub fn stats(&self) -> InferStats {
+        self.stats.clone()
+    }
+
+    /// Check the invariants of every chunk and its packed cursors when loading jobs,
+    /// failing the job instead of running the kernels on a malformed layout. Meant for debugging.
+    pub fn validate(mut self, value: bool) -> Self {
+        self.validate = value;
+        self
+    }
+}
+
+impl<F: Float> super::model::ModelRuntime for ModelRuntime<F> {
+    #[inline]
+    fn info(&self) -> ModelInfo {
+        self.model.info.clone()
+    }
+
+    #[inline]
+    fn state(&self) -> impl super::model::State + AsAny + 'static {
+        self.state.clone()
+    }
+
+    #[inline]
+    fn model(&self) -> impl Serialize + 'static {
+        self.model.clone()
+    }
+}
+
+fn turbo(num_token: usize) -> bool {
+    num_token % super::infer::MIN_TOKEN_CHUNK_SIZE == 0
+}
+
+fn hook_op<F: Float>(
+    hooks: &HookMap<F>,
+    hook: &Hook,
+    frame: &Frame<F>,
+) -> Result<TensorOp, TensorError> {
+    match hooks.get(hook) {
+        Some(f) => f(frame.clone()),
+        None => Ok(TensorOp::empty()),
+    }
+}
+
+impl<F: Float> JobBuilder<InferJob> for ModelRuntime<F> {
+    type Info = InferInfo;
+
+    fn build(&self, seed: Self::Info) -> Result<InferJob> {
+        let model = &self.model;
+        let state = &self.state;
+        let context = &model.context;
+        let info = &model.info;
+        let tensor = &model.tensor;
+
+        let num_token = seed.num_token();
+        let head_size = info.num_emb / info.num_head;
+
+        let redirect = seed.redirect();
+        let num_header = redirect.headers.len();
+
+        let buffer = Runtime::<F>::new(context, info, model.adapter, num_token);
+        if self.sparse_output == Some(0) {
+            return Err(TensorError::Empty.into());
+        }
+        let header = Header::<F>::new(
+            context,
+            info,
+            tensor.head.w.num_row(),
+            num_header,
+            self.half_output,
+            self.sparse_output,
+        );
+        let frame = Frame {
+            state: state.clone(),
+            buffer: buffer.clone(),
+            header: header.clone(),
+        };
+
+        context.step_caches();
+
+        if num_token == 0 {
+            let embed_device = match &tensor.embed.u {
+                Some(_) => EmbedDevice::Gpu,
+                None => EmbedDevice::Cpu,
+            };
+            return Ok(InferJob {
+                commands: vec![],
+                redirect,
+                embed_device,
+                embed: model.tensor.embed.w.clone(),
+                tokens: buffer.tokens,
+                cursors: buffer.cursors,
+                input: buffer.input,
+                output: header.head_o,
+                output_half: header.head_h,
+                output_sparse: header.head_k,
+                stats: self.stats.clone(),
+                validate: self.validate,
+            });
+        }
+
+        #[cfg(feature = "trace")]
+        let _span = tracing::trace_span!("build").entered();
+
+        let (head_ops, head_x) = if num_token == 1 || num_token == num_header {
+            (vec![], buffer.x.clone())
+        } else {
+            let headers = &redirect.headers;
+            let mut start = 0;
+            let mut end = 1;
+            let mut ops = vec![];
+            while end <= headers.len() {
+                if end == headers.len() || headers[end - 1] + 1 != headers[end] {
+                    let first = headers[start];
+                    let last = headers[end - 1];
+                    assert_eq!(last - first + 1, end - start);
+
+                    let input = buffer.x.view(.., first..=last, .., ..)?;
+                    let output = header.head_x.view(.., start..end, .., ..)?;
+                    ops.push(TensorOp::blit(input, output)?);
+
+                    start = end;
+                }
+                end += 1;
+            }
+            (ops, header.head_x.clone())
+        };
+
+        let hook_op = |hook: Hook| hook_op(&self.hooks, &hook, &frame);
+        let mut ops = vec![];
+
+        let embed_device = {
+            #[cfg(feature = "trace")]
+            let _span = tracing::trace_span!("embed").entered();
+
+            let embed_device = match &tensor.embed.u {
+                Some(u) => {
+                    ops.push(TensorOp::embed(&buffer.tokens, u, &buffer.input)?);
+                    EmbedDevice::Gpu
+                }
+                None => EmbedDevice::Cpu,
+            };
+            ops.append(&mut vec![
+                hook_op(Hook::PostEmbedLoaded)?,
+                TensorOp::layer_norm(
+                    &tensor.embed.layer_norm.w,
+                    &tensor.embed.layer_norm.b,
+                    &buffer.input,
+                    model.ln_eps,
+                )?,
+                TensorOp::blit(
+                    buffer.input.view(.., .., .., ..)?,
+                    buffer.x.view(.., .., .., ..)?,
+                )?,
+                hook_op(Hook::PostEmbedLayerNorm)?,
+            ]);
+            embed_device
+        };
+
+        for (index, layer) in tensor.layers.iter().enumerate() {
+            #[cfg(feature = "trace")]
+            let _span = tracing::trace_span!("layer", index).entered();
+
+            let hooks = self.hooks.clone();
+            let frame = frame.clone();
+            let layer = layer.clone();
+
+            let op = build_layer(hooks, frame, layer, index, num_token, model, head_size)?;
+            ops.push(op);
+
+            if (index + 1) % (info.num_layer / super::infer::NUM_LAYER_CHUNK) == 0 {
+                ops.push(TensorOp::Sep);
+            }
+        }
+
+        {
+            #[cfg(feature = "trace")]
+            let _span = tracing::trace_span!("header").entered();
+
+            let hooks = self.hooks.clone();
+            let frame = frame.clone();
+            let head = model.tensor.head.clone();
+
+            let op = build_header(
+                hooks,
+                frame,
+                head,
+                head_x,
+                num_header,
+                model.ln_eps,
+                head_ops,
+            )?;
+            ops.push(op);
+        }
+
+        let commands = {
+            #[cfg(feature = "trace")]
+            let _span = tracing::trace_span!("encode").entered();
+            context.encode(&TensorOp::List(ops))
+        };
+
+        Ok(InferJob {
+            commands,
+            redirect,
+            embed_device,
+            embed: model.tensor.embed.w.clone(),
+            tokens: buffer.tokens,
+            cursors: buffer.cursors,
+            input: buffer.input,
+            output: header.head_o,
+            output_half: header.head_h,
+            output_sparse: header.head_k,
+            stats: self.stats.clone(),
+            validate: self.validate,
+        })
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn build_layer<F: Float>(
+    hooks: Arc<HookMap<F>>,
+    frame: Frame<F>,
+    layer: Layer,
+    index: usize,
+    num_token: usize,
+    model: &Model,
+    head_size: usize,
+) -> Result<TensorOp> {
+    let hook_op = |hook: Hook| hook_op(&hooks, &hook, &frame);
+    let Frame { state, buffer, .. } = &frame;
+
+    use TensorDimension::{Auto, Dimension};
+    let r_k = layer
+        .att
+        .r_k
+        .reshape(Dimension(head_size), Auto, Dimension(1), Dimension(1))?;
+    let reshape = |x: &TensorGpu<f32, ReadWrite>| {
+        x.reshape(
+            Dimension(head_size),
+            Auto,
+            Dimension(num_token),
+            Dimension(1),
+        )
+    };
+    let aux_x = reshape(&buffer.aux_x)?;
+    let att_r = reshape(&buffer.att_r)?;
+    let att_w = reshape(&buffer.att_w)?;
+    let att_k = reshape(&buffer.att_k)?;
+    let att_v = reshape(&buffer.att_v)?;
+    let att_a = reshape(&buffer.att_a)?;
+    let att_kk = reshape(&buffer.att_kk)?;
+
+    let mut ops = vec![];
+
+    ops.append(&mut vec![
+        TensorOp::blit(
+            buffer.x.view(.., .., .., ..)?,
+            buffer.att_x.view(.., .., .., ..)?,
+        )?,
+        hook_op(Hook::PreAtt(index))?,
+        TensorOp::layer_norm(
+            &layer.att_layer_norm.w,
+            &layer.att_layer_norm.b,
+            &buffer.att_x,
+            model.ln_eps,
+        )?,
+        hook_op(Hook::PostAttLayerNorm(index))?,
+        hook_op(Hook::PreAttTokenShift(index))?,
+        TensorOp::token_shift(
+            &buffer.cursors,
+            layer.att.x_r.view(.., .., .., ..)?,
+            state.att(index)?,
+            &buffer.att_x,
+            &buffer.att_rx,
+            true,
+        )?,
+        TensorOp::token_shift(
+            &buffer.cursors,
+            layer.att.x_w.view(.., .., .., ..)?,
+            state.att(index)?,
+            &buffer.att_x,
+            &buffer.att_wx,
+            true,
+        )?,
+        TensorOp::token_shift(
+            &buffer.cursors,
+            layer.att.x_k.view(.., .., .., ..)?,
+            state.att(index)?,
+            &buffer.att_x,
+            &buffer.att_kx,
+            true,
+        )?,
+        TensorOp::token_shift(
+            &buffer.cursors,
+            layer.att.x_v.view(.., .., .., ..)?,
+            state.att(index)?,
+            &buffer.att_x,
+            &buffer.att_vx,
+            true,
+        )?,
+        TensorOp::token_shift(
+            &buffer.cursors,
+            layer.att.x_a.view(.., .., .., ..)?,
+            state.att(index)?,
+            &buffer.att_x,
+            &buffer.att_ax,
+            true,
+        )?,
+        TensorOp::token_shift(
+            &buffer.cursors,
+            layer.att.x_g.view(.., .., .., ..)?,
+            state.att(index)?,
+            &buffer.att_x,
+            &buffer.att_gx,
+            true,
+        )?,
+        hook_op(Hook::PostAttTokenShift(index))?,
+        hook_op(Hook::PreAttLinear(index))?,
+        layer.att.w_r.matmul_op(
+            buffer.att_rx.view(.., .., .., ..)?,
+            buffer.att_r.view(.., .., .., ..)?,
+            Activation::None,
+            turbo(num_token),
+        )?,
+        layer.att.w_k.matmul_op(
+            buffer.att_kx.view(.., .., .., ..)?,
+            buffer.att_k.view(.., .., .., ..)?,
+            Activation::None,
+            turbo(num_token),
+        )?,
+        layer.att.w_v.matmul_op(
+            buffer.att_vx.view(.., .., .., ..)?,
+            buffer.att_v.view(.., .., .., ..)?,
+            Activation::None,
+            turbo(num_token),
+        )?,
+        hook_op(Hook::PostAttLinear(index))?,
+        hook_op(Hook::PreAttTimeDecayAdapt(index))?,
+        layer.att.w1.matmul_op(
+            buffer.att_wx.view(.., .., .., ..)?,
+            buffer.adapt_w.view(.., .., .., ..)?,
+            Activation::Tanh,
+            turbo(num_token),
+        )?,
+        hook_op(Hook::PostAttTimeDecayAdaptActivate(index))?,
+        layer.att.w2.matmul_op(
+            buffer.adapt_w.view(.., .., .., ..)?,
+            buffer.att_w.view(.., .., .., ..)?,
+            Activation::None,
+            turbo(num_token),
+        )?,
+        TensorOp::add(
+            layer.att.w0.view(.., .., .., ..)?,
+            buffer.att_w.view(.., .., .., ..)?,
+        )?,
+        hook_op(Hook::PostAttTimeDecayAdapt(index))?,
+        hook_op(Hook::PreAttControl(index))?,
+        layer.att.a1.matmul_op(
+            buffer.att_ax.view(.., .., .., ..)?,
+            buffer.adapt_a.view(.., .., .., ..)?,
+            Activation::None,
+            turbo(num_token),
+        )?,
+        layer.att.a2.matmul_op(
+            buffer.adapt_a.view(.., .., .., ..)?,
+            buffer.att_a.view(.., .., .., ..)?,
+            Activation::None,
+            turbo(num_token),
+        )?,
+        TensorOp::add(
+            layer.att.a0.view(.., .., .., ..)?,
+            buffer.att_a.view(.., .., .., ..)?,
+        )?,
+        TensorOp::sigmoid(&buffer.att_a)?,
+        TensorOp::blit(
+            buffer.att_k.view(.., .., .., ..)?,
+            buffer.att_kk.view(.., .., .., ..)?,
+        )?,
+        TensorOp::mul(
+            layer.att.k_k.view(.., .., .., ..)?,
+            buffer.att_kk.view(.., .., .., ..)?,
+        )?,
+        TensorOp::l2_norm(&att_kk, Model::L2_EPS)?,
+        TensorOp::control_k_v7(&layer.att.k_a, &buffer.att_a, &buffer.att_k)?,
+        hook_op(Hook::PostAttControl(index))?,
+        hook_op(Hook::PreAttValueResidual(index))?,
+    ]);
+
+    match (&layer.att.v0, &layer.att.v1, &layer.att.v2) {
+        (Some(v0), Some(v1), Some(v2)) if index > 0 => ops.append(&mut vec![
+            v1.matmul_op(
+                buffer.att_vx.view(.., .., .., ..)?,
+                buffer.adapt_v.view(.., .., .., ..)?,
+                Activation::None,
+                turbo(num_token),
+            )?,
+            v2.matmul_op(
+                buffer.adapt_v.view(.., .., .., ..)?,
+                buffer.att_vg.view(.., .., .., ..)?,
+                Activation::None,
+                turbo(num_token),
+            )?,
+            TensorOp::add(
+                v0.view(.., .., .., ..)?,
+                buffer.att_vg.view(.., .., .., ..)?,
+            )?,
+            TensorOp::sigmoid(&buffer.att_vg)?,
+            TensorOp::lerp(
+                buffer.att_v0.view(.., .., .., ..)?,
+                buffer.att_v.view(.., .., .., ..)?,
+                buffer.att_vg.view(.., .., .., ..)?,
+            )?,
+        ]),
+        _ => ops.push(TensorOp::blit(
+            buffer.att_v.view(.., .., .., ..)?,
+            buffer.att_v0.view(.., .., .., ..)?,
+        )?),
+    }
+
+    ops.append(&mut vec![
+        hook_op(Hook::PostAttValueResidual(index))?,
+        hook_op(Hook::PreAttGateAdapt(index))?,
+        layer.att.g1.matmul_op(
+            buffer.att_gx.view(.., .., .., ..)?,
+            buffer.adapt_g.view(.., .., .., ..)?,
+            Activation::None,
+            turbo(num_token),
+        )?,
+        TensorOp::sigmoid(&buffer.adapt_g)?,
+        layer.att.g2.matmul_op(
+            buffer.adapt_g.view(.., .., .., ..)?,
+            buffer.att_g.view(.., .., .., ..)?,
+            Activation::None,
+            turbo(num_token),
+        )?,
+        hook_op(Hook::PostAttGateAdapt(index))?,
+        hook_op(Hook::PreAttTimeMix(index))?,
+        TensorOp::blit(
+            buffer.att_r.view(.., .., .., ..)?,
+            buffer.aux_x.view(.., .., .., ..)?,
+        )?,
+        TensorOp::time_mix_v7(
+            &buffer.cursors,
+            state.att(index)?,
+            &att_w,
+            &att_k,
+            &att_v,
+            &att_a,
+            &att_kk,
+            &aux_x,
+        )?,
+        TensorOp::group_norm(
+            &layer.att.group_norm.w,
+            &layer.att.group_norm.b,
+            &aux_x,
+            model.gn_eps,
+        )?,
+        TensorOp::time_first_v7(&r_k, &att_r, &att_k, &att_v, &aux_x)?,
+        TensorOp::blit(
+            buffer.aux_x.view(.., .., .., ..)?,
+            buffer.att_o.view(.., .., .., ..)?,
+        )?,
+        TensorOp::channel_mix_v7(
+            &buffer.cursors,
+            state.att(index)?,
+            &buffer.att_o,
+            &buffer.att_x,
+        )?,
+        hook_op(Hook::PostAttTimeMix(index))?,
+        hook_op(Hook::PreAttGate(index))?,
+        TensorOp::mul(
+            buffer.att_g.view(.., .., .., ..)?,
+            buffer.att_x.view(.., .., .., ..)?,
+        )?,
+        hook_op(Hook::PostAttGate(index))?,
+        hook_op(Hook::PreAttOut(index))?,
+        layer.att.w_o.matmul_op(
+            buffer.att_x.view(.., .., .., ..)?,
+            buffer.att_o.view(.., .., .., ..)?,
+            Activation::None,
+            turbo(num_token),
+        )?,
+        hook_op(Hook::PostAttOut(index))?,
+        TensorOp::add(
+            buffer.att_o.view(.., .., .., ..)?,
+            buffer.x.view(.., .., .., ..)?,
+        )?,
+        hook_op(Hook::PostAtt(index))?,
+    ]);
+
+    ops.append(&mut vec![
+        TensorOp::blit(
+            buffer.x.view(.., .., .., ..)?,
+            buffer.ffn_x.view(.., .., .., ..)?,
+        )?,
+        hook_op(Hook::PreFfn(index))?,
+        TensorOp::layer_norm(
+            &layer.ffn_layer_norm.w,
+            &layer.ffn_layer_norm.b,
+            &buffer.ffn_x,
+            model.ln_eps,
+        )?,
+        hook_op(Hook::PostFfnLayerNorm(index))?,
+        hook_op(Hook::PreFfnTokenShift(index))?,
+        TensorOp::token_shift(
+            &buffer.cursors,
+            layer.ffn.x_k.view(.., .., .., ..)?,
+            state.ffn(index)?,
+            &buffer.ffn_x,
+            &buffer.ffn_kx,
+            true,
+        )?,
+        hook_op(Hook::PostFfnTokenShift(index))?,
+        hook_op(Hook::PreFfnLinear(index))?,
+        layer.ffn.w_k.matmul_op(
+            buffer.ffn_kx.view(.., .., .., ..)?,
+            buffer.ffn_k.view(.., .., .., ..)?,
+            Activation::SquaredRelu,
+            turbo(num_token),
+        )?,
+        hook_op(Hook::PostFfnActivate(index))?,
+        layer.ffn.w_v.matmul_op(
+            buffer.ffn_k.view(.., .., .., ..)?,
+            buffer.ffn_v.view(.., .., .., ..)?,
+            Activation::None,
+            turbo(num_token),
+        )?,
+        hook_op(Hook::PostFfnLinear(index))?,
+        hook_op(Hook::PreFfnChannelMix(index))?,
+        TensorOp::channel_mix_v7(
+            &buffer.cursors,
+            state.ffn(index)?,
+            &buffer.ffn_v,
+            &buffer.ffn_x,
+        )?,
+        hook_op(Hook::PostFfnChannelMix(index))?,
+        TensorOp::add(
+            buffer.ffn_x.view(.., .., .., ..)?,
+            buffer.x.view(.., .., .., ..)?,
+        )?,
+        hook_op(Hook::PostFfn(index))?,
+    ]);
+
+    match model.layer_scale.get(index) {
+        Some(&scale) if scale != 1.0 => ops.push(TensorOp::discount(&buffer.x, scale, 0.0)?),
+        _ => {}
+    }
+
+    Ok(TensorOp::List(ops))
+}
+
+fn build_header<F: Float>(
+    hooks: Arc<HookMap<F>>,
+    frame: Frame<F>,
+    head: Head,
+    head_x: TensorGpu<F, ReadWrite>,
+    num_header: usize,
+    ln_eps: f32,
+    mut ops: Vec<TensorOp>,
+) -> Result<TensorOp> {
+    let hook_op = |hook: Hook| hook_op(&hooks, &hook, &frame);
+    let header = &frame.header;
+
+    if num_header > 0 {
+        ops.append(&mut vec![
+            hook_op(Hook::PreHead)?,
+            TensorOp::layer_norm(&head.layer_norm.w, &head.layer_norm.b, &head_x, ln_eps)?,
+            hook_op(Hook::PostHeadLayerNorm)?,
+            head.w.matmul_op(
+                head_x.view(.., .., .., ..)?,
+                header.head_o.view(.., .., .., ..)?,
+                Activation::None,
+                turbo(num_header),
+            )?,
+        ]);
+        if let Some(b) = &head.b {
+            ops.push(TensorOp::add(
+                b.view(.., .., .., ..)?,
+                header.head_o.view(.., .., .., ..)?,
+            )?);
+        }
+        ops.push(hook_op(Hook::PostHead)?);
+        if let Some(mask) = &head.mask {
+            ops.push(TensorOp::masked_fill(
+                mask,
+                &header.head_o,
+                f32::NEG_INFINITY,
+            )?);
+        }
+        if let Some(head_h) = &header.head_h {
+            ops.push(TensorOp::blit(
+                header.head_o.view(.., .., .., ..)?,
+                head_h.view(.., .., .., ..)?,
+            )?);
+        }
+        if let Some((indices, values)) = &header.head_k {
+            ops.push(TensorOp::top_k(&header.head_o, indices, values)?);
+        }
+    }
+    Ok(TensorOp::List(ops))
+}
+
+impl<R: Reader> Build<Model> for ModelBuilder<R> {
+    /// Build the model. Running out of GPU memory while loading fails with
+    /// [`ContextError::OutOfMemory`](crate::context::ContextError::OutOfMemory),
+    /// unless the [`oom_policy`](ModelBuilder::oom_policy) finds a way out.
+    /// The context must not be used by anything else until the build finishes.
+    async fn build(self) -> Result<Model> {
+        build_model(self).await
+    }
+}
+
+async fn build_model<R: Reader>(builder: ModelBuilder<R>) -> Result<Model> {
+    let ModelBuilder {
+        context,
+        model,
+        lora,
+        quant,
+        embed_device,
+        rescale,
+        layer_scale,
+        ln_eps,
+        gn_eps,
+        real_vocab,
+        head_size,
+        strict,
+        tied_head,
+        verify,
+        metadata,
+        oom_policy,
+    } = builder;
+
+    let rescale = rescale
+        .or_else(|| parse_rescale_layer(&metadata))
+        .unwrap_or(Model::RESCALE_LAYER);
+    let layer_scale = layer_scale.or_else(|| parse_layer_scale(&metadata));
+    let ln_eps = ln_eps.unwrap_or(Model::LN_EPS);
+    let gn_eps = gn_eps.unwrap_or(Model::GN_EPS);
+
+    let mut info = Loader::info(&model)?;
+    let layer_scale = super::model::resolve_layer_scale(info.num_layer, rescale, layer_scale)?;
+    let discounts = super::model::layer_discounts(&layer_scale);
+    if let Some(head_size) = head_size {
+        info.set_head_size(head_size)?;
+    }
+    if strict {
+        Loader::check(&model, &info, tied_head)?;
+    }
+    if verify {
+        Loader::verify(&model, &parse_tensor_hashes(&metadata)).await?;
+    }
+    let loader = Loader {
+        context: context.with_memory_kind(MemoryKind::Weights),
+        model,
+        lora,
+    };
+
+    // allocations are caught in small scopes, since other tasks may run at the yields in between
+    let mut embed_device = embed_device;
+    let (embed, head) = loop {
+        let load = async {
+            let embed = Embed {
+                layer_norm: LayerNorm {
+                    w: loader.load_vector_f16("blocks.0.ln0.weight").await?,
+                    b: loader.load_vector_f16("blocks.0.ln0.bias").await?,
+                },
+                w: loader.load_embed().await?,
+                u: match embed_device {
+                    EmbedDevice::Cpu => None,
+                    EmbedDevice::Gpu => Some(loader.load_matrix_f16("emb.weight").await?),
+                },
+            };
+
+            // a head tied to the embedding shares its buffer, which has the same layout `[C, V]`
+            let head_w = match (tied_head, &embed.u) {
+                (false, _) => loader.load_matrix_f16("head.weight").await?,
+                (true, Some(u)) => u.clone(),
+                (true, None) => loader.load_matrix_f16("emb.weight").await?,
+            };
+            let head = Head {
+                layer_norm: LayerNorm {
+                    w: loader.load_vector_f16("ln_out.weight").await?,
+                    b: loader.load_vector_f16("ln_out.bias").await?,
+                },
+                w: Matrix::Fp16(head_w),
+                b: match loader.model.contains("head.bias") {
+                    true => Some(loader.load_vector_f32("head.bias").await?),
+                    false => None,
+                },
+                mask: None,
+            };
+
+            let head = match real_vocab {
+                Some(num_vocab) if num_vocab > head.w.num_row() => {
+                    return Err(ModelError::InvalidVocab.into());
+                }
+                Some(num_vocab) if num_vocab < head.w.num_row() => {
+                    let flags = (0..head.w.num_row())
+                        .map(|index| index < num_vocab)
+                        .collect_vec();
+                    let mask = TensorMask::new(&loader.context, [flags.len(), 1, 1, 1], &flags)?;
+                    info.num_vocab = num_vocab;
+                    Head {
+                        mask: Some(mask),
+                        ..head
+                    }
+                }
+                Some(num_vocab) => {
+                    info.num_vocab = num_vocab;
+                    head
+                }
+                None => head,
+            };
+
+            anyhow::Ok((embed, head))
+        };
+        let err = match context.catch_oom(load).await {
+            Ok(output) => break output?,
+            Err(err) => err,
+        };
+        match oom_policy
+            .as_ref()
+            .map_or(OomAction::Fail, |f| f(OomStage::Embed))
+        {
+            OomAction::EmbedCpu if embed_device == EmbedDevice::Gpu => {
+                embed_device = EmbedDevice::Cpu
+            }
+            _ => return Err(err.into()),
+        }
+    };
+
+    context.queue.submit(None);
+    context.device.poll(wgpu::MaintainBase::Wait);
+
+    let load_matrix = |name: String, quant: Quant| loader.load_matrix(name, quant);
+    let load_matrix_discount = |name: String, quant: Quant, discount: f32| {
+        loader.load_matrix_discount(name, quant, discount)
+    };
+
+    // the first layer has no value residual, so its adapter is looked up in the next layer
+    let adapter = {
+        let rank = |name: &str| loader.model.shape(name).map(|shape| shape[0]);
+        AdapterSize {
+            w: rank("blocks.0.att.w1")?,
+            a: rank("blocks.0.att.a1")?,
+            v: match info.num_layer {
+                0 | 1 => 0,
+                _ => rank("blocks.1.att.v1")?,
+            },
+            g: rank("blocks.0.att.g1")?,
+        }
+    };
+
+    let mut layers = vec![];
+    let mut fallback = None;
+    for (layer, discount) in (0..info.num_layer).zip(discounts) {
+        let mut quant = fallback.unwrap_or(quant.get(&layer).copied().unwrap_or_default());
+        let block = loop {
+            let load = async {
+                let att_layer_norm = LayerNorm {
+                    w: loader
+                        .load_vector_f16(format!("blocks.{layer}.ln1.weight"))
+                        .await?,
+                    b: loader
+                        .load_vector_f16(format!("blocks.{layer}.ln1.bias"))
+                        .await?,
+                };
+
+                let att = format!("blocks.{layer}.att");
+                let loader = &loader;
+                let load_adapter = |name: String| async move {
+                    let matrix = loader.load_matrix_f16(name).await?;
+                    Ok::<_, anyhow::Error>(Matrix::Fp16(matrix))
+                };
+                let (v0, v1, v2) = match loader.model.contains(&format!("{att}.v0")) {
+                    true => (
+                        Some(loader.load_vector_f16(format!("{att}.v0")).await?),
+                        Some(load_adapter(format!("{att}.v1")).await?),
+                        Some(load_adapter(format!("{att}.v2")).await?),
+                    ),
+                    false => (None, None, None),
+                };
+
+                let group_norm = LayerNorm {
+                    w: loader
+                        .load_vector_f16(format!("{att}.ln_x.weight"))
+                        .await?
+                        .reshape(
+                            TensorDimension::Auto,
+                            TensorDimension::Dimension(info.num_head),
+                            TensorDimension::Dimension(1),
+                            TensorDimension::Dimension(1),
+                        )?,
+                    b: loader
+                        .load_vector_f16(format!("{att}.ln_x.bias"))
+                        .await?
+                        .reshape(
+                            TensorDimension::Auto,
+                            TensorDimension::Dimension(info.num_head),
+                            TensorDimension::Dimension(1),
+                            TensorDimension::Dimension(1),
+                        )?,
+                };
+
+                let att = Att {
+                    x_r: loader.load_vector_f16(format!("{att}.x_r")).await?,
+                    x_w: loader.load_vector_f16(format!("{att}.x_w")).await?,
+                    x_k: loader.load_vector_f16(format!("{att}.x_k")).await?,
+                    x_v: loader.load_vector_f16(format!("{att}.x_v")).await?,
+                    x_a: loader.load_vector_f16(format!("{att}.x_a")).await?,
+                    x_g: loader.load_vector_f16(format!("{att}.x_g")).await?,
+                    w0: loader.load_vector_f16(format!("{att}.w0")).await?,
+                    w1: load_adapter(format!("{att}.w1")).await?,
+                    w2: load_adapter(format!("{att}.w2")).await?,
+                    a0: loader.load_vector_f16(format!("{att}.a0")).await?,
+                    a1: load_adapter(format!("{att}.a1")).await?,
+                    a2: load_adapter(format!("{att}.a2")).await?,
+                    v0,
+                    v1,
+                    v2,
+                    g1: load_adapter(format!("{att}.g1")).await?,
+                    g2: load_adapter(format!("{att}.g2")).await?,
+                    k_k: loader.load_vector_f16(format!("{att}.k_k")).await?,
+                    k_a: loader.load_vector_f16(format!("{att}.k_a")).await?,
+                    r_k: loader.load_vector_f16(format!("{att}.r_k")).await?,
+                    w_k: load_matrix(format!("{att}.key.weight"), quant).await?,
+                    w_v: load_matrix(format!("{att}.value.weight"), quant).await?,
+                    w_r: load_matrix(format!("{att}.receptance.weight"), quant).await?,
+                    w_o: load_matrix_discount(format!("{att}.output.weight"), quant, discount)
+                        .await?,
+                    group_norm,
+                };
+
+                let ffn_layer_norm = LayerNorm {
+                    w: loader
+                        .load_vector_f16(format!("blocks.{layer}.ln2.weight"))
+                        .await?,
+                    b: loader
+                        .load_vector_f16(format!("blocks.{layer}.ln2.bias"))
+                        .await?,
+                };
+
+                let ffn = format!("blocks.{layer}.ffn");
+                let ffn = Ffn {
+                    x_k: loader.load_vector_f16(format!("{ffn}.x_k")).await?,
+                    w_k: load_matrix(format!("{ffn}.key.weight"), quant).await?,
+                    w_v: load_matrix_discount(format!("{ffn}.value.weight"), quant, discount)
+                        .await?,
+                };
+
+                anyhow::Ok(Layer {
+                    att_layer_norm,
+                    ffn_layer_norm,
+                    att,
+                    ffn,
+                })
+            };
+            let err = match context.catch_oom(load).await {
+                Ok(output) => break output?,
+                Err(err) => err,
+            };
+            match oom_policy
+                .as_ref()
+                .map_or(OomAction::Fail, |f| f(OomStage::Layer(layer)))
+            {
+                OomAction::Quant(value) if value != quant => {
+                    quant = value;
+                    fallback = Some(value);
+                }
+                _ => return Err(err.into()),
+            }
+        };
+
+        context.queue.submit(None);
+        context.device.poll(wgpu::MaintainBase::Wait);
+
+        // give other tasks a chance to run, since single-threaded executors (e.g., on web)
+        // would otherwise be blocked until the whole model is loaded
+        tokio::task::yield_now().await;
+
+        layers.push(block);
+    }
+
+    context.queue.submit(None);
+    context.device.poll(wgpu::MaintainBase::Wait);
+
+    let tensor = ModelTensor {
+        embed,
+        head,
+        layers,
+    };
+    let model = {
+        let context = context.clone();
+        let info = info.clone();
+        Model {
+            context,
+            info,
+            rescale,
+            layer_scale,
+            ln_eps,
+            gn_eps,
+            adapter,
+            tensor,
+        }
+    };
+    Ok(model)
+}
+
+/// Read the pre-trained state from the file.
+pub async fn read_state<R: Reader>(
+    context: &Context,
+    info: &ModelInfo,
+    model: R,
+) -> Result<TensorCpu<f32>> {
+    use crate::tensor::TensorInitContext;
+    use TensorDimension::{Auto, Dimension};
+
+    let loader = Loader {
+        context: context.clone(),
+        model,
+        lora: vec![],
+    };
+
+    let head_size = info.num_emb / info.num_head;
+    let data: TensorGpu<f32, _> = context.zeros([info.num_emb, head_size + 2, info.num_layer, 1]);
+
+    let mut ops = vec![];
+    for layer in 0..info.num_layer {
+        let matrix = loader
+            .load_matrix_f16(format!("blocks.{layer}.att.time_state"))
+            .await?;
+        let state = TensorGpu::init(context, [head_size, info.num_head, head_size, 1]);
+        let reshaped: TensorGpu<f16, _> = state.reshape(
+            Dimension(info.num_emb),
+            Dimension(head_size),
+            Dimension(1),
+            Auto,
+        )?;
+        ops.append(&mut vec![
+            TensorOp::transpose(matrix.view(.., .., .., ..)?, state.view(.., .., .., ..)?)?,
+            TensorOp::blit(
+                reshaped.view(.., .., .., ..)?,
+                data.view(.., 1..head_size + 1, layer, ..)?,
+            )?,
+        ]);
+    }
+    context.submit(context.encode(&TensorOp::List(ops)));
+
+    Ok(data.back().await)
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use itertools::Itertools;
+    use wgpu::{Instance, PowerPreference};
+
+    use super::State;
+    use crate::{
+        context::{Context, ContextBuilder, InstanceExt},
+        runtime::model::{ModelInfo, ModelVersion, State as _},
+        tensor::{TensorCpu, TensorInit, TensorShape},
+    };
+
+    async fn create_context() -> Result<Context> {
+        let instance = Instance::default();
+        let adapter = instance.adapter(PowerPreference::HighPerformance).await?;
+        let context = ContextBuilder::new(adapter).build().await?;
+        Ok(context)
+    }
+
+    const C: usize = 8;
+    const H: usize = 2;
+    const S: usize = C / H;
+    const L: usize = 2;
+
+    fn create_state(context: Context) -> State {
+        let info = ModelInfo {
+            version: ModelVersion::V7,
+            num_layer: L,
+            num_emb: C,
+            num_hidden: C,
+            num_vocab: 1,
+            num_head: H,
+            time_mix_adapter_size: 0,
+            time_decay_adapter_size: 0,
+        };
+        State {
+            context,
+            info,
+            data: vec![],
+        }
+    }
+
+    #[test]
+    fn test_back_many() -> Result<()> {
+        let context = match pollster::block_on(create_context()) {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+        const B: usize = 3;
+        let state = State {
+            data: (0..L)
+                .map(|_| context.tensor_init([C, S + 2, B, 1]))
+                .collect(),
+            ..create_state(context.clone())
+        };
+
+        let shape = state.init().shape();
+        let backed: Vec<TensorCpu<f32>> = (0..B)
+            .map(|batch| {
+                let data = (0..shape.len()).map(|x| (batch * shape.len() + x) as f32);
+                TensorCpu::from_data(shape, data.collect_vec())
+            })
+            .try_collect()?;
+        for (batch, backed) in backed.iter().enumerate() {
+            state.load(backed.clone(), batch)?;
+        }
+
+        let many = pollster::block_on(state.back_many(&[2, 0]))?;
+        assert_eq!(many.len(), 2);
+        assert_eq!(many[0].to_vec(), backed[2].to_vec());
+        assert_eq!(many[1].to_vec(), backed[0].to_vec());
+        assert_eq!(
+            many[0].to_vec(),
+            pollster::block_on(state.back(2))?.to_vec()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_head_state() -> Result<()> {
+        let context = match pollster::block_on(create_context()) {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+        let state = create_state(context);
+
+        let shape = state.init().shape();
+        let backed = TensorCpu::from_data(shape, (0..shape.len()).map(|x| x as f32).collect_vec())?;
+
+        let heads = state.head_state(&backed, 1)?;
+        heads.check_shape([S, S, H, 1])?;
+        for (head, row, col) in itertools::iproduct!(0..H, 0..S, 0..S) {
+            assert_eq!(
+                heads[(col, row, head, 0)],
+                backed[(head * S + col, row + 1, 1, 0)]
+            );
+        }
+
+        let scaled = state.scale_head(backed.clone(), 1, 1, 2.0)?;
+        for (index, (&a, &b)) in itertools::zip_eq(scaled.iter(), backed.iter()).enumerate() {
+            let [col, row, layer] = [index % C, index / C % (S + 2), index / C / (S + 2)];
+            let inside = layer == 1 && col >= S && (1..=S).contains(&row);
+            assert_eq!(
+                a,
+                if inside { 2.0 * b } else { b },
+                "failed at index {index}"
+            );
+        }
+
+        let zeroed = state.zero_head(backed.clone(), 0, 0)?;
+        let heads = state.head_state(&zeroed, 0)?;
+        assert!(heads.iter().take(S * S).all(|&x| x == 0.0));
+        assert!(heads.iter().skip(S * S).all(|&x| x != 0.0));
+
+        let heads = state.head_state(&backed, 1)?;
+        let moved = state.set_head_state(backed.clone(), 0, &heads)?;
+        assert_eq!(
+            state.head_state(&moved, 0)?.to_vec(),
+            state.head_state(&backed, 1)?.to_vec()
+        );
+
+        // invalid layers, heads and shapes are errors rather than panics
+        assert!(state.scale_head(backed.clone(), L, 0, 2.0).is_err());
+        assert!(state.scale_head(backed.clone(), 0, H, 2.0).is_err());
+        let wrong = TensorCpu::from_data([C, S + 2, 1, 1], vec![0.0; C * (S + 2)])?;
+        assert!(state.scale_head(wrong.clone(), 0, 0, 2.0).is_err());
+        assert!(state.head_state(&wrong, 0).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reference_layout() -> Result<()> {
+        let context = match pollster::block_on(create_context()) {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+        let state = create_state(context);
+
+        let shape = state.init().shape();
+        let backed = TensorCpu::from_data(shape, (0..shape.len()).map(|x| x as f32).collect_vec())?;
+
+        let tensors = state.to_reference_layout(&backed)?;
+        assert_eq!(tensors.len(), 3 * L);
+        for (layer, tensors) in tensors.chunks(3).enumerate() {
+            let [att_x, att_kv, ffn_x] = tensors else {
+                unreachable!()
+            };
+            att_x.check_shape([C, 1, 1, 1])?;
+            att_kv.check_shape([S, S, H, 1])?;
+            ffn_x.check_shape([C, 1, 1, 1])?;
+            for index in 0..C {
+                assert_eq!(att_x[(index, 0, 0, 0)], backed[(index, 0, layer, 0)]);
+                assert_eq!(ffn_x[(index, 0, 0, 0)], backed[(index, S + 1, layer, 0)]);
+            }
+            // `(H, S, S)` in row-major order, indexed by head, value and key
+            for (head, value, key) in itertools::iproduct!(0..H, 0..S, 0..S) {
+                assert_eq!(
+                    att_kv.data()[(head * S + value) * S + key],
+                    backed[(head * S + value, key + 1, layer, 0)]
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -49,6 +49,23 @@ fn act_tanh(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
     }
 }
 
+@compute @workgroup_size(BLOCK_SIZE, 1, 1)
+fn act_sigmoid(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let stride = shape[0] / 4u;
+    let index = invocation_id.x;
+    let token = invocation_id.y;
+    let batch = invocation_id.z;
+
+    if index < stride {
+        let bti = (batch * shape[1] + token) * stride + index;
+#ifdef FP16
+        x[bti] = pack4x16float(1.0 / (1.0 + exp(-unpack4x16float(x[bti]))));
+#else
+        x[bti] = 1.0 / (1.0 + exp(-x[bti]));
+#endif
+    }
+}
+
 @compute @workgroup_size(BLOCK_SIZE, 1, 1)
 fn stable_exp(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
     let stride = shape[0] / 4u;
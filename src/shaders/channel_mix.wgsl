@@ -75,4 +75,25 @@ fn channel_mix(@builtin(global_invocation_id) invocation_id: vec3<u32>, @builtin
     let vv = v[bti];
     x[bti] = rr * vv;
 #endif
-}
\ No newline at end of file
+}
+
+@compute @workgroup_size(BLOCK_SIZE, 1, 1)
+fn channel_mix_v7(@builtin(global_invocation_id) invocation_id: vec3<u32>, @builtin(num_workgroups) num_blocks: vec3<u32>) {
+    let stride = shape[0] / 4u;
+    let index = invocation_id.x;
+    let stack = invocation_id.y;
+    let cursor = compute_cursor(cursors[stack]);
+    let token = stack - cursor.token;
+
+    let bti = stack * stride + index;
+
+    if token + 1u == cursor.len {
+#ifdef FP16
+        state[compute_index(cursor.batch, 0u, index)] = unpack4x16float(x[bti]);
+#else
+        state[compute_index(cursor.batch, 0u, index)] = x[bti];
+#endif
+    }
+
+    x[bti] = v[bti];
+}
@@ -0,0 +1,39 @@
+@group(0) @binding(0) var<uniform> shape: vec4<u32>;                        // [C, A, 1]
+
+@group(0) @binding(1) var<storage, read> p: array<vec2<u32>>;               // (C)
+#ifdef FP16
+@group(0) @binding(2) var<storage, read> a: array<vec2<u32>>;               // (A, C)
+@group(0) @binding(3) var<storage, read_write> k: array<vec2<u32>>;         // (A, C)
+#else
+@group(0) @binding(2) var<storage, read> a: array<vec4<f32>>;               // (A, C)
+@group(0) @binding(3) var<storage, read_write> k: array<vec4<f32>>;         // (A, C)
+#endif
+
+fn pack4x16float(x: vec4<f32>) -> vec2<u32> {
+    return vec2<u32>(pack2x16float(x.xy), pack2x16float(x.zw));
+}
+
+fn unpack4x16float(x: vec2<u32>) -> vec4<f32> {
+    return vec4<f32>(unpack2x16float(x.x), unpack2x16float(x.y));
+}
+
+@compute @workgroup_size(BLOCK_SIZE, 1, 1)
+fn control_k(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let stride = shape[0] / 4u;
+    let index = invocation_id.x;
+    let token = invocation_id.y;
+
+    if index < stride {
+        let bti = token * stride + index;
+        let pp = unpack4x16float(p[index]);
+#ifdef FP16
+        let aa = unpack4x16float(a[bti]);
+        let kk = unpack4x16float(k[bti]);
+        k[bti] = pack4x16float(kk * fma(aa - 1.0, pp, vec4<f32>(1.0)));
+#else
+        let aa = a[bti];
+        let kk = k[bti];
+        k[bti] = kk * fma(aa - 1.0, pp, vec4<f32>(1.0));
+#endif
+    }
+}
@@ -0,0 +1,67 @@
+@group(0) @binding(0) var<uniform> shape: vec4<u32>;                        // [S, H, A]
+#ifdef FP16
+@group(0) @binding(1) var<storage, read_write> x: array<vec2<u32>>;         // (A, H, S)
+#else
+@group(0) @binding(1) var<storage, read_write> x: array<vec4<f32>>;         // (A, H, S)
+#endif
+
+var<workgroup> sketch: array<vec4<f32>, BLOCK_SIZE>;
+var<workgroup> norm: f32;
+
+fn pack4x16float(x: vec4<f32>) -> vec2<u32> {
+    return vec2<u32>(pack2x16float(x.xy), pack2x16float(x.zw));
+}
+
+fn unpack4x16float(x: vec2<u32>) -> vec4<f32> {
+    return vec4<f32>(unpack2x16float(x.x), unpack2x16float(x.y));
+}
+
+fn reduce_sum(index: u32, stride: u32) {
+    if index < stride {
+        sketch[index] += sketch[index + stride];
+    }
+    workgroupBarrier();
+}
+
+@compute @workgroup_size(BLOCK_SIZE, 1, 1)
+fn l2_norm(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let stride = shape[0] / 4u;
+    let index = invocation_id.x;
+    let head = invocation_id.y;
+    let token = invocation_id.z;
+
+    let th = (token * shape[1] + head) * stride;
+
+    var _sum_4: vec4<f32>;
+    for (var i = index; i < stride; i += BLOCK_SIZE) {
+#ifdef FP16
+        let value = unpack4x16float(x[th + i]);
+#else
+        let value = x[th + i];
+#endif
+        _sum_4 += value * value;
+    }
+    sketch[index] = _sum_4;
+    workgroupBarrier();
+
+    reduce_sum(index, 16u);
+    reduce_sum(index, 8u);
+    reduce_sum(index, 4u);
+    reduce_sum(index, 2u);
+    reduce_sum(index, 1u);
+
+    if index == 0u {
+        norm = 1.0 / max(sqrt(dot(sketch[0], vec4<f32>(1.0))), EPS);
+    }
+    workgroupBarrier();
+
+    for (var i = index; i < stride; i += BLOCK_SIZE) {
+#ifdef FP16
+        let value = unpack4x16float(x[th + i]) * norm;
+        x[th + i] = pack4x16float(value);
+#else
+        let value = x[th + i] * norm;
+        x[th + i] = value;
+#endif
+    }
+}
@@ -0,0 +1,99 @@
+struct View {
+    shape: vec4<u32>,
+    stride: vec4<u32>,
+    offset: vec4<u32>,
+};
+
+@group(0) @binding(0) var<uniform> source: View;
+@group(0) @binding(1) var<uniform> destination: View;
+@group(0) @binding(2) var<uniform> mask_shape: vec4<u32>;                   // [⌈C / 32⌉, T, B]
+
+@group(0) @binding(3) var<storage, read> mask: array<u32>;                  // (B, T, ⌈C / 32⌉)
+#ifdef IN_FP16
+@group(0) @binding(4) var<storage, read> input: array<vec2<u32>>;           // (B, T, C)
+#else
+@group(0) @binding(4) var<storage, read> input: array<vec4<f32>>;           // (B, T, C)
+#endif
+#ifdef OUT_FP16
+@group(0) @binding(5) var<storage, read_write> output: array<vec2<u32>>;    // (B, T, C)
+#else
+@group(0) @binding(5) var<storage, read_write> output: array<vec4<f32>>;    // (B, T, C)
+#endif
+
+fn compute_index(view: View, batch: u32, token: u32, index: u32) -> u32 {
+    let stride = view.stride.x >> 2u;
+    let offset = vec3<u32>(view.offset.zy, view.offset.x >> 2u);
+    return dot(vec3<u32>(batch, token, index) + offset, vec3<u32>(view.stride.y * stride, stride, 1u));
+}
+
+fn pack4x16float(x: vec4<f32>) -> vec2<u32> {
+    return vec2<u32>(pack2x16float(x.xy), pack2x16float(x.zw));
+}
+
+fn unpack4x16float(x: vec2<u32>) -> vec4<f32> {
+    return vec4<f32>(unpack2x16float(x.x), unpack2x16float(x.y));
+}
+
+fn load_mask(batch: u32, token: u32, index: u32) -> vec4<bool> {
+    // the mask is broadcast along the token and batch axes if they are of size 1
+    let mask_token = select(token, 0u, mask_shape[1] == 1u);
+    let mask_batch = select(batch, 0u, mask_shape[2] == 1u);
+    let word = mask[(mask_batch * mask_shape[1] + mask_token) * mask_shape[0] + (index >> 3u)];
+    let bits = (word >> ((index & 7u) << 2u)) & 0xfu;
+    return vec4<bool>((bits & 1u) != 0u, (bits & 2u) != 0u, (bits & 4u) != 0u, (bits & 8u) != 0u);
+}
+
+fn load_input(batch: u32, token: u32, index: u32) -> vec4<f32> {
+#ifdef IN_FP16
+    return unpack4x16float(input[compute_index(source, batch, select(token, 0u, source.shape.y == 1u), index)]);
+#else
+    return input[compute_index(source, batch, select(token, 0u, source.shape.y == 1u), index)];
+#endif
+}
+
+fn load_output(bti: u32) -> vec4<f32> {
+#ifdef OUT_FP16
+    return unpack4x16float(output[bti]);
+#else
+    return output[bti];
+#endif
+}
+
+fn store_output(bti: u32, x: vec4<f32>) {
+#ifdef OUT_FP16
+    output[bti] = pack4x16float(x);
+#else
+    output[bti] = x;
+#endif
+}
+
+@compute @workgroup_size(BLOCK_SIZE, 1, 1)
+fn masked_blit(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let stride = destination.shape.x / 4u;
+    let index = invocation_id.x;
+    let token = invocation_id.y;
+    let batch = invocation_id.z;
+
+    if index < stride {
+        let cond = load_mask(batch, token, index);
+        let x = load_input(batch, token, index);
+        let bti = compute_index(destination, batch, token, index);
+        store_output(bti, select(load_output(bti), x, cond));
+    }
+}
+
+@compute @workgroup_size(BLOCK_SIZE, 1, 1)
+fn masked_add(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let stride = destination.shape.x / 4u;
+    let index = invocation_id.x;
+    let token = invocation_id.y;
+    let batch = invocation_id.z;
+
+    if index < stride {
+        let cond = load_mask(batch, token, index);
+        let x = load_input(batch, token, index);
+        let bti = compute_index(destination, batch, token, index);
+        let y = load_output(bti);
+        store_output(bti, select(y, x + y, cond));
+    }
+}
@@ -0,0 +1,43 @@
+@group(0) @binding(0) var<uniform> shape: vec4<u32>;                        // [C, T, B]
+@group(0) @binding(1) var<uniform> mask_shape: vec4<u32>;                   // [⌈C / 32⌉, T, B]
+@group(0) @binding(2) var<storage, read> mask: array<u32>;                  // (B, T, ⌈C / 32⌉)
+#ifdef FP16
+@group(0) @binding(3) var<storage, read_write> x: array<vec2<u32>>;    // (B, T, C)
+#else
+@group(0) @binding(3) var<storage, read_write> x: array<vec4<f32>>;    // (B, T, C)
+#endif
+
+fn pack4x16float(x: vec4<f32>) -> vec2<u32> {
+    return vec2<u32>(pack2x16float(x.xy), pack2x16float(x.zw));
+}
+
+fn unpack4x16float(x: vec2<u32>) -> vec4<f32> {
+    return vec4<f32>(unpack2x16float(x.x), unpack2x16float(x.y));
+}
+
+@compute @workgroup_size(BLOCK_SIZE, 1, 1)
+fn masked_fill(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let stride = shape[0] / 4u;
+    let index = invocation_id.x;
+    let token = invocation_id.y;
+    let batch = invocation_id.z;
+
+    if index < stride {
+        let bti = (batch * shape[1] + token) * stride + index;
+
+        // the mask is broadcast along the token and batch axes if they are of size 1
+        let mask_token = select(token, 0u, mask_shape[1] == 1u);
+        let mask_batch = select(batch, 0u, mask_shape[2] == 1u);
+        let word = mask[(mask_batch * mask_shape[1] + mask_token) * mask_shape[0] + (index >> 3u)];
+        let bits = (word >> ((index & 7u) << 2u)) & 0xfu;
+        let cond = vec4<bool>((bits & 1u) != 0u, (bits & 2u) != 0u, (bits & 4u) != 0u, (bits & 8u) != 0u);
+        let value = vec4<f32>(bitcast<f32>(VALUE));
+
+        // elements with unset flags are masked out and filled
+#ifdef FP16
+        x[bti] = pack4x16float(select(value, unpack4x16float(x[bti]), cond));
+#else
+        x[bti] = select(value, x[bti], cond);
+#endif
+    }
+}
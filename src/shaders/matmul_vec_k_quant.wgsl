@@ -0,0 +1,157 @@
+struct View {
+    shape: vec4<u32>,
+    stride: vec4<u32>,
+    offset: vec4<u32>,
+};
+
+@group(0) @binding(0) var<uniform> shape: vec4<u32>;                        // [Q, R, B]
+@group(0) @binding(1) var<uniform> source: View;                            // [C, T, B]
+@group(0) @binding(2) var<uniform> destination: View;                       // [R, T, B]
+
+@group(0) @binding(3) var<storage, read> matrix: array<u32>;                // (B, R, Q)
+
+#ifdef IN_FP16
+@group(0) @binding(4) var<storage, read> input: array<vec2<u32>>;           // (B, T, C)
+#else
+@group(0) @binding(4) var<storage, read> input: array<vec4<f32>>;           // (B, T, C)
+#endif
+#ifdef OUT_FP16
+@group(0) @binding(5) var<storage, read_write> output: array<vec2<u32>>;    // (B, T, R)
+#else
+@group(0) @binding(5) var<storage, read_write> output: array<vec4<f32>>;    // (B, T, R)
+#endif
+
+// a super-block holds 256 weights in 8 sub-blocks of 32, laid out in words as:
+// `d, dmin` (1), `scales` (3), `qh` (8, Q5_K only), `qs` (32)
+#ifdef Q5K
+const BLOCK_WORDS: u32 = 44u;
+const QH_OFFSET: u32 = 4u;
+const QS_OFFSET: u32 = 12u;
+#else
+const BLOCK_WORDS: u32 = 36u;
+const QS_OFFSET: u32 = 4u;
+#endif
+
+var<workgroup> sketch: array<vec4<f32>, BLOCK_SIZE>;
+
+fn compute_index(view: View, batch: u32, token: u32, index: u32) -> u32 {
+    let stride = view.stride.x >> 2u;
+    let offset = vec3<u32>(view.offset.zy, view.offset.x >> 2u);
+    return dot(vec3<u32>(batch, token, index) + offset, vec3<u32>(view.stride.y * stride, stride, 1u));
+}
+
+fn pack4x16float(x: vec4<f32>) -> vec2<u32> {
+    return vec2<u32>(pack2x16float(x.xy), pack2x16float(x.zw));
+}
+
+fn unpack4x16float(x: vec2<u32>) -> vec4<f32> {
+    return vec4<f32>(unpack2x16float(x.x), unpack2x16float(x.y));
+}
+
+fn squared_relu(x: vec4<f32>) -> vec4<f32> {
+    let p = max(x, vec4<f32>(0.0));
+    return p * p;
+}
+
+fn reduce_sum(index: u32, stride: u32) {
+    if index < stride {
+        sketch[index] += sketch[index + stride];
+    }
+    workgroupBarrier();
+}
+
+fn read_byte(base: u32, index: u32) -> u32 {
+    return (matrix[base + (index >> 2u)] >> ((index & 3u) * 8u)) & 0xffu;
+}
+
+fn unpack_bytes(x: u32) -> vec4<u32> {
+    return vec4<u32>(x, x >> 8u, x >> 16u, x >> 24u);
+}
+
+// 6-bit scale and min of sub-block `j`, with `scales` starting at byte 4 of the super-block
+fn unpack_scale_min(base: u32, j: u32) -> vec2<f32> {
+    if j < 4u {
+        let scale = read_byte(base, 4u + j) & 63u;
+        let min = read_byte(base, 8u + j) & 63u;
+        return vec2<f32>(f32(scale), f32(min));
+    }
+    let scale = (read_byte(base, 8u + j) & 0xfu) | ((read_byte(base, j) >> 6u) << 4u);
+    let min = (read_byte(base, 8u + j) >> 4u) | ((read_byte(base, 4u + j) >> 6u) << 4u);
+    return vec2<f32>(f32(scale), f32(min));
+}
+
+@compute @workgroup_size(BLOCK_SIZE, 1, 1)
+fn matmul(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let stride = source.shape.x / 32u;              // sub-blocks in a row
+    let stride_row = shape.x / 4u;                  // words in a row
+    let index = invocation_id.x % BLOCK_SIZE;
+    let channel = invocation_id.x / BLOCK_SIZE;     // 1 channel: 4 rows in matrix
+    let token = invocation_id.y;
+    let batch = invocation_id.z;
+
+    let bb = compute_index(source, batch, token, 0u);
+    let cb = (batch * shape.y + channel * 4u) * stride_row;
+
+    var local_sum = vec4<f32>(0.0);
+    for (var i = index; i < stride; i += BLOCK_SIZE) {
+        let block = i / 8u;
+        let j = i % 8u;
+
+        // read 32 elements from the input
+        var x: array<vec4<f32>, 8>;
+        var sum_x = 0.0;
+        for (var l = 0u; l < 8u; l += 1u) {
+#ifdef IN_FP16
+            x[l] = unpack4x16float(input[bb + i * 8u + l]);
+#else
+            x[l] = input[bb + i * 8u + l];
+#endif
+            sum_x += dot(x[l], vec4<f32>(1.0));
+        }
+
+        // each weight is `d * scale * q - dmin * min`
+        for (var r = 0u; r < 4u; r += 1u) {
+            let base = cb + r * stride_row + block * BLOCK_WORDS;
+            let d = unpack2x16float(matrix[base]);
+            let m = unpack_scale_min(base, j);
+
+            var sum_qx = 0.0;
+            for (var l = 0u; l < 8u; l += 1u) {
+                let qs = matrix[base + QS_OFFSET + (j >> 1u) * 8u + l] >> ((j & 1u) * 4u);
+                var q = vec4<f32>(unpack_bytes(qs) & vec4<u32>(0xfu));
+#ifdef Q5K
+                let qh = matrix[base + QH_OFFSET + l] >> j;
+                q += vec4<f32>((unpack_bytes(qh) & vec4<u32>(1u)) * 16u);
+#endif
+                sum_qx += dot(q, x[l]);
+            }
+            local_sum[r] += d[0] * m[0] * sum_qx - d[1] * m[1] * sum_x;
+        }
+    }
+    sketch[index] = local_sum;
+    workgroupBarrier();
+
+    reduce_sum(index, 64u);
+    reduce_sum(index, 32u);
+    reduce_sum(index, 16u);
+    reduce_sum(index, 8u);
+    reduce_sum(index, 4u);
+    reduce_sum(index, 2u);
+    reduce_sum(index, 1u);
+
+    if index == 0u {
+        let btc = compute_index(destination, batch, token, channel);
+        var out = sketch[0];
+#ifdef ACT_SQUARED_RELU
+        out = squared_relu(out);
+#endif
+#ifdef ACT_TANH
+        out = tanh(out);
+#endif
+#ifdef OUT_FP16
+        output[btc] = pack4x16float(out);
+#else
+        output[btc] = out;
+#endif
+    }
+}
@@ -0,0 +1,79 @@
+@group(0) @binding(0) var<uniform> shape: vec4<u32>;                        // [C, T, B]
+@group(0) @binding(1) var<storage, read_write> state: array<vec4<u32>>;     // (B, [key, counter])
+#ifdef FP16
+@group(0) @binding(2) var<storage, read_write> x: array<vec2<u32>>;    // (B, T, C)
+#else
+@group(0) @binding(2) var<storage, read_write> x: array<vec4<f32>>;    // (B, T, C)
+#endif
+
+const PHILOX_M0: u32 = 0xd2511f53u;
+const PHILOX_M1: u32 = 0xcd9e8d57u;
+const PHILOX_W0: u32 = 0x9e3779b9u;
+const PHILOX_W1: u32 = 0xbb67ae85u;
+
+fn pack4x16float(x: vec4<f32>) -> vec2<u32> {
+    return vec2<u32>(pack2x16float(x.xy), pack2x16float(x.zw));
+}
+
+// returns the high and low 32 bits of the 64-bit product
+fn mul_hi_lo(a: u32, b: u32) -> vec2<u32> {
+    let a_lo = a & 0xffffu;
+    let a_hi = a >> 16u;
+    let b_lo = b & 0xffffu;
+    let b_hi = b >> 16u;
+
+    let lo_lo = a_lo * b_lo;
+    let hi_lo = a_hi * b_lo;
+    let lo_hi = a_lo * b_hi;
+    let hi_hi = a_hi * b_hi;
+
+    let cross = (lo_lo >> 16u) + (hi_lo & 0xffffu) + lo_hi;
+    let hi = hi_hi + (hi_lo >> 16u) + (cross >> 16u);
+    return vec2<u32>(hi, a * b);
+}
+
+// Philox4x32-10 counter-based generator
+fn philox(counter: vec4<u32>, key: vec2<u32>) -> vec4<u32> {
+    var c = counter;
+    var k = key;
+    for (var i = 0u; i < 10u; i += 1u) {
+        let p0 = mul_hi_lo(PHILOX_M0, c.x);
+        let p1 = mul_hi_lo(PHILOX_M1, c.z);
+        c = vec4<u32>(p1[0] ^ c.y ^ k.x, p1[1], p0[0] ^ c.w ^ k.y, p0[1]);
+        k += vec2<u32>(PHILOX_W0, PHILOX_W1);
+    }
+    return c;
+}
+
+@compute @workgroup_size(BLOCK_SIZE, 1, 1)
+fn random_uniform(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let stride = shape[0] / 4u;
+    let index = invocation_id.x;
+    let token = invocation_id.y;
+    let batch = invocation_id.z;
+
+    if index < stride {
+        let bti = (batch * shape[1] + token) * stride + index;
+        let slot = state[batch];
+
+        let bits = philox(vec4<u32>(index, token, slot.z, slot.w), slot.xy);
+        // take the top 24 bits to get uniformly distributed floats in [0, 1)
+        let value = vec4<f32>(bits >> vec4<u32>(8u)) * (1.0 / 16777216.0);
+#ifdef FP16
+        x[bti] = pack4x16float(value);
+#else
+        x[bti] = value;
+#endif
+    }
+}
+
+@compute @workgroup_size(BLOCK_SIZE, 1, 1)
+fn random_advance(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let batch = invocation_id.x;
+    if batch < arrayLength(&state) {
+        let slot = state[batch];
+        let lo = slot.z + 1u;
+        let hi = slot.w + select(0u, 1u, lo == 0u);
+        state[batch] = vec4<u32>(slot.xy, lo, hi);
+    }
+}
@@ -0,0 +1,79 @@
+@group(0) @binding(0) var<uniform> shape: vec4<u32>;                        // [S, H, A]
+
+@group(0) @binding(1) var<storage, read> u: array<vec2<u32>>;               // (H, S)
+#ifdef FP16
+@group(0) @binding(2) var<storage, read> r: array<vec2<u32>>;               // (A, H, S)
+@group(0) @binding(3) var<storage, read> k: array<vec2<u32>>;               // (A, H, S)
+@group(0) @binding(4) var<storage, read> v: array<vec2<u32>>;               // (A, H, S)
+@group(0) @binding(5) var<storage, read_write> x: array<vec2<u32>>;         // (A, H, S)
+#else
+@group(0) @binding(2) var<storage, read> r: array<vec4<f32>>;               // (A, H, S)
+@group(0) @binding(3) var<storage, read> k: array<vec4<f32>>;               // (A, H, S)
+@group(0) @binding(4) var<storage, read> v: array<vec4<f32>>;               // (A, H, S)
+@group(0) @binding(5) var<storage, read_write> x: array<vec4<f32>>;         // (A, H, S)
+#endif
+
+var<workgroup> sketch: array<vec4<f32>, BLOCK_SIZE>;
+var<workgroup> bonus: f32;
+
+fn pack4x16float(x: vec4<f32>) -> vec2<u32> {
+    return vec2<u32>(pack2x16float(x.xy), pack2x16float(x.zw));
+}
+
+fn unpack4x16float(x: vec2<u32>) -> vec4<f32> {
+    return vec4<f32>(unpack2x16float(x.x), unpack2x16float(x.y));
+}
+
+fn reduce_sum(index: u32, stride: u32) {
+    if index < stride {
+        sketch[index] += sketch[index + stride];
+    }
+    workgroupBarrier();
+}
+
+@compute @workgroup_size(BLOCK_SIZE, 1, 1)
+fn time_first(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let stride = shape[0] / 4u;
+    let index = invocation_id.x;
+    let head = invocation_id.y;
+    let token = invocation_id.z;
+
+    let h = head * stride;
+    let th = (token * shape[1] + head) * stride;
+
+    var _sum_4: vec4<f32>;
+    for (var i = index; i < stride; i += BLOCK_SIZE) {
+        let uu = unpack4x16float(u[h + i]);
+#ifdef FP16
+        let rr = unpack4x16float(r[th + i]);
+        let kk = unpack4x16float(k[th + i]);
+#else
+        let rr = r[th + i];
+        let kk = k[th + i];
+#endif
+        _sum_4 += rr * kk * uu;
+    }
+    sketch[index] = _sum_4;
+    workgroupBarrier();
+
+    reduce_sum(index, 16u);
+    reduce_sum(index, 8u);
+    reduce_sum(index, 4u);
+    reduce_sum(index, 2u);
+    reduce_sum(index, 1u);
+
+    if index == 0u {
+        bonus = dot(sketch[0], vec4<f32>(1.0));
+    }
+    workgroupBarrier();
+
+    for (var i = index; i < stride; i += BLOCK_SIZE) {
+#ifdef FP16
+        let value = unpack4x16float(x[th + i]);
+        x[th + i] = pack4x16float(fma(vec4<f32>(bonus), unpack4x16float(v[th + i]), value));
+#else
+        let value = x[th + i];
+        x[th + i] = fma(vec4<f32>(bonus), v[th + i], value);
+#endif
+    }
+}
@@ -0,0 +1,154 @@
+struct View {
+    shape: vec4<u32>,
+    stride: vec4<u32>,
+    offset: vec4<u32>,
+};
+
+struct Cursor {
+    batch: u32,
+    token: u32,
+    len: u32,
+};
+
+struct Input {
+    @builtin(global_invocation_id) uid: vec3<u32>,
+    @builtin(local_invocation_id) tid: vec3<u32>,
+};
+
+@group(0) @binding(0) var<uniform> shape: vec4<u32>;                    // [S, H, A]
+@group(0) @binding(1) var<uniform> view: View;                          // [C, S + 1, B]
+@group(0) @binding(2) var<storage, read> cursors: array<u32>;           // [A]
+
+@group(0) @binding(3) var<storage, read> w: array<vec4<f32>>;           // (A, H, S)
+@group(0) @binding(4) var<storage, read_write> state: array<vec4<f32>>; // (B, S + 1, C)
+
+#ifdef FP16
+@group(0) @binding(5) var<storage, read> k: array<vec2<u32>>;           // (A, H, S)
+@group(0) @binding(6) var<storage, read> v: array<vec2<u32>>;           // (A, H, S)
+@group(0) @binding(7) var<storage, read> a: array<vec2<u32>>;           // (A, H, S)
+@group(0) @binding(8) var<storage, read> kk: array<vec2<u32>>;          // (A, H, S)
+@group(0) @binding(9) var<storage, read_write> x: array<vec2<u32>>;     // (A, H, S)
+#else
+@group(0) @binding(5) var<storage, read> k: array<vec4<f32>>;           // (A, H, S)
+@group(0) @binding(6) var<storage, read> v: array<vec4<f32>>;           // (A, H, S)
+@group(0) @binding(7) var<storage, read> a: array<vec4<f32>>;           // (A, H, S)
+@group(0) @binding(8) var<storage, read> kk: array<vec4<f32>>;          // (A, H, S)
+@group(0) @binding(9) var<storage, read_write> x: array<vec4<f32>>;     // (A, H, S)
+#endif
+
+var<workgroup> shared_r: array<vec4<f32>, BLOCK_SIZE>;
+var<workgroup> shared_w: array<vec4<f32>, BLOCK_SIZE>;
+var<workgroup> shared_k: array<vec4<f32>, BLOCK_SIZE>;
+var<workgroup> shared_a: array<vec4<f32>, BLOCK_SIZE>;
+var<workgroup> shared_b: array<vec4<f32>, BLOCK_SIZE>;
+
+fn compute_index(batch: u32, token: u32, index: u32) -> u32 {
+    let stride = view.stride.x >> 2u;
+    let offset = vec3<u32>(view.offset.zy, view.offset.x >> 2u);
+    return dot(vec3<u32>(batch, token, index) + offset, vec3<u32>(view.stride.y * stride, stride, 1u));
+}
+
+fn compute_cursor(x: u32) -> Cursor {
+    // let unpacked = vec4<u32>(unpack4x8unorm(x) * 255.0 + 0.5);
+    var cursor: Cursor;
+    cursor.batch = x & 0xffu;
+    cursor.token = (x >> 8u) & 0xffffu;
+    cursor.len = (x >> 24u) & 0xffu;
+    return cursor;
+}
+
+fn pack4x16float(x: vec4<f32>) -> vec2<u32> {
+    return vec2<u32>(pack2x16float(x.xy), pack2x16float(x.zw));
+}
+
+fn unpack4x16float(x: vec2<u32>) -> vec4<f32> {
+    return vec4<f32>(unpack2x16float(x.x), unpack2x16float(x.y));
+}
+
+@compute @workgroup_size(BLOCK_SIZE, 1, 1)
+fn time_mix(in: Input) {
+    let stride_head = shape[0] / 4u;
+    let stride = shape[1] * stride_head;
+
+    let index = in.uid.x;
+    let head = in.tid.x / stride_head;
+    let h = head * stride_head;
+
+    for (var t = 0u; t < shape[2]; t += 1u) {
+        let bti = t * stride + index;
+        let cursor = compute_cursor(cursors[t]);
+
+        workgroupBarrier();
+        // decay `exp(-exp(-0.5) * sigmoid(w))`
+        shared_w[in.tid.x] = exp(-0.606531 / (1.0 + exp(-w[bti])));
+#ifdef FP16
+        let _kk = unpack4x16float(kk[bti]);
+        shared_r[in.tid.x] = unpack4x16float(x[bti]);
+        shared_k[in.tid.x] = unpack4x16float(k[bti]);
+        shared_a[in.tid.x] = -_kk;
+        shared_b[in.tid.x] = _kk * unpack4x16float(a[bti]);
+#else
+        let _kk = kk[bti];
+        shared_r[in.tid.x] = x[bti];
+        shared_k[in.tid.x] = k[bti];
+        shared_a[in.tid.x] = -_kk;
+        shared_b[in.tid.x] = _kk * a[bti];
+#endif
+        workgroupBarrier();
+
+#ifdef FP16
+        let vv = unpack4x16float(v[bti]);
+#else
+        let vv = v[bti];
+#endif
+
+        // project the state onto the (negative) normalized key: `sa = S · a`
+        var sa = vec4<f32>(0.0);
+        for (var j = 0u; j < stride_head; j += 1u) {
+            let aa = shared_a[h + j];
+            let bji = compute_index(cursor.batch, j * 4u + 1u, index);
+
+            sa = fma(vec4<f32>(aa[0]), state[bji + stride * 0u], sa);
+            sa = fma(vec4<f32>(aa[1]), state[bji + stride * 1u], sa);
+            sa = fma(vec4<f32>(aa[2]), state[bji + stride * 2u], sa);
+            sa = fma(vec4<f32>(aa[3]), state[bji + stride * 3u], sa);
+        }
+
+        var y = vec4<f32>(0.0);
+        for (var j = 0u; j < stride_head; j += 1u) {
+            let rr = shared_r[h + j];
+            let ww = shared_w[h + j];
+            let key = shared_k[h + j];
+            let bb = shared_b[h + j];
+
+            var ss: array<vec4<f32>, 4>;
+            let bji = compute_index(cursor.batch, j * 4u + 1u, index);
+
+            ss[0] = state[bji + stride * 0u];
+            ss[1] = state[bji + stride * 1u];
+            ss[2] = state[bji + stride * 2u];
+            ss[3] = state[bji + stride * 3u];
+
+            // `S = S * w + sa * b + v * k`
+            ss[0] = fma(vec4<f32>(ww[0]), ss[0], fma(sa, vec4<f32>(bb[0]), key[0] * vv));
+            ss[1] = fma(vec4<f32>(ww[1]), ss[1], fma(sa, vec4<f32>(bb[1]), key[1] * vv));
+            ss[2] = fma(vec4<f32>(ww[2]), ss[2], fma(sa, vec4<f32>(bb[2]), key[2] * vv));
+            ss[3] = fma(vec4<f32>(ww[3]), ss[3], fma(sa, vec4<f32>(bb[3]), key[3] * vv));
+
+            y += rr[0] * ss[0];
+            y += rr[1] * ss[1];
+            y += rr[2] * ss[2];
+            y += rr[3] * ss[3];
+
+            state[bji + stride * 0u] = ss[0];
+            state[bji + stride * 1u] = ss[1];
+            state[bji + stride * 2u] = ss[2];
+            state[bji + stride * 3u] = ss[3];
+        }
+#ifdef FP16
+        x[bti] = pack4x16float(y);
+#else
+        x[bti] = y;
+#endif
+    }
+}
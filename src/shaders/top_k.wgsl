@@ -0,0 +1,66 @@
+@group(0) @binding(0) var<uniform> shape: vec4<u32>;                        // [C, T, B]
+@group(0) @binding(1) var<storage, read> x: array<f32>;                     // (B, T, C)
+@group(0) @binding(2) var<storage, read_write> indices: array<u32>;         // (B, T, K)
+@group(0) @binding(3) var<storage, read_write> values: array<f32>;          // (B, T, K)
+
+const NONE: u32 = 0xffffffffu;
+
+var<workgroup> sketch_value: array<f32, BLOCK_SIZE>;
+var<workgroup> sketch_index: array<u32, BLOCK_SIZE>;
+
+// larger values come first, and ties are broken by smaller indices
+fn precedes(v0: f32, i0: u32, v1: f32, i1: u32) -> bool {
+    return i0 != NONE && (i1 == NONE || v0 > v1 || (v0 == v1 && i0 < i1));
+}
+
+@compute @workgroup_size(BLOCK_SIZE, 1, 1)
+fn top_k(@builtin(local_invocation_id) invocation_id: vec3<u32>, @builtin(workgroup_id) workgroup_id: vec3<u32>) {
+    let index = invocation_id.x;
+    let token = workgroup_id.y;
+    let batch = workgroup_id.z;
+
+    let bt = batch * shape[1] + token;
+    let bti = bt * shape[0];
+
+    var prev_value = 0.0;
+    var prev_index = NONE;
+
+    // select the best element ranked after the previous selection, for K rounds
+    for (var k = 0u; k < TOP_K; k += 1u) {
+        var best_value = 0.0;
+        var best_index = NONE;
+        for (var i = index; i < shape[0]; i += BLOCK_SIZE) {
+            let value = x[bti + i];
+            if k > 0u && !precedes(prev_value, prev_index, value, i) {
+                continue;
+            }
+            if precedes(value, i, best_value, best_index) {
+                best_value = value;
+                best_index = i;
+            }
+        }
+        sketch_value[index] = best_value;
+        sketch_index[index] = best_index;
+        workgroupBarrier();
+
+        for (var step = BLOCK_SIZE >> 1u; step > 0u; step >>= 1u) {
+            if index < step {
+                let value = sketch_value[index + step];
+                let i = sketch_index[index + step];
+                if precedes(value, i, sketch_value[index], sketch_index[index]) {
+                    sketch_value[index] = value;
+                    sketch_index[index] = i;
+                }
+            }
+            workgroupBarrier();
+        }
+
+        prev_value = sketch_value[0];
+        prev_index = sketch_index[0];
+        if index == 0u {
+            indices[bt * TOP_K + k] = prev_index;
+            values[bt * TOP_K + k] = prev_value;
+        }
+        workgroupBarrier();
+    }
+}
@@ -44,6 +44,16 @@ where
         }
     }
 
+    /// Visit every cached value with its key, along with whether it is still referenced outside the cache.
+    pub fn for_each(&self, mut f: impl FnMut(&K, &V, bool)) {
+        let map = self.map.read().unwrap();
+        for (key, items) in map.iter() {
+            for item in items {
+                f(key, &item.value, item.ref_count() > 1);
+            }
+        }
+    }
+
     /// Step the cache for one frame.
     pub fn step(&self) {
         if self.limit == 0 {
@@ -62,13 +72,15 @@ where
 
     /// Checkout the item with the given key. If the item doesn't exist, `miss` is called to construct it.
     pub fn checkout(&self, key: K, miss: impl FnOnce() -> V, hit: impl FnOnce(&V)) -> Arc<V> {
-        let map = self.map.read().unwrap();
+        let mut map = self.map.write().unwrap();
         let value = match map
-            .get(&key)
-            .and_then(|items| items.iter().find(|&item| item.ref_count() <= 1))
+            .get_mut(&key)
+            .and_then(|items| items.iter_mut().find(|item| item.ref_count() <= 1))
         {
-            Some(CachedItem { value, .. }) => {
-                let value = value.clone();
+            Some(item) => {
+                // a reused item is alive again; reset its life so it isn't evicted while in steady use
+                item.life = 0;
+                let value = item.value.clone();
                 drop(map);
 
                 #[cfg(feature = "trace")]
@@ -100,3 +112,44 @@ where
         value
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::ResourceCache;
+
+    #[test]
+    fn test_reuse_after_steps() {
+        let cache = ResourceCache::<usize, usize>::new(2);
+        let mut misses = 0;
+
+        // emulate a steady loop that checks out the same item every step
+        for _ in 0..8 {
+            let value = cache.checkout(
+                0,
+                || {
+                    misses += 1;
+                    42
+                },
+                |_| {},
+            );
+            assert_eq!(*value, 42);
+            drop(value);
+            cache.step();
+        }
+        assert_eq!(misses, 1);
+
+        // an item left idle for long enough is still evicted
+        for _ in 0..3 {
+            cache.step();
+        }
+        let _ = cache.checkout(
+            0,
+            || {
+                misses += 1;
+                42
+            },
+            |_| {},
+        );
+        assert_eq!(misses, 2);
+    }
+}
@@ -4,6 +4,7 @@ use web_rwkv_derive::DeserializeSeed;
 
 use super::{ops::Activation, TensorCpu, TensorInit, TensorInto};
 use crate::{
+    context::Context,
     num::Float,
     tensor::{
         kind::{ReadWrite, Uniform},
@@ -42,6 +43,33 @@ impl Default for Nf4Quant {
     }
 }
 
+/// GGML k-quant formats, which pack every 256 weights of a row into a super-block of 8 sub-blocks,
+/// each with a 6-bit scale and min.
+///
+/// Only the kernels are provided: there is no GGUF reader, so the blocks must be read elsewhere and
+/// wrapped with [`Matrix::from_k_quant`]. There is no tiled kernel either, so prompts (prefill) run
+/// token by token on the matrix-vector kernel, much slower than with the other formats.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KQuant {
+    /// `block_q4_K`: 4-bit weights.
+    Q4K,
+    /// `block_q5_K`: 5-bit weights, whose high bits are stored separately.
+    Q5K,
+}
+
+impl KQuant {
+    /// Number of weights in a super-block.
+    pub const BLOCK_SIZE: usize = 256;
+
+    /// Number of bytes of a super-block.
+    pub const fn block_bytes(self) -> usize {
+        match self {
+            KQuant::Q4K => 144,
+            KQuant::Q5K => 176,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, DeserializeSeed)]
 pub enum Matrix {
     Fp16(TensorGpu<f16, ReadWrite>),
@@ -54,9 +82,25 @@ pub enum Matrix {
         w: TensorGpu<u8, ReadWrite>,
         m: TensorGpu<f16, ReadWrite>,
     },
+    /// Raw `block_q4_K` super-blocks of shape `[Q, R, B]`, `Q` being the number of bytes in a row.
+    /// See [`KQuant`] for limitations.
+    Q4K(TensorGpu<u8, ReadWrite>),
+    /// Raw `block_q5_K` super-blocks of shape `[Q, R, B]`, `Q` being the number of bytes in a row.
+    /// See [`KQuant`] for limitations.
+    Q5K(TensorGpu<u8, ReadWrite>),
 }
 
 impl Matrix {
+    /// Number of rows, i.e., output features of the matrix.
+    pub fn num_row(&self) -> usize {
+        match self {
+            Matrix::Fp16(matrix) => matrix.shape()[1],
+            Matrix::Int8 { w, .. } => w.shape()[1],
+            Matrix::NF4 { w, .. } => w.shape()[1],
+            Matrix::Q4K(w) | Matrix::Q5K(w) => w.shape()[1],
+        }
+    }
+
     pub fn matmul_vec_op(
         &self,
         input: TensorGpuView<impl Float>,
@@ -67,6 +111,8 @@ impl Matrix {
             Matrix::Fp16(matrix) => TensorOp::matmul_vec_fp16(matrix, input, output, active),
             Matrix::Int8 { w, m } => TensorOp::matmul_vec_int8(w, m, input, output, active),
             Matrix::NF4 { w, q, m } => TensorOp::matmul_vec_nf4(w, q, m, input, output, active),
+            Matrix::Q4K(w) => TensorOp::matmul_vec_k_quant(w, KQuant::Q4K, input, output, active),
+            Matrix::Q5K(w) => TensorOp::matmul_vec_k_quant(w, KQuant::Q5K, input, output, active),
         }
     }
 
@@ -86,6 +132,8 @@ impl Matrix {
             Matrix::NF4 { w, q, m } => {
                 TensorOp::matmul_mat_nf4(w.view(.., .., .., ..)?, q, m, input, output, active)
             }
+            // there is no tiled kernel for k-quants; the vector kernel runs token by token
+            Matrix::Q4K(_) | Matrix::Q5K(_) => self.matmul_vec_op(input, output, active),
         }
     }
 
@@ -102,6 +150,33 @@ impl Matrix {
         }
     }
 
+    /// Overwrite the weights in place with `matrix`, re-quantizing it into the existing buffers if needed.
+    pub fn patch(&self, matrix: &TensorCpu<f16>) -> Result<(), TensorError> {
+        let (context, shape) = match self {
+            Matrix::Fp16(w) => return w.load(matrix),
+            Matrix::Int8 { w, .. } => (w.context(), w.shape()),
+            Matrix::NF4 { w, .. } => {
+                let shape = w.shape();
+                (
+                    w.context(),
+                    Shape::new(shape[0] * 2, shape[1], shape[2], shape[3]),
+                )
+            }
+            Matrix::Q4K(_) | Matrix::Q5K(_) => return Err(TensorError::Requantize),
+        };
+
+        let buffer: TensorGpu<f16, ReadWrite> = context.tensor_init(shape);
+        buffer.load(matrix)?;
+
+        let op = match self {
+            Matrix::Fp16(_) | Matrix::Q4K(_) | Matrix::Q5K(_) => unreachable!(),
+            Matrix::Int8 { w, m } => TensorOp::quantize_mat_int8(&buffer, m, w)?,
+            Matrix::NF4 { q, w, m } => TensorOp::quantize_mat_nf4(&buffer, q, m, w)?,
+        };
+        context.submit(context.encode(&op));
+        Ok(())
+    }
+
     pub fn quant_u8(matrix: &TensorGpu<f16, ReadWrite>) -> Result<Self, TensorError> {
         let context = matrix.context();
         let shape = matrix.shape();
@@ -115,7 +190,7 @@ impl Matrix {
         ));
 
         let op = TensorOp::quantize_mat_int8(matrix, &m, &w)?;
-        context.queue.submit(context.encode(&op));
+        context.submit(context.encode(&op));
 
         Ok(Matrix::Int8 { w, m })
     }
@@ -137,8 +212,33 @@ impl Matrix {
         let m = context.tensor_init(absmax_shape);
 
         let op = TensorOp::quantize_mat_nf4(matrix, &q, &m, &w)?;
-        context.queue.submit(context.encode(&op));
+        context.submit(context.encode(&op));
 
         Ok(Matrix::NF4 { w, q, m })
     }
+
+    /// Wrap GGML k-quant super-blocks as they are, without re-quantizing them.
+    /// `shape` is the shape of the dequantized matrix, `[C, R, B]`, where `C` must be multiples of 256.
+    pub fn from_k_quant(
+        context: &Context,
+        quant: KQuant,
+        shape: impl Into<Shape>,
+        blocks: &[u8],
+    ) -> Result<Self, TensorError> {
+        let shape: Shape = shape.into();
+        if shape[0] % KQuant::BLOCK_SIZE != 0 {
+            let aligned = shape[0].next_multiple_of(KQuant::BLOCK_SIZE);
+            let expected = Shape::new(aligned, shape[1], shape[2], shape[3]);
+            return Err(TensorError::Shape(shape, expected));
+        }
+
+        let num_byte = shape[0] / KQuant::BLOCK_SIZE * quant.block_bytes();
+        let shape = Shape::new(num_byte, shape[1], shape[2], shape[3]);
+        let w = context.tensor_from_data(shape, blocks)?;
+
+        Ok(match quant {
+            KQuant::Q4K => Matrix::Q4K(w),
+            KQuant::Q5K => Matrix::Q5K(w),
+        })
+    }
 }
@@ -1,8 +1,11 @@
 use std::{marker::PhantomData, sync::Arc};
 
+use half::{f16, slice::HalfFloatSliceExt};
 use itertools::Itertools;
+use safetensors::Dtype;
+use serde::Serialize;
 use thiserror::Error;
-use web_rwkv_derive::JsError;
+use web_rwkv_derive::{DeserializeSeed, JsError};
 use wgpu::{BindingResource, Buffer, BufferBinding, BufferUsages};
 
 use self::{
@@ -17,6 +20,7 @@ use crate::{
 pub mod cache;
 pub mod matrix;
 pub mod ops;
+pub mod reference;
 pub mod serialization;
 pub mod shape;
 
@@ -103,6 +107,8 @@ pub enum TensorError {
     Deduce,
     #[error("batch {batch} out of range of max {max}")]
     BatchOutOfRange { batch: usize, max: usize },
+    #[error("batch of {size} bytes is not aligned to {align} bytes")]
+    BatchUnaligned { size: usize, align: usize },
     #[error("slice {start}..{end} out of range for dimension size {dim}")]
     SliceOutOfRange {
         dim: usize,
@@ -113,6 +119,34 @@ pub enum TensorError {
     SliceInvalid,
     #[error("cannot split along the axis {0}")]
     SplitInvalid(usize),
+    #[error("invalid cursor {0:?}")]
+    Cursor(Cursor),
+    #[error("cannot re-quantize into k-quant blocks")]
+    Requantize,
+    #[error("state has no per-head WKV matrices")]
+    HeadState,
+    #[error("state has no reference layout")]
+    ReferenceLayout,
+}
+
+/// Pad a buffer size in bytes to [`wgpu::COPY_BUFFER_ALIGNMENT`], so that tensors of
+/// narrow integer types (`u8`, `u16`) can be uploaded, copied and read back.
+#[inline]
+fn align_buffer_size(size: usize) -> usize {
+    size.next_multiple_of(wgpu::COPY_BUFFER_ALIGNMENT as usize)
+}
+
+/// Pad the contents to be written into a buffer to [`wgpu::COPY_BUFFER_ALIGNMENT`].
+#[inline]
+fn align_contents(contents: &[u8]) -> std::borrow::Cow<'_, [u8]> {
+    match align_buffer_size(contents.len()) {
+        size if size == contents.len() => contents.into(),
+        size => {
+            let mut contents = contents.to_vec();
+            contents.resize(size, 0);
+            contents.into()
+        }
+    }
 }
 
 /// Data defining a tensor view in shader.
@@ -135,6 +169,9 @@ impl IntoBytes for View {
 }
 
 /// A record in order to separate different batches of input of various lengths.
+///
+/// Cursors are packed into `u32`s for the kernels, with 8 bits for the batch index,
+/// 16 bits for the starting token and 8 bits for the length.
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Cursor {
     pub batch: usize,
@@ -143,21 +180,78 @@ pub struct Cursor {
 }
 
 impl Cursor {
+    /// Maximum batch index that can be packed.
+    pub const MAX_BATCH: usize = u8::MAX as usize;
+    /// Maximum starting token that can be packed.
+    pub const MAX_TOKEN: usize = u16::MAX as usize;
+    /// Maximum length of a batch that can be packed.
+    pub const MAX_LEN: usize = u8::MAX as usize;
+
+    /// Lay out batches of given lengths one after another, in the order of batches.
+    pub fn stack(lens: impl IntoIterator<Item = usize>) -> Vec<Self> {
+        lens.into_iter()
+            .enumerate()
+            .scan(0, |token, (batch, len)| {
+                let cursor = Self {
+                    batch,
+                    token: *token,
+                    len,
+                };
+                *token += len;
+                Some(cursor)
+            })
+            .collect()
+    }
+
+    /// Check the invariants of cursors before packing: batches are laid out one after another
+    /// in the order of batches (as by [`Cursor::stack`]), and each cursor fits in the packed encoding.
+    pub fn check(cursors: &[Self]) -> Result<(), TensorError> {
+        let mut token = 0;
+        for (batch, &cursor) in cursors.iter().enumerate() {
+            let fits = cursor.batch <= Self::MAX_BATCH
+                && cursor.token <= Self::MAX_TOKEN
+                && cursor.len <= Self::MAX_LEN;
+            if !fits || cursor.batch != batch || cursor.token != token {
+                return Err(TensorError::Cursor(cursor));
+            }
+            token += cursor.len;
+        }
+        Ok(())
+    }
+
     pub fn pack(self) -> u32 {
         let batch = self.batch as u8;
         let token = (self.token as u16).to_ne_bytes();
         let len = self.len as u8;
         bytemuck::cast([batch, token[0], token[1], len])
     }
+
+    pub fn unpack(x: u32) -> Self {
+        let [batch, token_0, token_1, len]: [u8; 4] = bytemuck::cast(x);
+        Self {
+            batch: batch as usize,
+            token: u16::from_ne_bytes([token_0, token_1]) as usize,
+            len: len as usize,
+        }
+    }
+}
+
+/// Check the invariants of `cursors` and pack them for each token, as read by the kernels.
+pub fn pack_cursors(cursors: &[Cursor]) -> Result<Vec<u32>, TensorError> {
+    Cursor::check(cursors)?;
+    Ok(cursors.to_vec().into_cursors())
 }
 
 pub trait IntoPackedCursors {
+    /// Pack one cursor for each non-empty batch.
     fn into_stack(self) -> Vec<u32>;
+    /// Pack one cursor for each token, repeating the cursor of its batch.
     fn into_cursors(self) -> Vec<u32>;
 }
 
 impl IntoPackedCursors for Vec<Cursor> {
     fn into_stack(self) -> Vec<u32> {
+        debug_assert!(Cursor::check(&self).is_ok(), "invalid cursors: {self:?}");
         self.into_iter()
             .filter(|cursor| cursor.len > 0)
             .map(Cursor::pack)
@@ -165,6 +259,7 @@ impl IntoPackedCursors for Vec<Cursor> {
     }
 
     fn into_cursors(self) -> Vec<u32> {
+        debug_assert!(Cursor::check(&self).is_ok(), "invalid cursors: {self:?}");
         self.into_iter()
             .filter(|cursor| cursor.len > 0)
             .map(|cursor| {
@@ -408,7 +503,7 @@ impl<T: Scalar, K: Kind> TensorInitContext<T> for TensorGpu<T, K> {
         let shape = shape.into();
         let meta = context.checkout_shape_uniform(shape);
 
-        let size = shape.len() * std::mem::size_of::<T>();
+        let size = align_buffer_size(shape.len() * std::mem::size_of::<T>());
         let buffer = context.checkout_buffer(size, K::buffer_usages());
 
         let id = uid::Id::new();
@@ -431,8 +526,8 @@ impl<T: Scalar, K: Kind> TensorInto<TensorGpu<T, K>> for TensorCpu<T> {
         let Tensor { shape, data, .. } = self;
         let context = context.clone();
         let meta = context.checkout_shape_uniform(shape);
-        let contents = bytemuck::cast_slice(&data);
-        let buffer = context.checkout_buffer_init(contents, K::buffer_usages());
+        let contents = align_contents(bytemuck::cast_slice(&data));
+        let buffer = context.checkout_buffer_init(&contents, K::buffer_usages());
         let id = uid::Id::new();
         TensorGpu {
             shape,
@@ -520,7 +615,9 @@ impl<T: Scalar, K: Kind> TensorGpu<T, K> {
             let slice = bytemuck::cast_slice_mut::<_, T>(data);
             Box::from_raw(slice)
         };
-        let data = data.into_vec().into();
+        let mut data = data.into_vec();
+        data.truncate(self.len());
+        let data = data.into();
         let shape = self.shape;
 
         let id = uid::Id::new();
@@ -557,7 +654,9 @@ impl<T: Scalar, K: Kind> TensorGpu<T, K> {
             let slice = bytemuck::cast_slice_mut::<_, T>(data);
             Box::from_raw(slice)
         };
-        let data = data.into_vec().into();
+        let mut data = data.into_vec();
+        data.truncate(self.len());
+        let data = data.into();
 
         let id = uid::Id::new();
 
@@ -592,7 +691,9 @@ impl<T: Scalar, K: Kind> TensorGpu<T, K> {
 
         let data = {
             let map = slice.get_mapped_range();
-            Vec::from(bytemuck::cast_slice(&map)).into()
+            let mut data = Vec::from(bytemuck::cast_slice(&map));
+            data.truncate(self.len());
+            data.into()
         };
         buffer.unmap();
 
@@ -613,11 +714,48 @@ impl<T: Scalar, K: Kind> TensorGpu<T, K> {
         &self.context
     }
 
-    pub fn load(&self, host: &TensorCpu<T>) -> Result<(), TensorError> {
-        host.check_shape(self.shape)?;
+    /// Create a tensor directly from its raw bytes (e.g., a memory-mapped safetensors slice),
+    /// skipping the copy into a [`TensorCpu`]. The bytes need not be aligned to `T`.
+    pub fn from_bytes(
+        context: &Context,
+        shape: impl Into<Shape>,
+        contents: &[u8],
+    ) -> Result<Self, TensorError> {
+        let shape = shape.into();
+        if contents.len() != shape.len() * T::size() {
+            return Err(TensorError::Size(contents.len(), shape.len() * T::size()));
+        }
+        let context = context.clone();
+        let meta = context.checkout_shape_uniform(shape);
+        let buffer = context.checkout_buffer_init(&align_contents(contents), K::buffer_usages());
+        let id = uid::Id::new();
+        Ok(Self {
+            shape,
+            data: TensorGpuData {
+                context,
+                meta,
+                buffer,
+            },
+            id,
+            phantom: PhantomData,
+        })
+    }
+
+    /// Load raw bytes of the whole tensor from CPU, skipping the copy into a [`TensorCpu`].
+    pub fn load_bytes(&self, contents: &[u8]) -> Result<(), TensorError> {
+        if contents.len() != self.size() {
+            return Err(TensorError::Size(contents.len(), self.size()));
+        }
         self.context
             .queue
-            .write_buffer(&self.buffer, 0, bytemuck::cast_slice(&host.data[..]));
+            .write_buffer(&self.buffer, 0, &align_contents(contents));
+        Ok(())
+    }
+
+    pub fn load(&self, host: &TensorCpu<T>) -> Result<(), TensorError> {
+        host.check_shape(self.shape)?;
+        let contents = align_contents(bytemuck::cast_slice(&host.data[..]));
+        self.context.queue.write_buffer(&self.buffer, 0, &contents);
         Ok(())
     }
 
@@ -629,10 +767,17 @@ impl<T: Scalar, K: Kind> TensorGpu<T, K> {
                 max: self.shape[2],
             });
         }
-        let offset = (T::size() * self.shape[0] * self.shape[1] * batch) as u64;
+        // padding a batch to the copy alignment must not spill into the next batch
+        let size = T::size() * self.shape[0] * self.shape[1];
+        let align = wgpu::COPY_BUFFER_ALIGNMENT as usize;
+        if self.shape[2] > 1 && size % align != 0 {
+            return Err(TensorError::BatchUnaligned { size, align });
+        }
+        let offset = (size * batch) as u64;
+        let contents = align_contents(bytemuck::cast_slice(&host.data[..]));
         self.context
             .queue
-            .write_buffer(&self.buffer, offset, bytemuck::cast_slice(&host.data[..]));
+            .write_buffer(&self.buffer, offset, &contents);
         Ok(())
     }
 
@@ -805,6 +950,186 @@ impl<T: Scalar> TensorCpu<T> {
     }
 }
 
+impl<T: Scalar> TensorCpu<T> {
+    /// Gather rows of a matrix of shape `[C, V]` into a tensor of shape `[C, T]`,
+    /// where `T` is the number of indices. Rows are copied in parallel with the `parallel` feature.
+    pub fn gather(&self, indices: &[u16]) -> Result<Self, TensorError> {
+        let num_emb = self.shape[0];
+        let num_row = self.shape[1];
+        self.check_shape([num_emb, num_row, 1, 1])?;
+        if let Some(&index) = indices.iter().find(|&&index| index as usize >= num_row) {
+            return Err(TensorError::SliceOutOfRange {
+                dim: num_row,
+                start: index as usize,
+                end: index as usize + 1,
+            });
+        }
+
+        let mut data = vec![T::zero(); num_emb * indices.len()];
+        let copy = |(row, &index): (&mut [T], &u16)| {
+            let start = num_emb * index as usize;
+            row.copy_from_slice(&self.data[start..start + num_emb]);
+        };
+        #[cfg(feature = "parallel")]
+        {
+            use rayon::prelude::*;
+            data.par_chunks_mut(num_emb.max(1))
+                .zip(indices.par_iter())
+                .for_each(copy);
+        }
+        #[cfg(not(feature = "parallel"))]
+        data.chunks_mut(num_emb.max(1))
+            .zip(indices.iter())
+            .for_each(copy);
+
+        TensorInit::from_data([num_emb, indices.len(), 1, 1], data)
+    }
+}
+
+impl TensorCpu<f16> {
+    /// Convert into a tensor of another float type, using the vectorized slice conversion
+    /// for `f32` instead of converting element by element.
+    pub fn to_float<F: Float>(self) -> TensorCpu<F> {
+        let Self { shape, data, .. } = self;
+        let data: Vec<F> = match F::DATA_TYPE {
+            Dtype::F16 => bytemuck::cast_slice(&data[..]).to_vec(),
+            _ => {
+                let mut output = vec![0.0f32; data.len()];
+                data.convert_to_f32_slice(&mut output);
+                bytemuck::cast_vec(output)
+            }
+        };
+        TensorInit::from_data(shape, data).expect("this never happens")
+    }
+}
+
+impl TensorCpu<u32> {
+    /// Create the random number generator state of shape `[4, 1, B]` from per-batch seeds.
+    /// Each batch slot holds a 64-bit key (the seed) and a 64-bit counter starting from 0.
+    /// See [`TensorOp::random_uniform`](ops::TensorOp::random_uniform).
+    pub fn from_seeds(seeds: &[u64]) -> Self {
+        let data = seeds
+            .iter()
+            .flat_map(|&seed| [seed as u32, (seed >> 32) as u32, 0, 0])
+            .collect_vec();
+        TensorInit::from_data([4, 1, seeds.len(), 1], data).expect("this never happens")
+    }
+}
+
+/// Packed bitmasks, with 32 flags stored in each `u32` word along the first axis.
+/// A mask of `[C, T, B]` flags has shape `[⌈C / 32⌉, T, B]`.
+impl TensorCpu<u32> {
+    /// Number of `u32` words needed to pack `len` flags.
+    #[inline]
+    pub const fn mask_words(len: usize) -> usize {
+        len.div_ceil(32)
+    }
+
+    /// Pack boolean flags of shape `[C, T, B]` into a bitmask of shape `[⌈C / 32⌉, T, B]`.
+    /// Bit `i % 32` of word `i / 32` in each row is set if the `i`-th flag is `true`.
+    pub fn from_mask(shape: impl Into<Shape>, mask: &[bool]) -> Result<Self, TensorError> {
+        let shape = shape.into();
+        if shape.len() != mask.len() {
+            return Err(TensorError::Size(shape.len(), mask.len()));
+        }
+        let words = Self::mask_words(shape[0]);
+        let data = mask
+            .chunks(shape[0].max(1))
+            .flat_map(|row| {
+                let mut packed = vec![0u32; words];
+                for (index, flag) in row.iter().enumerate() {
+                    if *flag {
+                        packed[index / 32] |= 1 << (index % 32);
+                    }
+                }
+                packed
+            })
+            .collect_vec();
+        let shape = Shape::new(words, shape[1], shape[2], shape[3]);
+        TensorInit::from_data(shape, data)
+    }
+
+    /// Unpack the bitmask into boolean flags, given the original number of flags `len` in each row.
+    pub fn to_mask(&self, len: usize) -> Result<Vec<bool>, TensorError> {
+        if Self::mask_words(len) != self.shape[0] {
+            return Err(TensorError::Size(Self::mask_words(len), self.shape[0]));
+        }
+        let mask = self
+            .data
+            .chunks(self.shape[0].max(1))
+            .flat_map(|row| (0..len).map(move |index| row[index / 32] & (1 << (index % 32)) != 0))
+            .collect();
+        Ok(mask)
+    }
+}
+
+/// A packed bitmask on GPU selecting elements of a `[C, T, B]` tensor, with 32 flags stored
+/// in each `u32` word along the first axis. The packed words have shape `[⌈C / 32⌉, T, B]`.
+///
+/// Masked ops act on the elements whose flags are set; elements whose flags are unset are
+/// masked out. See [`TensorOp::masked_fill`](ops::TensorOp::masked_fill).
+#[derive(Debug, Clone, Serialize, DeserializeSeed)]
+pub struct TensorMask {
+    tensor: TensorGpu<u32, ReadWrite>,
+    len: usize,
+}
+
+impl TensorMask {
+    /// Pack boolean flags of shape `[C, T, B]` and upload them.
+    pub fn new(
+        context: &Context,
+        shape: impl Into<Shape>,
+        flags: &[bool],
+    ) -> Result<Self, TensorError> {
+        let shape = shape.into();
+        let tensor = TensorCpu::from_mask(shape, flags)?.transfer_into(context);
+        let len = shape[0];
+        Ok(Self { tensor, len })
+    }
+
+    /// Wrap packed words holding `len` flags in each row.
+    pub fn from_packed(tensor: TensorGpu<u32, ReadWrite>, len: usize) -> Result<Self, TensorError> {
+        let words = TensorCpu::mask_words(len);
+        if tensor.shape[0] != words {
+            return Err(TensorError::Size(words, tensor.shape[0]));
+        }
+        Ok(Self { tensor, len })
+    }
+
+    /// Overwrite the mask with new flags of the same shape.
+    pub fn load(&self, flags: &[bool]) -> Result<(), TensorError> {
+        self.tensor
+            .load(&TensorCpu::from_mask(self.shape(), flags)?)
+    }
+
+    /// The packed words, of shape `[⌈C / 32⌉, T, B]`.
+    #[inline]
+    pub fn tensor(&self) -> &TensorGpu<u32, ReadWrite> {
+        &self.tensor
+    }
+
+    /// Check that the mask applies to a tensor of `shape`, with the mask optionally
+    /// broadcast along the token and batch axes.
+    pub fn check_broadcast(&self, shape: impl Into<Shape>) -> Result<(), TensorError> {
+        let shape = shape.into();
+        let mask = self.shape();
+        let broadcast = |axis: usize| mask[axis] == 1 || mask[axis] == shape[axis];
+        match mask[0] == shape[0] && broadcast(1) && broadcast(2) {
+            true => Ok(()),
+            false => Err(TensorError::Shape(mask, shape)),
+        }
+    }
+}
+
+impl TensorShape for TensorMask {
+    /// Shape of the flags, `[C, T, B]`.
+    #[inline]
+    fn shape(&self) -> Shape {
+        let shape = self.tensor.shape;
+        Shape::new(self.len, shape[1], shape[2], shape[3])
+    }
+}
+
 /// Like a reference to a tensor, but refer to a sub-chunk of it.
 #[derive(Debug, Clone)]
 pub struct TensorGpuView<'a, T: Scalar> {
@@ -899,7 +1224,7 @@ impl<T: Scalar> DeepClone for TensorGpu<T, ReadWrite> {
     fn deep_clone(&self) -> Self {
         let context = &self.context;
         let shape = self.shape;
-        let size = shape.len() as u64;
+        let size = self.buffer.size();
         let cloned: TensorGpu<_, _> = context.tensor_init(shape);
 
         let mut encoder = context.device.create_command_encoder(&Default::default());
@@ -949,20 +1274,7 @@ impl<T: Scalar> TryFrom<Vec<TensorCpu<T>>> for TensorStack<T> {
             .iter()
             .try_for_each(|batch| batch.check_shape([shape[0], batch.shape[1], 1, 1]))?;
 
-        let cursors = value
-            .iter()
-            .enumerate()
-            .scan(0, |token, (batch, tensor)| {
-                let len = tensor.shape[1];
-                let cursor = Cursor {
-                    batch,
-                    token: *token,
-                    len,
-                };
-                *token += len;
-                Some(cursor)
-            })
-            .collect_vec();
+        let cursors = Cursor::stack(value.iter().map(|tensor| tensor.shape[1]));
 
         let (shape, data) = value.into_iter().fold(
             (Shape::new(shape[0], 0, 1, 1), vec![]),
@@ -1044,8 +1356,8 @@ mod sealed {
 mod tests {
     use anyhow::Result;
 
-    use super::Shape;
-    use crate::tensor::{TensorCpu, TensorInit, TensorShape};
+    use super::{pack_cursors, Cursor, Shape};
+    use crate::tensor::{TensorCpu, TensorError, TensorInit, TensorShape};
 
     #[test]
     fn test_repeat() -> Result<()> {
@@ -1091,4 +1403,80 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_gather() -> Result<()> {
+        let shape = Shape::new(3, 4, 1, 1);
+        let x: Vec<_> = (0..12).map(|x| half::f16::from_f32(x as f32)).collect();
+        let x = TensorCpu::from_data(shape, x)?;
+
+        let y = x.gather(&[2, 0, 2])?;
+        y.check_shape([3, 3, 1, 1])?;
+        let y: TensorCpu<f32> = y.to_float();
+        assert_eq!(
+            y.to_vec(),
+            vec![6.0, 7.0, 8.0, 0.0, 1.0, 2.0, 6.0, 7.0, 8.0]
+        );
+
+        assert!(x.gather(&[4]).is_err());
+        x.gather(&[])?.check_shape([3, 0, 1, 1])?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pack_cursors() -> Result<()> {
+        fastrand::seed(42);
+
+        for _ in 0..1000 {
+            let num_batch = fastrand::usize(1..=Cursor::MAX_BATCH + 1);
+            let lens = (0..num_batch)
+                .map(|_| match fastrand::u8(0..4) {
+                    0 => 0,
+                    1 => 1,
+                    _ => fastrand::usize(0..=Cursor::MAX_LEN),
+                })
+                .collect::<Vec<_>>();
+            let num_token: usize = lens.iter().sum();
+
+            let cursors = Cursor::stack(lens.iter().copied());
+            let packed = pack_cursors(&cursors)?;
+            assert_eq!(packed.len(), num_token);
+
+            // every token is unpacked to the cursor of its own batch
+            let mut stack = 0;
+            for (batch, &len) in lens.iter().enumerate() {
+                for _ in 0..len {
+                    let cursor = Cursor::unpack(packed[stack]);
+                    assert_eq!(cursor, cursors[batch]);
+                    assert!(cursor.token <= stack && stack < cursor.token + cursor.len);
+                    stack += 1;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_cursors() {
+        let check = Cursor::check;
+        let cursor = |batch, token, len| Cursor { batch, token, len };
+
+        assert!(check(&Cursor::stack([2, 0, 3])).is_ok());
+        assert!(check(&[]).is_ok());
+
+        // batch too long to pack
+        let cursors = Cursor::stack([1, Cursor::MAX_LEN + 1]);
+        assert_eq!(check(&cursors), Err(TensorError::Cursor(cursors[1])));
+        // batches out of order
+        let cursors = [cursor(1, 0, 2), cursor(0, 2, 2)];
+        assert_eq!(check(&cursors), Err(TensorError::Cursor(cursors[0])));
+        // gap between batches
+        let cursors = [cursor(0, 0, 2), cursor(1, 3, 2)];
+        assert_eq!(check(&cursors), Err(TensorError::Cursor(cursors[1])));
+        // too many batches
+        let cursors = Cursor::stack(vec![1; Cursor::MAX_BATCH + 2]);
+        assert!(check(&cursors).is_err());
+    }
 }
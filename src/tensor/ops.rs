@@ -7,7 +7,8 @@ use wgpu::{
 
 use super::{
     kind::{Kind, ReadWrite, Uniform},
-    Shape, TensorError, TensorGpu, TensorGpuView, TensorScalar, TensorShape,
+    matrix::KQuant,
+    Shape, TensorError, TensorGpu, TensorGpuView, TensorMask, TensorScalar, TensorShape,
 };
 use crate::{
     context::{CachedPipeline, Macros},
@@ -37,7 +38,7 @@ impl<T: Scalar, K: Kind> TensorCommand<T, K> for CommandEncoder {
         destination: &TensorGpu<T, K>,
     ) -> Result<(), TensorError> {
         destination.check_shape(source.shape())?;
-        let size = destination.size() as u64;
+        let size = destination.buffer.size();
         self.copy_buffer_to_buffer(&source.buffer, 0, &destination.buffer, 0, size);
         Ok(())
     }
@@ -101,18 +102,28 @@ impl crate::context::Context {
             commands: &'b mut Vec<Vec<Atom<'a>>>,
             passes: &'b mut Vec<Atom<'a>>,
             op: &'a TensorOp,
+            limit: Option<usize>,
+            merge: bool,
         ) {
             match op {
                 TensorOp::Atom {
                     pipeline,
                     bindings,
                     dispatch,
-                } => passes.push(Atom {
-                    pipeline,
-                    bindings,
-                    dispatch,
-                }),
-                TensorOp::List(ops) => ops.iter().for_each(|op| flatten(commands, passes, op)),
+                } => {
+                    passes.push(Atom {
+                        pipeline,
+                        bindings,
+                        dispatch,
+                    });
+                    if limit.is_some_and(|limit| passes.len() >= limit) {
+                        commands.push(std::mem::take(passes));
+                    }
+                }
+                TensorOp::List(ops) => ops
+                    .iter()
+                    .for_each(|op| flatten(commands, passes, op, limit, merge)),
+                TensorOp::Sep if merge => {}
                 TensorOp::Sep => {
                     let mut temp = vec![];
                     std::mem::swap(&mut temp, passes);
@@ -123,7 +134,8 @@ impl crate::context::Context {
 
         let mut commands = vec![];
         let mut passes = vec![];
-        flatten(&mut commands, &mut passes, op);
+        let (limit, merge) = (self.max_dispatch(), self.single_submission());
+        flatten(&mut commands, &mut passes, op, limit, merge);
         commands.push(passes);
 
         commands
@@ -249,6 +261,16 @@ impl TensorOp {
     pub const NF4_BLOCK_SIZE: u32 = 64;
     pub const INT8_BLOCK_SIZE: u32 = 128;
 
+    /// Workgroup size of time mix kernels, which must hold whole heads: the stock 32 if heads
+    /// of `head_size` tile it, or one head per workgroup otherwise.
+    fn time_mix_block_size(head_size: usize) -> u32 {
+        const BLOCK_SIZE: u32 = 32;
+        match head_size as u32 / 4 {
+            stride if BLOCK_SIZE % stride == 0 => BLOCK_SIZE,
+            stride => stride,
+        }
+    }
+
     #[inline]
     fn block_count(count: u32, block_size: u32) -> u32 {
         (count + block_size - 1) / block_size
@@ -306,6 +328,68 @@ impl TensorOp {
         })
     }
 
+    /// Find the `K` largest elements of each row of `input`, sorted in descending order, with ties broken
+    /// by smaller indices. Apply on the head output to get the top token logits.
+    /// If a row has less than `K` elements, remaining slots have index `u32::MAX` and value `0.0`.
+    /// Fails if `K` is zero.
+    /// - `input` shape: `[C, T, B]`.
+    /// - `indices` shape: `[K, T, B]`.
+    /// - `values` shape: `[K, T, B]`.
+    pub fn top_k(
+        input: &TensorGpu<f32, ReadWrite>,
+        indices: &TensorGpu<u32, ReadWrite>,
+        values: &TensorGpu<f32, ReadWrite>,
+    ) -> Result<Self, TensorError> {
+        const BLOCK_SIZE: u32 = 128;
+
+        let shape = input.shape();
+        let k = indices.shape()[0];
+        if k == 0 {
+            return Err(TensorError::Empty);
+        }
+        indices.check_shape([k, shape[1], shape[2], 1])?;
+        values.check_shape([k, shape[1], shape[2], 1])?;
+
+        let context = input.context();
+        let pipeline = context.checkout_pipeline(
+            "top_k",
+            include_str!("../shaders/top_k.wgsl"),
+            "top_k",
+            None,
+            Macros::new()
+                .u32("BLOCK_SIZE", BLOCK_SIZE)
+                .u32("TOP_K", k as u32),
+        );
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: input.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: input.binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: indices.binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: values.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [1, shape[1] as u32, shape[2] as u32],
+        })
+    }
+
     /// Embedding on GPU.
     /// - `tokens` shape: `[T, B]`.
     /// - `input` shape: `[C, V]`.
@@ -622,6 +706,50 @@ impl TensorOp {
         })
     }
 
+    /// L2 normalization applied on each group of `x`, i.e., `x / max(|x|, eps)`.
+    /// - `x` shape: `[S, H, A]`.
+    pub fn l2_norm(x: &TensorGpu<impl Float, ReadWrite>, eps: f32) -> Result<Self, TensorError> {
+        const BLOCK_SIZE: u32 = 32;
+
+        let shape = {
+            let [index, head, token, _] = *x.shape();
+            x.check_shape([index, head, token, 1])?;
+            x.shape()
+        };
+
+        let context = x.context();
+        let pipeline = context.checkout_pipeline(
+            "l2_norm",
+            include_str!("../shaders/l2_norm.wgsl"),
+            "l2_norm",
+            None,
+            Macros::new()
+                .u32("BLOCK_SIZE", BLOCK_SIZE)
+                .tensor(x, None)
+                .f32("EPS", eps),
+        );
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: x.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: x.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [1, shape[1] as u32, shape[2] as u32],
+        })
+    }
+
     /// Fp32 matrix-vector multiplication.
     /// - `matrix` shape: `[C, R, B]`.
     /// - `input` shape: `[C, T, B]`.
@@ -897,6 +1025,86 @@ impl TensorOp {
         })
     }
 
+    /// GGML k-quant matrix-vector multiplication, dequantizing the super-blocks on the fly.
+    /// - `matrix` shape: `[Q, R, B]`, where `Q` is the number of bytes of the blocks in a row.
+    /// - `input` shape: `[C, T, B]`.
+    /// - `output` shape: `[R, T, B]`.
+    ///
+    /// Note: `C` must be multiples of 256.
+    pub fn matmul_vec_k_quant(
+        matrix: &TensorGpu<u8, ReadWrite>,
+        quant: KQuant,
+        input: TensorGpuView<impl Float>,
+        output: TensorGpuView<impl Float>,
+        active: Activation,
+    ) -> Result<Self, TensorError> {
+        const BLOCK_SIZE: u32 = 128;
+
+        let shape = {
+            let [m, n, b, _] = *output.shape();
+            let [k, _, _, _] = *input.shape();
+            let num_block = k / KQuant::BLOCK_SIZE;
+            matrix.check_shape([num_block * quant.block_bytes(), m, b, 1])?;
+            input.check_shape([num_block * KQuant::BLOCK_SIZE, n, b, 1])?;
+            output.check_shape([m, n, b, 1])?;
+            output.shape()
+        };
+
+        let name = match quant {
+            KQuant::Q4K => "matmul_vec_q4k",
+            KQuant::Q5K => "matmul_vec_q5k",
+        };
+        let context = matrix.context();
+        let pipeline = context.checkout_pipeline(
+            name,
+            include_str!("../shaders/matmul_vec_k_quant.wgsl"),
+            "matmul",
+            None,
+            Macros::new()
+                .u32("BLOCK_SIZE", BLOCK_SIZE)
+                .bool("Q5K", quant == KQuant::Q5K)
+                .tensor(&input, Some("IN"))
+                .tensor(&output, Some("OUT"))
+                .custom(active, Some("ACT")),
+        );
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: matrix.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: input.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: output.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: matrix.binding(),
+                },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: input.binding(),
+                },
+                BindGroupEntry {
+                    binding: 5,
+                    resource: output.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [matrix.shape[1] as u32 / 4, shape[1] as u32, shape[2] as u32],
+        })
+    }
+
     /// Fp16 matrix-matrix multiplication.
     /// - `matrix` shape: `[K, M, B]`.
     /// - `input` shape: `[K, N, B]`.
@@ -1274,6 +1482,78 @@ impl TensorOp {
         })
     }
 
+    /// Interpolate `output` towards `input` by `factor`, i.e., `output + (input - output) * factor`.
+    /// - `input` shape: `[C, T, B]`.
+    /// - `output` shape: `[C, T, B]`.
+    /// - `factor` shape: `[C, T, B]`.
+    pub fn lerp(
+        input: TensorGpuView<impl Float>,
+        output: TensorGpuView<impl Float>,
+        factor: TensorGpuView<impl Float>,
+    ) -> Result<Self, TensorError> {
+        const BLOCK_SIZE: u32 = 128;
+
+        let shape = {
+            let [index, token, batch, _] = *output.shape();
+            input.check_shape([index, token, batch, 1])?;
+            factor.check_shape([index, token, batch, 1])?;
+            output.shape()
+        };
+
+        let context = output.context();
+        let pipeline = context.checkout_pipeline(
+            "lerp",
+            include_str!("../shaders/lerp.wgsl"),
+            "lerp",
+            None,
+            Macros::new()
+                .u32("BLOCK_SIZE", BLOCK_SIZE)
+                .tensor(&factor, Some("FACTOR"))
+                .tensor(&input, Some("IN"))
+                .tensor(&output, Some("OUT")),
+        );
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: factor.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: input.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: output.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: factor.binding(),
+                },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: input.binding(),
+                },
+                BindGroupEntry {
+                    binding: 5,
+                    resource: output.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [
+                Self::block_count(shape[0] as u32 / 4, BLOCK_SIZE),
+                shape[1] as u32,
+                shape[2] as u32,
+            ],
+        })
+    }
+
     pub fn token_shift(
         cursors: &TensorGpu<u32, ReadWrite>,
         time_mix: TensorGpuView<impl Float>,
@@ -1454,9 +1734,8 @@ impl TensorOp {
         r: &TensorGpu<T, ReadWrite>,
         x: &TensorGpu<T, ReadWrite>,
     ) -> Result<Self, TensorError> {
-        const BLOCK_SIZE: u32 = 32;
-
         let shape = x.shape();
+        let block_size = Self::time_mix_block_size(shape[0]);
         let dim = shape[0] * shape[1];
 
         k.check_shape(shape)?;
@@ -1472,7 +1751,7 @@ impl TensorOp {
             include_str!("../shaders/time_mix_v5.wgsl"),
             "time_mix",
             None,
-            Macros::new().u32("BLOCK_SIZE", BLOCK_SIZE).tensor(x, None),
+            Macros::new().u32("BLOCK_SIZE", block_size).tensor(x, None),
         );
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
@@ -1524,7 +1803,7 @@ impl TensorOp {
         Ok(Self::Atom {
             pipeline,
             bindings,
-            dispatch: [Self::block_count(dim as u32 / 4, BLOCK_SIZE), 1, 1],
+            dispatch: [Self::block_count(dim as u32 / 4, block_size), 1, 1],
         })
     }
 
@@ -1539,9 +1818,8 @@ impl TensorOp {
         r: &TensorGpu<T, ReadWrite>,
         x: &TensorGpu<T, ReadWrite>,
     ) -> Result<Self, TensorError> {
-        const BLOCK_SIZE: u32 = 32;
-
         let shape = x.shape();
+        let block_size = Self::time_mix_block_size(shape[0]);
         let dim = shape[0] * shape[1];
 
         k.check_shape(shape)?;
@@ -1557,7 +1835,7 @@ impl TensorOp {
             include_str!("../shaders/time_mix_v6.wgsl"),
             "time_mix",
             None,
-            Macros::new().u32("BLOCK_SIZE", BLOCK_SIZE).tensor(x, None),
+            Macros::new().u32("BLOCK_SIZE", block_size).tensor(x, None),
         );
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
@@ -1609,29 +1887,45 @@ impl TensorOp {
         Ok(Self::Atom {
             pipeline,
             bindings,
-            dispatch: [Self::block_count(dim as u32 / 4, BLOCK_SIZE), 1, 1],
+            dispatch: [Self::block_count(dim as u32 / 4, block_size), 1, 1],
         })
     }
 
-    pub fn silu(
-        input: &TensorGpu<impl Float, ReadWrite>,
-        output: &TensorGpu<impl Float, ReadWrite>,
+    /// The time mix of RWKV v7, which updates the WKV state of each head with
+    /// `S = S * w + (S · -kk) * (kk * a) + v * k` and reads it out with the receptance.
+    /// - `w` is the decay before activation, i.e., the actual decay is `exp(-exp(-0.5) * sigmoid(w))`.
+    /// - `x` holds the receptance on input, and is overwritten with the output.
+    ///
+    /// Unlike earlier versions, the token shift state is not updated here; see [`TensorOp::channel_mix_v7`].
+    #[allow(clippy::too_many_arguments)]
+    pub fn time_mix_v7<T: Float>(
+        cursors: &TensorGpu<u32, ReadWrite>,
+        state: TensorGpuView<f32>,
+        w: &TensorGpu<f32, ReadWrite>,
+        k: &TensorGpu<T, ReadWrite>,
+        v: &TensorGpu<T, ReadWrite>,
+        a: &TensorGpu<T, ReadWrite>,
+        kk: &TensorGpu<T, ReadWrite>,
+        x: &TensorGpu<T, ReadWrite>,
     ) -> Result<Self, TensorError> {
-        const BLOCK_SIZE: u32 = 128;
+        let shape = x.shape();
+        let block_size = Self::time_mix_block_size(shape[0]);
+        let dim = shape[0] * shape[1];
 
-        let shape = output.shape();
-        input.check_shape(shape)?;
+        w.check_shape(shape)?;
+        k.check_shape(shape)?;
+        v.check_shape(shape)?;
+        a.check_shape(shape)?;
+        kk.check_shape(shape)?;
+        state.check_shape([dim, shape[0] + 1, state.shape()[2], 1])?;
 
-        let context = output.context();
+        let context = x.context();
         let pipeline = context.checkout_pipeline(
-            "silu",
-            include_str!("../shaders/silu.wgsl"),
-            "silu",
+            "time_mix_v7",
+            include_str!("../shaders/time_mix_v7.wgsl"),
+            "time_mix",
             None,
-            Macros::new()
-                .u32("BLOCK_SIZE", BLOCK_SIZE)
-                .tensor(input, Some("IN"))
-                .tensor(output, Some("OUT")),
+            Macros::new().u32("BLOCK_SIZE", block_size).tensor(x, None),
         );
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
@@ -1639,15 +1933,217 @@ impl TensorOp {
             entries: &[
                 BindGroupEntry {
                     binding: 0,
-                    resource: output.meta_binding(),
+                    resource: x.meta_binding(),
                 },
                 BindGroupEntry {
                     binding: 1,
-                    resource: input.binding(),
+                    resource: state.meta_binding(),
                 },
                 BindGroupEntry {
                     binding: 2,
-                    resource: output.binding(),
+                    resource: cursors.binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: w.binding(),
+                },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: state.binding(),
+                },
+                BindGroupEntry {
+                    binding: 5,
+                    resource: k.binding(),
+                },
+                BindGroupEntry {
+                    binding: 6,
+                    resource: v.binding(),
+                },
+                BindGroupEntry {
+                    binding: 7,
+                    resource: a.binding(),
+                },
+                BindGroupEntry {
+                    binding: 8,
+                    resource: kk.binding(),
+                },
+                BindGroupEntry {
+                    binding: 9,
+                    resource: x.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [Self::block_count(dim as u32 / 4, block_size), 1, 1],
+        })
+    }
+
+    /// Add the bonus of the current token of RWKV v7 to `x`, i.e., `x += sum(r * k * u) * v` of each head.
+    /// - `u` shape: `[S, H, 1]`.
+    /// - `r`, `k`, `v` and `x` shape: `[S, H, A]`.
+    pub fn time_first_v7<T: Float>(
+        u: &TensorGpu<f16, ReadWrite>,
+        r: &TensorGpu<T, ReadWrite>,
+        k: &TensorGpu<T, ReadWrite>,
+        v: &TensorGpu<T, ReadWrite>,
+        x: &TensorGpu<T, ReadWrite>,
+    ) -> Result<Self, TensorError> {
+        const BLOCK_SIZE: u32 = 32;
+
+        let shape = {
+            let [index, head, token, _] = *x.shape();
+            x.check_shape([index, head, token, 1])?;
+            r.check_shape([index, head, token, 1])?;
+            k.check_shape([index, head, token, 1])?;
+            v.check_shape([index, head, token, 1])?;
+            u.check_shape([index, head, 1, 1])?;
+            x.shape()
+        };
+
+        let context = x.context();
+        let pipeline = context.checkout_pipeline(
+            "time_first_v7",
+            include_str!("../shaders/time_first_v7.wgsl"),
+            "time_first",
+            None,
+            Macros::new().u32("BLOCK_SIZE", BLOCK_SIZE).tensor(x, None),
+        );
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: x.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: u.binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: r.binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: k.binding(),
+                },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: v.binding(),
+                },
+                BindGroupEntry {
+                    binding: 5,
+                    resource: x.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [1, shape[1] as u32, shape[2] as u32],
+        })
+    }
+
+    /// Scale the key of RWKV v7 by the in-context learning rate, i.e., `k *= 1 + (a - 1) * p`.
+    /// - `p` shape: `[C, 1, 1]`.
+    /// - `a` and `k` shape: `[C, A, 1]`.
+    pub fn control_k_v7<T: Float>(
+        p: &TensorGpu<f16, ReadWrite>,
+        a: &TensorGpu<T, ReadWrite>,
+        k: &TensorGpu<T, ReadWrite>,
+    ) -> Result<Self, TensorError> {
+        const BLOCK_SIZE: u32 = 128;
+
+        let shape = {
+            let [index, token, _, _] = *k.shape();
+            k.check_shape([index, token, 1, 1])?;
+            a.check_shape([index, token, 1, 1])?;
+            p.check_shape([index, 1, 1, 1])?;
+            k.shape()
+        };
+
+        let context = k.context();
+        let pipeline = context.checkout_pipeline(
+            "control_k_v7",
+            include_str!("../shaders/control_k_v7.wgsl"),
+            "control_k",
+            None,
+            Macros::new().u32("BLOCK_SIZE", BLOCK_SIZE).tensor(k, None),
+        );
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: k.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: p.binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: a.binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: k.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [
+                Self::block_count(shape[0] as u32 / 4, BLOCK_SIZE),
+                shape[1] as u32,
+                1,
+            ],
+        })
+    }
+
+    pub fn silu(
+        input: &TensorGpu<impl Float, ReadWrite>,
+        output: &TensorGpu<impl Float, ReadWrite>,
+    ) -> Result<Self, TensorError> {
+        const BLOCK_SIZE: u32 = 128;
+
+        let shape = output.shape();
+        input.check_shape(shape)?;
+
+        let context = output.context();
+        let pipeline = context.checkout_pipeline(
+            "silu",
+            include_str!("../shaders/silu.wgsl"),
+            "silu",
+            None,
+            Macros::new()
+                .u32("BLOCK_SIZE", BLOCK_SIZE)
+                .tensor(input, Some("IN"))
+                .tensor(output, Some("OUT")),
+        );
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: output.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: input.binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: output.binding(),
                 },
             ],
         })];
@@ -1701,6 +2197,44 @@ impl TensorOp {
         })
     }
 
+    pub fn sigmoid(x: &TensorGpu<impl Float, ReadWrite>) -> Result<Self, TensorError> {
+        const BLOCK_SIZE: u32 = 128;
+
+        let shape = x.shape();
+        let context = x.context();
+        let pipeline = context.checkout_pipeline(
+            "sigmoid",
+            include_str!("../shaders/activation.wgsl"),
+            "act_sigmoid",
+            None,
+            Macros::new().u32("BLOCK_SIZE", BLOCK_SIZE).tensor(x, None),
+        );
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: x.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: x.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [
+                Self::block_count(shape[0] as u32 / 4, BLOCK_SIZE),
+                shape[1] as u32,
+                shape[2] as u32,
+            ],
+        })
+    }
+
     pub fn opposite_exp(x: &TensorGpu<f32, ReadWrite>) -> Result<Self, TensorError> {
         const BLOCK_SIZE: u32 = 128;
 
@@ -1883,6 +2417,70 @@ impl TensorOp {
         })
     }
 
+    /// Store the last token of each batch in `x` into `state`, and then replace `x` with `v`.
+    /// - `state` shape: `[C, _, B]`, of which the first token is written.
+    pub fn channel_mix_v7<T: Float>(
+        cursors: &TensorGpu<u32, ReadWrite>,
+        state: TensorGpuView<f32>,
+        v: &TensorGpu<T, ReadWrite>,
+        x: &TensorGpu<T, ReadWrite>,
+    ) -> Result<Self, TensorError> {
+        const BLOCK_SIZE: u32 = 128;
+
+        let shape = x.shape();
+        v.check_shape(shape)?;
+        state.check_shape([shape[0], state.shape()[1], state.shape()[2], 1])?;
+
+        let context = x.context();
+        let pipeline = context.checkout_pipeline(
+            "channel_mix_v7",
+            include_str!("../shaders/channel_mix.wgsl"),
+            "channel_mix_v7",
+            None,
+            Macros::new().u32("BLOCK_SIZE", BLOCK_SIZE).tensor(x, None),
+        );
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: x.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: state.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: cursors.binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: state.binding(),
+                },
+                BindGroupEntry {
+                    binding: 5,
+                    resource: v.binding(),
+                },
+                BindGroupEntry {
+                    binding: 6,
+                    resource: x.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [
+                Self::block_count(shape[0] as u32 / 4, BLOCK_SIZE),
+                shape[1] as u32,
+                1,
+            ],
+        })
+    }
+
     /// Copy the content of `input` into `output` of the same shape.
     pub fn blit(
         input: TensorGpuView<impl Float>,
@@ -2066,17 +2664,194 @@ impl TensorOp {
             _ => [16, 16],
         };
 
-        let context = output.context();
+        let context = output.context();
+        let pipeline = context.checkout_pipeline(
+            "blend",
+            include_str!("../shaders/blend.wgsl"),
+            "blend",
+            None,
+            Macros::new()
+                .u32("BLOCK_SIZE_X", block_size[0])
+                .u32("BLOCK_SIZE_Y", block_size[1])
+                .tensor(input, Some("IN"))
+                .tensor(output, Some("OUT")),
+        );
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: input.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: output.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: factor.binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: input.binding(),
+                },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: output.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [
+                Self::block_count(shape[0] as u32 / 4, block_size[0]),
+                Self::block_count(shape[1] as u32, block_size[1]),
+                shape[2] as u32,
+            ],
+        })
+    }
+
+    pub fn blend_lora(
+        factor: &TensorGpu<f32, Uniform>,
+        xa: TensorGpuView<f16>,
+        xb: TensorGpuView<f16>,
+        output: TensorGpuView<f16>,
+    ) -> Result<Self, TensorError> {
+        const BLOCK_SIZE: u32 = 8;
+
+        let shape = output.shape();
+        factor.check_shape([4, 1, 1, 1])?;
+        xa.check_shape([xa.shape()[0], shape[0], shape[2], 1])?;
+        xb.check_shape([xb.shape()[0], shape[1], shape[2], 1])?;
+
+        let context = output.context();
+        let pipeline = context.checkout_pipeline(
+            "blend_lora",
+            include_str!("../shaders/blend_lora.wgsl"),
+            "blend_lora",
+            None,
+            Macros::new().u32("BLOCK_SIZE", BLOCK_SIZE),
+        );
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: xa.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: xb.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: output.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: factor.binding(),
+                },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: xa.binding(),
+                },
+                BindGroupEntry {
+                    binding: 5,
+                    resource: xb.binding(),
+                },
+                BindGroupEntry {
+                    binding: 6,
+                    resource: output.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [
+                Self::block_count(Self::block_count(shape[0] as u32, 4), BLOCK_SIZE),
+                Self::block_count(Self::block_count(shape[1] as u32, 4), BLOCK_SIZE),
+                shape[2] as u32,
+            ],
+        })
+    }
+
+    pub fn discount(
+        x: &TensorGpu<impl Float, ReadWrite>,
+        factor: f32,
+        bias: f32,
+    ) -> Result<Self, TensorError> {
+        const BLOCK_SIZE: u32 = 128;
+
+        let shape = x.shape();
+        let context = x.context();
+        let pipeline = context.checkout_pipeline(
+            "discount",
+            include_str!("../shaders/discount.wgsl"),
+            "discount",
+            None,
+            Macros::new()
+                .u32("BLOCK_SIZE", BLOCK_SIZE)
+                .tensor(x, None)
+                .f32("FACTOR", factor)
+                .f32("BIAS", bias),
+        );
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: x.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: x.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [
+                Self::block_count(shape[0] as u32 / 4, BLOCK_SIZE),
+                shape[1] as u32,
+                shape[2] as u32,
+            ],
+        })
+    }
+
+    /// Fill the elements of `x` that are masked out (whose flags in `mask` are unset) with `value`.
+    /// - `mask` shape: `[C, T, B]`, with `T` and `B` optionally being 1 for broadcasting.
+    /// - `x` shape: `[C, T, B]`.
+    ///
+    /// Note that for half-precision `x`, `value` should be finite (e.g., `f16::MIN` rather than `-inf`).
+    pub fn masked_fill(
+        mask: &TensorMask,
+        x: &TensorGpu<impl Float, ReadWrite>,
+        value: f32,
+    ) -> Result<Self, TensorError> {
+        const BLOCK_SIZE: u32 = 128;
+
+        let shape = x.shape();
+        mask.check_broadcast(shape)?;
+
+        let context = x.context();
         let pipeline = context.checkout_pipeline(
-            "blend",
-            include_str!("../shaders/blend.wgsl"),
-            "blend",
+            "masked_fill",
+            include_str!("../shaders/masked_fill.wgsl"),
+            "masked_fill",
             None,
             Macros::new()
-                .u32("BLOCK_SIZE_X", block_size[0])
-                .u32("BLOCK_SIZE_Y", block_size[1])
-                .tensor(input, Some("IN"))
-                .tensor(output, Some("OUT")),
+                .u32("BLOCK_SIZE", BLOCK_SIZE)
+                .tensor(x, None)
+                .u32("VALUE", value.to_bits()),
         );
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
@@ -2084,23 +2859,19 @@ impl TensorOp {
             entries: &[
                 BindGroupEntry {
                     binding: 0,
-                    resource: input.meta_binding(),
+                    resource: x.meta_binding(),
                 },
                 BindGroupEntry {
                     binding: 1,
-                    resource: output.meta_binding(),
+                    resource: mask.tensor().meta_binding(),
                 },
                 BindGroupEntry {
                     binding: 2,
-                    resource: factor.binding(),
+                    resource: mask.tensor().binding(),
                 },
                 BindGroupEntry {
                     binding: 3,
-                    resource: input.binding(),
-                },
-                BindGroupEntry {
-                    binding: 4,
-                    resource: output.binding(),
+                    resource: x.binding(),
                 },
             ],
         })];
@@ -2109,33 +2880,84 @@ impl TensorOp {
             pipeline,
             bindings,
             dispatch: [
-                Self::block_count(shape[0] as u32 / 4, block_size[0]),
-                Self::block_count(shape[1] as u32, block_size[1]),
+                Self::block_count(shape[0] as u32 / 4, BLOCK_SIZE),
+                shape[1] as u32,
                 shape[2] as u32,
             ],
         })
     }
 
-    pub fn blend_lora(
-        factor: &TensorGpu<f32, Uniform>,
-        xa: TensorGpuView<f16>,
-        xb: TensorGpuView<f16>,
-        output: TensorGpuView<f16>,
+    /// Copy elements of `input` into `output` where their flags in `mask` are set,
+    /// leaving the masked out elements untouched.
+    /// - `mask` shape: `[C, T, B]`, with `T` and `B` optionally being 1 for broadcasting.
+    /// - `input` shape: `[C, T, B]`.
+    /// - `output` shape: `[C, T, B]`.
+    pub fn masked_blit(
+        mask: &TensorMask,
+        input: TensorGpuView<impl Float>,
+        output: TensorGpuView<impl Float>,
     ) -> Result<Self, TensorError> {
-        const BLOCK_SIZE: u32 = 8;
+        let shape = output.shape();
+        input.check_shape(shape)?;
+        Self::masked_binary("masked_blit", mask, input, output)
+    }
+
+    /// Add elements of `input` to `output` where their flags in `mask` are set,
+    /// leaving the masked out elements untouched.
+    /// - `mask` shape: `[C, T, B]`, with `T` and `B` optionally being 1 for broadcasting.
+    /// - `input` shape: `[C, 1, B]` or `[C, T, B]`.
+    /// - `output` shape: `[C, T, B]`.
+    pub fn masked_add(
+        mask: &TensorMask,
+        input: TensorGpuView<impl Float>,
+        output: TensorGpuView<impl Float>,
+    ) -> Result<Self, TensorError> {
+        let [index, token, batch, _] = *output.shape();
+        input
+            .check_shape([index, 1, batch, 1])
+            .or(input.check_shape([index, token, batch, 1]))?;
+        Self::masked_binary("masked_add", mask, input, output)
+    }
+
+    /// Softmax over the elements of `x` whose flags in `mask` are set. Masked out elements get
+    /// probability 0, as if filled with a large negative value by [`TensorOp::masked_fill`] first.
+    /// - `mask` shape: `[C, T, B]`, with `T` and `B` optionally being 1 for broadcasting.
+    /// - `x` shape: `[C, T, B]`.
+    ///
+    /// Note that if all elements of a row are masked out, the row ends up uniform.
+    pub fn masked_softmax(
+        mask: &TensorMask,
+        x: &TensorGpu<impl Float, ReadWrite>,
+    ) -> Result<Self, TensorError> {
+        // finite so that it also fits half-precision `x`
+        let value = f16::MIN.to_f32();
+        Ok(Self::List(vec![
+            Self::masked_fill(mask, x, value)?,
+            Self::softmax(x)?,
+        ]))
+    }
+
+    fn masked_binary(
+        entry_point: &str,
+        mask: &TensorMask,
+        input: TensorGpuView<impl Float>,
+        output: TensorGpuView<impl Float>,
+    ) -> Result<Self, TensorError> {
+        const BLOCK_SIZE: u32 = 128;
 
         let shape = output.shape();
-        factor.check_shape([4, 1, 1, 1])?;
-        xa.check_shape([xa.shape()[0], shape[0], shape[2], 1])?;
-        xb.check_shape([xb.shape()[0], shape[1], shape[2], 1])?;
+        mask.check_broadcast(shape)?;
 
         let context = output.context();
         let pipeline = context.checkout_pipeline(
-            "blend_lora",
-            include_str!("../shaders/blend_lora.wgsl"),
-            "blend_lora",
+            entry_point,
+            include_str!("../shaders/masked.wgsl"),
+            entry_point,
             None,
-            Macros::new().u32("BLOCK_SIZE", BLOCK_SIZE),
+            Macros::new()
+                .u32("BLOCK_SIZE", BLOCK_SIZE)
+                .tensor(&input, Some("IN"))
+                .tensor(&output, Some("OUT")),
         );
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
@@ -2143,30 +2965,26 @@ impl TensorOp {
             entries: &[
                 BindGroupEntry {
                     binding: 0,
-                    resource: xa.meta_binding(),
+                    resource: input.meta_binding(),
                 },
                 BindGroupEntry {
                     binding: 1,
-                    resource: xb.meta_binding(),
+                    resource: output.meta_binding(),
                 },
                 BindGroupEntry {
                     binding: 2,
-                    resource: output.meta_binding(),
+                    resource: mask.tensor().meta_binding(),
                 },
                 BindGroupEntry {
                     binding: 3,
-                    resource: factor.binding(),
+                    resource: mask.tensor().binding(),
                 },
                 BindGroupEntry {
                     binding: 4,
-                    resource: xa.binding(),
+                    resource: input.binding(),
                 },
                 BindGroupEntry {
                     binding: 5,
-                    resource: xb.binding(),
-                },
-                BindGroupEntry {
-                    binding: 6,
                     resource: output.binding(),
                 },
             ],
@@ -2176,32 +2994,34 @@ impl TensorOp {
             pipeline,
             bindings,
             dispatch: [
-                Self::block_count(Self::block_count(shape[0] as u32, 4), BLOCK_SIZE),
-                Self::block_count(Self::block_count(shape[1] as u32, 4), BLOCK_SIZE),
+                Self::block_count(shape[0] as u32 / 4, BLOCK_SIZE),
+                shape[1] as u32,
                 shape[2] as u32,
             ],
         })
     }
 
-    pub fn discount(
+    /// Fill `x` with uniformly distributed random numbers in `[0, 1)` and advance the generator.
+    /// The numbers are generated by Philox4x32-10, keyed by the seed of each batch slot,
+    /// so the same seed always produces the same sequence of draws.
+    /// - `state` shape: `[4, 1, B]`, created by [`TensorCpu::from_seeds`].
+    /// - `x` shape: `[C, T, B]`.
+    pub fn random_uniform(
+        state: &TensorGpu<u32, ReadWrite>,
         x: &TensorGpu<impl Float, ReadWrite>,
-        factor: f32,
-        bias: f32,
     ) -> Result<Self, TensorError> {
         const BLOCK_SIZE: u32 = 128;
 
         let shape = x.shape();
+        state.check_shape([4, 1, shape[2], 1])?;
+
         let context = x.context();
         let pipeline = context.checkout_pipeline(
-            "discount",
-            include_str!("../shaders/discount.wgsl"),
-            "discount",
+            "random_uniform",
+            include_str!("../shaders/random.wgsl"),
+            "random_uniform",
             None,
-            Macros::new()
-                .u32("BLOCK_SIZE", BLOCK_SIZE)
-                .tensor(x, None)
-                .f32("FACTOR", factor)
-                .f32("BIAS", bias),
+            Macros::new().u32("BLOCK_SIZE", BLOCK_SIZE).tensor(x, None),
         );
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
@@ -2213,12 +3033,15 @@ impl TensorOp {
                 },
                 BindGroupEntry {
                     binding: 1,
+                    resource: state.binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
                     resource: x.binding(),
                 },
             ],
         })];
-
-        Ok(Self::Atom {
+        let random = Self::Atom {
             pipeline,
             bindings,
             dispatch: [
@@ -2226,7 +3049,30 @@ impl TensorOp {
                 shape[1] as u32,
                 shape[2] as u32,
             ],
-        })
+        };
+
+        let pipeline = context.checkout_pipeline(
+            "random_advance",
+            include_str!("../shaders/random.wgsl"),
+            "random_advance",
+            None,
+            Macros::new().u32("BLOCK_SIZE", BLOCK_SIZE),
+        );
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.layout,
+            entries: &[BindGroupEntry {
+                binding: 1,
+                resource: state.binding(),
+            }],
+        })];
+        let advance = Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [Self::block_count(shape[2] as u32, BLOCK_SIZE), 1, 1],
+        };
+
+        Ok(Self::List(vec![random, advance]))
     }
 
     pub fn quantize_mat_int8(
@@ -2455,8 +3301,13 @@ mod tests {
 
     use super::TensorOp;
     use crate::{
-        context::{Context, ContextBuilder, InstanceExt},
-        tensor::{ops::Activation, Shape, TensorGpu},
+        context::{Context, ContextBuilder, InstanceExt, MemoryKind},
+        tensor::{
+            kind::ReadWrite,
+            matrix::{KQuant, Matrix},
+            ops::Activation,
+            DeepClone, Shape, TensorCpu, TensorGpu, TensorInit, TensorInto, TensorMask,
+        },
     };
 
     fn is_approx(a: f32, b: f32) -> bool {
@@ -2477,6 +3328,34 @@ mod tests {
         Ok(context)
     }
 
+    #[test]
+    fn test_memory_report() -> Result<()> {
+        let context = match pollster::block_on(create_context()) {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        let weights = context.with_memory_kind(MemoryKind::Weights);
+        let state = context.with_memory_kind(MemoryKind::State);
+        let _w: TensorGpu<f32, ReadWrite> = weights.zeros([1024, 4, 1, 1]);
+        let _s: TensorGpu<f32, ReadWrite> = state.zeros([1024, 2, 1, 1]);
+        let x: TensorGpu<f32, ReadWrite> = context.zeros([1024, 1, 1, 1]);
+
+        let report = context.memory_report();
+        assert_eq!(report.weights, 1024 * 4 * 4);
+        assert_eq!(report.state, 1024 * 2 * 4);
+        assert_eq!(report.scratch, 1024 * 4);
+        assert_eq!(report.staging, 0);
+
+        // buffers of dropped tensors stay in the cache as idle
+        drop(x);
+        let report = context.memory_report();
+        assert_eq!(report.scratch, 0);
+        assert_eq!(report.idle, 1024 * 4);
+
+        Ok(())
+    }
+
     #[test]
     fn test_softmax() -> Result<()> {
         let context = match pollster::block_on(create_context()) {
@@ -2497,7 +3376,7 @@ mod tests {
         let x_dev: TensorGpu<_, _> = context.tensor_from_data(shape, x.clone())?;
         let softmax = TensorOp::softmax(&x_dev)?;
 
-        context.queue.submit(context.encode(&softmax));
+        context.submit(context.encode(&softmax));
 
         let x_host = x_dev.back_in_place().to_vec();
 
@@ -2559,7 +3438,7 @@ mod tests {
         // let s_dev = context.tensor_init(shape);
 
         let layer_norm = TensorOp::layer_norm(&w_dev, &b_dev, &x_dev, EPS)?;
-        context.queue.submit(context.encode(&layer_norm));
+        context.submit(context.encode(&layer_norm));
 
         let x_host = x_dev.back_in_place().to_vec();
         // let s_host = s_dev.back_in_place().to_vec();
@@ -2571,7 +3450,7 @@ mod tests {
             TensorOp::recenter(&x_dev)?,
             TensorOp::rms_norm(&w_dev, &b_dev, &x_dev, EPS)?,
         ]);
-        context.queue.submit(context.encode(&ops));
+        context.submit(context.encode(&ops));
 
         let x_rms_host = x_dev.back_in_place().to_vec();
 
@@ -2693,7 +3572,7 @@ mod tests {
         ]);
 
         // profiler.resolve_queries(&mut encoder);
-        context.queue.submit(context.encode(&ops));
+        context.submit(context.encode(&ops));
 
         let output_host = output_dev.back_in_place();
         let output_host = Vec::from(output_host);
@@ -2815,7 +3694,7 @@ mod tests {
                 Activation::None,
             )?,
         ]);
-        context.queue.submit(context.encode(&ops));
+        context.submit(context.encode(&ops));
 
         let matrix_u8_host = matrix_u8_dev.back_in_place().to_vec();
         let output_host = output_dev.back_in_place().to_vec();
@@ -2983,7 +3862,7 @@ mod tests {
                 Activation::None,
             )?,
         ]);
-        context.queue.submit(context.encode(&ops));
+        context.submit(context.encode(&ops));
 
         let matrix_u4_host = matrix_u4_dev.back_in_place().to_vec();
         let absmax_host = absmax_dev.back_in_place().to_vec();
@@ -3050,6 +3929,116 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_matmul_k_quant() -> Result<()> {
+        let context = match pollster::block_on(create_context()) {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+        fastrand::seed(42);
+
+        const C: usize = 2560;
+        const R: usize = 256;
+        const T: usize = 8;
+        const BLOCK_SIZE: usize = KQuant::BLOCK_SIZE;
+
+        fn scale_min(j: usize, scales: &[u8]) -> (f32, f32) {
+            let (scale, min) = match j {
+                0..=3 => (scales[j] & 63, scales[j + 4] & 63),
+                _ => (
+                    (scales[j + 4] & 0xf) | ((scales[j - 4] >> 6) << 4),
+                    (scales[j + 4] >> 4) | ((scales[j] >> 6) << 4),
+                ),
+            };
+            (scale as f32, min as f32)
+        }
+
+        fn dequantize(quant: KQuant, block: &[u8]) -> Vec<f32> {
+            let d = f16::from_le_bytes([block[0], block[1]]).to_f32();
+            let dmin = f16::from_le_bytes([block[2], block[3]]).to_f32();
+            let scales = &block[4..16];
+            let (qh, qs) = match quant {
+                KQuant::Q4K => (None, &block[16..144]),
+                KQuant::Q5K => (Some(&block[16..48]), &block[48..176]),
+            };
+            (0..BLOCK_SIZE)
+                .map(|index| {
+                    let (j, l) = (index / 32, index % 32);
+                    let (scale, min) = scale_min(j, scales);
+                    let mut q = (qs[32 * (j / 2) + l] >> (4 * (j % 2))) & 0xf;
+                    if let Some(qh) = qh {
+                        q += ((qh[l] >> j) & 1) << 4;
+                    }
+                    d * scale * q as f32 - dmin * min
+                })
+                .collect()
+        }
+
+        let input_f16 = vec![(); C * T]
+            .into_iter()
+            .map(|_| f16::from_f32(fastrand::f32() - 0.5))
+            .collect_vec();
+        let input_shape = Shape::new(C, T, 1, 1);
+        let output_shape = Shape::new(R, T, 1, 1);
+
+        for quant in [KQuant::Q4K, KQuant::Q5K] {
+            let bytes = quant.block_bytes();
+            let blocks = vec![(); C / BLOCK_SIZE * R]
+                .into_iter()
+                .flat_map(|_| {
+                    let d = f16::from_f32(0.01 * fastrand::f32());
+                    let dmin = f16::from_f32(0.01 * fastrand::f32());
+                    let mut block = vec![0u8; bytes];
+                    fastrand::fill(&mut block);
+                    block[0..2].copy_from_slice(&d.to_le_bytes());
+                    block[2..4].copy_from_slice(&dmin.to_le_bytes());
+                    block
+                })
+                .collect_vec();
+            let matrix = blocks
+                .chunks_exact(bytes)
+                .flat_map(|block| dequantize(quant, block))
+                .collect_vec();
+
+            let matrix_dev = Matrix::from_k_quant(&context, quant, [C, R, 1, 1], &blocks)?;
+            let input_dev: TensorGpu<_, _> =
+                context.tensor_from_data(input_shape, input_f16.clone())?;
+            let output_dev: TensorGpu<f32, _> = context.tensor_init(output_shape);
+
+            let ops = matrix_dev.matmul_op(
+                input_dev.view(.., .., .., ..)?,
+                output_dev.view(.., .., .., ..)?,
+                Activation::None,
+                true,
+            )?;
+            context.submit(context.encode(&ops));
+            let output_host = output_dev.back_in_place().to_vec();
+
+            let mut ans = vec![0.0; output_host.len()];
+            for token in 0..T {
+                for line in 0..R {
+                    let matrix = &matrix[line * C..(line + 1) * C];
+                    let input = &input_f16[token * C..(token + 1) * C];
+                    ans[token * R + line] = matrix
+                        .iter()
+                        .zip_eq(input.iter())
+                        .fold(0.0f32, |acc, (x, y)| acc + x * y.to_f32());
+                }
+            }
+
+            itertools::zip_eq(output_host, ans)
+                .enumerate()
+                .for_each(|(index, (a, b))| {
+                    assert!(
+                        is_approx_eps(a, b, 0.01),
+                        "{quant:?} failed at index {index}, computed: {a} vs. answer: {b}"
+                    );
+                });
+        }
+
+        Ok(())
+    }
+
     #[test]
     fn test_blit() -> Result<()> {
         let context = match pollster::block_on(create_context()) {
@@ -3076,7 +4065,7 @@ mod tests {
         ops.push(TensorOp::blit(input, output.view(.., 2.., 1..2, ..)?)?);
 
         let ops = TensorOp::List(ops);
-        context.queue.submit(context.encode(&ops));
+        context.submit(context.encode(&ops));
 
         let output_host = output.back_in_place();
         let output_host = Vec::from(output_host);
@@ -3092,6 +4081,245 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_integer_round_trip() -> Result<()> {
+        let context = match pollster::block_on(create_context()) {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        // odd lengths are not multiples of the copy alignment
+        let x = (0..7u16).map(|x| x * 1000).collect_vec();
+        let x_dev: TensorGpu<u16, ReadWrite> = context.tensor_from_data([7, 1, 1, 1], x.clone())?;
+        assert_eq!(Vec::from(x_dev.back_in_place()), x);
+        assert_eq!(Vec::from(x_dev.deep_clone().back_in_place()), x);
+
+        let y = (0..5u8).collect_vec();
+        let y_dev: TensorGpu<u8, ReadWrite> = context.tensor_init([5, 1, 1, 1]);
+        y_dev.load(&TensorCpu::from_data([5, 1, 1, 1], y.clone())?)?;
+        assert_eq!(Vec::from(y_dev.back_in_place()), y);
+
+        let z = (0..3u32).map(|x| u32::MAX - x).collect_vec();
+        let z_dev: TensorGpu<u32, ReadWrite> = context.tensor_from_data([3, 1, 1, 1], z.clone())?;
+        assert_eq!(Vec::from(z_dev.back_in_place()), z);
+
+        // padding an unaligned batch would overwrite the start of the next one
+        let w_dev: TensorGpu<u8, ReadWrite> = context.tensor_init([5, 1, 2, 1]);
+        let w = TensorCpu::from_data([5, 1, 1, 1], y.clone())?;
+        assert!(w_dev.load_batch(&w, 0).is_err());
+
+        let w_dev: TensorGpu<u8, ReadWrite> = context.tensor_init([4, 1, 2, 1]);
+        w_dev.load_batch(&TensorCpu::from_data([4, 1, 1, 1], vec![1u8, 2, 3, 4])?, 1)?;
+        w_dev.load_batch(&TensorCpu::from_data([4, 1, 1, 1], vec![5u8, 6, 7, 8])?, 0)?;
+        assert_eq!(
+            Vec::from(w_dev.back_in_place()),
+            vec![5, 6, 7, 8, 1, 2, 3, 4]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_masked_fill() -> Result<()> {
+        let context = match pollster::block_on(create_context()) {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+        fastrand::seed(42);
+
+        const C: usize = 100;
+        const T: usize = 3;
+        const B: usize = 2;
+
+        let x = [(); C * T * B].map(|_| fastrand::f32()).to_vec();
+        let x_dev: TensorGpu<_, _> = context.tensor_from_data([C, T, B, 1], x.clone())?;
+
+        // one mask per batch, broadcast along tokens
+        let mask = [(); C * B].map(|_| fastrand::bool()).to_vec();
+        let mask_host = TensorCpu::from_mask([C, 1, B, 1], &mask)?;
+        assert_eq!(mask_host.to_mask(C)?, mask);
+        let mask_dev = TensorMask::new(&context, [C, 1, B, 1], &mask)?;
+        assert!(mask_dev.check_broadcast([C, T, B, 1]).is_ok());
+        assert!(mask_dev.check_broadcast([C + 1, T, B, 1]).is_err());
+        assert!(TensorMask::from_packed(mask_dev.tensor().clone(), C + 32).is_err());
+
+        let op = TensorOp::masked_fill(&mask_dev, &x_dev, f32::NEG_INFINITY)?;
+        context.submit(context.encode(&op));
+
+        let x_host = Vec::from(x_dev.back_in_place());
+        for (index, (a, b)) in itertools::zip_eq(x_host, x).enumerate() {
+            let c = index % C;
+            let batch = index / (C * T);
+            match mask[batch * C + c] {
+                true => assert_eq!(a, b, "Failed at index {index}"),
+                false => assert_eq!(a, f32::NEG_INFINITY, "Failed at index {index}"),
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_masked_ops() -> Result<()> {
+        let context = match pollster::block_on(create_context()) {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+        fastrand::seed(42);
+
+        const C: usize = 100;
+        const T: usize = 3;
+        const B: usize = 2;
+
+        let x = [(); C * T * B].map(|_| fastrand::f32()).to_vec();
+        let y = [(); C * T * B].map(|_| fastrand::f32()).to_vec();
+        let mask = [(); C * T * B].map(|_| fastrand::bool()).to_vec();
+        let mask_dev = TensorMask::new(&context, [C, T, B, 1], &mask)?;
+
+        let x_dev: TensorGpu<f32, _> = context.tensor_from_data([C, T, B, 1], x.clone())?;
+        let y_dev: TensorGpu<f32, _> = context.tensor_from_data([C, T, B, 1], y.clone())?;
+        let op = TensorOp::masked_blit(
+            &mask_dev,
+            x_dev.view(.., .., .., ..)?,
+            y_dev.view(.., .., .., ..)?,
+        )?;
+        context.submit(context.encode(&op));
+        let output = Vec::from(y_dev.back_in_place());
+        for (index, &a) in output.iter().enumerate() {
+            let b = if mask[index] { x[index] } else { y[index] };
+            assert_eq!(a, b, "Failed at index {index}");
+        }
+
+        let y_dev: TensorGpu<f32, _> = context.tensor_from_data([C, T, B, 1], y.clone())?;
+        let op = TensorOp::masked_add(
+            &mask_dev,
+            x_dev.view(.., .., .., ..)?,
+            y_dev.view(.., .., .., ..)?,
+        )?;
+        context.submit(context.encode(&op));
+        let output = Vec::from(y_dev.back_in_place());
+        for (index, &a) in output.iter().enumerate() {
+            let b = if mask[index] {
+                x[index] + y[index]
+            } else {
+                y[index]
+            };
+            assert!(
+                is_approx(a, b),
+                "Failed at index {index}, computed: {a} vs. answer: {b}"
+            );
+        }
+
+        let op = TensorOp::masked_softmax(&mask_dev, &x_dev)?;
+        context.submit(context.encode(&op));
+        let output = Vec::from(x_dev.back_in_place());
+        for (row, (x, mask)) in x.chunks(C).zip(mask.chunks(C)).enumerate() {
+            let sum: f32 = itertools::zip_eq(x, mask)
+                .filter(|(_, &selected)| selected)
+                .map(|(x, _)| x.exp())
+                .sum();
+            for (c, (&x, &selected)) in itertools::zip_eq(x, mask).enumerate() {
+                let a = output[row * C + c];
+                let b = if selected { x.exp() / sum } else { 0.0 };
+                assert!(
+                    is_approx(a, b),
+                    "Failed at row {row}, index {c}, computed: {a} vs. answer: {b}"
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_random_uniform() -> Result<()> {
+        let context = match pollster::block_on(create_context()) {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        const C: usize = 4096;
+        const T: usize = 2;
+        const B: usize = 3;
+
+        let draw = |state: &TensorGpu<u32, ReadWrite>| -> Result<Vec<f32>> {
+            let x: TensorGpu<f32, ReadWrite> = context.tensor_init([C, T, B, 1]);
+            let op = TensorOp::random_uniform(state, &x)?;
+            context.submit(context.encode(&op));
+            Ok(Vec::from(x.back_in_place()))
+        };
+
+        let seeds = [42, 42, 0x1234_5678_9abc_def0];
+        let state: TensorGpu<u32, ReadWrite> =
+            TensorCpu::from_seeds(&seeds).transfer_into(&context);
+        let first = draw(&state)?;
+        let second = draw(&state)?;
+
+        let chunks = first.chunks(C * T).collect_vec();
+        assert_eq!(chunks[0], chunks[1], "same seeds must give same draws");
+        assert_ne!(chunks[0], chunks[2]);
+        assert_ne!(first, second, "the generator must advance after each draw");
+
+        assert!(first.iter().all(|x| (0.0..1.0).contains(x)));
+        let mean = first.iter().sum::<f32>() / first.len() as f32;
+        assert!(is_approx_eps(mean, 0.5, 0.01), "mean: {mean}");
+
+        // replaying from the same seeds reproduces the sequence
+        let state: TensorGpu<u32, ReadWrite> =
+            TensorCpu::from_seeds(&seeds).transfer_into(&context);
+        assert_eq!(draw(&state)?, first);
+        assert_eq!(draw(&state)?, second);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_top_k() -> Result<()> {
+        let context = match pollster::block_on(create_context()) {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+        fastrand::seed(42);
+
+        const C: usize = 1000;
+        const T: usize = 3;
+        const B: usize = 2;
+        const K: usize = 8;
+
+        // quantize values to produce ties, and make some negative like logits
+        let x = [(); C * T * B]
+            .map(|_| (100.0 * fastrand::f32() - 50.0).round())
+            .to_vec();
+        let x_dev: TensorGpu<_, _> = context.tensor_from_data([C, T, B, 1], x.clone())?;
+        let indices_dev: TensorGpu<u32, ReadWrite> = context.tensor_init([K, T, B, 1]);
+        let values_dev: TensorGpu<f32, ReadWrite> = context.tensor_init([K, T, B, 1]);
+
+        let op = TensorOp::top_k(&x_dev, &indices_dev, &values_dev)?;
+        context.submit(context.encode(&op));
+
+        let indices_host = Vec::from(indices_dev.back_in_place());
+        let values_host = Vec::from(values_dev.back_in_place());
+
+        for (row, x) in x.chunks(C).enumerate() {
+            let ans = x
+                .iter()
+                .enumerate()
+                .sorted_by(|(i0, v0), (i1, v1)| v1.total_cmp(v0).then(i0.cmp(i1)))
+                .take(K)
+                .collect_vec();
+            for (k, (index, &value)) in ans.into_iter().enumerate() {
+                assert_eq!(
+                    indices_host[row * K + k] as usize,
+                    index,
+                    "row {row}, k {k}"
+                );
+                assert_eq!(values_host[row * K + k], value, "row {row}, k {k}");
+            }
+        }
+
+        Ok(())
+    }
+
     #[test]
     fn test_transpose() -> Result<()> {
         let context = match pollster::block_on(create_context()) {
@@ -3107,7 +4335,7 @@ mod tests {
         let input: TensorGpu<_, _> = context.tensor_from_data([4, 3, 2, 1], input)?;
 
         let ops = TensorOp::transpose(input.view(.., .., .., ..)?, output.view(.., ..2, .., ..)?)?;
-        context.queue.submit(context.encode(&ops));
+        context.submit(context.encode(&ops));
 
         let output_host = output.back_in_place();
         let output_host: Vec<f32> = Vec::from(output_host);
@@ -0,0 +1,637 @@
+//! Reference implementations of some kernels on the CPU, following the shaders step by step,
+//! so that changes to the shaders can be tested against them.
+//!
+//! All tensors are in `f32`. Tokens of all batches are stacked along the second axis (`A`),
+//! and `cursors` are the packed cursors of each token, as uploaded to the kernels
+//! (see [`IntoPackedCursors::into_cursors`](super::IntoPackedCursors::into_cursors)).
+
+use super::{Cursor, Shape, TensorCpu, TensorError, TensorInit, TensorShape};
+
+/// Layouts of batch lengths to test against, covering edge cases of the cursor packing:
+/// single tokens, empty batches, and batches of different lengths next to each other.
+pub const BATCH_LENS: &[&[usize]] = &[
+    &[1],
+    &[4],
+    &[1, 1, 1],
+    &[3, 0, 2],
+    &[0, 5, 1, 0],
+    &[2, 1, 4, 3],
+];
+
+#[inline]
+fn mix(x: f32, y: f32, factor: f32) -> f32 {
+    x * (1.0 - factor) + y * factor
+}
+
+#[inline]
+fn sigmoid(x: f32) -> f32 {
+    1.0 / (1.0 + (-x).exp())
+}
+
+/// Unpack the cursor of each token, checking them against the number of tokens and batches.
+fn unpack_cursors(
+    cursors: &[u32],
+    num_token: usize,
+    num_batch: usize,
+) -> Result<Vec<Cursor>, TensorError> {
+    if cursors.len() != num_token {
+        return Err(TensorError::Size(cursors.len(), num_token));
+    }
+    cursors
+        .iter()
+        .copied()
+        .map(Cursor::unpack)
+        .map(|cursor| match cursor.batch < num_batch {
+            true => Ok(cursor),
+            false => Err(TensorError::BatchOutOfRange {
+                batch: cursor.batch,
+                max: num_batch,
+            }),
+        })
+        .collect()
+}
+
+/// Mix each token with the previous one in its batch, or with the state if it is the first.
+/// - `time_mix` shape: `[C, 1, 1]` or `[C, A, 1]`.
+/// - `state` shape: `[C, _, B]`, of which only the first row of each batch is used.
+/// - `input` shape: `[C, A, 1]`.
+///
+/// Returns the output of shape `[C, A, 1]`.
+pub fn token_shift(
+    cursors: &[u32],
+    time_mix: &TensorCpu<f32>,
+    state: &TensorCpu<f32>,
+    input: &TensorCpu<f32>,
+    reversed: bool,
+) -> Result<TensorCpu<f32>, TensorError> {
+    let [num_emb, num_token, _, _] = *input.shape();
+    let [_, num_row, num_batch, _] = *state.shape();
+    input.check_shape([num_emb, num_token, 1, 1])?;
+    time_mix
+        .check_shape([num_emb, 1, 1, 1])
+        .or(time_mix.check_shape([num_emb, num_token, 1, 1]))?;
+    state.check_shape([num_emb, num_row, num_batch, 1])?;
+    let cursors = unpack_cursors(cursors, num_token, num_batch)?;
+
+    let shape = state.shape();
+    let broadcast = time_mix.shape()[1] == 1;
+    let (time_mix, state, data) = (time_mix.data(), state.data(), input.data());
+
+    let mut output = vec![0.0; num_emb * num_token];
+    for (stack, cursor) in cursors.iter().enumerate() {
+        let token = stack - cursor.token;
+        for index in 0..num_emb {
+            let factor = match broadcast {
+                true => time_mix[index],
+                false => time_mix[stack * num_emb + index],
+            };
+            let x = data[stack * num_emb + index];
+            let last = match token {
+                0 => state[shape.shape_index(Shape::new(index, 0, cursor.batch, 0))],
+                _ => data[(stack - 1) * num_emb + index],
+            };
+            output[stack * num_emb + index] = match reversed {
+                true => mix(x, last, factor),
+                false => mix(last, x, factor),
+            };
+        }
+    }
+    TensorCpu::from_data(input.shape(), output)
+}
+
+/// Channel mix, after the key and value have been computed.
+/// - `state` shape: `[C, _, B]`, of which only the first row of each batch is updated.
+/// - `r`, `v` and `x` shape: `[C, A, 1]`.
+///
+/// Returns the output of shape `[C, A, 1]` and the updated state.
+pub fn channel_mix(
+    cursors: &[u32],
+    state: &TensorCpu<f32>,
+    r: &TensorCpu<f32>,
+    v: &TensorCpu<f32>,
+    x: &TensorCpu<f32>,
+) -> Result<(TensorCpu<f32>, TensorCpu<f32>), TensorError> {
+    let [num_emb, num_token, _, _] = *x.shape();
+    let [_, num_row, num_batch, _] = *state.shape();
+    x.check_shape([num_emb, num_token, 1, 1])?;
+    r.check_shape(x.shape())?;
+    v.check_shape(x.shape())?;
+    state.check_shape([num_emb, num_row, num_batch, 1])?;
+    let cursors = unpack_cursors(cursors, num_token, num_batch)?;
+
+    let mut output = vec![0.0; num_emb * num_token];
+    let mut next = state.to_vec();
+    let shape = state.shape();
+    let (r, v, data) = (r.data(), v.data(), x.data());
+    for (stack, cursor) in cursors.iter().enumerate() {
+        let token = stack - cursor.token;
+        for index in 0..num_emb {
+            let bti = stack * num_emb + index;
+            if token + 1 == cursor.len {
+                next[shape.shape_index(Shape::new(index, 0, cursor.batch, 0))] = data[bti];
+            }
+            output[bti] = sigmoid(r[bti]) * v[bti];
+        }
+    }
+    Ok((
+        TensorCpu::from_data(x.shape(), output)?,
+        TensorCpu::from_data(shape, next)?,
+    ))
+}
+
+/// Time mix of v4 (the WKV operator), processing tokens in order.
+/// - `time_decay` and `time_first` shape: `[C, 1, 1]`.
+/// - `state` shape: `[C, 4, B]`, whose rows are the last input, `aa`, `bb` and `pp` respectively.
+/// - `k`, `v`, `r` and `x` shape: `[C, A, 1]`.
+///
+/// Returns the output of shape `[C, A, 1]` and the updated state.
+#[allow(clippy::too_many_arguments)]
+pub fn time_mix_v4(
+    cursors: &[u32],
+    time_decay: &TensorCpu<f32>,
+    time_first: &TensorCpu<f32>,
+    state: &TensorCpu<f32>,
+    k: &TensorCpu<f32>,
+    v: &TensorCpu<f32>,
+    r: &TensorCpu<f32>,
+    x: &TensorCpu<f32>,
+) -> Result<(TensorCpu<f32>, TensorCpu<f32>), TensorError> {
+    let [num_emb, num_token, _, _] = *x.shape();
+    let num_batch = state.shape()[2];
+    x.check_shape([num_emb, num_token, 1, 1])?;
+    k.check_shape(x.shape())?;
+    v.check_shape(x.shape())?;
+    r.check_shape(x.shape())?;
+    time_decay.check_shape([num_emb, 1, 1, 1])?;
+    time_first.check_shape([num_emb, 1, 1, 1])?;
+    state.check_shape([num_emb, 4, num_batch, 1])?;
+    let cursors = unpack_cursors(cursors, num_token, num_batch)?;
+
+    let mut output = vec![0.0; num_emb * num_token];
+    let mut next = state.to_vec();
+    let shape = state.shape();
+    let (time_decay, time_first) = (time_decay.data(), time_first.data());
+    let (k, v, r, data) = (k.data(), v.data(), r.data(), x.data());
+    for (t, cursor) in cursors.iter().enumerate() {
+        for index in 0..num_emb {
+            let [si, ai, bi, pi] =
+                [0, 1, 2, 3].map(|row| shape.shape_index(Shape::new(index, row, cursor.batch, 0)));
+            let bti = t * num_emb + index;
+
+            next[si] = data[(cursor.token + cursor.len - 1) * num_emb + index];
+
+            let (aa, bb, pp) = (next[ai], next[bi], next[pi]);
+            let (kk, vv, rr) = (k[bti], v[bti], sigmoid(r[bti]));
+
+            let ww = time_first[index] + kk;
+            let q = pp.max(ww);
+            let e1 = (pp - q).exp();
+            let e2 = (ww - q).exp();
+            output[bti] = rr * (e1 * aa + e2 * vv) / (e1 * bb + e2);
+
+            let ww = time_decay[index] + pp;
+            let q = ww.max(kk);
+            let e1 = (ww - q).exp();
+            let e2 = (kk - q).exp();
+            next[ai] = e1 * aa + e2 * vv;
+            next[bi] = e1 * bb + e2;
+            next[pi] = q;
+        }
+    }
+    Ok((
+        TensorCpu::from_data(x.shape(), output)?,
+        TensorCpu::from_data(shape, next)?,
+    ))
+}
+
+/// Time mix of v5, processing tokens in order.
+/// - `time_decay` and `time_first` shape: `[S, H, 1]`.
+/// - `state` shape: `[C, S + 1, B]`, where `C = S * H`. The first row is the last input,
+///   and the rest `S` rows hold the `S * S` matrix of each head.
+/// - `k`, `v`, `r` and `x` shape: `[S, H, A]`.
+///
+/// Returns the output of shape `[S, H, A]` and the updated state.
+#[allow(clippy::too_many_arguments)]
+pub fn time_mix_v5(
+    cursors: &[u32],
+    time_decay: &TensorCpu<f32>,
+    time_first: &TensorCpu<f32>,
+    state: &TensorCpu<f32>,
+    k: &TensorCpu<f32>,
+    v: &TensorCpu<f32>,
+    r: &TensorCpu<f32>,
+    x: &TensorCpu<f32>,
+) -> Result<(TensorCpu<f32>, TensorCpu<f32>), TensorError> {
+    let [head_size, num_head, num_token, _] = *x.shape();
+    let num_emb = head_size * num_head;
+    let num_batch = state.shape()[2];
+    k.check_shape(x.shape())?;
+    v.check_shape(x.shape())?;
+    r.check_shape(x.shape())?;
+    time_decay.check_shape([head_size, num_head, 1, 1])?;
+    time_first.check_shape([head_size, num_head, 1, 1])?;
+    state.check_shape([num_emb, head_size + 1, num_batch, 1])?;
+    let cursors = unpack_cursors(cursors, num_token, num_batch)?;
+
+    let mut output = vec![0.0; num_emb * num_token];
+    let mut next = state.to_vec();
+    let shape = state.shape();
+    let (time_decay, time_first) = (time_decay.data(), time_first.data());
+    let (k, v, r, data) = (k.data(), v.data(), r.data(), x.data());
+    for (t, cursor) in cursors.iter().enumerate() {
+        // the state of the last input is written before any output of this step
+        for index in 0..num_emb {
+            let si = shape.shape_index(Shape::new(index, 0, cursor.batch, 0));
+            next[si] = data[(cursor.token + cursor.len - 1) * num_emb + index];
+        }
+
+        for index in 0..num_emb {
+            let h = index / head_size * head_size;
+            let bti = t * num_emb + index;
+            let vv = v[bti];
+
+            let mut y = 0.0;
+            for j in 0..head_size {
+                let kk = k[t * num_emb + h + j];
+                let rr = r[t * num_emb + h + j];
+                let uu = time_first[h + j];
+                let ww = time_decay[h + j];
+
+                let bji = shape.shape_index(Shape::new(index, j + 1, cursor.batch, 0));
+                let ss = next[bji];
+                let kv = kk * vv;
+
+                y += rr * (uu * kv + ss);
+                next[bji] = ww * ss + kv;
+            }
+            output[bti] = y;
+        }
+    }
+    Ok((
+        TensorCpu::from_data(x.shape(), output)?,
+        TensorCpu::from_data(shape, next)?,
+    ))
+}
+
+/// The time mix of RWKV v7; see [`TensorOp::time_mix_v7`](super::ops::TensorOp::time_mix_v7).
+/// - `state` shape: `[C, S + 1, B]`, of which the first row is left untouched.
+/// - `w`, `k`, `v`, `a`, `kk` and `x` (the receptance) shape: `[S, H, A]`.
+///
+/// Returns the output of shape `[S, H, A]` and the next state.
+#[allow(clippy::too_many_arguments)]
+pub fn time_mix_v7(
+    cursors: &[u32],
+    state: &TensorCpu<f32>,
+    w: &TensorCpu<f32>,
+    k: &TensorCpu<f32>,
+    v: &TensorCpu<f32>,
+    a: &TensorCpu<f32>,
+    kk: &TensorCpu<f32>,
+    x: &TensorCpu<f32>,
+) -> Result<(TensorCpu<f32>, TensorCpu<f32>), TensorError> {
+    let [head_size, num_head, num_token, _] = *x.shape();
+    let num_emb = head_size * num_head;
+    let num_batch = state.shape()[2];
+    for tensor in [w, k, v, a, kk] {
+        tensor.check_shape(x.shape())?;
+    }
+    state.check_shape([num_emb, head_size + 1, num_batch, 1])?;
+    let cursors = unpack_cursors(cursors, num_token, num_batch)?;
+
+    let mut output = vec![0.0; num_emb * num_token];
+    let mut next = state.to_vec();
+    let shape = state.shape();
+    let (w, k, v, a, kk, data) = (w.data(), k.data(), v.data(), a.data(), kk.data(), x.data());
+    for (t, cursor) in cursors.iter().enumerate() {
+        for index in 0..num_emb {
+            let h = index / head_size * head_size;
+            let bti = t * num_emb + index;
+            let vv = v[bti];
+            let row = |j: usize| shape.shape_index(Shape::new(index, j + 1, cursor.batch, 0));
+
+            let sa: f32 = (0..head_size)
+                .map(|j| -kk[t * num_emb + h + j] * next[row(j)])
+                .sum();
+
+            let mut y = 0.0;
+            for j in 0..head_size {
+                let tj = t * num_emb + h + j;
+                let ww = (-0.606531 * sigmoid(w[tj])).exp();
+                let bb = kk[tj] * a[tj];
+
+                let ss = next[row(j)] * ww + sa * bb + vv * k[tj];
+                y += data[tj] * ss;
+                next[row(j)] = ss;
+            }
+            output[bti] = y;
+        }
+    }
+    Ok((
+        TensorCpu::from_data(x.shape(), output)?,
+        TensorCpu::from_data(shape, next)?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use wgpu::{Instance, PowerPreference};
+
+    use super::BATCH_LENS;
+    use crate::{
+        context::{Context, ContextBuilder, InstanceExt},
+        tensor::{
+            kind::ReadWrite, ops::TensorOp, Cursor, IntoPackedCursors, Shape, TensorCpu, TensorGpu,
+            TensorInit, TensorShape,
+        },
+    };
+
+    async fn create_context() -> Result<Context> {
+        let instance = Instance::default();
+        let adapter = instance.adapter(PowerPreference::HighPerformance).await?;
+        let context = ContextBuilder::new(adapter).build().await?;
+        Ok(context)
+    }
+
+    fn random(shape: impl Into<Shape>) -> Result<TensorCpu<f32>> {
+        let shape = shape.into();
+        let data = (0..shape.len())
+            .map(|_| fastrand::f32() - 0.5)
+            .collect::<Vec<_>>();
+        Ok(TensorCpu::from_data(shape, data)?)
+    }
+
+    fn check(a: &TensorCpu<f32>, b: &TensorCpu<f32>, eps: f32) {
+        assert_eq!(a.shape(), b.shape());
+        for (index, (&a, &b)) in itertools::zip_eq(a.iter(), b.iter()).enumerate() {
+            assert!(
+                (a - b).abs() <= eps * f32::max(1.0, f32::max(a.abs(), b.abs())),
+                "failed at index {index}, computed: {a} vs. reference: {b}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_token_shift() -> Result<()> {
+        let context = match pollster::block_on(create_context()) {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+        fastrand::seed(42);
+
+        const C: usize = 128;
+
+        for lens in BATCH_LENS {
+            let num_token: usize = lens.iter().sum();
+            let num_batch = lens.len();
+            let cursors = Cursor::stack(lens.iter().copied()).into_cursors();
+
+            let input = random([C, num_token, 1, 1])?;
+            let state = random([C, 2, num_batch, 1])?;
+            for time_mix in [random([C, 1, 1, 1])?, random([C, num_token, 1, 1])?] {
+                for reversed in [false, true] {
+                    let cursors_dev: TensorGpu<u32, ReadWrite> =
+                        context.tensor_from_data([num_token, 1, 1, 1], cursors.clone())?;
+                    let time_mix_dev: TensorGpu<f32, ReadWrite> =
+                        context.tensor_from_data(time_mix.shape(), time_mix.to_vec())?;
+                    let state_dev: TensorGpu<f32, ReadWrite> =
+                        context.tensor_from_data(state.shape(), state.to_vec())?;
+                    let input_dev: TensorGpu<f32, ReadWrite> =
+                        context.tensor_from_data(input.shape(), input.to_vec())?;
+                    let output_dev: TensorGpu<f32, ReadWrite> = context.tensor_init(input.shape());
+
+                    let op = TensorOp::token_shift(
+                        &cursors_dev,
+                        time_mix_dev.view(.., .., .., ..)?,
+                        state_dev.view(.., .., .., ..)?,
+                        &input_dev,
+                        &output_dev,
+                        reversed,
+                    )?;
+                    context.submit(context.encode(&op));
+
+                    let output = super::token_shift(&cursors, &time_mix, &state, &input, reversed)?;
+                    check(&output_dev.back_in_place(), &output, 1.0e-6);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_channel_mix() -> Result<()> {
+        let context = match pollster::block_on(create_context()) {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+        fastrand::seed(42);
+
+        const C: usize = 128;
+
+        for lens in BATCH_LENS {
+            let num_token: usize = lens.iter().sum();
+            let num_batch = lens.len();
+            let cursors = Cursor::stack(lens.iter().copied()).into_cursors();
+
+            let state = random([C, 1, num_batch, 1])?;
+            let r = random([C, num_token, 1, 1])?;
+            let v = random([C, num_token, 1, 1])?;
+            let x = random([C, num_token, 1, 1])?;
+
+            let cursors_dev: TensorGpu<u32, ReadWrite> =
+                context.tensor_from_data([num_token, 1, 1, 1], cursors.clone())?;
+            let state_dev: TensorGpu<f32, ReadWrite> =
+                context.tensor_from_data(state.shape(), state.to_vec())?;
+            let r_dev: TensorGpu<f32, ReadWrite> =
+                context.tensor_from_data(r.shape(), r.to_vec())?;
+            let v_dev: TensorGpu<f32, ReadWrite> =
+                context.tensor_from_data(v.shape(), v.to_vec())?;
+            let x_dev: TensorGpu<f32, ReadWrite> =
+                context.tensor_from_data(x.shape(), x.to_vec())?;
+
+            let op = TensorOp::channel_mix(
+                &cursors_dev,
+                state_dev.view(.., .., .., ..)?,
+                &r_dev,
+                &v_dev,
+                &x_dev,
+            )?;
+            context.submit(context.encode(&op));
+
+            let (output, state) = super::channel_mix(&cursors, &state, &r, &v, &x)?;
+            check(&x_dev.back_in_place(), &output, 1.0e-6);
+            check(&state_dev.back_in_place(), &state, 0.0);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_time_mix_v4() -> Result<()> {
+        let context = match pollster::block_on(create_context()) {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+        fastrand::seed(42);
+
+        const C: usize = 128;
+
+        for lens in BATCH_LENS {
+            let num_token: usize = lens.iter().sum();
+            let num_batch = lens.len();
+            let cursors = Cursor::stack(lens.iter().copied()).into_cursors();
+
+            let time_decay = random([C, 1, 1, 1])?;
+            let time_first = random([C, 1, 1, 1])?;
+            let state = random([C, 4, num_batch, 1])?;
+            let k = random([C, num_token, 1, 1])?;
+            let v = random([C, num_token, 1, 1])?;
+            let r = random([C, num_token, 1, 1])?;
+            let x = random([C, num_token, 1, 1])?;
+
+            let upload = |tensor: &TensorCpu<f32>| -> Result<TensorGpu<f32, ReadWrite>> {
+                Ok(context.tensor_from_data(tensor.shape(), tensor.to_vec())?)
+            };
+            let cursors_dev: TensorGpu<u32, ReadWrite> =
+                context.tensor_from_data([num_token, 1, 1, 1], cursors.clone())?;
+            let state_dev = upload(&state)?;
+            let x_dev = upload(&x)?;
+
+            let op = TensorOp::time_mix_v4(
+                &cursors_dev,
+                &upload(&time_decay)?,
+                &upload(&time_first)?,
+                state_dev.view(.., .., .., ..)?,
+                &upload(&k)?,
+                &upload(&v)?,
+                &upload(&r)?,
+                &x_dev,
+            )?;
+            context.submit(context.encode(&op));
+
+            let (output, state) =
+                super::time_mix_v4(&cursors, &time_decay, &time_first, &state, &k, &v, &r, &x)?;
+            check(&x_dev.back_in_place(), &output, 1.0e-5);
+            check(&state_dev.back_in_place(), &state, 1.0e-5);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_time_mix_v5() -> Result<()> {
+        let context = match pollster::block_on(create_context()) {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+        fastrand::seed(42);
+
+        const S: usize = 32;
+        const H: usize = 4;
+        const C: usize = S * H;
+
+        for lens in BATCH_LENS {
+            let num_token: usize = lens.iter().sum();
+            let num_batch = lens.len();
+            let cursors = Cursor::stack(lens.iter().copied()).into_cursors();
+
+            let time_decay = random([S, H, 1, 1])?;
+            let time_first = random([S, H, 1, 1])?;
+            let state = random([C, S + 1, num_batch, 1])?;
+            let k = random([S, H, num_token, 1])?;
+            let v = random([S, H, num_token, 1])?;
+            let r = random([S, H, num_token, 1])?;
+            let x = random([S, H, num_token, 1])?;
+
+            let upload = |tensor: &TensorCpu<f32>| -> Result<TensorGpu<f32, ReadWrite>> {
+                Ok(context.tensor_from_data(tensor.shape(), tensor.to_vec())?)
+            };
+            let cursors_dev: TensorGpu<u32, ReadWrite> =
+                context.tensor_from_data([num_token, 1, 1, 1], cursors.clone())?;
+            let state_dev = upload(&state)?;
+            let x_dev = upload(&x)?;
+
+            let op = TensorOp::time_mix_v5(
+                &cursors_dev,
+                &upload(&time_decay)?,
+                &upload(&time_first)?,
+                state_dev.view(.., .., .., ..)?,
+                &upload(&k)?,
+                &upload(&v)?,
+                &upload(&r)?,
+                &x_dev,
+            )?;
+            context.submit(context.encode(&op));
+
+            let (output, state) =
+                super::time_mix_v5(&cursors, &time_decay, &time_first, &state, &k, &v, &r, &x)?;
+            check(&x_dev.back_in_place(), &output, 1.0e-5);
+            check(&state_dev.back_in_place(), &state, 1.0e-5);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_time_mix_v7() -> Result<()> {
+        let context = match pollster::block_on(create_context()) {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+        fastrand::seed(42);
+
+        const S: usize = 32;
+        const H: usize = 4;
+        const C: usize = S * H;
+
+        for lens in BATCH_LENS {
+            let num_token: usize = lens.iter().sum();
+            let num_batch = lens.len();
+            let cursors = Cursor::stack(lens.iter().copied()).into_cursors();
+
+            let state = random([C, S + 1, num_batch, 1])?;
+            let w = random([S, H, num_token, 1])?;
+            let k = random([S, H, num_token, 1])?;
+            let v = random([S, H, num_token, 1])?;
+            let a = random([S, H, num_token, 1])?;
+            let kk = random([S, H, num_token, 1])?;
+            let x = random([S, H, num_token, 1])?;
+
+            let upload = |tensor: &TensorCpu<f32>| -> Result<TensorGpu<f32, ReadWrite>> {
+                Ok(context.tensor_from_data(tensor.shape(), tensor.to_vec())?)
+            };
+            let cursors_dev: TensorGpu<u32, ReadWrite> =
+                context.tensor_from_data([num_token, 1, 1, 1], cursors.clone())?;
+            let state_dev = upload(&state)?;
+            let x_dev = upload(&x)?;
+
+            let op = TensorOp::time_mix_v7(
+                &cursors_dev,
+                state_dev.view(.., .., .., ..)?,
+                &upload(&w)?,
+                &upload(&k)?,
+                &upload(&v)?,
+                &upload(&a)?,
+                &upload(&kk)?,
+                &x_dev,
+            )?;
+            context.submit(context.encode(&op));
+
+            let (output, state) = super::time_mix_v7(&cursors, &state, &w, &k, &v, &a, &kk, &x)?;
+            check(&x_dev.back_in_place(), &output, 1.0e-4);
+            check(&state_dev.back_in_place(), &state, 1.0e-4);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cursor_round_trip() {
+        for lens in BATCH_LENS {
+            for cursor in Cursor::stack(lens.iter().copied()) {
+                assert_eq!(Cursor::unpack(cursor.pack()), cursor);
+            }
+        }
+    }
+}
@@ -182,6 +182,7 @@ macro_rules! impl_deserialize_seed {
 
 impl_deserialize_seed!(bool);
 impl_deserialize_seed!(usize);
+impl_deserialize_seed!(f32);
 impl_deserialize_seed!(PhantomData, T);
 
 impl<'de, C, T> DeserializeSeed<'de> for Seed<'de, C, Vec<T>>
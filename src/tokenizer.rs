@@ -1,10 +1,12 @@
 use ahash::{AHashMap as HashMap, AHashSet as HashSet};
 use derive_getters::Getters;
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, ops::Range};
 use thiserror::Error;
 use wasm_bindgen::prelude::wasm_bindgen;
 use web_rwkv_derive::JsError;
 
+use crate::tensor::{TensorCpu, TensorShape};
+
 #[derive(Debug, Error, JsError)]
 pub enum TokenizerError {
     #[error("failed to parse vocabulary: {0}")]
@@ -107,14 +109,66 @@ impl Tokenizer {
 }
 
 impl Tokenizer {
-    pub fn encode_into(
+    pub fn encode_into(&self, input: &[u8], output: &mut Vec<u16>) -> Result<(), TokenizerError> {
+        self.encode_with(input, |token, _| output.push(token))
+    }
+
+    /// Encode the input, also returning the byte range each token covers in the input.
+    pub fn encode_with_offsets(
+        &self,
+        input: &[u8],
+    ) -> Result<(Vec<u16>, Vec<Range<usize>>), TokenizerError> {
+        let mut tokens = Vec::new();
+        let mut offsets = Vec::new();
+        self.encode_with(input, |token, range| {
+            tokens.push(token);
+            offsets.push(range);
+        })?;
+        Ok((tokens, offsets))
+    }
+
+    /// Encode multiple inputs, in parallel if the `parallel` feature is enabled.
+    pub fn encode_batch<T: AsRef<[u8]> + Sync>(
+        &self,
+        inputs: &[T],
+    ) -> Result<Vec<Vec<u16>>, TokenizerError> {
+        par_map(inputs, |input| self.encode(input.as_ref()))
+            .into_iter()
+            .collect()
+    }
+
+    /// Encode multiple inputs with byte offsets, in parallel if the `parallel` feature is enabled.
+    /// See [`Tokenizer::encode_with_offsets`].
+    #[allow(clippy::type_complexity)]
+    pub fn encode_batch_with_offsets<T: AsRef<[u8]> + Sync>(
+        &self,
+        inputs: &[T],
+    ) -> Result<Vec<(Vec<u16>, Vec<Range<usize>>)>, TokenizerError> {
+        par_map(inputs, |input| self.encode_with_offsets(input.as_ref()))
+            .into_iter()
+            .collect()
+    }
+
+    /// Decode multiple token sequences, in parallel if the `parallel` feature is enabled.
+    pub fn decode_batch<T: AsRef<[u16]> + Sync>(
         &self,
-        mut input: &[u8],
-        output: &mut Vec<u16>,
+        tokens: &[T],
+    ) -> Result<Vec<Vec<u8>>, TokenizerError> {
+        par_map(tokens, |tokens| self.decode(tokens.as_ref()))
+            .into_iter()
+            .collect()
+    }
+
+    fn encode_with(
+        &self,
+        input: &[u8],
+        mut f: impl FnMut(u16, Range<usize>),
     ) -> Result<(), TokenizerError> {
-        'next_token: while !input.is_empty() {
-            let lengths = if input.len() >= 2 {
-                let key = u16::from_ne_bytes([input[0], input[1]]) as usize;
+        let mut offset = 0;
+        'next_token: while offset < input.len() {
+            let remain = &input[offset..];
+            let lengths = if remain.len() >= 2 {
+                let key = u16::from_ne_bytes([remain[0], remain[1]]) as usize;
                 &self.first_bytes_to_lengths[key][..]
             } else {
                 &[1][..]
@@ -122,13 +176,13 @@ impl Tokenizer {
 
             for &length in lengths {
                 let length = length as usize;
-                if length > input.len() {
+                if length > remain.len() {
                     continue;
                 }
 
-                if let Some(&token_index) = self.bytes_to_token_index.get(&input[..length]) {
-                    output.push(token_index);
-                    input = &input[length..];
+                if let Some(&token_index) = self.bytes_to_token_index.get(&remain[..length]) {
+                    f(token_index, offset..offset + length);
+                    offset += length;
                     continue 'next_token;
                 }
             }
@@ -139,6 +193,26 @@ impl Tokenizer {
         Ok(())
     }
 
+    /// The raw bytes of a token, which may be an incomplete UTF-8 sequence (e.g., a byte token).
+    #[inline]
+    pub fn token_bytes(&self, token: u16) -> Option<&[u8]> {
+        self.token_index_to_bytes
+            .get(token as usize)
+            .map(|bytes| bytes.as_slice())
+    }
+
+    /// Pair embedding rows with their tokens, keyed by the token strings.
+    /// The embedding is of shape `[C, V]`; tokens that are empty or not valid UTF-8 are skipped.
+    pub fn map_embed(&self, embed: &TensorCpu<f32>) -> HashMap<String, Vec<f32>> {
+        let num_emb = embed.shape()[0];
+        self.token_index_to_bytes
+            .iter()
+            .zip(embed.chunks(num_emb))
+            .filter(|(bytes, _)| !bytes.is_empty())
+            .filter_map(|(bytes, row)| Some((String::from_utf8(bytes.clone()).ok()?, row.to_vec())))
+            .collect()
+    }
+
     pub fn decode_into(&self, tokens: &[u16], output: &mut Vec<u8>) -> Result<(), TokenizerError> {
         for &token in tokens {
             let bytes = self
@@ -152,3 +226,305 @@ impl Tokenizer {
         Ok(())
     }
 }
+
+/// Decoded output of one step of a [`DecodeStream`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DecodeOutput {
+    /// Exact bytes of the tokens pushed in this step.
+    pub bytes: Vec<u8>,
+    /// Text completed in this step. Incomplete UTF-8 sequences at the end are held back until
+    /// later tokens complete them; invalid sequences are replaced with `U+FFFD`.
+    pub text: String,
+}
+
+/// Incrementally decodes generated tokens, exposing both the lossless byte stream and
+/// the UTF-8-validated text stream.
+#[derive(Debug, Clone)]
+pub struct DecodeStream<'a> {
+    tokenizer: &'a Tokenizer,
+    buffer: Vec<u8>,
+}
+
+impl<'a> DecodeStream<'a> {
+    pub fn new(tokenizer: &'a Tokenizer) -> Self {
+        Self {
+            tokenizer,
+            buffer: vec![],
+        }
+    }
+
+    /// Bytes held back because they do not form a complete UTF-8 sequence yet.
+    #[inline]
+    pub fn pending(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    pub fn push(&mut self, tokens: &[u16]) -> Result<DecodeOutput, TokenizerError> {
+        let bytes = self.tokenizer.decode(tokens)?;
+        self.buffer.extend_from_slice(&bytes);
+
+        let mut text = String::new();
+        let mut input = &self.buffer[..];
+        loop {
+            match std::str::from_utf8(input) {
+                Ok(valid) => {
+                    text.push_str(valid);
+                    input = &[];
+                    break;
+                }
+                Err(err) => {
+                    let (valid, remain) = input.split_at(err.valid_up_to());
+                    text.push_str(std::str::from_utf8(valid).expect("this never happens"));
+                    match err.error_len() {
+                        Some(len) => {
+                            text.push(char::REPLACEMENT_CHARACTER);
+                            input = &remain[len..];
+                        }
+                        None => {
+                            input = remain;
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        self.buffer = input.to_vec();
+
+        Ok(DecodeOutput { bytes, text })
+    }
+
+    /// Flush the held back bytes, replacing any incomplete sequence with `U+FFFD`.
+    pub fn finish(self) -> String {
+        String::from_utf8_lossy(&self.buffer).into_owned()
+    }
+}
+
+/// Where a [`StopPatterns`] matched in the text of one step.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StopMatch {
+    /// Index of the pattern that matched.
+    pub pattern: usize,
+    /// Byte offset into the pushed text right after the match; the text should be cut here.
+    /// This is 0 for a match held back from an earlier step, which ends before this text.
+    pub end: usize,
+}
+
+/// Evaluates regex stop patterns incrementally over the text of a [`DecodeStream`].
+///
+/// Only the last `lookback` bytes of the text are kept, so a match can span several steps
+/// but never more than `lookback` bytes in total.
+///
+/// A match reaching the end of the text seen so far may still change with more text
+/// (e.g., patterns ending with `$`, `\b`, lazy or optional parts), so it is held back until
+/// the next [`push`](Self::push) or [`finish`](Self::finish).
+#[derive(Debug, Clone)]
+pub struct StopPatterns {
+    patterns: Vec<regex::Regex>,
+    lookback: usize,
+    window: String,
+    /// Matches ending at or before this offset into the window are already reported or ruled out.
+    checked: usize,
+}
+
+impl StopPatterns {
+    pub fn new<S: AsRef<str>>(
+        patterns: impl IntoIterator<Item = S>,
+        lookback: usize,
+    ) -> Result<Self, regex::Error> {
+        let patterns = patterns
+            .into_iter()
+            .map(|pattern| regex::Regex::new(pattern.as_ref()))
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            patterns,
+            lookback,
+            window: String::new(),
+            checked: 0,
+        })
+    }
+
+    /// The earliest match not checked yet, as the pattern index and the end offset into the window.
+    fn find(&self) -> Option<(usize, usize)> {
+        self.patterns
+            .iter()
+            .enumerate()
+            .filter_map(|(pattern, regex)| {
+                regex
+                    .find_iter(&self.window)
+                    .find(|m| m.end() > self.checked)
+                    .map(|m| (pattern, m.end()))
+            })
+            .min_by_key(|&(_, end)| end)
+    }
+
+    /// Push the text of one step. Returns the earliest match that is complete, if any.
+    pub fn push(&mut self, text: &str) -> Option<StopMatch> {
+        let offset = self.window.len();
+        self.window.push_str(text);
+
+        let found = match self.find() {
+            Some((_, end)) if end == self.window.len() => None,
+            Some((pattern, end)) => {
+                self.checked = end;
+                Some(StopMatch {
+                    pattern,
+                    end: end.saturating_sub(offset),
+                })
+            }
+            None => {
+                self.checked = self.window.len();
+                None
+            }
+        };
+
+        if self.window.len() > self.lookback {
+            let mut start = self.window.len() - self.lookback;
+            while !self.window.is_char_boundary(start) {
+                start += 1;
+            }
+            self.window.drain(..start);
+            self.checked = self.checked.saturating_sub(start);
+        }
+
+        found
+    }
+
+    /// Mark the end of the text, returning the match held back by the last [`push`](Self::push), if any.
+    /// The text seen so far is forgotten afterwards.
+    pub fn finish(&mut self) -> Option<StopMatch> {
+        let found = self
+            .find()
+            .map(|(pattern, _)| StopMatch { pattern, end: 0 });
+        self.clear();
+        found
+    }
+
+    /// Forget the text seen so far.
+    pub fn clear(&mut self) {
+        self.window.clear();
+        self.checked = 0;
+    }
+}
+
+fn par_map<T: Sync, U: Send>(items: &[T], f: impl Fn(&T) -> U + Send + Sync) -> Vec<U> {
+    #[cfg(feature = "parallel")]
+    {
+        use rayon::prelude::*;
+        items.par_iter().map(f).collect()
+    }
+    #[cfg(not(feature = "parallel"))]
+    {
+        items.iter().map(f).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::{DecodeStream, StopMatch, StopPatterns, Tokenizer};
+
+    #[test]
+    fn test_decode_stream() -> Result<()> {
+        let vocab = std::fs::read_to_string("assets/rwkv_vocab_v20230424.json")?;
+        let tokenizer = Tokenizer::new(&vocab)?;
+
+        let input = "你好，世界！ Hello 🦀";
+        let tokens = tokenizer.encode(input.as_bytes())?;
+
+        // split multi-byte characters into byte tokens
+        let tokens: Vec<u16> = tokens
+            .into_iter()
+            .flat_map(|token| tokenizer.decode(&[token]).unwrap())
+            .map(|byte| tokenizer.encode(&[byte]).unwrap()[0])
+            .collect();
+
+        let mut stream = DecodeStream::new(&tokenizer);
+        let mut bytes = vec![];
+        let mut text = String::new();
+        for token in tokens {
+            let output = stream.push(&[token])?;
+            bytes.extend(output.bytes);
+            text.push_str(&output.text);
+        }
+        assert!(stream.pending().is_empty());
+        assert_eq!(bytes, input.as_bytes());
+        assert_eq!(text, input);
+
+        let mut stream = DecodeStream::new(&tokenizer);
+        let token = tokenizer.encode(&[0xe4])?;
+        assert_eq!(stream.push(&token)?.text, "");
+        assert_eq!(stream.pending(), &[0xe4]);
+        assert_eq!(stream.finish(), "\u{fffd}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_batch() -> Result<()> {
+        let vocab = std::fs::read_to_string("assets/rwkv_vocab_v20230424.json")?;
+        let tokenizer = Tokenizer::new(&vocab)?;
+
+        let inputs = ["Hello, world!", "你好，世界！", ""];
+        let batch = tokenizer.encode_batch(&inputs)?;
+        for (input, tokens) in inputs.iter().zip(batch.iter()) {
+            assert_eq!(&tokenizer.encode(input.as_bytes())?, tokens);
+        }
+
+        let decoded = tokenizer.decode_batch(&batch)?;
+        for (input, output) in inputs.iter().zip(decoded.iter()) {
+            assert_eq!(input.as_bytes(), output);
+        }
+
+        let offsets = tokenizer.encode_batch_with_offsets(&inputs)?;
+        for (input, (tokens, offsets)) in inputs.iter().zip(offsets.iter()) {
+            assert_eq!(tokens.len(), offsets.len());
+            for (&token, range) in tokens.iter().zip(offsets.iter()) {
+                let bytes = tokenizer.decode(&[token])?;
+                assert_eq!(&input.as_bytes()[range.clone()], &bytes[..]);
+            }
+            assert_eq!(offsets.last().map_or(0, |range| range.end), input.len());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_stop_patterns() -> Result<()> {
+        // stop at the end of the next sentence, which spans several steps
+        let mut stop = StopPatterns::new([r"[.!?。！？](\s|$)", "User:"], 16)?;
+        assert_eq!(stop.push("Hello wor"), None);
+        assert_eq!(stop.push("ld"), None);
+        assert_eq!(stop.push("! How"), Some(StopMatch { pattern: 0, end: 2 }));
+
+        // a match reaching the end of the text is held back, since `$` may not be the end yet
+        stop.clear();
+        assert_eq!(stop.push("你好，世界"), None);
+        assert_eq!(stop.push("！"), None);
+        assert_eq!(stop.push("\nHi"), Some(StopMatch { pattern: 0, end: 1 }));
+
+        stop.clear();
+        assert_eq!(stop.push("你好，世界！"), None);
+        assert_eq!(stop.finish(), Some(StopMatch { pattern: 0, end: 0 }));
+        assert_eq!(stop.finish(), None);
+
+        // a held back match that turns out complete ends before the next text
+        assert_eq!(stop.push("User:"), None);
+        assert_eq!(stop.push(" Hi"), Some(StopMatch { pattern: 1, end: 0 }));
+
+        // optional tails are taken when the text goes on
+        let mut stop = StopPatterns::new(["Users?"], 16)?;
+        assert_eq!(stop.push("User"), None);
+        assert_eq!(stop.push("s are"), Some(StopMatch { pattern: 0, end: 1 }));
+        assert_eq!(stop.push(" Users"), None);
+        assert_eq!(stop.push("."), Some(StopMatch { pattern: 0, end: 0 }));
+
+        // matches longer than the lookback are not found
+        let mut stop = StopPatterns::new(["Us+er:"], 4)?;
+        assert_eq!(stop.push("Us"), None);
+        assert_eq!(stop.push("sss"), None);
+        assert_eq!(stop.push("er:"), None);
+
+        Ok(())
+    }
+}